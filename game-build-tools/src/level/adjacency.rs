@@ -1,4 +1,4 @@
-const ADJACENCY_RULES: &[&[u8]] = &[
+pub(crate) const ADJACENCY_RULES: &[&[u8]] = &[
     &[0, 0, 0, 0, 1, 1, 0, 1, 1],
     &[0, 1, 1, 0, 1, 1, 0, 1, 1],
     &[0, 1, 1, 0, 1, 1, 0, 0, 0],
@@ -48,6 +48,9 @@ const ADJACENCY_RULES: &[&[u8]] = &[
     &[1, 1, 0, 1, 1, 1, 0, 1, 0],
 ];
 
+/// Number of tiles an autotile layout needs, one per adjacency rule.
+pub const AUTOTILE_RULE_COUNT: usize = ADJACENCY_RULES.len();
+
 pub fn fix_rule(rule: &[u8]) -> [u8; 9] {
     let mut fixed_rule = [0; 9];
     for (i, &value) in rule.iter().enumerate() {
@@ -80,29 +83,79 @@ pub fn rule_complexity(rule: &[u8]) -> usize {
     rule.iter().filter(|&&x| x > 0).count()
 }
 
-/// Returns the index of the matching rule with most complexity.
-pub fn match_adjacency_rule(neighborhood: &[bool; 9]) -> Option<usize> {
-    let mut max_complexity = 0;
-    let mut best_match = None;
-    for (i, rule) in ADJACENCY_RULES.iter().enumerate() {
-        let fixed_rule = fix_rule(rule);
-        if neighborhood
-            .iter()
-            .zip(fixed_rule)
-            .all(|(&neighbor, rule)| {
-                if rule == 2 {
-                    true
-                } else {
-                    neighbor == (rule == 1)
-                }
-            })
-        {
+fn rule_matches(rule: &[u8], neighborhood: &[bool; 9]) -> bool {
+    neighborhood
+        .iter()
+        .zip(fix_rule(rule))
+        .all(|(&neighbor, rule)| {
+            if rule == 2 {
+                true
+            } else {
+                neighbor == (rule == 1)
+            }
+        })
+}
+
+/// Returns the index of the matching rule with most complexity. Ties are
+/// broken in favor of the rule that comes first in `rules`.
+pub fn match_rule(rules: &[&[u8]], neighborhood: &[bool; 9]) -> Option<usize> {
+    let mut best_match: Option<(usize, usize)> = None;
+    for (i, rule) in rules.iter().enumerate() {
+        if rule_matches(rule, neighborhood) {
             let complexity = rule_complexity(rule);
-            if complexity >= max_complexity {
-                max_complexity = complexity;
-                best_match = Some(i);
+            if best_match.is_none_or(|(_, max_complexity)| complexity > max_complexity) {
+                best_match = Some((i, complexity));
             }
         }
     }
-    best_match
+    best_match.map(|(i, _)| i)
+}
+
+/// Lists every neighborhood matched by several rules sharing the highest
+/// complexity, along with the indices of the tied rules. For these the
+/// result of [`match_rule`] depends on rule order.
+pub fn ambiguous_neighborhoods(rules: &[&[u8]]) -> Vec<([bool; 9], Vec<usize>)> {
+    let mut ambiguous = Vec::new();
+    for bits in 0..(1u32 << 9) {
+        let neighborhood: [bool; 9] = std::array::from_fn(|i| bits & (1 << i) != 0);
+        let Some(best) = match_rule(rules, &neighborhood) else {
+            continue;
+        };
+        let max_complexity = rule_complexity(rules[best]);
+        let tied: Vec<usize> = (0..rules.len())
+            .filter(|&i| {
+                rule_complexity(rules[i]) == max_complexity && rule_matches(rules[i], &neighborhood)
+            })
+            .collect();
+        if tied.len() > 1 {
+            ambiguous.push((neighborhood, tied));
+        }
+    }
+    ambiguous
+}
+
+/// Returns the index of the matching adjacency rule with most complexity.
+pub fn match_adjacency_rule(neighborhood: &[bool; 9]) -> Option<usize> {
+    match_rule(ADJACENCY_RULES, neighborhood)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ties_go_to_the_first_rule() {
+        // Both rules match a lone tile with its right neighbor, with equal complexity
+        let rules: &[&[u8]] = &[&[0, 0, 0, 0, 1, 1, 0, 0, 0], &[0, 0, 0, 0, 1, 1, 0, 0, 0]];
+        let neighborhood = [false, false, false, false, true, true, false, false, false];
+        assert_eq!(match_rule(rules, &neighborhood), Some(0));
+        let ambiguous = ambiguous_neighborhoods(rules);
+        assert!(ambiguous.iter().any(|(n, _)| *n == neighborhood));
+        assert!(ambiguous.iter().all(|(_, tied)| *tied == vec![0, 1]));
+    }
+
+    #[test]
+    fn adjacency_rules_are_unambiguous() {
+        assert!(ambiguous_neighborhoods(ADJACENCY_RULES).is_empty());
+    }
 }
@@ -0,0 +1,88 @@
+//! What the ids in a level's collision layer mean, shared by the room
+//! generator writing the layer and the game reading it.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DoorDirection {
+    Down,
+    Right,
+    Up,
+    Left,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CollisionKind {
+    Wall,
+    Door(DoorDirection),
+}
+
+impl CollisionKind {
+    /// Kind of a nonzero collision id. Zero, and ids nothing uses, are `None`.
+    pub fn from_id(id: u32) -> Option<Self> {
+        match id {
+            1 => Some(CollisionKind::Wall),
+            2 => Some(CollisionKind::Door(DoorDirection::Down)),
+            3 => Some(CollisionKind::Door(DoorDirection::Right)),
+            4 => Some(CollisionKind::Door(DoorDirection::Up)),
+            5 => Some(CollisionKind::Door(DoorDirection::Left)),
+            _ => None,
+        }
+    }
+
+    pub fn id(self) -> u32 {
+        match self {
+            CollisionKind::Wall => 1,
+            CollisionKind::Door(DoorDirection::Down) => 2,
+            CollisionKind::Door(DoorDirection::Right) => 3,
+            CollisionKind::Door(DoorDirection::Up) => 4,
+            CollisionKind::Door(DoorDirection::Left) => 5,
+        }
+    }
+}
+
+impl DoorDirection {
+    /// Step in room coordinates taken by walking through the door. Room
+    /// coordinates grow upwards, unlike tile coordinates.
+    pub fn room_offset(self) -> (i32, i32, i32) {
+        match self {
+            DoorDirection::Down => (0, -1, 0),
+            DoorDirection::Right => (1, 0, 0),
+            DoorDirection::Up => (0, 1, 0),
+            DoorDirection::Left => (-1, 0, 0),
+        }
+    }
+
+    pub fn opposite(self) -> Self {
+        match self {
+            DoorDirection::Down => DoorDirection::Up,
+            DoorDirection::Right => DoorDirection::Left,
+            DoorDirection::Up => DoorDirection::Down,
+            DoorDirection::Left => DoorDirection::Right,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn door_ids_lead_where_they_always_have() {
+        let doors = [
+            (2, DoorDirection::Down, (0, -1, 0)),
+            (3, DoorDirection::Right, (1, 0, 0)),
+            (4, DoorDirection::Up, (0, 1, 0)),
+            (5, DoorDirection::Left, (-1, 0, 0)),
+        ];
+        for (id, direction, offset) in doors {
+            assert_eq!(
+                CollisionKind::from_id(id),
+                Some(CollisionKind::Door(direction))
+            );
+            assert_eq!(CollisionKind::Door(direction).id(), id);
+            assert_eq!(direction.room_offset(), offset);
+        }
+        assert_eq!(CollisionKind::from_id(1), Some(CollisionKind::Wall));
+        assert_eq!(CollisionKind::from_id(0), None);
+        assert_eq!(CollisionKind::from_id(6), None);
+    }
+}
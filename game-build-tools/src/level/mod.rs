@@ -1,4 +1,14 @@
 mod adjacency;
+mod collision_kind;
+mod room;
+mod tile_grid;
+mod tile_rng;
+
+pub use adjacency::AUTOTILE_RULE_COUNT;
+pub use collision_kind::{CollisionKind, DoorDirection};
+pub use room::{GeneratedRoom, RoomConfig, TILE_SIZE, generate_room};
+pub use tile_grid::TileGrid;
+pub use tile_rng::stable_tile_hash;
 
 use std::{
     collections::{HashMap, HashSet},
@@ -6,10 +16,12 @@ use std::{
     ops::RangeInclusive,
 };
 
-use image::{GenericImage, GenericImageView, RgbImage, RgbaImage};
-use ndarray::Array2;
+use image::{GenericImage, GenericImageView, RgbImage, Rgba, RgbaImage};
+pub use ndarray::Array2;
+use ndarray::{Axis, s};
+use rand::{SeedableRng, rngs::StdRng};
 
-use crate::level::adjacency::match_adjacency_rule;
+use crate::level::adjacency::{ADJACENCY_RULES, ambiguous_neighborhoods, match_adjacency_rule};
 
 macro_rules! build_log {
     ($($arg:tt)*) => {
@@ -74,6 +86,20 @@ pub enum AbyssPolicy {
     PadWithAir,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArrowDirection {
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+/// How a single tile is marked in a debug overlay.
+pub struct DebugMark {
+    pub tint: Rgba<u8>,
+    pub arrow: Option<(ArrowDirection, Rgba<u8>)>,
+}
+
 impl Neighborhood7x7 {
     /// Get a value from the neighborhood using relative coordinates
     /// (0, 0) is the center, (-3, -3) is top-left, (3, 3) is bottom-right
@@ -146,6 +172,8 @@ pub struct TileSheet {
     num_tiles: (usize, usize),
     tile_mapping: HashMap<u32, (usize, usize)>,
     tile_inv_mapping: HashMap<(usize, usize), u32>,
+    // Cells covered by tiles larger than one cell, (width, height)
+    tile_spans: HashMap<u32, (usize, usize)>,
 }
 
 impl TileSheet {
@@ -155,6 +183,7 @@ impl TileSheet {
             num_tiles,
             tile_mapping: HashMap::new(),
             tile_inv_mapping: HashMap::new(),
+            tile_spans: HashMap::new(),
         }
     }
 
@@ -162,6 +191,16 @@ impl TileSheet {
         self.tile_mapping.len()
     }
 
+    /// Tiles across and down the sheet
+    pub fn num_tiles(&self) -> (usize, usize) {
+        self.num_tiles
+    }
+
+    /// Where `tile_id` is in the sheet, in tiles
+    pub fn tile_position(&self, tile_id: u32) -> Option<(usize, usize)> {
+        self.tile_mapping.get(&tile_id).copied()
+    }
+
     pub fn new_with_tile_size(image: RgbaImage, tile_size: (u32, u32)) -> Self {
         let num_tiles = (
             image.width() as usize / tile_size.0 as usize,
@@ -172,6 +211,7 @@ impl TileSheet {
             num_tiles,
             tile_mapping: HashMap::new(),
             tile_inv_mapping: HashMap::new(),
+            tile_spans: HashMap::new(),
         }
     }
 
@@ -191,6 +231,34 @@ impl TileSheet {
         tile_sheet
     }
 
+    /// Registers a tile whose image is `span` (width, height) tiles of the
+    /// sheet starting at `position`. Rendered, it covers as many cells to the
+    /// right of and below the cell it's in, whatever those cells hold.
+    pub fn register_large(
+        self,
+        tile_id: u32,
+        position: (usize, usize),
+        span: (usize, usize),
+    ) -> Self {
+        if span.0 == 0 || span.1 == 0 {
+            panic!("Tile span {:?} must be at least one tile", span);
+        }
+        if position.0 + span.0 > self.num_tiles.0 || position.1 + span.1 > self.num_tiles.1 {
+            panic!(
+                "Tile of {:?} tiles at {:?} does not fit in a sheet of {:?} tiles",
+                span, position, self.num_tiles
+            );
+        }
+        let mut tile_sheet = self.register(tile_id, position);
+        tile_sheet.tile_spans.insert(tile_id, span);
+        tile_sheet
+    }
+
+    /// Cells covered by `tile_id`, (width, height)
+    pub fn tile_span(&self, tile_id: u32) -> (usize, usize) {
+        self.tile_spans.get(&tile_id).copied().unwrap_or((1, 1))
+    }
+
     pub fn allocate_tile_id(&mut self, position: (usize, usize)) -> u32 {
         if self.tile_inv_mapping.contains_key(&position) {
             return *self.tile_inv_mapping.get(&position).unwrap();
@@ -212,8 +280,14 @@ impl TileSheet {
 
             let x_start = x as u32 * tile_width;
             let y_start = y as u32 * tile_height;
+            let (span_x, span_y) = self.tile_span(tile_id);
 
-            let result = self.image.view(x_start, y_start, tile_width, tile_height);
+            let result = self.image.view(
+                x_start,
+                y_start,
+                tile_width * span_x as u32,
+                tile_height * span_y as u32,
+            );
             Some(result)
         } else {
             None
@@ -232,6 +306,7 @@ impl TileSheet {
             num_tiles: self.num_tiles,
             tile_mapping: HashMap::new(),
             tile_inv_mapping: HashMap::new(),
+            tile_spans: HashMap::new(),
         }
     }
 
@@ -241,10 +316,15 @@ impl TileSheet {
             num_tiles: self.num_tiles,
             tile_mapping: self.tile_mapping.clone(),
             tile_inv_mapping: self.tile_inv_mapping.clone(),
+            tile_spans: self.tile_spans.clone(),
         }
     }
 
-    pub fn canonical_autotile(&self, (start_x, start_y): (u32, u32), air: (u32, u32)) -> Self {
+    pub fn canonical_autotile(
+        &self,
+        (start_x, start_y): (u32, u32),
+        air: (u32, u32),
+    ) -> Result<Self, String> {
         // Canonical autotile defines an autotile region which is always 10 rows
         // and 5 cols rows, starting from the given position like so:
         // 0 5 10 ..
@@ -252,16 +332,48 @@ impl TileSheet {
         // 2 7 12 ..
         // 3 8 13 ..
         // 4 9 14 ..
+        let (num_cols, num_rows) = self.num_tiles;
+        if start_x as usize + 10 > num_cols || start_y as usize + 5 > num_rows {
+            return Err(format!(
+                "Autotile block of 10x5 tiles at {:?} does not fit in a sheet of {}x{} tiles",
+                (start_x, start_y),
+                num_cols,
+                num_rows
+            ));
+        }
+
+        let positions =
+            std::array::from_fn(|i| (start_x as usize + i / 5, start_y as usize + i % 5));
+        self.autotile_from_layout(&positions, (air.0 as usize, air.1 as usize))
+    }
+
+    /// Builds an autotile sheet where the tile for adjacency rule `i` sits at
+    /// `positions[i]`, so it renders layers produced by `canonical_adjacency`.
+    /// `air` is used wherever no rule matches.
+    pub fn autotile_from_layout(
+        &self,
+        positions: &[(usize, usize); AUTOTILE_RULE_COUNT],
+        air: (usize, usize),
+    ) -> Result<Self, String> {
+        let (num_cols, num_rows) = self.num_tiles;
         let mut autotile = self.clean_clone();
 
-        autotile.allocate_tile_id((air.0 as usize, air.1 as usize));
-        for col in 0..10 {
-            for row in 0..5 {
-                let position = (start_x as usize + col, start_y as usize + row);
-                autotile.allocate_tile_id(position);
+        for &position in std::iter::once(&air).chain(positions) {
+            if position.0 >= num_cols || position.1 >= num_rows {
+                return Err(format!(
+                    "Autotile position {:?} is outside a sheet of {}x{} tiles",
+                    position, num_cols, num_rows
+                ));
             }
+            if autotile.tile_inv_mapping.contains_key(&position) {
+                return Err(format!(
+                    "Autotile position {:?} is used more than once",
+                    position
+                ));
+            }
+            autotile.allocate_tile_id(position);
         }
-        autotile
+        Ok(autotile)
     }
 
     pub fn contiguous_tiles(
@@ -300,6 +412,11 @@ impl LevelLayer {
         Self { data }
     }
 
+    /// Wraps a grid computed with the generic `*_to` operations back into a layer.
+    pub fn from_array(data: Array2<u32>) -> Self {
+        Self { data }
+    }
+
     pub fn hardcoded(self, data: &[u32]) -> Self {
         let mut layer = self;
         assert!(
@@ -313,21 +430,35 @@ impl LevelLayer {
         layer
     }
 
+    /// The grid of this layer's cells, drawn with tiles of `tile_size`
+    pub fn grid(&self, tile_size: (u32, u32)) -> TileGrid {
+        TileGrid::with_tile_size((self.data.ncols(), self.data.nrows()), tile_size)
+    }
+
     pub fn render(&self, tile_sheet: &TileSheet) -> Result<RgbaImage, String> {
-        let (tile_width, tile_height) = tile_sheet.implied_tile_size();
-        let mut image = RgbaImage::new(
-            (self.data.ncols() * tile_width as usize) as u32,
-            (self.data.nrows() * tile_height as usize) as u32,
-        );
+        let grid = self.grid(tile_sheet.implied_tile_size());
+        let (width, height) = grid.pixel_size();
+        let mut image = RgbaImage::new(width, height);
+
+        // Cells under a large tile placed earlier, which aren't drawn
+        let mut covered = Array2::from_elem(self.data.dim(), false);
 
         for (y, row) in self.data.outer_iter().enumerate() {
             for (x, &tile_id) in row.iter().enumerate() {
+                if covered[[y, x]] {
+                    continue;
+                }
                 if let Some(tile_image) = tile_sheet.grab_tile(tile_id) {
-                    let x_start = x as u32 * tile_width;
-                    let y_start = y as u32 * tile_height;
+                    let (x_start, y_start) = grid.tile_to_pixel((x, y));
                     image
                         .copy_from(&tile_image.to_image(), x_start, y_start)
-                        .expect("Failed to copy tile image to level layer image");
+                        .map_err(|_| {
+                            format!("Tile ID {} at ({}, {}) overflows the level", tile_id, x, y)
+                        })?;
+                    let (span_x, span_y) = tile_sheet.tile_span(tile_id);
+                    covered
+                        .slice_mut(s![y..y + span_y, x..x + span_x])
+                        .fill(true);
                 } else {
                     return Err(format!("Tile ID {} not found in tile sheet", tile_id));
                 }
@@ -351,59 +482,85 @@ impl LevelLayer {
         new_layer
     }
 
-    pub fn convolve<F: Fn(&Neighborhood7x7) -> u32>(&self, func: F) -> LevelLayer {
-        let mut new_layer = LevelLayer::new(self.data.ncols(), self.data.nrows());
+    pub fn map_to<T, F: Fn(u32) -> T>(&self, func: F) -> Array2<T> {
+        self.data.map(|&tile_id| func(tile_id))
+    }
+
+    pub fn convolve_to<T, F: Fn(&Neighborhood7x7) -> T>(&self, func: F) -> Array2<T> {
         let (rows, cols) = (self.data.nrows(), self.data.ncols());
 
-        for y in 0..rows {
-            for x in 0..cols {
-                let mut neighborhood = Neighborhood7x7::default();
-
-                // Fill the 7x7 neighborhood
-                for dy in -3..=3 {
-                    for dx in -3..=3 {
-                        let ny = y as isize + dy;
-                        let nx = x as isize + dx;
-
-                        // Check if the neighbor position is within bounds
-                        if ny >= 0 && ny < rows as isize && nx >= 0 && nx < cols as isize {
-                            neighborhood.set(
-                                dx as i32,
-                                dy as i32,
-                                Some(self.data[[ny as usize, nx as usize]]),
-                            );
-                        }
+        Array2::from_shape_fn((rows, cols), |(y, x)| {
+            let mut neighborhood = Neighborhood7x7::default();
+
+            // Fill the 7x7 neighborhood
+            for dy in -3..=3 {
+                for dx in -3..=3 {
+                    let ny = y as isize + dy;
+                    let nx = x as isize + dx;
+
+                    // Check if the neighbor position is within bounds
+                    if ny >= 0 && ny < rows as isize && nx >= 0 && nx < cols as isize {
+                        neighborhood.set(
+                            dx as i32,
+                            dy as i32,
+                            Some(self.data[[ny as usize, nx as usize]]),
+                        );
                     }
                 }
-
-                // Apply the convolution function with the clean interface
-                let result = func(&neighborhood);
-                new_layer.data[[y, x]] = result;
             }
-        }
 
-        new_layer
+            // Apply the convolution function with the clean interface
+            func(&neighborhood)
+        })
     }
 
-    pub fn zip_with<F: Fn(u32, u32) -> u32>(&self, other: &LevelLayer, func: F) -> LevelLayer {
+    pub fn convolve<F: Fn(&Neighborhood7x7) -> u32>(&self, func: F) -> LevelLayer {
+        LevelLayer::from_array(self.convolve_to(func))
+    }
+
+    pub fn zip_with_to<T, F: Fn(u32, u32) -> T>(&self, other: &LevelLayer, func: F) -> Array2<T> {
         assert_eq!(
             self.data.shape(),
             other.data.shape(),
             "Layers must have the same shape"
         );
-        let mut new_layer = LevelLayer::new(self.data.ncols(), self.data.nrows());
+        ndarray::Zip::from(&self.data)
+            .and(&other.data)
+            .map_collect(|&tile_id, &other_tile_id| func(tile_id, other_tile_id))
+    }
 
-        for (y, row) in self.data.outer_iter().enumerate() {
-            for (x, &tile_id) in row.iter().enumerate() {
-                let other_tile_id = other.data[[y, x]];
-                new_layer.data[[y, x]] = func(tile_id, other_tile_id);
+    pub fn zip_with<F: Fn(u32, u32) -> u32>(&self, other: &LevelLayer, func: F) -> LevelLayer {
+        LevelLayer::from_array(self.zip_with_to(other, func))
+    }
+
+    /// Euclidean distance, in tiles, from every cell to the nearest cell whose
+    /// value satisfies `predicate`. Uses the exact two-pass transform of
+    /// Felzenszwalb and Huttenlocher. Cells are infinitely far away when no
+    /// cell satisfies the predicate.
+    pub fn distance_field<F: Fn(u32) -> bool>(&self, predicate: F) -> Array2<f32> {
+        let mut squared = self.map_to(|tile_id| {
+            if predicate(tile_id) {
+                0.0
+            } else {
+                f32::INFINITY
+            }
+        });
+        for axis in [Axis(1), Axis(0)] {
+            for mut lane in squared.lanes_mut(axis) {
+                let transformed = squared_distance_1d(&lane.to_vec());
+                for (value, new_value) in lane.iter_mut().zip(transformed) {
+                    *value = new_value;
+                }
             }
         }
-
-        new_layer
+        squared.mapv_into(f32::sqrt)
     }
 
     pub fn canonical_adjacency(&self, pad_with_adjacent: bool) -> LevelLayer {
+        debug_assert!(
+            ambiguous_neighborhoods(ADJACENCY_RULES).is_empty(),
+            "Adjacency rules have ambiguous complexity ties"
+        );
         self.convolve(|neighborhood| {
             let get_at = |dx, dy| -> bool {
                 if let Some(value) = neighborhood.get(dx, dy) {
@@ -457,6 +614,70 @@ impl LevelLayer {
         new_layer
     }
 
+    /// Like `fill_with`, handing `func` a single RNG seeded with `seed`. Cells
+    /// are visited row by row, so the same seed and layer size always fill
+    /// the same way.
+    pub fn fill_with_rng<F: FnMut(usize, usize, &mut StdRng) -> u32>(
+        &self,
+        seed: u64,
+        mut func: F,
+    ) -> LevelLayer {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut new_layer = LevelLayer::new(self.data.ncols(), self.data.nrows());
+        for ((y, x), value) in new_layer.data.indexed_iter_mut() {
+            *value = func(x, y, &mut rng);
+        }
+        new_layer
+    }
+
+    /// Renders a transparent image with every tile marked by `mark` tinted,
+    /// optionally with an arrow drawn on top. Meant to be blended over the
+    /// rendered level with `alpha_blend_new`.
+    pub fn render_debug_overlay<F: Fn(u32) -> Option<DebugMark>>(
+        &self,
+        tile_size: (u32, u32),
+        mark: F,
+    ) -> RgbaImage {
+        let grid = self.grid(tile_size);
+        let (tile_width, tile_height) = tile_size;
+        let (width, height) = grid.pixel_size();
+        let mut image = RgbaImage::new(width, height);
+
+        for (y, row) in self.data.outer_iter().enumerate() {
+            for (x, &tile_id) in row.iter().enumerate() {
+                let Some(DebugMark { tint, arrow }) = mark(tile_id) else {
+                    continue;
+                };
+                let (x_start, y_start) = grid.tile_to_pixel((x, y));
+                for dy in 0..tile_height {
+                    for dx in 0..tile_width {
+                        // Tile-local coordinates in [-1, 1]
+                        let u = (dx as f32 + 0.5) / tile_width as f32 * 2.0 - 1.0;
+                        let v = (dy as f32 + 0.5) / tile_height as f32 * 2.0 - 1.0;
+                        let color = match arrow {
+                            Some((direction, color)) if Self::in_arrow(direction, u, v) => color,
+                            _ => tint,
+                        };
+                        image.put_pixel(x_start + dx, y_start + dy, color);
+                    }
+                }
+            }
+        }
+
+        image
+    }
+
+    fn in_arrow(direction: ArrowDirection, u: f32, v: f32) -> bool {
+        // Rotate into the frame of an arrow pointing up
+        let (u, v) = match direction {
+            ArrowDirection::Up => (u, v),
+            ArrowDirection::Down => (u, -v),
+            ArrowDirection::Left => (v, u),
+            ArrowDirection::Right => (v, -u),
+        };
+        (-0.5..=0.5).contains(&v) && u.abs() <= (v + 0.5) * 0.5
+    }
+
     pub fn dump_csv(&self, path: &str) -> Result<(), Box<dyn Error>> {
         let mut csv_data = String::new();
         for (i, row) in self.data.outer_iter().enumerate() {
@@ -472,6 +693,52 @@ impl LevelLayer {
     }
 }
 
+/// One-dimensional squared distance transform: the lower envelope of the
+/// parabolas rooted at every finite sample of `f`.
+fn squared_distance_1d(f: &[f32]) -> Vec<f32> {
+    // Roots of the parabolas in the envelope, and the left boundary of each
+    let mut roots: Vec<usize> = Vec::new();
+    let mut boundaries: Vec<f32> = Vec::new();
+
+    let intersection = |p: usize, q: usize| {
+        let (p_f, q_f) = (p as f32, q as f32);
+        ((f[q] + q_f * q_f) - (f[p] + p_f * p_f)) / (2.0 * (q_f - p_f))
+    };
+
+    for q in (0..f.len()).filter(|&q| f[q].is_finite()) {
+        let mut boundary = f32::NEG_INFINITY;
+        while let Some(&p) = roots.last() {
+            boundary = intersection(p, q);
+            if boundary <= *boundaries.last().unwrap() {
+                roots.pop();
+                boundaries.pop();
+            } else {
+                break;
+            }
+        }
+        if roots.is_empty() {
+            boundary = f32::NEG_INFINITY;
+        }
+        roots.push(q);
+        boundaries.push(boundary);
+    }
+
+    if roots.is_empty() {
+        return vec![f32::INFINITY; f.len()];
+    }
+
+    let mut k = 0;
+    (0..f.len())
+        .map(|q| {
+            while k + 1 < roots.len() && boundaries[k + 1] < q as f32 {
+                k += 1;
+            }
+            let offset = q as f32 - roots[k] as f32;
+            offset * offset + f[roots[k]]
+        })
+        .collect()
+}
+
 type Color = (u8, u8, u8);
 type TilePosition = (u32, u32);
 type ColorMapEntry = (Color, TilePosition);
@@ -479,6 +746,7 @@ type ColorMapEntry = (Color, TilePosition);
 pub struct LevelSpec {
     layout: RgbImage,
     color_map: Vec<ColorMapEntry>,
+    ignored_colors: Vec<Color>,
     tile_size: (u32, u32),
     tileset: RgbaImage,
 }
@@ -488,6 +756,7 @@ impl LevelSpec {
         Self {
             layout,
             color_map: Vec::new(),
+            ignored_colors: Vec::new(),
             tile_size,
             tileset,
         }
@@ -495,7 +764,7 @@ impl LevelSpec {
 
     pub fn register(self, color: (u8, u8, u8), tile_id: (u32, u32)) -> Self {
         let mut spec = self;
-        if spec.color_map.iter().any(|&(c, _)| c == color) {
+        if spec.color_map.iter().any(|&(c, _)| c == color) || spec.ignored_colors.contains(&color) {
             panic!("Color {:?} already registered", color);
         }
         if spec.color_map.iter().any(|&(_, t)| t == tile_id) {
@@ -505,10 +774,23 @@ impl LevelSpec {
         spec
     }
 
+    /// Pixels of this color compile to tile 0, the first color registered
+    /// (air, by convention), so layouts can carry annotations or padding
+    /// that isn't part of the level.
+    pub fn ignore(self, color: (u8, u8, u8)) -> Self {
+        let mut spec = self;
+        if spec.color_map.iter().any(|&(c, _)| c == color) || spec.ignored_colors.contains(&color) {
+            panic!("Color {:?} already registered", color);
+        }
+        spec.ignored_colors.push(color);
+        spec
+    }
+
     pub fn compile(self) -> Result<(TileSheet, LevelLayer), String> {
         let LevelSpec {
             layout,
             color_map,
+            ignored_colors,
             tile_size,
             tileset,
         } = self;
@@ -524,6 +806,9 @@ impl LevelSpec {
 
         for (y, row) in layout.rows().enumerate() {
             for (x, pixel) in row.enumerate() {
+                if ignored_colors.contains(&(pixel[0], pixel[1], pixel[2])) {
+                    continue; // Left as air
+                }
                 if let Some(tile_id) = {
                     color_map.iter().find_map(|&(c, t)| {
                         if c == (pixel[0], pixel[1], pixel[2]) {
@@ -556,3 +841,168 @@ impl LevelSpec {
         Ok((tile_sheet, layer))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn convolve_to_computes_f32_distances() {
+        let layer = LevelLayer::new(5, 4).hardcoded(&[
+            0, 0, 0, 0, 0, //
+            0, 0, 0, 0, 0, //
+            0, 0, 1, 0, 0, //
+            0, 0, 0, 0, 0, //
+        ]);
+        // Distance to the nearest marked cell within the neighborhood
+        let distances = layer.convolve_to(|neighborhood| {
+            neighborhood
+                .iter()
+                .filter(|&(_, value)| value == Some(1))
+                .map(|((dx, dy), _)| ((dx * dx + dy * dy) as f32).sqrt())
+                .fold(f32::INFINITY, f32::min)
+        });
+
+        assert_eq!(distances.dim(), (4, 5));
+        for ((y, x), &distance) in distances.indexed_iter() {
+            let expected = ((x as f32 - 2.0).powi(2) + (y as f32 - 2.0).powi(2)).sqrt();
+            assert!((distance - expected).abs() < 1e-6, "cell ({}, {})", x, y);
+        }
+
+        let near = layer.map_to(|tile_id| tile_id == 1);
+        let weights = layer.zip_with_to(&layer.ones_like(), |a, b| (a + b) as f32 * 0.5);
+        assert!(near[[2, 2]] && !near[[0, 0]]);
+        assert_eq!(weights[[2, 2]], 1.0);
+        assert_eq!(weights[[0, 0]], 0.5);
+    }
+
+    #[test]
+    fn distance_field_matches_brute_force() {
+        let mut layer = LevelLayer::new(9, 6);
+        let seeds = [(2, 1), (7, 4)];
+        for &(x, y) in &seeds {
+            layer.data[[y, x]] = 1;
+        }
+        let distances = layer.distance_field(|tile_id| tile_id == 1);
+
+        for ((y, x), &distance) in distances.indexed_iter() {
+            let expected = seeds
+                .iter()
+                .map(|&(sx, sy)| {
+                    ((x as f32 - sx as f32).powi(2) + (y as f32 - sy as f32).powi(2)).sqrt()
+                })
+                .fold(f32::INFINITY, f32::min);
+            assert!((distance - expected).abs() < 1e-4, "cell ({}, {})", x, y);
+        }
+
+        let empty = layer.distance_field(|tile_id| tile_id == 2);
+        assert!(empty.iter().all(|distance| distance.is_infinite()));
+    }
+
+    #[test]
+    fn canonical_autotile_rejects_out_of_bounds_blocks() {
+        let sheet = TileSheet::new(RgbaImage::new(12 * 4, 6 * 4), (12, 6));
+        assert!(sheet.canonical_autotile((2, 1), (0, 0)).is_ok());
+        assert!(sheet.canonical_autotile((2, 1), (0, 1)).is_ok());
+        assert!(sheet.canonical_autotile((3, 0), (0, 0)).is_err());
+        assert!(sheet.canonical_autotile((0, 2), (0, 0)).is_err());
+        assert!(sheet.canonical_autotile((0, 0), (12, 0)).is_err());
+        // Air overlapping the block would shift every rule's tile
+        assert!(sheet.canonical_autotile((0, 0), (0, 0)).is_err());
+    }
+
+    #[test]
+    fn autotile_from_layout_maps_rules_to_positions() {
+        let sheet = TileSheet::new(RgbaImage::new(8 * 4, 8 * 4), (8, 8));
+        // Row-major instead of the canonical column-major order
+        let positions = std::array::from_fn(|i| (i % 8, i / 8 + 1));
+        let autotile = sheet.autotile_from_layout(&positions, (0, 0)).unwrap();
+
+        assert_eq!(autotile.tile_mapping.get(&0), Some(&(0, 0)));
+        for (rule_index, position) in positions.iter().enumerate() {
+            // canonical_adjacency emits rule index + 1, leaving 0 for air
+            assert_eq!(
+                autotile.tile_mapping.get(&(rule_index as u32 + 1)),
+                Some(position)
+            );
+        }
+    }
+
+    #[test]
+    fn debug_overlay_marks_exactly_the_nonzero_tiles() {
+        let layer = LevelLayer::new(3, 2).hardcoded(&[0, 1, 0, 2, 0, 1]);
+        let overlay = layer.render_debug_overlay((4, 4), |tile_id| {
+            (tile_id != 0).then_some(DebugMark {
+                tint: Rgba([255, 0, 0, 96]),
+                arrow: (tile_id == 2).then_some((ArrowDirection::Down, Rgba([0, 0, 255, 255]))),
+            })
+        });
+
+        assert_eq!(overlay.dimensions(), (12, 8));
+        for (x, y, pixel) in overlay.enumerate_pixels() {
+            let tile_id = layer.data[[(y / 4) as usize, (x / 4) as usize]];
+            assert_eq!(pixel[3] > 0, tile_id != 0, "pixel ({}, {})", x, y);
+        }
+        // The door tile carries an arrow on top of its tint
+        assert!(
+            overlay
+                .view(0, 4, 4, 4)
+                .pixels()
+                .any(|(_, _, pixel)| pixel == Rgba([0, 0, 255, 255]))
+        );
+    }
+
+    #[test]
+    fn ignored_colors_compile_to_air() {
+        let mut layout = RgbImage::new(3, 1);
+        layout.put_pixel(0, 0, image::Rgb([255, 0, 0]));
+        layout.put_pixel(1, 0, image::Rgb([255, 0, 255]));
+        layout.put_pixel(2, 0, image::Rgb([0, 0, 0]));
+        let (_, layer) = LevelSpec::new(layout, RgbaImage::new(4, 4), (2, 2))
+            .register((0, 0, 0), (0, 0)) // air
+            .register((255, 0, 0), (1, 0)) // wall
+            .ignore((255, 0, 255))
+            .compile()
+            .expect("ignored colors aren't an error");
+        assert_eq!(layer.data.row(0).to_vec(), vec![1, 0, 0]);
+    }
+
+    #[test]
+    fn large_tiles_cover_several_cells() {
+        // 3x2 tiles of 2x2 pixels, each pixel colored by its position
+        let sheet_image = RgbaImage::from_fn(6, 4, |x, y| {
+            image::Rgba([x as u8 * 10, y as u8 * 10, 1, 255])
+        });
+        let sheet = TileSheet::new(sheet_image.clone(), (3, 2))
+            .register(0, (2, 1))
+            .register_large(1, (0, 0), (2, 2));
+        let layer = LevelLayer::new(4, 4).hardcoded(&[
+            0, 0, 0, 0, //
+            0, 1, 0, 0, //
+            0, 0, 0, 0, //
+            0, 0, 0, 0, //
+        ]);
+
+        let image = layer.render(&sheet).unwrap();
+        for y in 0..8 {
+            for x in 0..8 {
+                let expected = if (2..6).contains(&x) && (2..6).contains(&y) {
+                    *sheet_image.get_pixel(x - 2, y - 2)
+                } else {
+                    *sheet_image.get_pixel(4 + x % 2, 2 + y % 2)
+                };
+                assert_eq!(*image.get_pixel(x, y), expected, "pixel ({}, {})", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn fill_with_rng_is_reproducible() {
+        use rand::Rng;
+
+        let base = LevelLayer::new(6, 5);
+        let fill = |seed| base.fill_with_rng(seed, |_, _, rng| rng.random_range(0..100));
+        assert_eq!(fill(7).data, fill(7).data);
+        assert_ne!(fill(7).data, fill(8).data);
+    }
+}
@@ -0,0 +1,271 @@
+//! The whole pipeline turning a room layout into the images and grids the game
+//! draws and collides with. `build.rs` bakes rooms with it, and the game can
+//! run it on layouts it makes up at runtime.
+
+use image::{RgbImage, Rgba, RgbaImage};
+use rand::Rng;
+
+use crate::level::{
+    AbyssPolicy, ArrowDirection, CollisionKind, DebugMark, DoorDirection, LevelLayer, LevelSpec,
+    alpha_blend_new,
+};
+
+/// Size in pixels of the tiles rooms are drawn with. The game zooms its camera
+/// to match, so rooms baked with another size don't line up with their grids.
+pub const TILE_SIZE: (u32, u32) = (32, 32);
+
+pub struct RoomConfig {
+    /// Size in pixels of a tile of the tileset
+    pub tile_size: (u32, u32),
+    /// Seed for the floor tile variety
+    pub floor_seed: u64,
+}
+
+impl Default for RoomConfig {
+    fn default() -> Self {
+        Self {
+            tile_size: TILE_SIZE,
+            floor_seed: 0,
+        }
+    }
+}
+
+pub struct GeneratedRoom {
+    /// The layout drawn with one plain tile per cell
+    pub tiles: RgbaImage,
+    pub floor: RgbaImage,
+    /// The tile of the tileset drawn on each cell of `floor`, by its index
+    /// counting along the rows of the tileset
+    pub floor_tiles: LevelLayer,
+    /// Walls, ceilings and shadows, drawn over the floor
+    pub with_walls: RgbaImage,
+    /// `CollisionKind` ids, 0 where nothing collides
+    pub collision: LevelLayer,
+    /// Wave (starting at 1) of the enemy spawning at each cell, 0 for none
+    pub enemies: LevelLayer,
+    /// Floor and walls with collisions tinted and doors pointing where they lead
+    pub debug: RgbaImage,
+}
+
+fn debug_mark(kind: CollisionKind) -> DebugMark {
+    let door_mark = |direction| DebugMark {
+        tint: Rgba([0, 128, 255, 96]),
+        arrow: Some((direction, Rgba([0, 128, 255, 255]))),
+    };
+    match kind {
+        CollisionKind::Wall => DebugMark {
+            tint: Rgba([255, 0, 0, 96]),
+            arrow: None,
+        },
+        CollisionKind::Door(DoorDirection::Down) => door_mark(ArrowDirection::Down),
+        CollisionKind::Door(DoorDirection::Right) => door_mark(ArrowDirection::Right),
+        CollisionKind::Door(DoorDirection::Up) => door_mark(ArrowDirection::Up),
+        CollisionKind::Door(DoorDirection::Left) => door_mark(ArrowDirection::Left),
+    }
+}
+
+/// Generates a room from a `layout` where black is air, red is wall, yellow
+/// is door and blue is an enemy over air.
+pub fn generate_room(
+    layout: &RgbImage,
+    tileset: &RgbaImage,
+    config: &RoomConfig,
+) -> Result<GeneratedRoom, String> {
+    let (tile_sheet, level_layer) =
+        LevelSpec::new(layout.clone(), tileset.clone(), config.tile_size)
+            .register((0, 0, 0), (0, 2)) // air
+            .register((255, 0, 0), (0, 1)) // wall
+            .register((255, 255, 0), (0, 7)) // door
+            .register((0, 0, 255), (0, 0)) // enemy
+            .compile()?;
+
+    let enemies = level_layer.value_where(|v| v == 3, 1);
+    let level_layer = level_layer.zip_with(&enemies, |original, enemy| {
+        if enemy == 1 {
+            0 // Remove enemies from the level layer
+        } else {
+            original
+        }
+    });
+
+    let tiles = level_layer.render(&tile_sheet)?;
+
+    // Find the places where we should put front walls
+    let wall_locations = level_layer.convolve(|neighborhood| {
+        if neighborhood.get(0, 0) == Some(1)
+            && neighborhood.get(0, -1) == Some(1)
+            && neighborhood.get(0, 1) != Some(1)
+            && neighborhood.get(0, 1).is_some()
+        {
+            1
+        } else {
+            0
+        }
+    });
+
+    let ceiling_locations =
+        level_layer.zip_with(
+            &wall_locations,
+            |original, wall| {
+                if wall == 1 { 0 } else { original }
+            },
+        );
+
+    let ceiling_autotile_sheet = tile_sheet.canonical_autotile((1, 5), (0, 2))?;
+    let ceiling_autotile_layer = ceiling_locations.autotile_with(1, AbyssPolicy::PadWithSelf);
+
+    let ceiling_image = ceiling_autotile_layer.render(&ceiling_autotile_sheet)?;
+
+    let front_walls_image = wall_locations.render(&tile_sheet)?;
+
+    // Ambient occlusion
+    let ao_locations = ceiling_locations.convolve(|neighborhood| {
+        let top_value = neighborhood
+            .get(0, -1)
+            .unwrap_or(neighborhood.get(0, 0).unwrap());
+        if top_value == 1 { 0 } else { 1 }
+    });
+    let ao_autotile_sheet = tile_sheet.canonical_autotile((1, 0), (0, 2))?;
+    let ao_autotile_layer = ao_locations.autotile_with(1, AbyssPolicy::PadWithSelf);
+
+    // We want the ao to be hidden by the ceiling, so we can replace the ceiling layer
+    let ao_image = ao_autotile_layer.render(&ao_autotile_sheet)?;
+
+    let door_shadow_tiles = tile_sheet
+        .clean_clone()
+        .register(0, (0, 2))
+        .contiguous_tiles(&(0..=0), &(7..=10), false);
+
+    let door_shadow_layer = level_layer.convolve(|neighborhood| {
+        if neighborhood.get(0, 0) == Some(2) {
+            if neighborhood.get(0, 1).is_none() {
+                1
+            } else if neighborhood.get(1, 0).is_none() {
+                2
+            } else if neighborhood.get(0, -1).is_none() {
+                3
+            } else if neighborhood.get(-1, 0).is_none() {
+                4
+            } else {
+                0
+            }
+        } else {
+            0
+        }
+    });
+
+    let door_shadow_image = door_shadow_layer.render(&door_shadow_tiles)?;
+
+    let ao_image = alpha_blend_new(&ao_image, &door_shadow_image, 0, 0);
+
+    let ceiling_image = alpha_blend_new(&ao_image, &ceiling_image, 0, 0);
+
+    // Merge the images
+    let with_walls = alpha_blend_new(&front_walls_image, &ceiling_image, 0, 0);
+
+    let floor_tiles = tile_sheet.contiguous_tiles(&(0..=0), &(3..=6), true);
+
+    let floor_layer = level_layer
+        .ones_like()
+        .fill_with_rng(config.floor_seed, |_, _, rng| {
+            let tile_id = rng.random_range(1..(floor_tiles.count_registered_tiles() - 1));
+            tile_id as u32
+        });
+
+    let floor = floor_layer.render(&floor_tiles)?;
+    let tileset_columns = floor_tiles.num_tiles().0;
+    let floor_tile_indices = LevelLayer::from_array(floor_layer.map_to(|tile_id| {
+        let (x, y) = floor_tiles
+            .tile_position(tile_id)
+            .expect("Floor tiles are all registered");
+        (y * tileset_columns + x) as u32
+    }));
+
+    // The shadow is drawn on the side of the door facing out of the room
+    let collision = level_layer.zip_with(&door_shadow_layer, |original, door_shadow| {
+        let direction = match door_shadow {
+            1 => DoorDirection::Down,
+            2 => DoorDirection::Right,
+            3 => DoorDirection::Up,
+            4 => DoorDirection::Left,
+            _ => return original,
+        };
+        CollisionKind::Door(direction).id()
+    });
+
+    let debug_overlay = collision.render_debug_overlay(tile_sheet.implied_tile_size(), |tile_id| {
+        CollisionKind::from_id(tile_id).map(debug_mark)
+    });
+    let preview = alpha_blend_new(&floor, &with_walls, 0, 0);
+    let debug = alpha_blend_new(&preview, &debug_overlay, 0, 0);
+
+    Ok(GeneratedRoom {
+        tiles,
+        floor,
+        floor_tiles: floor_tile_indices,
+        with_walls,
+        collision,
+        enemies,
+        debug,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgb;
+
+    #[test]
+    fn small_room_has_matching_grids_and_images() {
+        let air = Rgb([0, 0, 0]);
+        let wall = Rgb([255, 0, 0]);
+        let door = Rgb([255, 255, 0]);
+        let enemy = Rgb([0, 0, 255]);
+        let rows = [
+            [wall, wall, wall, wall, wall, wall],
+            [wall, air, air, air, air, wall],
+            [wall, air, enemy, air, air, door],
+            [wall, air, air, air, air, wall],
+            [wall, wall, wall, wall, wall, wall],
+        ];
+        let layout = RgbImage::from_fn(6, 5, |x, y| rows[y as usize][x as usize]);
+        // Large enough for every tile position the pipeline uses
+        let tileset = RgbaImage::new(11 * 2, 11 * 2);
+        let config = RoomConfig {
+            tile_size: (2, 2),
+            floor_seed: 0,
+        };
+
+        let room = generate_room(&layout, &tileset, &config).unwrap();
+
+        let wall = CollisionKind::Wall.id();
+        let right_door = CollisionKind::Door(DoorDirection::Right).id();
+        assert_eq!(
+            room.collision
+                .map_to(|id| id)
+                .rows()
+                .into_iter()
+                .map(|row| row.to_vec())
+                .collect::<Vec<_>>(),
+            vec![
+                vec![wall; 6],
+                vec![wall, 0, 0, 0, 0, wall],
+                vec![wall, 0, 0, 0, 0, right_door],
+                vec![wall, 0, 0, 0, 0, wall],
+                vec![wall; 6],
+            ]
+        );
+        assert_eq!(room.enemies.map_to(|wave| wave)[[2, 2]], 1);
+        for image in [&room.tiles, &room.floor, &room.with_walls, &room.debug] {
+            assert_eq!(image.dimensions(), (12, 10));
+        }
+        // Floor tiles come from the first column of rows 4 and 5
+        let floor_tiles = room.floor_tiles.map_to(|index| index);
+        assert_eq!(floor_tiles.dim(), (5, 6));
+        assert!(
+            floor_tiles
+                .iter()
+                .all(|&index| index == 4 * 11 || index == 5 * 11)
+        );
+    }
+}
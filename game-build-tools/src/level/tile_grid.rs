@@ -0,0 +1,142 @@
+//! The one place tile coordinates are converted to and from world space and
+//! pixels. In world space a tile is one unit wide with the grid's origin at
+//! the top left corner, so tile `(x, y)` covers `[x, x + 1) × [y, y + 1)`.
+
+use glam::Vec2;
+
+use crate::level::TILE_SIZE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TileGrid {
+    /// Size in pixels of a tile
+    pub tile_size: (u32, u32),
+    /// Number of tiles along x and y
+    pub dimensions: (usize, usize),
+}
+
+impl TileGrid {
+    /// A grid of `dimensions` tiles of `TILE_SIZE`
+    pub fn new(dimensions: (usize, usize)) -> Self {
+        Self::with_tile_size(dimensions, TILE_SIZE)
+    }
+
+    pub fn with_tile_size(dimensions: (usize, usize), tile_size: (u32, u32)) -> Self {
+        Self {
+            tile_size,
+            dimensions,
+        }
+    }
+
+    /// The grid over rows of cells, like the ones parsed from a level's csvs
+    pub fn of_rows<T>(rows: &[Vec<T>]) -> Self {
+        Self::new((rows.first().map_or(0, Vec::len), rows.len()))
+    }
+
+    /// The tile `world` falls in, which may be outside of the grid
+    pub fn world_to_tile(&self, world: Vec2) -> (i32, i32) {
+        let tile = world.floor().as_ivec2();
+        (tile.x, tile.y)
+    }
+
+    /// Top left corner of `tile`
+    pub fn tile_to_world(&self, tile: (usize, usize)) -> Vec2 {
+        Vec2::new(tile.0 as f32, tile.1 as f32)
+    }
+
+    pub fn tile_center(&self, tile: (usize, usize)) -> Vec2 {
+        self.tile_to_world(tile) + 0.5
+    }
+
+    /// Center of the tile `world` falls in, in or out of the grid
+    pub fn snap_to_center(&self, world: Vec2) -> Vec2 {
+        world.floor() + 0.5
+    }
+
+    pub fn contains(&self, tile: (i32, i32)) -> bool {
+        let (width, height) = self.dimensions;
+        (0..width as i64).contains(&(tile.0 as i64))
+            && (0..height as i64).contains(&(tile.1 as i64))
+    }
+
+    /// The tile `world` falls in, if it's inside the grid
+    pub fn tile_at(&self, world: Vec2) -> Option<(usize, usize)> {
+        let tile = self.world_to_tile(world);
+        self.contains(tile)
+            .then_some((tile.0 as usize, tile.1 as usize))
+    }
+
+    /// Top left pixel of `tile` in images of the whole grid
+    pub fn tile_to_pixel(&self, tile: (usize, usize)) -> (u32, u32) {
+        (
+            tile.0 as u32 * self.tile_size.0,
+            tile.1 as u32 * self.tile_size.1,
+        )
+    }
+
+    /// Size in pixels of images of the whole grid
+    pub fn pixel_size(&self) -> (u32, u32) {
+        self.tile_to_pixel(self.dimensions)
+    }
+
+    /// Size of the whole grid in world units
+    pub fn world_size(&self) -> Vec2 {
+        self.tile_to_world(self.dimensions)
+    }
+
+    /// Every tile of the grid, row by row
+    pub fn tiles(&self) -> impl Iterator<Item = (usize, usize)> + use<> {
+        let (width, height) = self.dimensions;
+        (0..height).flat_map(move |y| (0..width).map(move |x| (x, y)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_tile_centers_and_edges() {
+        let grid = TileGrid::new((5, 3));
+        for tile in grid.tiles() {
+            let corner = grid.tile_to_world(tile);
+            let center = grid.tile_center(tile);
+            let expected = (tile.0 as i32, tile.1 as i32);
+            assert_eq!(grid.world_to_tile(corner), expected);
+            assert_eq!(grid.world_to_tile(center), expected);
+            // The far edges belong to the next tile over
+            let inside_edge = corner + Vec2::splat(1.0 - 1e-4);
+            assert_eq!(grid.world_to_tile(inside_edge), expected);
+            assert_eq!(
+                grid.world_to_tile(corner + Vec2::X),
+                (expected.0 + 1, expected.1)
+            );
+            assert_eq!(grid.tile_at(center), Some(tile));
+            assert_eq!(grid.snap_to_center(corner + 0.2), center);
+        }
+        assert_eq!(grid.tiles().count(), 15);
+    }
+
+    #[test]
+    fn checks_bounds() {
+        let grid = TileGrid::new((5, 3));
+        assert_eq!(grid.world_to_tile(Vec2::new(-0.5, 1.0)), (-1, 1));
+        assert_eq!(grid.tile_at(Vec2::new(-0.5, 1.0)), None);
+        assert_eq!(grid.tile_at(Vec2::new(5.0, 1.0)), None);
+        assert_eq!(grid.tile_at(Vec2::new(4.99, 2.99)), Some((4, 2)));
+        assert!(!grid.contains((0, 3)));
+        assert!(grid.contains((4, 0)));
+    }
+
+    #[test]
+    fn converts_to_pixels() {
+        let grid = TileGrid::with_tile_size((5, 3), (16, 8));
+        assert_eq!(grid.tile_to_pixel((2, 1)), (32, 8));
+        assert_eq!(grid.pixel_size(), (80, 24));
+        assert_eq!(grid.world_size(), Vec2::new(5.0, 3.0));
+        assert_eq!(TileGrid::new((2, 2)).pixel_size(), (64, 64));
+        assert_eq!(
+            TileGrid::of_rows(&[vec![0; 4], vec![0; 4]]).dimensions,
+            (4, 2)
+        );
+    }
+}
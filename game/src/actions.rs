@@ -0,0 +1,483 @@
+//! What the player can do, and the keys and gamepad buttons bound to it, so
+//! gameplay asks whether the player wants to attack rather than whether L is
+//! down, and rebinding doesn't touch `Game`. Bindings are saved in the
+//! browser's local storage as TOML, one line per action:
+//! `Attack = ["KeyL", "GamepadWest"]`.
+
+use std::collections::HashSet;
+
+use winit::keyboard::KeyCode;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Action {
+    MoveUp,
+    MoveDown,
+    MoveLeft,
+    MoveRight,
+    Attack,
+    Heal,
+    Interact,
+}
+
+impl Action {
+    pub const ALL: [Self; 7] = [
+        Self::MoveUp,
+        Self::MoveDown,
+        Self::MoveLeft,
+        Self::MoveRight,
+        Self::Attack,
+        Self::Heal,
+        Self::Interact,
+    ];
+}
+
+/// A button of a gamepad with the browser's standard mapping, named by
+/// where it is rather than what it's labeled, which differs between brands
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum GamepadButton {
+    South,
+    East,
+    West,
+    North,
+    LeftBumper,
+    RightBumper,
+    LeftTrigger,
+    RightTrigger,
+    Select,
+    Start,
+    LeftStick,
+    RightStick,
+    DpadUp,
+    DpadDown,
+    DpadLeft,
+    DpadRight,
+}
+
+impl GamepadButton {
+    /// In the order of the standard mapping's buttons
+    pub const STANDARD: [Self; 16] = [
+        Self::South,
+        Self::East,
+        Self::West,
+        Self::North,
+        Self::LeftBumper,
+        Self::RightBumper,
+        Self::LeftTrigger,
+        Self::RightTrigger,
+        Self::Select,
+        Self::Start,
+        Self::LeftStick,
+        Self::RightStick,
+        Self::DpadUp,
+        Self::DpadDown,
+        Self::DpadLeft,
+        Self::DpadRight,
+    ];
+}
+
+/// Keys actions can be bound to, the ones with a name to save them by
+const BINDABLE_KEYS: [KeyCode; 89] = [
+    KeyCode::KeyA,
+    KeyCode::KeyB,
+    KeyCode::KeyC,
+    KeyCode::KeyD,
+    KeyCode::KeyE,
+    KeyCode::KeyF,
+    KeyCode::KeyG,
+    KeyCode::KeyH,
+    KeyCode::KeyI,
+    KeyCode::KeyJ,
+    KeyCode::KeyK,
+    KeyCode::KeyL,
+    KeyCode::KeyM,
+    KeyCode::KeyN,
+    KeyCode::KeyO,
+    KeyCode::KeyP,
+    KeyCode::KeyQ,
+    KeyCode::KeyR,
+    KeyCode::KeyS,
+    KeyCode::KeyT,
+    KeyCode::KeyU,
+    KeyCode::KeyV,
+    KeyCode::KeyW,
+    KeyCode::KeyX,
+    KeyCode::KeyY,
+    KeyCode::KeyZ,
+    KeyCode::Digit0,
+    KeyCode::Digit1,
+    KeyCode::Digit2,
+    KeyCode::Digit3,
+    KeyCode::Digit4,
+    KeyCode::Digit5,
+    KeyCode::Digit6,
+    KeyCode::Digit7,
+    KeyCode::Digit8,
+    KeyCode::Digit9,
+    KeyCode::Numpad0,
+    KeyCode::Numpad1,
+    KeyCode::Numpad2,
+    KeyCode::Numpad3,
+    KeyCode::Numpad4,
+    KeyCode::Numpad5,
+    KeyCode::Numpad6,
+    KeyCode::Numpad7,
+    KeyCode::Numpad8,
+    KeyCode::Numpad9,
+    KeyCode::ArrowUp,
+    KeyCode::ArrowDown,
+    KeyCode::ArrowLeft,
+    KeyCode::ArrowRight,
+    KeyCode::Space,
+    KeyCode::Enter,
+    KeyCode::Tab,
+    KeyCode::Backspace,
+    KeyCode::ShiftLeft,
+    KeyCode::ShiftRight,
+    KeyCode::ControlLeft,
+    KeyCode::ControlRight,
+    KeyCode::AltLeft,
+    KeyCode::AltRight,
+    KeyCode::Backquote,
+    KeyCode::Minus,
+    KeyCode::Equal,
+    KeyCode::BracketLeft,
+    KeyCode::BracketRight,
+    KeyCode::Backslash,
+    KeyCode::Semicolon,
+    KeyCode::Quote,
+    KeyCode::Comma,
+    KeyCode::Period,
+    KeyCode::Slash,
+    KeyCode::IntlBackslash,
+    KeyCode::Insert,
+    KeyCode::Delete,
+    KeyCode::Home,
+    KeyCode::End,
+    KeyCode::PageUp,
+    KeyCode::PageDown,
+    KeyCode::F1,
+    KeyCode::F2,
+    KeyCode::F3,
+    KeyCode::F4,
+    KeyCode::F5,
+    KeyCode::F6,
+    KeyCode::F7,
+    KeyCode::F8,
+    KeyCode::F9,
+    KeyCode::F10,
+    // F11 toggles fullscreen
+    KeyCode::F12,
+];
+
+/// Where the bindings are kept in the browser's local storage
+#[cfg(target_arch = "wasm32")]
+const BINDINGS_STORAGE_KEY: &str = "bindings";
+
+/// Something pressed that actions are bound to. Keys are physical, by where
+/// they are on the keyboard, so WASD is ZQSD on an AZERTY one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Binding {
+    Key(KeyCode),
+    Gamepad(GamepadButton),
+}
+
+impl Binding {
+    /// What it's saved as, `KeyW` or `GamepadSouth`. `None` for keys that
+    /// can't be bound.
+    pub fn name(self) -> Option<String> {
+        match self {
+            Self::Key(key) => BINDABLE_KEYS.contains(&key).then(|| format!("{:?}", key)),
+            Self::Gamepad(button) => Some(format!("Gamepad{:?}", button)),
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        let keys = BINDABLE_KEYS.iter().copied().map(Self::Key);
+        let buttons = GamepadButton::STANDARD.iter().copied().map(Self::Gamepad);
+        keys.chain(buttons)
+            .find(|binding| binding.name().as_deref() == Some(name))
+    }
+
+    /// Whether `other` is on the same kind of device, both keys or both
+    /// gamepad buttons
+    fn same_device(self, other: Self) -> bool {
+        matches!(
+            (self, other),
+            (Self::Key(_), Self::Key(_)) | (Self::Gamepad(_), Self::Gamepad(_))
+        )
+    }
+}
+
+/// Which bindings trigger each action. An action can have any number of
+/// bindings, and a binding can trigger more than one action.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ActionBindings {
+    bindings: Vec<(Binding, Action)>,
+}
+
+impl Default for ActionBindings {
+    fn default() -> Self {
+        let mut bindings = Self::empty();
+        for (key, button, action) in [
+            (KeyCode::KeyW, GamepadButton::DpadUp, Action::MoveUp),
+            (KeyCode::KeyS, GamepadButton::DpadDown, Action::MoveDown),
+            (KeyCode::KeyA, GamepadButton::DpadLeft, Action::MoveLeft),
+            (KeyCode::KeyD, GamepadButton::DpadRight, Action::MoveRight),
+            (KeyCode::KeyL, GamepadButton::West, Action::Attack),
+            (KeyCode::KeyH, GamepadButton::North, Action::Heal),
+            (KeyCode::KeyE, GamepadButton::South, Action::Interact),
+        ] {
+            bindings.bind(action, Binding::Key(key));
+            bindings.bind(action, Binding::Gamepad(button));
+        }
+        bindings
+    }
+}
+
+impl ActionBindings {
+    /// Nothing bound to anything
+    pub fn empty() -> Self {
+        Self {
+            bindings: Vec::new(),
+        }
+    }
+
+    pub fn bind(&mut self, action: Action, binding: Binding) {
+        if !self.bindings.contains(&(binding, action)) {
+            self.bindings.push((binding, action));
+        }
+    }
+
+    pub fn unbind(&mut self, action: Action, binding: Binding) {
+        self.bindings.retain(|bound| *bound != (binding, action));
+    }
+
+    /// Leaves `action` without bindings, e.g. before binding it again
+    pub fn clear(&mut self, action: Action) {
+        self.bindings.retain(|(_, bound)| *bound != action);
+    }
+
+    pub fn bindings_of(&self, action: Action) -> impl Iterator<Item = Binding> + '_ {
+        self.bindings
+            .iter()
+            .filter(move |(_, bound)| *bound == action)
+            .map(|(binding, _)| *binding)
+    }
+
+    pub fn actions_of(&self, binding: Binding) -> impl Iterator<Item = Action> + '_ {
+        self.bindings
+            .iter()
+            .filter(move |(bound, _)| *bound == binding)
+            .map(|(_, action)| *action)
+    }
+
+    /// Binds `action` to `binding` in place of its other bindings on the
+    /// same device, so rebinding a key leaves the gamepad alone
+    pub fn rebind(&mut self, action: Action, binding: Binding) {
+        let replaced: Vec<Binding> = self
+            .bindings_of(action)
+            .filter(|bound| bound.same_device(binding))
+            .collect();
+        for bound in replaced {
+            self.unbind(action, bound);
+        }
+        self.bind(action, binding);
+    }
+
+    /// Bindings that trigger more than one action, with the actions they
+    /// trigger, in the order they were bound
+    pub fn conflicts(&self) -> Vec<(Binding, Vec<Action>)> {
+        let mut conflicts: Vec<(Binding, Vec<Action>)> = Vec::new();
+        for (binding, _) in &self.bindings {
+            let actions: Vec<Action> = self.actions_of(*binding).collect();
+            if actions.len() > 1 && !conflicts.iter().any(|(seen, _)| seen == binding) {
+                conflicts.push((*binding, actions));
+            }
+        }
+        conflicts
+    }
+
+    /// Only saved in browsers
+    #[cfg(any(test, target_arch = "wasm32"))]
+    pub fn to_toml(&self) -> String {
+        let mut table = toml::Table::new();
+        for action in Action::ALL {
+            let names = self
+                .bindings_of(action)
+                .filter_map(Binding::name)
+                .map(toml::Value::String)
+                .collect();
+            table.insert(format!("{:?}", action), toml::Value::Array(names));
+        }
+        table.to_string()
+    }
+
+    /// Reads bindings written by `to_toml`. Actions it leaves out keep their
+    /// default bindings, so saves from before an action existed still load.
+    pub fn from_toml(toml: &str) -> Result<Self, String> {
+        let table: toml::Table = toml
+            .parse()
+            .map_err(|err: toml::de::Error| err.to_string())?;
+        let mut bindings = Self::default();
+        for (name, value) in table {
+            let action = Action::ALL
+                .into_iter()
+                .find(|action| format!("{:?}", action) == name)
+                .ok_or_else(|| format!("Unknown action `{}`", name))?;
+            let list = value.as_array().ok_or_else(|| {
+                format!("Expected a list of bindings for {}, got `{}`", name, value)
+            })?;
+            bindings.clear(action);
+            for item in list {
+                let binding = item
+                    .as_str()
+                    .ok_or_else(|| format!("Expected a binding's name, got `{}`", item))?;
+                let binding = Binding::from_name(binding)
+                    .ok_or_else(|| format!("Unknown binding `{}`", binding))?;
+                bindings.bind(action, binding);
+            }
+        }
+        Ok(bindings)
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+fn local_storage() -> Option<web_sys::Storage> {
+    web_sys::window()?.local_storage().ok().flatten()
+}
+
+/// The bindings saved with `save_bindings`, if any were and they load
+pub fn load_bindings() -> Option<ActionBindings> {
+    #[cfg(target_arch = "wasm32")]
+    let saved = local_storage()?
+        .get_item(BINDINGS_STORAGE_KEY)
+        .ok()
+        .flatten();
+    #[cfg(not(target_arch = "wasm32"))]
+    let saved: Option<String> = None;
+    ActionBindings::from_toml(&saved?)
+        .inspect_err(|err| log::error!("Failed to load the key bindings: {}", err))
+        .ok()
+}
+
+pub fn save_bindings(bindings: &ActionBindings) {
+    #[cfg(target_arch = "wasm32")]
+    if let Some(storage) = local_storage() {
+        if let Err(err) = storage.set_item(BINDINGS_STORAGE_KEY, &bindings.to_toml()) {
+            log::error!("Failed to save the key bindings: {:?}", err);
+        }
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    let _ = bindings;
+}
+
+/// Buttons held on any connected gamepad with the standard mapping. Browsers
+/// don't send events for them, so they're polled.
+#[cfg(target_arch = "wasm32")]
+pub fn held_gamepad_buttons() -> HashSet<GamepadButton> {
+    use wasm_bindgen::JsCast;
+    use web_sys::{Gamepad, GamepadMappingType};
+
+    let mut held = HashSet::new();
+    let Some(gamepads) = web_sys::window().and_then(|window| {
+        window
+            .navigator()
+            .get_gamepads()
+            .inspect_err(|err| log::error!("Failed to get gamepads: {:?}", err))
+            .ok()
+    }) else {
+        return held;
+    };
+    // Disconnected slots are null
+    for gamepad in gamepads
+        .iter()
+        .filter_map(|gamepad| gamepad.dyn_into::<Gamepad>().ok())
+    {
+        if gamepad.mapping() != GamepadMappingType::Standard {
+            continue;
+        }
+        for (button, state) in GamepadButton::STANDARD.iter().zip(gamepad.buttons().iter()) {
+            if state.unchecked_into::<web_sys::GamepadButton>().pressed() {
+                held.insert(*button);
+            }
+        }
+    }
+    held
+}
+
+/// No gamepads outside the browser
+#[cfg(not(target_arch = "wasm32"))]
+pub fn held_gamepad_buttons() -> HashSet<GamepadButton> {
+    HashSet::new()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bindings_load_back_as_saved() {
+        let mut bindings = ActionBindings::default();
+        bindings.rebind(Action::Attack, Binding::Key(KeyCode::Space));
+        bindings.rebind(Action::Heal, Binding::Gamepad(GamepadButton::RightBumper));
+        bindings.unbind(Action::Interact, Binding::Key(KeyCode::KeyE));
+        let toml = bindings.to_toml();
+        assert!(toml.contains("Attack = [\"GamepadWest\", \"Space\"]\n"));
+        assert!(toml.contains("Interact = [\"GamepadSouth\"]\n"));
+        let loaded = ActionBindings::from_toml(&toml).unwrap();
+        for action in Action::ALL {
+            assert!(loaded.bindings_of(action).eq(bindings.bindings_of(action)));
+        }
+    }
+
+    #[test]
+    fn left_out_actions_keep_their_defaults() {
+        let bindings = ActionBindings::from_toml(
+            "# Arrows instead of WASD\nMoveUp = [\"ArrowUp\",]\n\nHeal = []",
+        )
+        .unwrap();
+        let up: Vec<_> = bindings.bindings_of(Action::MoveUp).collect();
+        assert_eq!(up, [Binding::Key(KeyCode::ArrowUp)]);
+        assert_eq!(bindings.bindings_of(Action::Heal).count(), 0);
+        let attack: Vec<_> = bindings.bindings_of(Action::Attack).collect();
+        let default: Vec<_> = ActionBindings::default()
+            .bindings_of(Action::Attack)
+            .collect();
+        assert_eq!(attack, default);
+
+        assert_eq!(
+            ActionBindings::from_toml("MoveUp = [\"KeyW\"]\nJump = [\"Space\"]"),
+            Err("Unknown action `Jump`".to_string())
+        );
+        assert!(ActionBindings::from_toml("Attack = [\"F11\"]").is_err());
+        assert!(ActionBindings::from_toml("Attack = \"KeyL\"").is_err());
+    }
+
+    #[test]
+    fn hand_edited_bindings_load() {
+        let bindings = ActionBindings::from_toml(
+            "\"Attack\" = [\"KeyJ\"] # closer to the arrows\nMoveUp = [\n    \"ArrowUp\",\n    \"KeyW\",\n]\n",
+        )
+        .unwrap();
+        let attack: Vec<_> = bindings.bindings_of(Action::Attack).collect();
+        assert_eq!(attack, [Binding::Key(KeyCode::KeyJ)]);
+        assert_eq!(bindings.bindings_of(Action::MoveUp).count(), 2);
+        assert!(ActionBindings::from_toml("Attack = [\"KeyJ\"").is_err());
+    }
+
+    #[test]
+    fn keys_bound_twice_conflict() {
+        let mut bindings = ActionBindings::default();
+        assert!(bindings.conflicts().is_empty());
+        bindings.rebind(Action::Heal, Binding::Key(KeyCode::KeyL));
+        assert_eq!(
+            bindings.conflicts(),
+            [(
+                Binding::Key(KeyCode::KeyL),
+                vec![Action::Attack, Action::Heal]
+            )]
+        );
+        // The gamepad's heal button is still there
+        assert_eq!(bindings.bindings_of(Action::Heal).count(), 2);
+    }
+}
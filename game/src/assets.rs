@@ -0,0 +1,625 @@
+//! Every asset shipped with the game, looked up by id. Assets are only decoded
+//! (and uploaded to the GPU, for textures) the first time they are requested,
+//! and later requests for the same id share what was loaded. They're read
+//! from the copies embedded in the game, or from the files themselves with
+//! `AssetSource::Directory`, as native builds do.
+//!
+//! They can also be requested ahead of time with the `request_*` methods,
+//! which return right away and load in the background while
+//! `AssetManager::poll` is called, e.g. once a frame behind a loading screen.
+//! Textures are decoded on a worker thread, or a few at a time per `poll` in
+//! the browser build where there are no threads, and sounds are decoded by
+//! the browser.
+
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    path::PathBuf,
+    rc::Rc,
+    sync::mpsc::{self, Receiver, TryRecvError},
+};
+
+#[cfg(target_arch = "wasm32")]
+use std::collections::VecDeque;
+
+use image::RgbaImage;
+
+use crate::{
+    audio::{AudioHandle, AudioSystem},
+    game::GameLevelLoadData,
+    renderer::{
+        gizmo::{GizmoBindableTexture, GizmoSpriteSheet},
+        RenderingSystem,
+    },
+    room_loading::LoadError,
+};
+
+/// An asset shipped with the game, at `path` under `src/assets`
+struct ManifestEntry {
+    id: &'static str,
+    path: &'static str,
+    bytes: &'static [u8],
+}
+
+macro_rules! asset {
+    ($id:expr, $path:expr) => {
+        ManifestEntry {
+            id: $id,
+            path: $path,
+            bytes: include_bytes!(concat!("assets/", $path)),
+        }
+    };
+}
+
+const MANIFEST: &[ManifestEntry] = &[
+    asset!("ui", "ui.png"),
+    asset!("char_template", "char_template.png"),
+    asset!("fountain_test", "fountain/test_processed.png"),
+    asset!("font/leko_majuna", "leko majuna.ttf"),
+    asset!("sfx/walk", "walk.wav"),
+    asset!("sfx/windup", "windup_2.wav"),
+    asset!("sfx/attack", "attack_1.wav"),
+    asset!("sfx/staggered", "staggered_1.wav"),
+    asset!("sfx/stance_broken", "stance_broken_1.wav"),
+    // Loops seamlessly over its whole length
+    asset!("music/drone", "music/drone.ogg"),
+    // Floors are drawn from its tiles by the indices in the floor csvs
+    asset!("level/tileset", "level_specs/environment.png"),
+    asset!("level/spawn/floor", "level_generated/spawn_floor.csv"),
+    asset!(
+        "level/spawn/with_walls",
+        "level_generated/spawn_with_walls.png"
+    ),
+    asset!(
+        "level/spawn/collision",
+        "level_generated/spawn_collision.csv"
+    ),
+    asset!("level/spawn/enemies", "level_generated/spawn_enemies.csv"),
+    asset!("level/base_0/floor", "level_generated/base_0_floor.csv"),
+    asset!(
+        "level/base_0/with_walls",
+        "level_generated/base_0_with_walls.png"
+    ),
+    asset!(
+        "level/base_0/collision",
+        "level_generated/base_0_collision.csv"
+    ),
+    asset!("level/base_0/enemies", "level_generated/base_0_enemies.csv"),
+];
+
+/// Assets needed before the first frame, see `AssetManager::request_preload`.
+/// Ids are fonts under `font/`, sounds under `sfx/` or `music/` and textures
+/// otherwise. Sounds can be in any format the browser decodes.
+pub const PRELOAD: &[&str] = &[
+    "ui",
+    "char_template",
+    "fountain_test",
+    "font/leko_majuna",
+    "sfx/walk",
+    "sfx/windup",
+    "sfx/attack",
+    "sfx/staggered",
+    "sfx/stance_broken",
+];
+
+/// Decodes run per `poll` where they can't run on a thread of their own
+#[cfg(target_arch = "wasm32")]
+const DECODES_PER_POLL: usize = 1;
+
+fn find_manifest_entry(id: &str) -> Result<&'static ManifestEntry, LoadError> {
+    MANIFEST
+        .iter()
+        .find(|entry| entry.id == id)
+        .ok_or_else(|| format!("Unknown asset id {}", id).into())
+}
+
+pub fn embedded_bytes(id: &str) -> Result<&'static [u8], LoadError> {
+    Ok(find_manifest_entry(id)?.bytes)
+}
+
+fn embedded_str(id: &str) -> Result<&'static str, LoadError> {
+    std::str::from_utf8(embedded_bytes(id)?)
+        .map_err(|_| format!("Asset {} is not valid UTF-8", id).into())
+}
+
+/// Raw data of one of the levels generated by `build.rs`
+pub fn embedded_level(name: &'static str) -> Result<GameLevelLoadData<'static>, LoadError> {
+    Ok(GameLevelLoadData {
+        name,
+        floor_csv: embedded_str(&format!("level/{}/floor", name))?,
+        decoration_bytes: embedded_bytes(&format!("level/{}/with_walls", name))?,
+        collision_csv: embedded_str(&format!("level/{}/collision", name))?,
+        enemies_csv: embedded_str(&format!("level/{}/enemies", name))?,
+    })
+}
+
+/// Where an `AssetManager` reads the assets in the manifest from
+#[derive(Debug, Clone, Default)]
+pub enum AssetSource {
+    /// The copies compiled into the game
+    #[default]
+    Embedded,
+    /// The files in a directory laid out like `src/assets`, read the first
+    /// time they're requested, e.g. to try out edited assets without a
+    /// rebuild. Not in the browser, which has no filesystem to read.
+    Directory(PathBuf),
+}
+
+impl AssetSource {
+    /// The files under `src/assets` on native builds, so edits show up
+    /// without a rebuild, and the embedded copies in the browser
+    pub fn for_platform() -> Self {
+        #[cfg(not(target_arch = "wasm32"))]
+        return AssetSource::Directory(PathBuf::from(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/src/assets"
+        )));
+        #[cfg(target_arch = "wasm32")]
+        AssetSource::Embedded
+    }
+
+    fn read(&self, entry: &ManifestEntry) -> Result<Cow<'static, [u8]>, LoadError> {
+        match self {
+            AssetSource::Embedded => Ok(Cow::Borrowed(entry.bytes)),
+            AssetSource::Directory(root) => {
+                let path = root.join(entry.path);
+                let bytes = std::fs::read(&path)
+                    .map_err(|err| format!("Failed to read {}: {}", path.display(), err))?;
+                Ok(Cow::Owned(bytes))
+            }
+        }
+    }
+}
+
+struct AssetCache<T: Clone> {
+    loaded: HashMap<&'static str, T>,
+}
+
+impl<T: Clone> AssetCache<T> {
+    fn new() -> Self {
+        Self {
+            loaded: HashMap::new(),
+        }
+    }
+
+    /// Failed loads aren't cached, so they're retried on the next request
+    fn get_or_load(
+        &mut self,
+        id: &str,
+        source: &AssetSource,
+        load: impl FnOnce(&[u8]) -> Result<T, LoadError>,
+    ) -> Result<T, LoadError> {
+        if let Some(asset) = self.loaded.get(id) {
+            return Ok(asset.clone());
+        }
+        let entry = find_manifest_entry(id)?;
+        let asset = load(&source.read(entry)?)?;
+        self.loaded.insert(entry.id, asset.clone());
+        Ok(asset)
+    }
+}
+
+type DecodedTexture = (&'static str, Result<RgbaImage, LoadError>);
+
+pub struct AssetManager {
+    source: AssetSource,
+    textures: AssetCache<Rc<GizmoBindableTexture>>,
+    sounds: AssetCache<AudioHandle>,
+    fonts: AssetCache<()>,
+    // Requested and not loaded yet, each texture hearing back from its own
+    // decode so one that panics can't be waited on forever
+    decoding_textures: HashMap<&'static str, Receiver<Result<RgbaImage, LoadError>>>,
+    loading_sounds: Vec<(&'static str, AudioHandle)>,
+    queued_fonts: Vec<&'static str>,
+    // Decodes waiting for a `poll` to run them, in the browser build
+    #[cfg(target_arch = "wasm32")]
+    queued_decodes: VecDeque<Box<dyn FnOnce() + Send>>,
+    requested: usize,
+    finished: usize,
+    failures: Vec<(&'static str, LoadError)>,
+}
+
+impl AssetManager {
+    pub fn new() -> Self {
+        Self {
+            source: AssetSource::Embedded,
+            textures: AssetCache::new(),
+            sounds: AssetCache::new(),
+            fonts: AssetCache::new(),
+            decoding_textures: HashMap::new(),
+            loading_sounds: Vec::new(),
+            queued_fonts: Vec::new(),
+            #[cfg(target_arch = "wasm32")]
+            queued_decodes: VecDeque::new(),
+            requested: 0,
+            finished: 0,
+            failures: Vec::new(),
+        }
+    }
+
+    /// Reads the assets from `source` rather than the embedded copies
+    pub fn with_source(mut self, source: AssetSource) -> Self {
+        self.source = source;
+        self
+    }
+
+    /// Starts loading the ids in `PRELOAD`, see `poll`
+    pub fn request_preload(&mut self, audio_system: &mut AudioSystem) {
+        for &id in PRELOAD {
+            let requested = if id.starts_with("font/") {
+                self.request_font(id)
+            } else if id.starts_with("sfx/") || id.starts_with("music/") {
+                self.request_sound(audio_system, id)
+            } else {
+                self.request_texture(id)
+            };
+            if let Err(err) = requested {
+                self.failures.push((id, err));
+            }
+        }
+    }
+
+    /// Starts decoding a texture, ready once a `poll` uploads it
+    pub fn request_texture(&mut self, id: &str) -> Result<(), LoadError> {
+        let entry = find_manifest_entry(id)?;
+        let id = entry.id;
+        if !self.textures.loaded.contains_key(id) && !self.decoding_textures.contains_key(id) {
+            let bytes = self.source.read(entry)?;
+            self.decode_texture(id, move || {
+                image::load_from_memory(&bytes)
+                    .map(|image| image.to_rgba8())
+                    .map_err(LoadError::from)
+            });
+        }
+        Ok(())
+    }
+
+    /// Starts decoding a sound, ready once the audio system is done with it.
+    /// Sounds that can't be decoded end up as silent dummies.
+    pub fn request_sound(
+        &mut self,
+        audio_system: &mut AudioSystem,
+        id: &str,
+    ) -> Result<(), LoadError> {
+        let id = find_manifest_entry(id)?.id;
+        if !self.sounds.loaded.contains_key(id) {
+            self.requested += 1;
+            let sound = self.sound(audio_system, id)?;
+            self.loading_sounds.push((id, sound));
+        }
+        Ok(())
+    }
+
+    /// Queues a font to be loaded by the next `poll`
+    pub fn request_font(&mut self, id: &str) -> Result<(), LoadError> {
+        let id = find_manifest_entry(id)?.id;
+        if !self.fonts.loaded.contains_key(id) && !self.queued_fonts.contains(&id) {
+            self.requested += 1;
+            self.queued_fonts.push(id);
+        }
+        Ok(())
+    }
+
+    /// Finishes loading whatever is ready, without blocking. What failed
+    /// is kept for `take_failures`.
+    pub fn poll(&mut self, rendering_system: &mut RenderingSystem, audio_system: &AudioSystem) {
+        #[cfg(target_arch = "wasm32")]
+        for _ in 0..DECODES_PER_POLL {
+            if let Some(decode) = self.queued_decodes.pop_front() {
+                decode();
+            }
+        }
+        for (id, image) in self.take_decoded(false) {
+            self.upload_texture(rendering_system, id, image);
+        }
+
+        for id in std::mem::take(&mut self.queued_fonts) {
+            self.finished += 1;
+            if let Err(err) = self.font(rendering_system, id) {
+                self.failures.push((id, err));
+            }
+        }
+
+        let before = self.loading_sounds.len();
+        self.loading_sounds
+            .retain(|(_, sound)| !audio_system.is_ready(sound));
+        self.finished += before - self.loading_sounds.len();
+    }
+
+    /// Blocks until every texture and font requested is loaded. Sounds go
+    /// on decoding, they're played once they're ready.
+    #[cfg(test)]
+    pub fn wait(&mut self, rendering_system: &mut RenderingSystem, audio_system: &AudioSystem) {
+        #[cfg(target_arch = "wasm32")]
+        while let Some(decode) = self.queued_decodes.pop_front() {
+            decode();
+        }
+        for (id, image) in self.take_decoded(true) {
+            self.upload_texture(rendering_system, id, image);
+        }
+        self.poll(rendering_system, audio_system);
+    }
+
+    /// Whether something requested isn't loaded yet
+    pub fn is_loading(&self) -> bool {
+        self.finished < self.requested
+    }
+
+    /// How much of what was requested is loaded, from 0 to 1
+    pub fn progress(&self) -> f32 {
+        if self.requested == 0 {
+            return 1.0;
+        }
+        self.finished as f32 / self.requested as f32
+    }
+
+    /// The requests that failed since the last call, along with why
+    pub fn take_failures(&mut self) -> Vec<(&'static str, LoadError)> {
+        std::mem::take(&mut self.failures)
+    }
+
+    /// The texture, once it's loaded
+    #[cfg(test)]
+    pub fn ready_texture(&self, id: &str) -> Option<Rc<GizmoBindableTexture>> {
+        self.textures.loaded.get(id).cloned()
+    }
+
+    /// The sound, once it's decoded
+    #[cfg(test)]
+    pub fn ready_sound(&self, id: &str) -> Option<AudioHandle> {
+        if self
+            .loading_sounds
+            .iter()
+            .any(|(loading, _)| *loading == id)
+        {
+            return None;
+        }
+        self.sounds.loaded.get(id).cloned()
+    }
+
+    fn upload_texture(
+        &mut self,
+        rendering_system: &mut RenderingSystem,
+        id: &'static str,
+        image: Result<RgbaImage, LoadError>,
+    ) {
+        self.finished += 1;
+        match image {
+            // Unless `texture` got to it first
+            Ok(image) if !self.textures.loaded.contains_key(id) => {
+                match rendering_system.gizmo_texture_from_image(&image) {
+                    Ok(texture) => {
+                        self.textures.loaded.insert(id, Rc::new(texture));
+                    }
+                    Err(err) => self.failures.push((id, err.into())),
+                }
+            }
+            Ok(_) => {}
+            Err(err) => self.failures.push((id, err)),
+        }
+    }
+
+    fn decode_texture(
+        &mut self,
+        id: &'static str,
+        decode: impl FnOnce() -> Result<RgbaImage, LoadError> + Send + 'static,
+    ) {
+        self.requested += 1;
+        let (sender, receiver) = mpsc::channel();
+        self.decoding_textures.insert(id, receiver);
+        self.spawn_decode(move || {
+            // The manager going away just means nobody wants it anymore
+            let _ = sender.send(decode());
+        });
+    }
+
+    /// Textures done decoding, waiting for all of them if `block`. A decode
+    /// that went away without a result, e.g. by panicking, failed.
+    fn take_decoded(&mut self, block: bool) -> Vec<DecodedTexture> {
+        let mut decoded = Vec::new();
+        self.decoding_textures.retain(|&id, receiver| {
+            let image = if block {
+                receiver.recv().map_err(|_| TryRecvError::Disconnected)
+            } else {
+                receiver.try_recv()
+            };
+            let image = match image {
+                Ok(image) => image,
+                Err(TryRecvError::Empty) => return true,
+                Err(TryRecvError::Disconnected) => {
+                    Err(format!("Decoding {} stopped before it was done", id).into())
+                }
+            };
+            decoded.push((id, image));
+            false
+        });
+        decoded
+    }
+
+    fn spawn_decode(&mut self, decode: impl FnOnce() + Send + 'static) {
+        #[cfg(not(target_arch = "wasm32"))]
+        std::thread::spawn(decode);
+        #[cfg(target_arch = "wasm32")]
+        self.queued_decodes.push_back(Box::new(decode));
+    }
+
+    pub fn texture(
+        &mut self,
+        rendering_system: &mut RenderingSystem,
+        id: &str,
+    ) -> Result<Rc<GizmoBindableTexture>, LoadError> {
+        self.textures.get_or_load(id, &self.source, |bytes| {
+            Ok(rendering_system.shared_gizmo_texture_from_encoded_image(bytes)?)
+        })
+    }
+
+    pub fn sprite_sheet(
+        &mut self,
+        rendering_system: &mut RenderingSystem,
+        id: &str,
+        region_start: [f32; 2],
+        region_end: [f32; 2],
+        num_tiles: [u32; 2],
+    ) -> Result<GizmoSpriteSheet, LoadError> {
+        Ok(GizmoSpriteSheet::new(
+            self.texture(rendering_system, id)?,
+            region_start,
+            region_end,
+            num_tiles,
+        ))
+    }
+
+    /// Sounds that can't be decoded still load, as silent dummies. Music is
+    /// streamed as it plays rather than decoded up front.
+    pub fn sound(
+        &mut self,
+        audio_system: &mut AudioSystem,
+        id: &str,
+    ) -> Result<AudioHandle, LoadError> {
+        let streamed = id.starts_with("music/");
+        self.sounds.get_or_load(id, &self.source, |bytes| {
+            Ok(if streamed {
+                audio_system.load_stream(bytes)
+            } else {
+                audio_system.load_buffer(bytes)
+            })
+        })
+    }
+
+    pub fn font(
+        &mut self,
+        rendering_system: &mut RenderingSystem,
+        id: &str,
+    ) -> Result<(), LoadError> {
+        self.fonts.get_or_load(id, &self.source, |bytes| {
+            rendering_system.load_font(bytes);
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::headless;
+
+    #[test]
+    fn same_id_shares_one_load() {
+        let mut cache = AssetCache::new();
+        let mut loads = 0;
+        let mut load = |bytes: &[u8]| {
+            loads += 1;
+            Ok(Rc::new(bytes.len()))
+        };
+        let source = AssetSource::Embedded;
+        let first = cache
+            .get_or_load("char_template", &source, &mut load)
+            .unwrap();
+        let second = cache
+            .get_or_load("char_template", &source, &mut load)
+            .unwrap();
+        assert!(Rc::ptr_eq(&first, &second));
+        assert_eq!(loads, 1);
+    }
+
+    #[test]
+    fn failed_loads_are_errors() {
+        let mut cache = AssetCache::<()>::new();
+        let source = AssetSource::Embedded;
+        let unknown = cache.get_or_load("not_an_asset", &source, |_| Ok(()));
+        assert_eq!(
+            unknown.unwrap_err().to_string(),
+            "Unknown asset id not_an_asset"
+        );
+
+        let corrupt = cache.get_or_load("ui", &source, |_| Err("Corrupt image".into()));
+        assert_eq!(corrupt.unwrap_err().to_string(), "Corrupt image");
+        // Nothing was cached, so the next request tries again
+        assert!(cache.get_or_load("ui", &source, |_| Ok(())).is_ok());
+    }
+
+    #[test]
+    fn assets_can_be_read_from_a_directory() {
+        let root = std::env::temp_dir().join(format!("assets_{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let source = AssetSource::Directory(root.clone());
+        let mut assets = AssetManager::new().with_source(source.clone());
+        let missing = assets.request_texture("ui").unwrap_err();
+        assert!(missing.to_string().contains("ui.png"), "{}", missing);
+
+        let mut cache = AssetCache::new();
+        std::fs::write(root.join("ui.png"), b"edited").unwrap();
+        let edited = cache.get_or_load("ui", &source, |bytes| Ok(bytes.to_vec()));
+        std::fs::remove_dir_all(&root).unwrap();
+        assert_eq!(edited.unwrap(), b"edited");
+        assert_ne!(embedded_bytes("ui").unwrap(), b"edited");
+    }
+
+    #[test]
+    fn decodes_that_panic_fail_instead_of_hanging() {
+        let mut assets = AssetManager::new();
+        assets.decode_texture("ui", || panic!("Out of memory"));
+        assets.decode_texture("char_template", || Ok(RgbaImage::new(1, 1)));
+        #[cfg(target_arch = "wasm32")]
+        while let Some(decode) = assets.queued_decodes.pop_front() {
+            decode();
+        }
+
+        let mut decoded = assets.take_decoded(true);
+        decoded.sort_by_key(|(id, _)| *id);
+        assert_eq!(decoded.len(), 2);
+        assert!(decoded[0].1.is_ok());
+        let err = decoded[1].1.as_ref().unwrap_err();
+        assert_eq!(err.to_string(), "Decoding ui stopped before it was done");
+        assert!(assets.decoding_textures.is_empty());
+    }
+
+    #[test]
+    fn embedded_levels_are_in_the_manifest() {
+        let spawn = embedded_level("spawn").unwrap();
+        assert!(!spawn.floor_csv.is_empty());
+        assert!(!spawn.collision_csv.is_empty());
+        let missing = embedded_level("not_a_level").err().unwrap();
+        assert_eq!(
+            missing.to_string(),
+            "Unknown asset id level/not_a_level/floor"
+        );
+    }
+
+    #[test]
+    fn requested_assets_stream_in_behind_the_request() {
+        let Some(mut renderer) = headless::renderer(64, 64) else {
+            return;
+        };
+        let mut audio = AudioSystem::silent();
+        let mut assets = AssetManager::new();
+        assert_eq!(assets.progress(), 1.0);
+
+        assets.request_texture("ui").unwrap();
+        // Asking again shares the same load
+        assets.request_texture("ui").unwrap();
+        assets.request_sound(&mut audio, "sfx/walk").unwrap();
+        assets.request_sound(&mut audio, "music/drone").unwrap();
+        assets.request_font("font/leko_majuna").unwrap();
+        assert!(assets.request_texture("not_an_asset").is_err());
+        assert!(assets.is_loading());
+        assert!(assets.progress() < 1.0);
+        assert_eq!(assets.requested, 4);
+        assert!(assets.ready_texture("ui").is_none());
+
+        // Only what's done by now, without waiting on the rest
+        assets.poll(&mut renderer, &audio);
+        assets.wait(&mut renderer, &audio);
+        assert!(!assets.is_loading());
+        assert_eq!(assets.progress(), 1.0);
+        assert!(assets.take_failures().is_empty());
+        let texture = assets.ready_texture("ui").expect("Loaded by now");
+        assert!(Rc::ptr_eq(
+            &texture,
+            &assets.texture(&mut renderer, "ui").unwrap()
+        ));
+        // Silent, so dummies, but done
+        assert!(assets.ready_sound("sfx/walk").is_some());
+        assert!(assets.ready_sound("music/drone").is_some());
+    }
+}
@@ -0,0 +1,78 @@
+// Multiplied over the frame: the ambient color, plus the light of every point
+// light that isn't blocked by an occluding tile on its way
+
+struct Light {
+    position_radius: vec4<f32>, // Position in xy, radius in z
+    color: vec4<f32>, // Scaled by its alpha
+}
+
+struct Lighting {
+    screen_to_space: mat4x4<f32>,
+    ambient: vec4<f32>,
+    info: vec4<u32>, // Number of lights in x, whether there's an occlusion map in y, its size in zw
+    lights: array<Light, 16>,
+}
+
+@group(0) @binding(0)
+var<uniform> lighting: Lighting;
+
+@group(1) @binding(0)
+var occlusion: texture_2d<f32>;
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) screen: vec2<f32>,
+}
+
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> VertexOutput {
+    // One triangle covering the whole screen
+    let corner = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+    var out: VertexOutput;
+    out.screen = corner * 2.0 - 1.0;
+    out.clip_position = vec4<f32>(out.screen, 0.0, 1.0);
+    return out;
+}
+
+fn is_occluder(tile: vec2<i32>) -> bool {
+    let size = vec2<i32>(lighting.info.zw);
+    if (any(tile < vec2<i32>(0)) || any(tile >= size)) {
+        return false;
+    }
+    return textureLoad(occlusion, tile, 0).r > 0.5;
+}
+
+// Whether light gets from `light` to `position`. The tile `position` is in
+// doesn't count, so occluders are lit on the side facing the light.
+fn visibility(position: vec2<f32>, light: vec2<f32>) -> f32 {
+    if (lighting.info.y == 0u) {
+        return 1.0;
+    }
+    let own_tile = vec2<i32>(floor(position));
+    let steps = min(i32(ceil(distance(position, light) * 4.0)), 64);
+    for (var i = 1; i <= steps; i++) {
+        let tile = vec2<i32>(floor(mix(position, light, f32(i) / f32(steps))));
+        if (any(tile != own_tile) && is_occluder(tile)) {
+            return 0.0;
+        }
+    }
+    return 1.0;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let position = (lighting.screen_to_space * vec4<f32>(in.screen, 0.0, 1.0)).xy;
+    var light = lighting.ambient.rgb;
+    for (var i = 0u; i < lighting.info.x; i++) {
+        let point = lighting.lights[i];
+        let radius = point.position_radius.z;
+        let distance_to_light = distance(position, point.position_radius.xy);
+        if (distance_to_light >= radius) {
+            continue;
+        }
+        let falloff = 1.0 - distance_to_light / radius;
+        let visible = visibility(position, point.position_radius.xy);
+        light += point.color.rgb * point.color.a * falloff * falloff * visible;
+    }
+    return vec4<f32>(light, 1.0);
+}
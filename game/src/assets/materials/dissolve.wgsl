@@ -0,0 +1,20 @@
+// Eats the sprite away pixel by pixel as params.x goes from 0 to 1, the
+// pixels about to go glowing in the params.yzw color
+fn material(in: VertexOutput) -> vec4<f32> {
+    let texel = sprite_texel(in, in.uv);
+    // The same threshold for every screen pixel of a sprite pixel
+    let pixel = floor(in.uv / sprite_pixel_size(in));
+    let threshold = fract(sin(dot(pixel, vec2<f32>(12.9898, 78.233))) * 43758.5453);
+    let progress = material_params.params.x;
+    if (threshold < progress) {
+        return vec4<f32>(0.0);
+    }
+    if (threshold < progress + 0.1 && progress > 0.0) {
+        var edge = material_params.params.yzw;
+        if (sprite_spec.use_texture_and_padding.z == 1u) {
+            edge = edge * texel.a;
+        }
+        return vec4<f32>(edge, texel.a);
+    }
+    return texel;
+}
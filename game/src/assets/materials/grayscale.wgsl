@@ -0,0 +1,7 @@
+// Drains the color out of the sprite, by params.x from none at 0 to all of
+// it at 1
+fn material(in: VertexOutput) -> vec4<f32> {
+    let texel = sprite_texel(in, in.uv);
+    let gray = dot(texel.rgb, vec3<f32>(0.2126, 0.7152, 0.0722));
+    return vec4<f32>(mix(texel.rgb, vec3<f32>(gray), material_params.params.x), texel.a);
+}
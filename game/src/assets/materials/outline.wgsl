@@ -0,0 +1,14 @@
+// Surrounds the opaque part of the sprite with params.w pixels of the
+// params.xyz color, in the sprite's own transparent margin
+fn material(in: VertexOutput) -> vec4<f32> {
+    let texel = sprite_texel(in, in.uv);
+    let step = sprite_pixel_size(in) * material_params.params.w;
+    let around = max(
+        max(sprite_texel(in, in.uv + vec2<f32>(step.x, 0.0)).a, sprite_texel(in, in.uv - vec2<f32>(step.x, 0.0)).a),
+        max(sprite_texel(in, in.uv + vec2<f32>(0.0, step.y)).a, sprite_texel(in, in.uv - vec2<f32>(0.0, step.y)).a),
+    );
+    if (texel.a > 0.0 || around <= 0.0) {
+        return texel;
+    }
+    return vec4<f32>(material_params.params.xyz, 1.0);
+}
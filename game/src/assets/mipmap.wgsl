@@ -0,0 +1,39 @@
+// Draws a mip level of a texture array layer as the level above it scaled
+// down by half
+
+@group(0) @binding(0)
+var source: texture_2d_array<f32>;
+@group(0) @binding(1)
+var source_sampler: sampler;
+
+struct MipParams {
+    params: vec4<f32>, // uv extent of the texture in the layer in xy, layer in z
+}
+
+@group(0) @binding(2)
+var<uniform> mip_params: MipParams;
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+}
+
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> VertexOutput {
+    // One triangle covering the whole level
+    let corner = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+    var out: VertexOutput;
+    out.uv = corner;
+    out.clip_position = vec4<f32>(corner.x * 2.0 - 1.0, 1.0 - corner.y * 2.0, 0.0, 1.0);
+    return out;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    // Averages the 2 by 2 texels above, repeating the texture's last ones
+    // past its edges instead of the empty rest of the layer
+    let half_texel = 0.5 / vec2<f32>(textureDimensions(source));
+    let extent = max(mip_params.params.xy - half_texel, half_texel);
+    let uv = min(max(in.uv, half_texel), extent);
+    return textureSampleLevel(source, source_sampler, uv, i32(mip_params.params.z), 0.0);
+}
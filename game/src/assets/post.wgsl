@@ -0,0 +1,113 @@
+// One post-processing effect, drawn over the whole frame from the frame
+// drawn before it
+
+struct Post {
+    params: vec4<f32>, // Depends on the effect, see `PostEffect`
+    color: vec4<f32>,
+    info: vec4<u32>, // Effect in x, LUT size in y, whether the frame is sRGB in z
+}
+
+@group(0) @binding(0)
+var<uniform> post: Post;
+
+@group(1) @binding(0)
+var source: texture_2d<f32>;
+@group(1) @binding(1)
+var source_sampler: sampler;
+
+@group(2) @binding(0)
+var lut: texture_2d<f32>;
+@group(2) @binding(1)
+var lut_sampler: sampler;
+
+const VIGNETTE: u32 = 0u;
+const CHROMATIC_ABERRATION: u32 = 1u;
+const CRT_CURVATURE: u32 = 2u;
+const COLOR_GRADING: u32 = 3u;
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+}
+
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> VertexOutput {
+    // One triangle covering the whole screen
+    let corner = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+    var out: VertexOutput;
+    out.uv = corner;
+    out.clip_position = vec4<f32>(corner.x * 2.0 - 1.0, 1.0 - corner.y * 2.0, 0.0, 1.0);
+    return out;
+}
+
+fn sample(uv: vec2<f32>) -> vec4<f32> {
+    return textureSampleLevel(source, source_sampler, uv, 0.0);
+}
+
+fn to_srgb(linear: vec3<f32>) -> vec3<f32> {
+    let low = linear * 12.92;
+    let high = 1.055 * pow(linear, vec3<f32>(1.0 / 2.4)) - 0.055;
+    return select(high, low, linear <= vec3<f32>(0.0031308));
+}
+
+fn to_linear(srgb: vec3<f32>) -> vec3<f32> {
+    let low = srgb / 12.92;
+    let high = pow((srgb + 0.055) / 1.055, vec3<f32>(2.4));
+    return select(high, low, srgb <= vec3<f32>(0.04045));
+}
+
+// Looks `color` up in a LUT of `size` slices of `size` by `size`, one per
+// blue level, laid side by side
+fn grade(color: vec3<f32>) -> vec3<f32> {
+    let size = f32(post.info.y);
+    var encoded = clamp(color, vec3<f32>(0.0), vec3<f32>(1.0));
+    if (post.info.z == 1u) {
+        encoded = to_srgb(encoded);
+    }
+    let slice = encoded.b * (size - 1.0);
+    let lower = floor(slice);
+    let upper = min(lower + 1.0, size - 1.0);
+    let within = (encoded.rg * (size - 1.0) + 0.5) / size;
+    let low = textureSampleLevel(lut, lut_sampler, vec2<f32>((lower + within.x) / size, within.y), 0.0);
+    let high = textureSampleLevel(lut, lut_sampler, vec2<f32>((upper + within.x) / size, within.y), 0.0);
+    let graded = mix(low.rgb, high.rgb, slice - lower);
+    if (post.info.z == 1u) {
+        return to_linear(graded);
+    }
+    return graded;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    switch post.info.x {
+        case VIGNETTE: {
+            // 0 in the middle, 1 in the corners
+            let from_center = distance(in.uv, vec2<f32>(0.5)) * sqrt(2.0);
+            let amount = smoothstep(post.params.x, 1.0, from_center) * post.color.a;
+            let color = sample(in.uv);
+            return vec4<f32>(mix(color.rgb, post.color.rgb, amount), color.a);
+        }
+        case CHROMATIC_ABERRATION: {
+            // Red and blue pulled apart towards the edges
+            let offset = (in.uv - 0.5) * post.params.x;
+            let color = sample(in.uv);
+            return vec4<f32>(sample(in.uv + offset).r, color.g, sample(in.uv - offset).b, color.a);
+        }
+        case CRT_CURVATURE: {
+            let centered = in.uv - 0.5;
+            let bend = dot(centered, centered) * post.params.x;
+            let uv = in.uv + centered * (1.0 + bend) * bend;
+            if (any(uv < vec2<f32>(0.0)) || any(uv > vec2<f32>(1.0))) {
+                return vec4<f32>(0.0, 0.0, 0.0, 1.0);
+            }
+            return sample(uv);
+        }
+        case COLOR_GRADING: {
+            let color = sample(in.uv);
+            return vec4<f32>(grade(color.rgb), color.a);
+        }
+        default: {
+            return sample(in.uv);
+        }
+    }
+}
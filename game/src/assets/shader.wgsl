@@ -4,10 +4,17 @@ struct VertexInput {
     @location(2) uv: vec2<f32>,
 }
 
+// One tile of a tilemap, drawn as an instance of the quad
+struct TileInstance {
+    @location(3) position: vec2<f32>,
+    @location(4) tile: vec2<u32>, // Tile of the sheet, all ones for none
+}
+
 struct VertexOutput {
     @builtin(position) clip_position: vec4<f32>,
     @location(0) color: vec3<f32>,
     @location(1) uv: vec2<f32>,
+    @location(2) @interpolate(flat) tile: vec2<u32>,
 }
 
 struct Transform {
@@ -17,12 +24,39 @@ struct Transform {
 @group(0) @binding(0)
 var<uniform> transform: Transform;
 
+struct SpriteSpec {
+    use_texture_and_padding: vec4<u32>, // use_texture in x, array layer in y, premultiplied in z, uv rect in w
+    region_start_and_end: vec4<f32>, // Start and end of the sprite region, or its uv rect
+    tiles_info: vec4<u32>, // Number of tiles and selected tile
+    sampling_info: vec4<u32>, // Linear in x, repeat in y, mipmapped in z
+    texture_extent: vec4<f32>, // uv extent of the texture in its layer in xy
+}
+
+@group(3) @binding(4)
+var<uniform> sprite_spec: SpriteSpec;
+
 @vertex
 fn vs_main(model: VertexInput) -> VertexOutput {
     var out: VertexOutput;
     out.color = model.color;
     out.clip_position = transform.matrix * vec4<f32>(model.position, 1.0);
     out.uv = model.uv;
+    out.tile = sprite_spec.tiles_info.zw;
+    return out;
+}
+
+@vertex
+fn vs_tilemap(model: VertexInput, instance: TileInstance) -> VertexOutput {
+    var out: VertexOutput;
+    out.color = model.color;
+    out.uv = model.uv;
+    out.tile = instance.tile;
+    let position = model.position + vec3<f32>(instance.position, 0.0);
+    out.clip_position = transform.matrix * vec4<f32>(position, 1.0);
+    if (all(instance.tile == vec2<u32>(0xffffffffu))) {
+        // Empty cells collapse to a point off the screen
+        out.clip_position = vec4<f32>(2.0, 2.0, 2.0, 1.0);
+    }
     return out;
 }
 
@@ -33,34 +67,163 @@ struct EngineColor {
 @group(1) @binding(1)
 var<uniform> engine_color: EngineColor;
 
+struct OutputSettings {
+    settings: vec4<f32>, // Brightness in x, whether to discard transparent fragments in y
+}
+
+@group(1) @binding(5)
+var<uniform> output_settings: OutputSettings;
+
+// Gamma in linear space, so the sRGB target still encodes the result
+fn adjust_brightness(rgb: vec3<f32>) -> vec3<f32> {
+    return pow(max(rgb, vec3<f32>(0.0)), vec3<f32>(1.0 / output_settings.settings.x));
+}
+
 @group(2) @binding(2)
-var gizmo_texture: texture_2d<f32>;
+var gizmo_texture: texture_2d_array<f32>;
 @group(2) @binding(3)
 var gizmo_sampler: sampler;
+@group(2) @binding(9)
+var gizmo_linear_sampler: sampler;
+// The same texture array, GL can only pair a texture with a single sampler
+@group(2) @binding(10)
+var gizmo_linear_texture: texture_2d_array<f32>;
 
-struct SpriteSpec {
-    use_texture_and_padding: vec4<u32>, // Use a vec4 to ensure alignment
-    region_start_and_end: vec4<f32>, // Start and end of the sprite region
-    tiles_info: vec4<u32>, // Number of tiles and selected tile
+// Parameters of the material the draw goes through, if any, see
+// `renderer::material`
+struct MaterialParams {
+    params: vec4<f32>,
 }
 
-@group(3) @binding(4)
-var<uniform> sprite_spec: SpriteSpec;
+@group(1) @binding(6)
+var<uniform> material_params: MaterialParams;
+
+// Settings of a draw besides its sprite, see `gizmo::draw_settings`
+struct DrawSettings {
+    info: vec4<u32>, // Whether the sprite is indexed in x, palette row in y, whether to premultiply in z
+}
+
+@group(1) @binding(7)
+var<uniform> draw_settings: DrawSettings;
+
+@group(2) @binding(8)
+var palette_texture: texture_2d<f32>;
+
+fn to_srgb(linear: vec3<f32>) -> vec3<f32> {
+    let low = linear * 12.92;
+    let high = 1.055 * pow(linear, vec3<f32>(1.0 / 2.4)) - 0.055;
+    return select(high, low, linear <= vec3<f32>(0.0031308));
+}
+
+// For indexed sprites, the color of the palette their red channel picks,
+// as it's stored in the image
+fn apply_palette(texel: vec4<f32>) -> vec4<f32> {
+    if (draw_settings.info.x == 0u) {
+        return texel;
+    }
+    let premultiplied = sprite_spec.use_texture_and_padding.z == 1u;
+    var red = texel.r;
+    if (premultiplied && texel.a > 0.0) {
+        red = red / texel.a;
+    }
+    let index = u32(round(to_srgb(vec3<f32>(red)).x * 255.0));
+    let color = textureLoad(palette_texture, vec2<u32>(index, draw_settings.info.y), 0);
+    let alpha = color.a * texel.a;
+    if (premultiplied) {
+        return vec4<f32>(color.rgb * alpha, alpha);
+    }
+    return vec4<f32>(color.rgb, alpha);
+}
+
+// Where a sprite-local uv lands in the texture array layer
+fn sprite_uv(in: VertexOutput, uv: vec2<f32>) -> vec2<f32> {
+    let region_start = sprite_spec.region_start_and_end.xy;
+    let region_end = sprite_spec.region_start_and_end.zw;
+    let num_tiles = vec2<f32>(f32(sprite_spec.tiles_info.x), f32(sprite_spec.tiles_info.y));
+    let selected_tile = vec2<f32>(in.tile);
+    var tile_size = (region_end - region_start)
+        / num_tiles;
+    var uv_offset = region_start + selected_tile * tile_size;
+    if (sprite_spec.use_texture_and_padding.w == 1u) {
+        // An explicit uv rect, no grid to pick a tile from
+        tile_size = region_end - region_start;
+        uv_offset = region_start;
+    }
+    return uv * tile_size + uv_offset;
+}
+
+// The sprite's texel at a sprite-local uv, clamped to the sprite so
+// neighbouring tiles don't bleed in, through the palette if it's indexed
+fn sprite_texel(in: VertexOutput, uv: vec2<f32>) -> vec4<f32> {
+    let layer = i32(sprite_spec.use_texture_and_padding.y);
+    let sampling = sprite_spec.sampling_info;
+    var layer_uv = sprite_uv(in, clamp(uv, vec2<f32>(0.0), vec2<f32>(1.0)));
+    // Before wrapping, or the seams would pick the smallest mip
+    let ddx = dpdx(layer_uv);
+    let ddy = dpdy(layer_uv);
+    var low = min(sprite_uv(in, vec2<f32>(0.0)), sprite_uv(in, vec2<f32>(1.0)));
+    var high = max(sprite_uv(in, vec2<f32>(0.0)), sprite_uv(in, vec2<f32>(1.0)));
+    if (sampling.y == 1u) {
+        let extent = sprite_spec.texture_extent.xy;
+        layer_uv = fract(layer_uv / extent) * extent;
+        low = vec2<f32>(0.0);
+        high = extent;
+    }
+    if (sampling.x == 1u) {
+        // Linear filtering blends in the texels around, which mustn't be
+        // the neighbouring tiles or the empty rest of the layer
+        let half_texel = 0.5 / vec2<f32>(textureDimensions(gizmo_texture));
+        layer_uv = min(max(layer_uv, low + half_texel), max(high - half_texel, low + half_texel));
+    }
+    var texel: vec4<f32>;
+    if (sampling.z == 0u) {
+        // Only the first mip level has the texture
+        if (sampling.x == 1u) {
+            texel = textureSampleLevel(gizmo_linear_texture, gizmo_linear_sampler, layer_uv, layer, 0.0);
+        } else {
+            texel = textureSampleLevel(gizmo_texture, gizmo_sampler, layer_uv, layer, 0.0);
+        }
+    } else if (sampling.x == 1u) {
+        texel = textureSampleGrad(gizmo_linear_texture, gizmo_linear_sampler, layer_uv, layer, ddx, ddy);
+    } else {
+        texel = textureSampleGrad(gizmo_texture, gizmo_sampler, layer_uv, layer, ddx, ddy);
+    }
+    return apply_palette(texel);
+}
+
+// Size of one of the sprite's pixels in sprite-local uv
+fn sprite_pixel_size(in: VertexOutput) -> vec2<f32> {
+    let layer_size = vec2<f32>(textureDimensions(gizmo_texture));
+    let size = sprite_uv(in, vec2<f32>(1.0)) - sprite_uv(in, vec2<f32>(0.0));
+    return 1.0 / (size * layer_size);
+}
+
+// Tints `tex_color` and adjusts it for the output, what every fragment
+// shader ends with
+fn shade(in: VertexOutput, tex_color: vec4<f32>) -> vec4<f32> {
+    let color = vec4<f32>(in.color, 1.0) * engine_color.color * tex_color;
+    if (output_settings.settings.y == 1.0 && color.a <= 0.0) {
+        // Or it would still write its depth over what's under it
+        discard;
+    }
+    if (sprite_spec.use_texture_and_padding.z == 1u) {
+        // Premultiplied blending expects the tint's alpha in the color too,
+        // and the brightness goes on the color before it's multiplied
+        var rgb = color.rgb;
+        if (tex_color.a > 0.0) {
+            rgb = adjust_brightness(rgb / tex_color.a) * tex_color.a;
+        }
+        return vec4<f32>(rgb * engine_color.color.a, color.a);
+    }
+    let rgb = adjust_brightness(color.rgb);
+    if (draw_settings.info.z == 1u) {
+        // The blend mode expects premultiplied colors
+        return vec4<f32>(rgb * color.a, color.a);
+    }
+    return vec4<f32>(rgb, color.a);
+}
 
 @fragment
 fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
-    var tex_color = vec4<f32>(1.0, 1.0, 1.0, 1.0);
-    if (1 == 1) { 
-        let region_start = sprite_spec.region_start_and_end.xy;
-        let region_end = sprite_spec.region_start_and_end.zw;
-        let num_tiles = vec2<f32>(f32(sprite_spec.tiles_info.x), f32(sprite_spec.tiles_info.y));
-        let selected_tile = vec2<f32>(f32(sprite_spec.tiles_info.z), f32(sprite_spec.tiles_info.w));
-        let tile_size = (region_end - region_start)
-            / num_tiles;
-        let uv_offset = region_start + selected_tile * tile_size;
-
-        //tex_color = textureSample(gizmo_texture, gizmo_sampler, uv_offset + in.uv * tile_size);
-        tex_color = textureSample(gizmo_texture, gizmo_sampler, in.uv * tile_size + uv_offset);
-    }
-    return vec4<f32>(in.color, 1.0) * engine_color.color * tex_color;
-}
\ No newline at end of file
+    return shade(in, sprite_texel(in, in.uv));
+}
@@ -0,0 +1,53 @@
+// Draws the frame at the internal resolution over the viewport it's scaled
+// up to in the window, tone mapped
+
+// x: exposure, y: tonemap, 0 clamp, 1 Reinhard, 2 ACES
+struct Tonemap {
+    settings: vec4<f32>,
+}
+
+@group(0) @binding(0)
+var frame: texture_2d<f32>;
+@group(0) @binding(1)
+var frame_sampler: sampler;
+@group(0) @binding(2)
+var<uniform> tonemap: Tonemap;
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+}
+
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> VertexOutput {
+    // One triangle covering the whole viewport
+    let corner = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+    var out: VertexOutput;
+    out.uv = corner;
+    out.clip_position = vec4<f32>(corner.x * 2.0 - 1.0, 1.0 - corner.y * 2.0, 0.0, 1.0);
+    return out;
+}
+
+// Narkowicz's fit of the ACES filmic curve
+fn aces(color: vec3<f32>) -> vec3<f32> {
+    let a = color * (2.51 * color + 0.03);
+    let b = color * (2.43 * color + 0.59) + 0.14;
+    return a / b;
+}
+
+fn tone_map(color: vec3<f32>) -> vec3<f32> {
+    let exposed = max(color * tonemap.settings.x, vec3<f32>(0.0));
+    var mapped = exposed;
+    if (tonemap.settings.y == 1.0) {
+        mapped = exposed / (1.0 + exposed);
+    } else if (tonemap.settings.y == 2.0) {
+        mapped = aces(exposed);
+    }
+    return clamp(mapped, vec3<f32>(0.0), vec3<f32>(1.0));
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let color = textureSampleLevel(frame, frame_sampler, in.uv, 0.0);
+    return vec4<f32>(tone_map(color.rgb), clamp(color.a, 0.0, 1.0));
+}
@@ -3,14 +3,28 @@ use std::{
     rc::Rc,
 };
 
+use glam::Vec2;
 use log::error;
-use wasm_bindgen::JsCast;
+use wasm_bindgen::{JsCast, JsValue};
 use wasm_bindgen_futures::JsFuture;
 use web_sys::{
-    js_sys::{ArrayBuffer, Uint8Array},
-    AudioBuffer, AudioContext, AudioContextState,
+    js_sys::{Array, ArrayBuffer, Uint8Array},
+    AnalyserNode, AudioBuffer, AudioBufferSourceNode, AudioContext, AudioContextState, AudioNode,
+    AudioScheduledSourceNode, BiquadFilterNode, BiquadFilterType, Blob, GainNode, HtmlAudioElement,
+    MediaElementAudioSourceNode, Url,
 };
 
+/// Samples the loudness is measured over, about 40ms at usual sample rates
+const METER_WINDOW: u32 = 2048;
+/// Distance from the listener, in world units, sounds played at a position
+/// are at full volume within. Further away they get quieter with distance.
+const FULL_VOLUME_DISTANCE: f32 = 4.0;
+/// Distance to the listener's side a sound is only heard on that side at
+const FULL_PAN_DISTANCE: f32 = 8.0;
+/// Time constant of the filter's cutoff easing into a new one, in seconds,
+/// so changing it doesn't click
+const FILTER_EASING: f64 = 0.05;
+
 enum LoadState {
     Loading,
     Done(AudioBuffer),
@@ -20,26 +34,570 @@ enum LoadState {
 enum LoadableAudio {
     Loading(Rc<RefCell<LoadState>>),
     Loaded(AudioBuffer),
+    // Object URL of the encoded track, decoded bit by bit as it plays
+    Streamed(String),
     Dummy,
 }
 
 pub struct AudioSystem {
     audio_context: Option<AudioContext>,
+    // Everything played goes through it on the way to the output
+    meter: Option<AnalyserNode>,
+    // Low-pass on everything played, before the meter
+    filter: Option<BiquadFilterNode>,
+    filter_cutoff: Option<f32>,
     audio_buffers: Vec<LoadableAudio>,
+    // Sounds that are playing, other than the music
+    voices: Vec<Voice>,
+    music: Option<Music>,
+    // Streamed tracks taken off the music channel, until they fade out
+    fading_streams: Vec<Stream>,
+    // A gain per bus, by `AudioBus`, feeding the meter
+    bus_gains: Option<[GainNode; AudioBus::COUNT]>,
+    bus_volumes: [f32; AudioBus::COUNT],
+    muted: bool,
+    // Where sounds played at a position are heard from
+    listener: Vec2,
+    next_voice_id: u64,
+    // Watched sounds played to their end since the last `poll_finished`
+    finished: Vec<SoundInstance>,
 }
 
+#[derive(Clone)]
 pub struct AudioHandle {
     index: usize,
 }
 
+/// A sound started with one of the `AudioSystem::play` methods, to control
+/// while it plays. Once it's done, controlling it does nothing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SoundInstance {
+    id: u64,
+}
+
+/// Sounds whose volume is set together. `Sfx` and `Music` go through
+/// `Master`, which everything played goes through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioBus {
+    Master,
+    Sfx,
+    Music,
+}
+
+impl AudioBus {
+    const COUNT: usize = 3;
+}
+
+/// Where a track loops, in seconds from its start. It plays from the start
+/// once, then from `end` back to `start` for as long as it plays.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LoopPoints {
+    pub start: f64,
+    pub end: f64,
+}
+
+/// The track on the music channel
+struct Music {
+    handle: AudioHandle,
+    loop_points: Option<LoopPoints>,
+    // Playing the track, once it's loaded
+    track: Option<Track>,
+    // Seconds it fades in over once it starts
+    fade_in: f32,
+}
+
+/// How the music channel plays its track
+enum Track {
+    Buffered(Voice),
+    Streamed(Stream),
+}
+
+/// A track streamed by an audio element, through a gain of its own to fade
+/// it with
+struct Stream {
+    element: HtmlAudioElement,
+    source: MediaElementAudioSourceNode,
+    gain: GainNode,
+    loop_points: Option<LoopPoints>,
+    // Context time it's stopped at, once it's fading out
+    ends_at: Option<f64>,
+}
+
+/// How a sound is played
+#[derive(Debug, Clone, Copy)]
+struct Playback {
+    bus: AudioBus,
+    speed: f32,
+    // Where it's heard from, see `AudioSystem::play_at`
+    position: Option<Vec2>,
+    looped: bool,
+}
+
+/// A sound that's playing, through a gain of its own to fade it with
+struct Voice {
+    id: u64,
+    index: usize,
+    audio_buffer: AudioBuffer,
+    // Replaced whenever it resumes, as a source only plays once
+    source: AudioBufferSourceNode,
+    gain: GainNode,
+    speed: f32,
+    loop_points: Option<LoopPoints>,
+    // Seconds into the track it was at `started_at`, in context time
+    offset: f64,
+    started_at: f64,
+    paused: bool,
+    // Context time it's done playing at, `None` while it loops or is paused
+    ends_at: Option<f64>,
+    // Stopped rather than played to its end, so it didn't finish
+    stopping: bool,
+    // Reported by `AudioSystem::poll_finished` once done, see `watch`
+    watched: bool,
+}
+
+impl Voice {
+    /// A voice playing `audio_buffer` from the start at full volume, over
+    /// and over between `loop_points` if there are any
+    fn start(
+        audio_context: &AudioContext,
+        output: &AudioNode,
+        audio_buffer: &AudioBuffer,
+        speed: f32,
+        loop_points: Option<LoopPoints>,
+    ) -> Result<Self, JsValue> {
+        let gain = audio_context.create_gain()?;
+        gain.connect_with_audio_node(output)?;
+        let source =
+            Self::start_source(audio_context, &gain, audio_buffer, speed, loop_points, 0.0)?;
+        let mut voice = Self {
+            id: 0,
+            index: 0,
+            audio_buffer: audio_buffer.clone(),
+            source,
+            gain,
+            speed,
+            loop_points,
+            offset: 0.0,
+            started_at: audio_context.current_time(),
+            paused: false,
+            ends_at: None,
+            stopping: false,
+            watched: false,
+        };
+        voice.schedule_end();
+        Ok(voice)
+    }
+
+    /// Loops are sample accurate, with no gap where the track wraps around
+    fn start_source(
+        audio_context: &AudioContext,
+        gain: &GainNode,
+        audio_buffer: &AudioBuffer,
+        speed: f32,
+        loop_points: Option<LoopPoints>,
+        offset: f64,
+    ) -> Result<AudioBufferSourceNode, JsValue> {
+        let source = audio_context.create_buffer_source()?;
+        source.set_buffer(Some(audio_buffer));
+        source.playback_rate().set_value(speed);
+        if let Some(loop_points) = loop_points {
+            source.set_loop(true);
+            source.set_loop_start(loop_points.start);
+            source.set_loop_end(loop_points.end);
+        }
+        source.connect_with_audio_node(gain)?;
+        source.start_with_when_and_grain_offset(0.0, offset)?;
+        Ok(source)
+    }
+
+    /// Seconds into the track it's at
+    #[cfg(test)]
+    fn position(&self, now: f64) -> f64 {
+        if self.paused {
+            return self.offset;
+        }
+        let played = (now - self.started_at) * self.speed as f64;
+        track_position(self.offset, played, self.loop_points)
+    }
+
+    fn schedule_end(&mut self) {
+        self.ends_at = match (self.paused, self.loop_points) {
+            // Never, if it's not moving
+            (false, None) if self.speed > 0.0 => Some(
+                self.started_at + (self.audio_buffer.duration() - self.offset) / self.speed as f64,
+            ),
+            _ => None,
+        };
+    }
+
+    #[cfg(test)]
+    fn pause(&mut self, audio_context: &AudioContext) -> Result<(), JsValue> {
+        if self.paused {
+            return Ok(());
+        }
+        self.offset = self.position(audio_context.current_time());
+        self.paused = true;
+        self.schedule_end();
+        AudioScheduledSourceNode::stop(&self.source)
+    }
+
+    #[cfg(test)]
+    fn resume(&mut self, audio_context: &AudioContext) -> Result<(), JsValue> {
+        if !self.paused {
+            return Ok(());
+        }
+        self.source = Self::start_source(
+            audio_context,
+            &self.gain,
+            &self.audio_buffer,
+            self.speed,
+            self.loop_points,
+            self.offset,
+        )?;
+        self.started_at = audio_context.current_time();
+        self.paused = false;
+        self.schedule_end();
+        Ok(())
+    }
+
+    #[cfg(test)]
+    fn set_speed(&mut self, audio_context: &AudioContext, speed: f32) {
+        let now = audio_context.current_time();
+        self.offset = self.position(now);
+        self.started_at = now;
+        self.speed = speed;
+        self.source.playback_rate().set_value(speed);
+        self.schedule_end();
+    }
+
+    /// Ramps its volume from wherever it is to `volume` over `seconds`
+    fn fade_to(
+        &self,
+        audio_context: &AudioContext,
+        volume: f32,
+        seconds: f32,
+    ) -> Result<(), JsValue> {
+        let now = audio_context.current_time();
+        let gain = self.gain.gain();
+        gain.cancel_scheduled_values(now)?;
+        gain.set_value_at_time(gain.value(), now)?;
+        gain.linear_ramp_to_value_at_time(volume, now + seconds as f64)?;
+        Ok(())
+    }
+
+    fn stop_in(&mut self, audio_context: &AudioContext, seconds: f32) -> Result<(), JsValue> {
+        let at = audio_context.current_time() + seconds as f64;
+        AudioScheduledSourceNode::stop_with_when(&self.source, at)?;
+        self.ends_at = Some(at);
+        self.stopping = true;
+        Ok(())
+    }
+}
+
+impl Track {
+    fn gain(&self) -> &GainNode {
+        match self {
+            Track::Buffered(voice) => &voice.gain,
+            Track::Streamed(stream) => &stream.gain,
+        }
+    }
+
+    fn fade_to(
+        &self,
+        audio_context: &AudioContext,
+        volume: f32,
+        seconds: f32,
+    ) -> Result<(), JsValue> {
+        match self {
+            Track::Buffered(voice) => voice.fade_to(audio_context, volume, seconds),
+            Track::Streamed(stream) => stream.fade_to(audio_context, volume, seconds),
+        }
+    }
+}
+
+impl Stream {
+    /// Starts streaming the track at `url` from its start. Without loop
+    /// points the element loops it on its own, without a gap.
+    fn start(
+        audio_context: &AudioContext,
+        output: &AudioNode,
+        url: &str,
+        loop_points: Option<LoopPoints>,
+    ) -> Result<Self, JsValue> {
+        let element = HtmlAudioElement::new_with_src(url)?;
+        element.set_loop(loop_points.is_none());
+        let source = audio_context.create_media_element_source(&element)?;
+        let gain = audio_context.create_gain()?;
+        source.connect_with_audio_node(&gain)?;
+        gain.connect_with_audio_node(output)?;
+        let stream = Self {
+            element,
+            source,
+            gain,
+            loop_points,
+            ends_at: None,
+        };
+        stream.play();
+        Ok(stream)
+    }
+
+    /// Refused until the page is interacted with, so it's retried by
+    /// `AudioSystem::update` while the element stays paused
+    fn play(&self) {
+        if let Ok(promise) = self.element.play() {
+            wasm_bindgen_futures::spawn_local(async move {
+                let _ = JsFuture::from(promise).await;
+            });
+        }
+    }
+
+    /// Jumps back to the loop start once past the loop end. Checked once a
+    /// frame, so it's only as exact as the frame rate.
+    fn wrap(&self) {
+        let position = self.element.current_time();
+        let wrapped = track_position(position, 0.0, self.loop_points);
+        if wrapped != position {
+            self.element.set_current_time(wrapped);
+        }
+    }
+
+    fn fade_to(
+        &self,
+        audio_context: &AudioContext,
+        volume: f32,
+        seconds: f32,
+    ) -> Result<(), JsValue> {
+        let now = audio_context.current_time();
+        let gain = self.gain.gain();
+        gain.cancel_scheduled_values(now)?;
+        gain.set_value_at_time(gain.value(), now)?;
+        gain.linear_ramp_to_value_at_time(volume, now + seconds as f64)?;
+        Ok(())
+    }
+
+    fn stop(&self) {
+        let _ = self.element.pause();
+        let _ = self.source.disconnect();
+        let _ = self.gain.disconnect();
+    }
+}
+
 impl AudioSystem {
+    /// Opens the default output, running silent where there's none to open,
+    /// like on a machine without an audio device or outside of a browser
     pub fn new() -> Self {
+        #[cfg(target_arch = "wasm32")]
+        let audio_context = AudioContext::new()
+            .inspect_err(|err| error!("No audio output, running silent: {:?}", err))
+            .ok();
+        #[cfg(not(target_arch = "wasm32"))]
+        let audio_context = None;
+        let meter = audio_context.as_ref().and_then(|audio_context| {
+            Self::create_meter(audio_context)
+                .inspect_err(|err| error!("Failed to set up audio metering: {:?}", err))
+                .ok()
+        });
+        let filter = audio_context.as_ref().and_then(|audio_context| {
+            let destination = audio_context.destination();
+            let output: &AudioNode = match &meter {
+                Some(meter) => meter,
+                None => &destination,
+            };
+            Self::create_filter(audio_context, output)
+                .inspect_err(|err| error!("Failed to set up the audio filter: {:?}", err))
+                .ok()
+        });
+        let bus_gains = audio_context.as_ref().and_then(|audio_context| {
+            let destination = audio_context.destination();
+            let output: &AudioNode = match (&filter, &meter) {
+                (Some(filter), _) => filter,
+                (None, Some(meter)) => meter,
+                (None, None) => &destination,
+            };
+            Self::create_buses(audio_context, output)
+                .inspect_err(|err| error!("Failed to set up audio buses: {:?}", err))
+                .ok()
+        });
         Self {
-            audio_context: AudioContext::new().ok(),
+            audio_context,
+            meter,
+            filter,
+            filter_cutoff: None,
             audio_buffers: Vec::new(),
+            voices: Vec::new(),
+            music: None,
+            fading_streams: Vec::new(),
+            bus_gains,
+            bus_volumes: [1.0; AudioBus::COUNT],
+            muted: false,
+            listener: Vec2::ZERO,
+            next_voice_id: 0,
+            finished: Vec::new(),
         }
     }
 
+    /// An audio system without an output, where every sound is a dummy
+    #[cfg(test)]
+    pub fn silent() -> Self {
+        Self {
+            audio_context: None,
+            meter: None,
+            filter: None,
+            filter_cutoff: None,
+            audio_buffers: Vec::new(),
+            voices: Vec::new(),
+            music: None,
+            fading_streams: Vec::new(),
+            bus_gains: None,
+            bus_volumes: [1.0; AudioBus::COUNT],
+            muted: false,
+            listener: Vec2::ZERO,
+            next_voice_id: 0,
+            finished: Vec::new(),
+        }
+    }
+
+    fn create_meter(audio_context: &AudioContext) -> Result<AnalyserNode, JsValue> {
+        let meter = audio_context.create_analyser()?;
+        meter.set_fft_size(METER_WINDOW);
+        meter.connect_with_audio_node(&audio_context.destination())?;
+        Ok(meter)
+    }
+
+    /// Lets everything through until a cutoff is set
+    fn create_filter(
+        audio_context: &AudioContext,
+        output: &AudioNode,
+    ) -> Result<BiquadFilterNode, JsValue> {
+        let filter = audio_context.create_biquad_filter()?;
+        filter.set_type(BiquadFilterType::Lowpass);
+        filter
+            .frequency()
+            .set_value(audio_context.sample_rate() / 2.0);
+        // No resonance bump around the cutoff
+        filter.q().set_value(0.0);
+        filter.connect_with_audio_node(output)?;
+        Ok(filter)
+    }
+
+    fn create_buses(
+        audio_context: &AudioContext,
+        output: &AudioNode,
+    ) -> Result<[GainNode; AudioBus::COUNT], JsValue> {
+        let master = audio_context.create_gain()?;
+        master.connect_with_audio_node(output)?;
+        let sfx = audio_context.create_gain()?;
+        sfx.connect_with_audio_node(&master)?;
+        let music = audio_context.create_gain()?;
+        music.connect_with_audio_node(&master)?;
+        Ok([master, sfx, music])
+    }
+
+    /// Where sounds played on `bus` connect to, `None` when silent
+    fn output(&self, bus: AudioBus) -> Option<AudioNode> {
+        let audio_context = self.audio_context.as_ref()?;
+        if let Some(bus_gains) = &self.bus_gains {
+            return Some(bus_gains[bus as usize].clone().into());
+        }
+        Some(match (&self.filter, &self.meter) {
+            (Some(filter), _) => filter.clone().into(),
+            (None, Some(meter)) => meter.clone().into(),
+            (None, None) => audio_context.destination().into(),
+        })
+    }
+
+    /// Sets how loud `bus` is, from 0 for silent to 1 for as loud as the
+    /// sounds are
+    #[cfg(test)]
+    pub fn set_volume(&mut self, bus: AudioBus, volume: f32) {
+        self.bus_volumes[bus as usize] = volume.max(0.0);
+        self.apply_gain(bus);
+    }
+
+    #[cfg(test)]
+    pub fn volume(&self, bus: AudioBus) -> f32 {
+        self.bus_volumes[bus as usize]
+    }
+
+    /// Silences everything, keeping the master volume for when it's unmuted
+    pub fn set_muted(&mut self, muted: bool) {
+        self.muted = muted;
+        self.apply_gain(AudioBus::Master);
+    }
+
+    /// Muffles everything played by cutting frequencies above `cutoff` Hz,
+    /// or stops muffling with `None`. Eases into it, so it can be set every
+    /// frame, e.g. from how hurt the player is.
+    pub fn set_filter(&mut self, cutoff: Option<f32>) {
+        let cutoff = cutoff.map(|cutoff| cutoff.max(1.0));
+        if cutoff == self.filter_cutoff {
+            return;
+        }
+        self.filter_cutoff = cutoff;
+        let (Some(audio_context), Some(filter)) = (&self.audio_context, &self.filter) else {
+            return;
+        };
+        // Nothing is above half the sample rate, so nothing is cut
+        let frequency = cutoff.unwrap_or(audio_context.sample_rate() / 2.0);
+        let eased = filter.frequency().set_target_at_time(
+            frequency,
+            audio_context.current_time(),
+            FILTER_EASING,
+        );
+        if let Err(err) = eased {
+            error!("Failed to set the audio filter: {:?}", err);
+        }
+    }
+
+    #[cfg(test)]
+    pub fn filter(&self) -> Option<f32> {
+        self.filter_cutoff
+    }
+
+    /// How loud `bus` is set to be, after muting
+    fn gain(&self, bus: AudioBus) -> f32 {
+        match bus {
+            AudioBus::Master if self.muted => 0.0,
+            _ => self.bus_volumes[bus as usize],
+        }
+    }
+
+    fn apply_gain(&self, bus: AudioBus) {
+        if let Some(bus_gains) = &self.bus_gains {
+            bus_gains[bus as usize].gain().set_value(self.gain(bus));
+        }
+    }
+
+    /// Loudness of what has been playing lately, as the RMS of the last
+    /// `METER_WINDOW` samples of output. Always 0 when silent.
+    #[cfg(test)]
+    pub fn current_rms(&self) -> f32 {
+        let Some(meter) = &self.meter else {
+            return 0.0;
+        };
+        let mut samples = vec![0.0; meter.fft_size() as usize];
+        meter.get_float_time_domain_data(&mut samples);
+        rms(&samples)
+    }
+
+    /// Whether sounds played actually reach an output
+    #[cfg(test)]
+    pub fn is_active(&self) -> bool {
+        self.audio_context.is_some()
+    }
+
+    /// Whether the browser is holding the output back until the player
+    /// interacts with the page, as it does with sound on pages that were just
+    /// opened. Sounds played once in the meantime are skipped rather than all
+    /// heard at once when it's unlocked, while loops and music wait for it.
+    pub fn is_locked(&self) -> bool {
+        self.audio_context
+            .as_ref()
+            .is_some_and(|audio_context| audio_context.state() == AudioContextState::Suspended)
+    }
+
+    /// Unlocks the output, see `is_locked`. Only works when called from a
+    /// key press, click or touch.
     pub fn on_user_interaction(&mut self) {
         if let Some(audio_context) = &self.audio_context {
             if audio_context.state() == AudioContextState::Suspended {
@@ -48,6 +606,9 @@ impl AudioSystem {
         }
     }
 
+    /// Starts decoding a sound in any format the browser can decode, e.g.
+    /// WAV, OGG, FLAC or MP3, see `is_ready`. What it can't decode ends up
+    /// as a dummy.
     pub fn load_buffer(&mut self, bytes: &[u8]) -> AudioHandle {
         let handle = AudioHandle {
             index: self.audio_buffers.len(),
@@ -57,7 +618,15 @@ impl AudioSystem {
             let uint8_array = Uint8Array::new(&array_buffer);
             uint8_array.copy_from(bytes);
 
-            let future = JsFuture::from(audio_context.decode_audio_data(&array_buffer).unwrap());
+            let promise = match audio_context.decode_audio_data(&array_buffer) {
+                Ok(promise) => promise,
+                Err(err) => {
+                    error!("Failed to start decoding audio data: {:?}", err);
+                    self.audio_buffers.push(LoadableAudio::Dummy);
+                    return handle;
+                }
+            };
+            let future = JsFuture::from(promise);
 
             let entry = Rc::new(RefCell::new(LoadState::Loading));
 
@@ -71,13 +640,13 @@ impl AudioSystem {
                                 *entry_clone.borrow_mut() = LoadState::Done(audio_buffer);
                             }
                             Err(err) => {
-                                error!("Failed to decode audio data: {:?}", err);
+                                error!("Failed to decode audio: {:?}", err);
                                 *entry_clone.borrow_mut() = LoadState::Failed;
                             }
                         }
                     }
                     Err(err) => {
-                        error!("Failed to decode audio data: {:?}", err);
+                        error!("Failed to decode audio: {:?}", err);
                         *entry_clone.borrow_mut() = LoadState::Failed;
                     }
                 }
@@ -91,7 +660,203 @@ impl AudioSystem {
         handle
     }
 
-    pub fn play(&mut self, handle: &AudioHandle, speed: f32) {
+    /// Keeps a long track encoded, to be decoded bit by bit as it plays on
+    /// the music channel rather than all at once, see `play_music`. Only the
+    /// music channel plays streamed tracks.
+    pub fn load_stream(&mut self, bytes: &[u8]) -> AudioHandle {
+        let handle = AudioHandle {
+            index: self.audio_buffers.len(),
+        };
+        let loadable = match self.audio_context {
+            Some(_) => match Self::object_url(bytes) {
+                Ok(url) => LoadableAudio::Streamed(url),
+                Err(err) => {
+                    error!("Failed to stream audio: {:?}", err);
+                    LoadableAudio::Dummy
+                }
+            },
+            None => LoadableAudio::Dummy,
+        };
+        self.audio_buffers.push(loadable);
+        handle
+    }
+
+    fn object_url(bytes: &[u8]) -> Result<String, JsValue> {
+        let parts = Array::of1(&Uint8Array::from(bytes));
+        let blob = Blob::new_with_u8_array_sequence(&parts)?;
+        Url::create_object_url_with_blob(&blob)
+    }
+
+    /// Whether a sound is done decoding, into something playable or into a
+    /// dummy when it couldn't be. Streamed tracks are ready right away.
+    pub fn is_ready(&self, handle: &AudioHandle) -> bool {
+        match &self.audio_buffers[handle.index] {
+            LoadableAudio::Loading(state) => !matches!(*state.borrow(), LoadState::Loading),
+            LoadableAudio::Loaded(_) | LoadableAudio::Streamed(_) | LoadableAudio::Dummy => true,
+        }
+    }
+
+    /// Plays `handle` once on the `Sfx` bus, sped up by `speed`
+    pub fn play(&mut self, handle: &AudioHandle, speed: f32) -> SoundInstance {
+        self.play_on(handle, AudioBus::Sfx, speed)
+    }
+
+    pub fn play_on(&mut self, handle: &AudioHandle, bus: AudioBus, speed: f32) -> SoundInstance {
+        self.play_with(
+            handle,
+            Playback {
+                bus,
+                speed,
+                position: None,
+                looped: false,
+            },
+        )
+    }
+
+    /// Like `play`, panned towards where `position` is from the listener and
+    /// quieter the further it is, see `set_listener`
+    pub fn play_at(&mut self, handle: &AudioHandle, position: Vec2, speed: f32) -> SoundInstance {
+        self.play_with(
+            handle,
+            Playback {
+                bus: AudioBus::Sfx,
+                speed,
+                position: Some(position),
+                looped: false,
+            },
+        )
+    }
+
+    /// Like `play_on`, over and over until it's stopped, e.g. a hum while
+    /// something channels
+    #[cfg(test)]
+    pub fn play_looped(
+        &mut self,
+        handle: &AudioHandle,
+        bus: AudioBus,
+        speed: f32,
+    ) -> SoundInstance {
+        self.play_with(
+            handle,
+            Playback {
+                bus,
+                speed,
+                position: None,
+                looped: true,
+            },
+        )
+    }
+
+    fn play_with(&mut self, handle: &AudioHandle, playback: Playback) -> SoundInstance {
+        let instance = SoundInstance {
+            id: self.next_voice_id,
+        };
+        self.next_voice_id += 1;
+        self.start_voice(handle, playback, instance.id);
+        instance
+    }
+
+    /// Has `poll_finished` report `instance` once it's done, e.g. to play a
+    /// sound after another. Only watched sounds are kept track of. One that
+    /// isn't playing anymore, or couldn't play at all, is done right away.
+    #[cfg(test)]
+    pub fn watch(&mut self, instance: &SoundInstance) {
+        match find_voice(&mut self.voices, instance) {
+            Some(voice) => voice.watched = true,
+            None => {
+                if !self.finished.contains(instance) {
+                    self.finished.push(instance.clone());
+                }
+            }
+        }
+    }
+
+    /// Watched instances that played to their end, or couldn't play at all,
+    /// since the last call. Stopped ones don't finish. Ends are found by
+    /// `update`, so they can be a frame late.
+    #[cfg(test)]
+    pub fn poll_finished(&mut self) -> Vec<SoundInstance> {
+        std::mem::take(&mut self.finished)
+    }
+
+    #[cfg(test)]
+    pub fn stop(&mut self, instance: &SoundInstance) {
+        let Some(index) = self.voices.iter().position(|voice| voice.id == instance.id) else {
+            return;
+        };
+        let voice = self.voices.swap_remove(index);
+        if voice.paused {
+            return;
+        }
+        if let Err(err) = AudioScheduledSourceNode::stop(&voice.source) {
+            error!("Failed to stop audio: {:?}", err);
+        }
+    }
+
+    /// Stops `instance` where it is, to `resume` from there
+    #[cfg(test)]
+    pub fn pause(&mut self, instance: &SoundInstance) {
+        let (Some(audio_context), Some(voice)) =
+            (&self.audio_context, find_voice(&mut self.voices, instance))
+        else {
+            return;
+        };
+        if let Err(err) = voice.pause(audio_context) {
+            error!("Failed to pause audio: {:?}", err);
+        }
+    }
+
+    #[cfg(test)]
+    pub fn resume(&mut self, instance: &SoundInstance) {
+        let (Some(audio_context), Some(voice)) =
+            (&self.audio_context, find_voice(&mut self.voices, instance))
+        else {
+            return;
+        };
+        if let Err(err) = voice.resume(audio_context) {
+            error!("Failed to resume audio: {:?}", err);
+        }
+    }
+
+    /// Sets how loud `instance` is right away, see `fade_to` to ease into it
+    #[cfg(test)]
+    pub fn set_instance_volume(&mut self, instance: &SoundInstance, volume: f32) {
+        let (Some(audio_context), Some(voice)) =
+            (&self.audio_context, find_voice(&mut self.voices, instance))
+        else {
+            return;
+        };
+        if let Err(err) = voice.fade_to(audio_context, volume.max(0.0), 0.0) {
+            error!("Failed to set audio volume: {:?}", err);
+        }
+    }
+
+    /// Changes the speed `instance` plays at, and with it its pitch
+    #[cfg(test)]
+    pub fn set_speed(&mut self, instance: &SoundInstance, speed: f32) {
+        let (Some(audio_context), Some(voice)) =
+            (&self.audio_context, find_voice(&mut self.voices, instance))
+        else {
+            return;
+        };
+        voice.set_speed(audio_context, speed);
+    }
+
+    /// Whether `instance` is playing, not paused and not done yet. Sounds
+    /// don't play at all when silent.
+    #[cfg(test)]
+    pub fn is_playing(&self, instance: &SoundInstance) -> bool {
+        self.voices
+            .iter()
+            .any(|voice| voice.id == instance.id && !voice.paused)
+    }
+
+    /// Where sounds played with `play_at` are heard from, e.g. the player
+    pub fn set_listener(&mut self, position: Vec2) {
+        self.listener = position;
+    }
+
+    fn start_voice(&mut self, handle: &AudioHandle, playback: Playback, id: u64) {
         // If it's dummy, do nothing
         // If it's loading and failed, convert to dummy
         // If it's loading and done, convert to loaded and call play again
@@ -109,6 +874,10 @@ impl AudioSystem {
                 log::warn!("Attempted to play a dummy audio handle");
                 QueryResult::Noop
             }
+            LoadableAudio::Streamed(_) => {
+                log::warn!("Streamed audio only plays as music");
+                QueryResult::Noop
+            }
             LoadableAudio::Loading(state) => {
                 let state = state.borrow();
                 match &*state {
@@ -116,14 +885,14 @@ impl AudioSystem {
                         log::warn!("Audio is still loading, cannot play yet");
                         QueryResult::Noop
                     }
-                    LoadState::Done(audio_buffer) => QueryResult::IntoLoaded,
+                    LoadState::Done(_) => QueryResult::IntoLoaded,
                     LoadState::Failed => {
                         log::error!("Failed to load audio, converting to dummy");
                         QueryResult::IntoDummy
                     }
                 }
             }
-            LoadableAudio::Loaded(audio_buffer) => QueryResult::DoPlay,
+            LoadableAudio::Loaded(_) => QueryResult::DoPlay,
         };
         match result {
             QueryResult::IntoLoaded => {
@@ -140,22 +909,41 @@ impl AudioSystem {
                     _ => unreachable!(),
                 };
                 self.audio_buffers[handle.index] = LoadableAudio::Loaded(audio_buffer);
-                self.play(handle, speed); // Call play again with the loaded audio
+                self.start_voice(handle, playback, id); // Call play again with the loaded audio
             }
             QueryResult::IntoDummy => {
                 self.audio_buffers[handle.index] = LoadableAudio::Dummy;
             }
             QueryResult::Noop => {}
+            QueryResult::DoPlay if !playback.looped && self.is_locked() => {}
             QueryResult::DoPlay => {
                 if let LoadableAudio::Loaded(audio_buffer) = &self.audio_buffers[handle.index] {
-                    if let Some(audio_context) = &self.audio_context {
-                        let source = audio_context.create_buffer_source().unwrap();
-                        source.set_buffer(Some(audio_buffer));
-                        source.playback_rate().set_value(speed); // Set playback speed
-                        source
-                            .connect_with_audio_node(&audio_context.destination())
-                            .unwrap();
-                        source.start().unwrap();
+                    if let (Some(audio_context), Some(output)) =
+                        (&self.audio_context, self.output(playback.bus))
+                    {
+                        let placement = playback
+                            .position
+                            .map(|position| spatialize(position - self.listener));
+                        let loop_points = playback.looped.then(|| whole_track(audio_buffer));
+                        let started = Self::placed_output(audio_context, output, placement)
+                            .and_then(|output| {
+                                let speed = playback.speed;
+                                Voice::start(
+                                    audio_context,
+                                    &output,
+                                    audio_buffer,
+                                    speed,
+                                    loop_points,
+                                )
+                            });
+                        match started {
+                            Ok(mut voice) => {
+                                voice.id = id;
+                                voice.index = handle.index;
+                                self.voices.push(voice);
+                            }
+                            Err(err) => error!("Failed to play audio: {:?}", err),
+                        }
                     } else {
                         log::error!("Audio context is not initialized");
                     }
@@ -163,4 +951,477 @@ impl AudioSystem {
             }
         }
     }
+
+    /// Fades every sound playing `handle`, the music included, from its
+    /// volume to `volume` over `seconds`. Sounds played later start at full
+    /// volume.
+    #[cfg(test)]
+    pub fn fade_to(&mut self, handle: &AudioHandle, volume: f32, seconds: f32) {
+        let Some(audio_context) = &self.audio_context else {
+            return;
+        };
+        let (volume, seconds) = (volume.max(0.0), seconds.max(0.0));
+        let music = self
+            .music
+            .as_ref()
+            .filter(|music| music.handle.index == handle.index)
+            .and_then(|music| music.track.as_ref());
+        let voices = self
+            .voices
+            .iter()
+            .filter(|voice| voice.index == handle.index);
+        for faded in voices
+            .map(|voice| voice.fade_to(audio_context, volume, seconds))
+            .chain(music.map(|track| track.fade_to(audio_context, volume, seconds)))
+        {
+            if let Err(err) = faded {
+                error!("Failed to fade audio: {:?}", err);
+            }
+        }
+    }
+
+    /// Loops `handle` on the music channel in place of whatever it was
+    /// playing, from the start and then between `loop_points`, or over the
+    /// whole track without any. Asking for the track that's already playing
+    /// keeps it going, so each room can ask for its own when it's entered.
+    /// A track still loading starts once it's loaded, see `update`. Long
+    /// tracks are best loaded with `load_stream`, so they aren't decoded
+    /// all at once.
+    pub fn play_music(&mut self, handle: &AudioHandle, loop_points: Option<LoopPoints>) {
+        self.crossfade_music(handle, loop_points, 0.0);
+    }
+
+    /// Like `play_music`, fading the track that was playing out while
+    /// `handle` fades in, over `seconds`
+    pub fn crossfade_music(
+        &mut self,
+        handle: &AudioHandle,
+        loop_points: Option<LoopPoints>,
+        seconds: f32,
+    ) {
+        if self
+            .music()
+            .is_some_and(|music| music.index == handle.index)
+        {
+            return;
+        }
+        self.fade_out_music(seconds);
+        self.music = Some(Music {
+            handle: handle.clone(),
+            loop_points,
+            track: None,
+            fade_in: seconds.max(0.0),
+        });
+        self.start_music();
+    }
+
+    #[cfg(test)]
+    pub fn stop_music(&mut self) {
+        self.fade_out_music(0.0);
+    }
+
+    /// Takes the track off the music channel, to stop once it fades out
+    fn fade_out_music(&mut self, seconds: f32) {
+        let Some(track) = self.music.take().and_then(|music| music.track) else {
+            return;
+        };
+        let Some(audio_context) = &self.audio_context else {
+            return;
+        };
+        let seconds = seconds.max(0.0);
+        let faded = track.fade_to(audio_context, 0.0, seconds);
+        match track {
+            Track::Buffered(mut voice) => {
+                match faded.and_then(|()| voice.stop_in(audio_context, seconds)) {
+                    Ok(()) => self.voices.push(voice),
+                    Err(err) => error!("Failed to stop music: {:?}", err),
+                }
+            }
+            Track::Streamed(mut stream) => {
+                if let Err(err) = faded {
+                    error!("Failed to fade out music: {:?}", err);
+                }
+                stream.ends_at = Some(audio_context.current_time() + seconds as f64);
+                self.fading_streams.push(stream);
+            }
+        }
+    }
+
+    /// The track on the music channel, whether it's loaded yet or not
+    pub fn music(&self) -> Option<&AudioHandle> {
+        self.music.as_ref().map(|music| &music.handle)
+    }
+
+    /// Finds sounds that are done playing, see `watch`, starts music that
+    /// was waiting for its track to load and keeps streamed music between
+    /// its loop points. Call once a frame.
+    pub fn update(&mut self) {
+        if let Some(audio_context) = &self.audio_context {
+            let now = audio_context.current_time();
+            let finished = &mut self.finished;
+            self.voices.retain(|voice| {
+                let playing = voice.ends_at.is_none_or(|ends_at| ends_at > now);
+                if !playing && voice.watched && !voice.stopping {
+                    finished.push(SoundInstance { id: voice.id });
+                }
+                playing
+            });
+            self.fading_streams.retain(|stream| {
+                let playing = stream.ends_at.is_none_or(|ends_at| ends_at > now);
+                if !playing {
+                    stream.stop();
+                }
+                playing
+            });
+        }
+        let locked = self.is_locked();
+        match self.music.as_ref().map(|music| &music.track) {
+            Some(None) => self.start_music(),
+            Some(Some(Track::Streamed(stream))) => {
+                if stream.element.paused() && !locked {
+                    stream.play();
+                }
+                stream.wrap();
+            }
+            Some(Some(Track::Buffered(_))) | None => {}
+        }
+    }
+
+    fn start_music(&mut self) {
+        let Some((index, loop_points, fade_in)) = self
+            .music
+            .as_ref()
+            .map(|music| (music.handle.index, music.loop_points, music.fade_in))
+        else {
+            return;
+        };
+        let started = match &self.audio_buffers[index] {
+            LoadableAudio::Streamed(url) => {
+                let (Some(audio_context), Some(output)) =
+                    (&self.audio_context, self.output(AudioBus::Music))
+                else {
+                    return;
+                };
+                Stream::start(audio_context, &output, url, loop_points).map(Track::Streamed)
+            }
+            _ => {
+                let Some(audio_buffer) = self.loaded_buffer(index) else {
+                    return;
+                };
+                let (Some(audio_context), Some(output)) =
+                    (&self.audio_context, self.output(AudioBus::Music))
+                else {
+                    return;
+                };
+                let loop_points = loop_points.unwrap_or_else(|| whole_track(&audio_buffer));
+                let id = self.next_voice_id;
+                self.next_voice_id += 1;
+                Voice::start(
+                    audio_context,
+                    &output,
+                    &audio_buffer,
+                    1.0,
+                    Some(loop_points),
+                )
+                .map(|mut voice| {
+                    voice.id = id;
+                    voice.index = index;
+                    Track::Buffered(voice)
+                })
+            }
+        };
+        let started = started.and_then(|track| {
+            if let (Some(audio_context), true) = (&self.audio_context, fade_in > 0.0) {
+                track.gain().gain().set_value(0.0);
+                track.fade_to(audio_context, 1.0, fade_in)?;
+            }
+            Ok(track)
+        });
+        match started {
+            Ok(track) => {
+                if let Some(music) = &mut self.music {
+                    music.track = Some(track);
+                }
+            }
+            Err(err) => {
+                // Not retried every frame
+                error!("Failed to play music: {:?}", err);
+                self.audio_buffers[index] = LoadableAudio::Dummy;
+            }
+        }
+    }
+
+    /// The sound at `index` if it's done loading into something playable
+    fn loaded_buffer(&mut self, index: usize) -> Option<AudioBuffer> {
+        if let LoadableAudio::Loading(state) = &self.audio_buffers[index] {
+            let loaded = match &*state.borrow() {
+                LoadState::Loading => return None,
+                LoadState::Done(audio_buffer) => LoadableAudio::Loaded(audio_buffer.clone()),
+                LoadState::Failed => LoadableAudio::Dummy,
+            };
+            self.audio_buffers[index] = loaded;
+        }
+        match &self.audio_buffers[index] {
+            LoadableAudio::Loaded(audio_buffer) => Some(audio_buffer.clone()),
+            LoadableAudio::Loading(_) | LoadableAudio::Streamed(_) | LoadableAudio::Dummy => None,
+        }
+    }
+
+    /// What to connect a sound to for it to be panned by `placement.0` and
+    /// played at `placement.1` of its volume on the way to `output`
+    fn placed_output(
+        audio_context: &AudioContext,
+        output: AudioNode,
+        placement: Option<(f32, f32)>,
+    ) -> Result<AudioNode, JsValue> {
+        let Some((pan, volume)) = placement else {
+            return Ok(output);
+        };
+        let gain = audio_context.create_gain()?;
+        gain.gain().set_value(volume);
+        let panner = audio_context.create_stereo_panner()?;
+        panner.pan().set_value(pan);
+        gain.connect_with_audio_node(&panner)?;
+        panner.connect_with_audio_node(&output)?;
+        Ok(gain.into())
+    }
+}
+
+#[cfg(test)]
+fn find_voice<'a>(voices: &'a mut [Voice], instance: &SoundInstance) -> Option<&'a mut Voice> {
+    voices.iter_mut().find(|voice| voice.id == instance.id)
+}
+
+fn whole_track(audio_buffer: &AudioBuffer) -> LoopPoints {
+    LoopPoints {
+        start: 0.0,
+        end: audio_buffer.duration(),
+    }
+}
+
+/// Seconds into a track after playing `played` seconds of it from `offset`,
+/// wrapped back between the loop points once past their end
+fn track_position(offset: f64, played: f64, loop_points: Option<LoopPoints>) -> f64 {
+    let position = offset + played;
+    match loop_points {
+        Some(LoopPoints { start, end }) if end > start && position >= end => {
+            start + (position - start) % (end - start)
+        }
+        _ => position,
+    }
+}
+
+/// How a sound `offset` away from the listener is heard, as its pan from -1
+/// for only the left to 1 for only the right, and the fraction of its volume
+/// left after distance
+fn spatialize(offset: Vec2) -> (f32, f32) {
+    let pan = (offset.x / FULL_PAN_DISTANCE).clamp(-1.0, 1.0);
+    let volume = FULL_VOLUME_DISTANCE / offset.length().max(FULL_VOLUME_DISTANCE);
+    (pan, volume)
+}
+
+#[cfg(test)]
+fn rms(samples: &[f32]) -> f32 {
+    if samples.is_empty() {
+        return 0.0;
+    }
+    let power = samples.iter().map(|sample| sample * sample).sum::<f32>() / samples.len() as f32;
+    power.sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_output_means_silent_dummies() {
+        // Outside of a browser there's never an output to open
+        let mut audio = AudioSystem::new();
+        assert!(!audio.is_active());
+
+        let handle = audio.load_buffer(include_bytes!("assets/attack_1.wav"));
+        assert!(matches!(
+            audio.audio_buffers[handle.index],
+            LoadableAudio::Dummy
+        ));
+        audio.play(&handle, 1.0);
+        audio.play(&handle, 2.0);
+        audio.on_user_interaction();
+        assert!(!audio.is_locked());
+        assert!(!AudioSystem::silent().is_active());
+    }
+
+    #[test]
+    fn sounds_that_cant_be_decoded_are_dummies() {
+        let mut audio = AudioSystem::silent();
+        let garbage = audio.load_buffer(b"not a sound");
+        assert!(audio.is_ready(&garbage));
+        let music = audio.load_buffer(include_bytes!("assets/music/drone.ogg"));
+        assert!(audio.is_ready(&music));
+    }
+
+    #[test]
+    fn loud_output_measures_above_silence() {
+        let silence = vec![0.0; METER_WINDOW as usize];
+        assert_eq!(rms(&silence), 0.0);
+
+        // A loud hit, a sine at 80% of full scale
+        let hit: Vec<f32> = (0..METER_WINDOW)
+            .map(|i| 0.8 * (i as f32 * 0.05).sin())
+            .collect();
+        let loudness = rms(&hit);
+        assert!((loudness - 0.8 / 2.0f32.sqrt()).abs() < 0.01);
+        assert!(loudness > rms(&hit.iter().map(|s| s * 0.1).collect::<Vec<_>>()));
+        assert_eq!(rms(&[]), 0.0);
+
+        // Nothing is ever heard without an output
+        let mut audio = AudioSystem::silent();
+        let handle = audio.load_buffer(include_bytes!("assets/attack_1.wav"));
+        audio.play(&handle, 1.0);
+        assert_eq!(audio.current_rms(), 0.0);
+    }
+    #[test]
+    fn music_keeps_playing_the_same_track() {
+        let mut audio = AudioSystem::silent();
+        let forest = audio.load_buffer(include_bytes!("assets/walk.wav"));
+        let boss = audio.load_buffer(include_bytes!("assets/attack_1.wav"));
+        assert!(audio.music().is_none());
+
+        let loop_points = LoopPoints {
+            start: 0.5,
+            end: 2.0,
+        };
+        audio.play_music(&forest, Some(loop_points));
+        audio.update();
+        assert_eq!(audio.music().map(|music| music.index), Some(forest.index));
+        // Asked for again on entering another room of the same area
+        audio.play_music(&forest, None);
+        assert_eq!(audio.music.as_ref().unwrap().loop_points, Some(loop_points));
+
+        audio.play_music(&boss, None);
+        assert_eq!(audio.music().map(|music| music.index), Some(boss.index));
+        audio.stop_music();
+        assert!(audio.music().is_none());
+        audio.update();
+
+        // Long tracks stream instead, which sound effects can't
+        let drone = audio.load_stream(include_bytes!("assets/music/drone.ogg"));
+        assert!(audio.is_ready(&drone));
+        audio.play_music(&drone, None);
+        audio.update();
+        assert_eq!(audio.music().map(|music| music.index), Some(drone.index));
+        let effect = audio.play(&drone, 1.0);
+        assert!(!audio.is_playing(&effect));
+    }
+    #[test]
+    fn muting_keeps_the_master_volume() {
+        let mut audio = AudioSystem::silent();
+        audio.set_volume(AudioBus::Music, 0.25);
+        audio.set_volume(AudioBus::Sfx, -1.0);
+        assert_eq!(audio.volume(AudioBus::Music), 0.25);
+        assert_eq!(audio.volume(AudioBus::Sfx), 0.0);
+
+        audio.set_volume(AudioBus::Master, 0.5);
+        audio.set_muted(true);
+        assert_eq!(audio.gain(AudioBus::Master), 0.0);
+        assert_eq!(audio.gain(AudioBus::Music), 0.25);
+        audio.set_muted(false);
+        assert_eq!(audio.gain(AudioBus::Master), 0.5);
+
+        let handle = audio.load_buffer(include_bytes!("assets/walk.wav"));
+        audio.play_on(&handle, AudioBus::Music, 1.0);
+        assert!(audio.output(AudioBus::Sfx).is_none());
+
+        audio.set_filter(Some(600.0));
+        assert_eq!(audio.filter(), Some(600.0));
+        audio.set_filter(Some(-5.0));
+        assert_eq!(audio.filter(), Some(1.0));
+        audio.set_filter(None);
+        assert_eq!(audio.filter(), None);
+    }
+
+    #[test]
+    fn crossfading_switches_the_track_right_away() {
+        let mut audio = AudioSystem::silent();
+        let forest = audio.load_buffer(include_bytes!("assets/walk.wav"));
+        let boss = audio.load_buffer(include_bytes!("assets/attack_1.wav"));
+        audio.play_music(&forest, None);
+        audio.fade_to(&forest, 0.5, 1.0);
+
+        audio.crossfade_music(&boss, None, 2.0);
+        assert_eq!(audio.music().map(|music| music.index), Some(boss.index));
+        assert_eq!(audio.music.as_ref().unwrap().fade_in, 2.0);
+        audio.update();
+        // Nothing plays without an output, so nothing is left fading out
+        assert!(audio.voices.is_empty());
+    }
+
+    #[test]
+    fn far_sounds_are_quieter_and_to_the_side() {
+        assert_eq!(spatialize(Vec2::ZERO), (0.0, 1.0));
+        assert_eq!(spatialize(Vec2::new(0.0, -3.0)), (0.0, 1.0));
+
+        let (pan, volume) = spatialize(Vec2::new(-4.0, 0.0));
+        assert_eq!((pan, volume), (-0.5, 1.0));
+        // Off screen to the right, still heard there
+        let (pan, volume) = spatialize(Vec2::new(16.0, 0.0));
+        assert_eq!((pan, volume), (1.0, 0.25));
+        assert!(spatialize(Vec2::new(0.0, 32.0)).1 < volume);
+
+        let mut audio = AudioSystem::silent();
+        let windup = audio.load_buffer(include_bytes!("assets/windup_2.wav"));
+        audio.set_listener(Vec2::new(2.0, 2.0));
+        audio.play_at(&windup, Vec2::new(10.0, 2.0), 1.0);
+    }
+    #[test]
+    fn looped_tracks_wrap_back_to_the_loop_start() {
+        let loop_points = Some(LoopPoints {
+            start: 2.0,
+            end: 6.0,
+        });
+        // The intro plays once
+        assert_eq!(track_position(0.0, 1.5, loop_points), 1.5);
+        assert_eq!(track_position(1.0, 5.5, loop_points), 2.5);
+        assert_eq!(track_position(5.0, 9.0, loop_points), 2.0 + 8.0 % 4.0);
+        // Sounds that don't loop just go past the end
+        assert_eq!(track_position(1.0, 7.0, None), 8.0);
+    }
+
+    #[test]
+    fn each_play_is_an_instance_of_its_own() {
+        let mut audio = AudioSystem::silent();
+        let hum = audio.load_buffer(include_bytes!("assets/walk.wav"));
+        let first = audio.play_looped(&hum, AudioBus::Sfx, 1.0);
+        let second = audio.play(&hum, 1.0);
+        assert_ne!(first, second);
+
+        // Nothing plays without an output, so there's nothing to control
+        assert!(!audio.is_playing(&first));
+        audio.pause(&first);
+        audio.set_speed(&first, 0.5);
+        audio.set_instance_volume(&first, 0.2);
+        audio.resume(&first);
+        audio.stop(&first);
+        assert!(!audio.is_playing(&second));
+    }
+
+    #[test]
+    fn sounds_that_cant_play_finish_right_away() {
+        let mut audio = AudioSystem::silent();
+        let windup = audio.load_buffer(include_bytes!("assets/windup_2.wav"));
+        assert!(audio.poll_finished().is_empty());
+
+        let first = audio.play(&windup, 1.0);
+        let second = audio.play_at(&windup, Vec2::ZERO, 1.0);
+        audio.update();
+        // Nothing is kept track of unless it's watched
+        assert!(audio.poll_finished().is_empty());
+
+        audio.watch(&first);
+        audio.watch(&second);
+        audio.watch(&first);
+        audio.update();
+        assert_eq!(audio.poll_finished(), vec![first, second]);
+        assert!(audio.poll_finished().is_empty());
+    }
 }
@@ -0,0 +1,199 @@
+//! Moves the camera around: following a target with some slack and
+//! smoothing, staying inside the room, and shaking when things get hit.
+
+use glam::{Vec2, Vec3};
+
+use crate::{geometry::Transform, ortographic_camera::OrthoCamera};
+
+pub struct CameraController {
+    camera: OrthoCamera,
+    /// World point in the middle of the view, before any shake
+    pub center: Vec2,
+    /// How quickly the camera catches up, as the rate of an exponential
+    /// decay of the distance left: higher is snappier
+    pub follow_rate: f32,
+    /// Half the size of the box around the middle of the view the target can
+    /// move in without the camera following, in world units
+    pub dead_zone: Vec2,
+    /// Furthest the shake moves the view, in world units, at full trauma
+    pub max_shake: f32,
+    /// Trauma lost per second
+    pub trauma_decay: f32,
+    // The world rectangle the view stays in, as its corners
+    bounds: Option<(Vec2, Vec2)>,
+    // From 0 to 1, the shake grows with its square
+    trauma: f32,
+    // Drives the shake, so it's the same on every run
+    shake_time: f32,
+}
+
+impl CameraController {
+    pub fn new(camera: OrthoCamera) -> Self {
+        Self {
+            camera,
+            center: Vec2::ZERO,
+            follow_rate: 8.0,
+            dead_zone: Vec2::new(1.0, 0.75),
+            max_shake: 0.3,
+            trauma_decay: 1.5,
+            bounds: None,
+            trauma: 0.0,
+            shake_time: 0.0,
+        }
+    }
+
+    /// Keeps the view inside the rectangle from `min` to `max`, e.g. a room.
+    /// Rectangles smaller than the view are kept in its middle.
+    pub fn set_bounds(&mut self, min: Vec2, max: Vec2) {
+        self.bounds = Some((min, max));
+        self.center = self.clamped(self.center);
+    }
+
+    /// Moves straight to `target`, e.g. after changing rooms
+    pub fn snap_to(&mut self, target: Vec2) {
+        self.center = self.clamped(target);
+    }
+
+    /// Shakes the view more, e.g. on a hit. Trauma tops out at 1.
+    pub fn add_trauma(&mut self, amount: f32) {
+        self.trauma = (self.trauma + amount).clamp(0.0, 1.0);
+    }
+
+    #[cfg(test)]
+    pub fn trauma(&self) -> f32 {
+        self.trauma
+    }
+
+    /// Follows `target` and calms the shake down
+    pub fn update(&mut self, target: Vec2, delta_time: f32) {
+        // Only as far as it takes to get the target back in the dead zone
+        let offset = target - self.center;
+        let desired = self.center + offset - offset.clamp(-self.dead_zone, self.dead_zone);
+        let blend = 1.0 - (-self.follow_rate * delta_time).exp();
+        self.center = self.clamped(self.center.lerp(desired, blend));
+
+        self.trauma = (self.trauma - self.trauma_decay * delta_time).max(0.0);
+        self.shake_time += delta_time;
+    }
+
+    /// How far the shake moves the view right now
+    pub fn shake_offset(&self) -> Vec2 {
+        let amount = self.max_shake * self.trauma * self.trauma;
+        // Sines of unrelated frequencies, jittery without being random
+        let t = self.shake_time;
+        let noise = |a: f32, b: f32| ((t * a).sin() + (t * b).sin()) * 0.5;
+        Vec2::new(noise(47.0, 71.3), noise(53.7, 61.1)) * amount
+    }
+
+    /// Maps the world to the screen, `center` plus the shake in the middle
+    pub fn view_transform(&self) -> Transform {
+        let center = self.center + self.shake_offset();
+        self.camera
+            .get_transform()
+            .translate(Vec3::new(-center.x, -center.y, 0.0))
+    }
+
+    /// The world point drawn `screen` pixels from the top-left corner of the
+    /// view, shake included, so it's whatever is under the cursor
+    #[cfg(test)]
+    pub fn screen_to_world(&self, screen: Vec2) -> Vec2 {
+        self.center + self.shake_offset() + self.camera.screen_to_view(screen)
+    }
+
+    fn clamped(&self, center: Vec2) -> Vec2 {
+        let Some((min, max)) = self.bounds else {
+            return center;
+        };
+        let half_view = self.camera.view_size() / 2.0;
+        let low = min + half_view;
+        let high = max - half_view;
+        let middle = (min + max) / 2.0;
+        Vec2::new(
+            if low.x <= high.x {
+                center.x.clamp(low.x, high.x)
+            } else {
+                middle.x
+            },
+            if low.y <= high.y {
+                center.y.clamp(low.y, high.y)
+            } else {
+                middle.y
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A view of 20 by 15 world units
+    fn controller() -> CameraController {
+        CameraController::new(OrthoCamera::new(320.0, 240.0, 16.0))
+    }
+
+    #[test]
+    fn follows_past_the_dead_zone_and_settles() {
+        let mut camera = controller();
+        camera.update(Vec2::new(0.5, -0.5), 1.0 / 60.0);
+        assert_eq!(camera.center, Vec2::ZERO);
+
+        let target = Vec2::new(5.0, 0.0);
+        camera.update(target, 1.0 / 60.0);
+        assert!(camera.center.x > 0.0 && camera.center.x < 4.0);
+        for _ in 0..120 {
+            camera.update(target, 1.0 / 60.0);
+        }
+        // Stops with the target on the dead zone's edge
+        assert!((camera.center.x - (target.x - camera.dead_zone.x)).abs() < 1e-3);
+    }
+
+    #[test]
+    fn stays_inside_the_room() {
+        let mut camera = controller();
+        // As wide as the view is, taller than it
+        camera.set_bounds(Vec2::ZERO, Vec2::new(16.0, 30.0));
+        camera.snap_to(Vec2::new(2.0, 2.0));
+        assert_eq!(camera.center, Vec2::new(8.0, 7.5));
+        camera.snap_to(Vec2::new(30.0, 29.0));
+        assert_eq!(camera.center, Vec2::new(8.0, 22.5));
+    }
+
+    #[test]
+    fn shake_fades_with_trauma() {
+        let mut camera = controller();
+        assert_eq!(camera.shake_offset(), Vec2::ZERO);
+        camera.add_trauma(2.0);
+        assert_eq!(camera.trauma(), 1.0);
+        camera.update(Vec2::ZERO, 0.1);
+        let shaking = camera.shake_offset();
+        assert!(shaking != Vec2::ZERO && shaking.length() <= camera.max_shake * 1.5);
+
+        for _ in 0..60 {
+            camera.update(Vec2::ZERO, 1.0 / 60.0);
+        }
+        assert_eq!(camera.trauma(), 0.0);
+        assert_eq!(camera.shake_offset(), Vec2::ZERO);
+    }
+
+    #[test]
+    fn screen_points_map_back_to_what_is_drawn_there() {
+        let mut camera = controller();
+        camera.snap_to(Vec2::new(12.0, 9.0));
+        camera.add_trauma(0.5);
+        camera.update(Vec2::new(12.0, 9.0), 0.1);
+        assert_eq!(
+            camera.screen_to_world(Vec2::new(160.0, 120.0)),
+            camera.center + camera.shake_offset()
+        );
+        for screen in [Vec2::ZERO, Vec2::new(320.0, 240.0), Vec2::new(40.0, 200.0)] {
+            let world = camera.screen_to_world(screen);
+            let ndc = camera.view_transform().project(world.extend(0.0));
+            let drawn_at = Vec2::new(ndc.x + 1.0, 1.0 - ndc.y) / 2.0 * Vec2::new(320.0, 240.0);
+            assert!(
+                (drawn_at - screen).length() < 1e-3,
+                "{screen} is drawn at {drawn_at}"
+            );
+        }
+    }
+}
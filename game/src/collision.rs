@@ -52,18 +52,6 @@ impl VertexCollision {
     pub fn any(&self) -> bool {
         self.top_left || self.bottom_left || self.bottom_right || self.top_right
     }
-
-    pub fn count(&self) -> usize {
-        [
-            self.top_left,
-            self.bottom_left,
-            self.bottom_right,
-            self.top_right,
-        ]
-        .iter()
-        .filter(|&&b| b)
-        .count()
-    }
 }
 
 impl EdgeCollision {
@@ -82,10 +70,6 @@ impl EdgeCollision {
             || !self.bottom_edge.is_empty()
             || !self.right_edge.is_empty()
     }
-
-    pub fn total_intersections(&self) -> usize {
-        self.top_edge.len() + self.left_edge.len() + self.bottom_edge.len() + self.right_edge.len()
-    }
 }
 
 impl Collision {
@@ -227,7 +211,7 @@ impl Collision {
         let t = ((p1.x - p3.x) * (p3.y - p4.y) - (p1.y - p3.y) * (p3.x - p4.x)) / denom;
         let u = -((p1.x - p2.x) * (p1.y - p3.y) - (p1.y - p2.y) * (p1.x - p3.x)) / denom;
 
-        if t >= 0.0 && t <= 1.0 && u >= 0.0 && u <= 1.0 {
+        if (0.0..=1.0).contains(&t) && (0.0..=1.0).contains(&u) {
             // Calculate intersection point
             let intersection_x = p1.x + t * (p2.x - p1.x);
             let intersection_y = p1.y + t * (p2.y - p1.y);
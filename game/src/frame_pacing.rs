@@ -0,0 +1,201 @@
+//! Frame timing. The game is simulated in fixed steps fed by the measured
+//! frame times, and there's an optional frame cap for when rendering isn't
+//! already throttled by vsync. Most of the time left in a capped frame is
+//! slept through with the event loop waiting, and only the last bit is spun
+//! so the next frame isn't late.
+
+/// How much of the remaining frame time is spun instead of slept, in ms
+const SPIN_MS: f64 = 1.0;
+
+/// Seconds of game time in each simulation step
+pub const FIXED_STEP: f32 = 1.0 / 60.0;
+/// Longest frame taken into account, so a stall (a hidden tab, a debugger)
+/// doesn't come back as a burst of catching up
+const MAX_FRAME_DELTA: f32 = 0.25;
+/// Most steps simulated in one frame. Past this the game runs slower instead
+/// of falling further behind each frame trying to catch up.
+const MAX_STEPS_PER_FRAME: usize = 5;
+/// Weight of the newest frame time in the running average
+const DELTA_SMOOTHING: f32 = 0.2;
+/// Frame times off from the average by more than this factor aren't jitter
+/// but a change of pace (or a stall), and replace the average outright so it
+/// doesn't lag behind and keep the game running fast or slow after
+const DELTA_SNAP_FACTOR: f32 = 2.0;
+
+/// What to simulate for a frame
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameSteps {
+    /// Deltas to update the game with, in order
+    pub steps: Vec<f32>,
+    /// How far into the next step the frame is, from 0 to 1, for rendering
+    /// in between the last two steps
+    pub alpha: f32,
+}
+
+/// Turns irregular frame times into fixed simulation steps. Frame times are
+/// clamped and smoothed with a running average to take the jitter out, and
+/// the accumulator is the clock the game actually runs by.
+#[derive(Debug, Clone)]
+pub struct TimeStep {
+    step: f32,
+    smoothed_delta: Option<f32>,
+    accumulator: f32,
+}
+
+impl TimeStep {
+    pub fn new(step: f32) -> Self {
+        Self {
+            step,
+            smoothed_delta: None,
+            accumulator: 0.0,
+        }
+    }
+
+    /// Accounts for a frame that took `delta_time` seconds
+    pub fn advance(&mut self, delta_time: f32) -> FrameSteps {
+        let delta_time = delta_time.clamp(0.0, MAX_FRAME_DELTA);
+        let smoothed = match self.smoothed_delta {
+            Some(previous)
+                if delta_time <= previous * DELTA_SNAP_FACTOR
+                    && delta_time >= previous / DELTA_SNAP_FACTOR =>
+            {
+                previous + (delta_time - previous) * DELTA_SMOOTHING
+            }
+            _ => delta_time,
+        };
+        self.smoothed_delta = Some(smoothed);
+        self.accumulator += smoothed;
+
+        let due = (self.accumulator / self.step).floor() as usize;
+        let steps = due.min(MAX_STEPS_PER_FRAME);
+        self.accumulator -= steps as f32 * self.step;
+        if due > steps {
+            // Drop the time we can't keep up with rather than owe it
+            self.accumulator %= self.step;
+        }
+        FrameSteps {
+            steps: vec![self.step; steps],
+            alpha: (self.accumulator / self.step).clamp(0.0, 1.0),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FramePacing {
+    pub sleep_ms: f64,
+    pub spin_ms: f64,
+}
+
+impl FramePacing {
+    /// Splits what's left of a `target_ms` frame after `elapsed_ms` into a
+    /// sleep and a final spin. Late frames don't wait at all.
+    pub fn new(target_ms: f64, elapsed_ms: f64) -> Self {
+        let remaining = (target_ms - elapsed_ms).max(0.0);
+        let sleep_ms = (remaining - SPIN_MS).max(0.0);
+        Self {
+            sleep_ms,
+            spin_ms: remaining - sleep_ms,
+        }
+    }
+}
+
+pub fn now_ms() -> f64 {
+    web_sys::window().unwrap().performance().unwrap().now()
+}
+
+pub fn spin_until(deadline_ms: f64) {
+    while now_ms() < deadline_ms {
+        std::hint::spin_loop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sleeps_all_but_the_last_millisecond() {
+        let pacing = FramePacing::new(1000.0 / 30.0, 10.0);
+        assert!((pacing.sleep_ms - (1000.0 / 30.0 - 11.0)).abs() < 1e-9);
+        assert_eq!(pacing.spin_ms, 1.0);
+    }
+
+    #[test]
+    fn nearly_done_frames_only_spin() {
+        let pacing = FramePacing::new(16.0, 15.5);
+        assert_eq!(pacing.sleep_ms, 0.0);
+        assert_eq!(pacing.spin_ms, 0.5);
+    }
+
+    #[test]
+    fn late_frames_do_not_wait() {
+        assert_eq!(
+            FramePacing::new(16.0, 20.0),
+            FramePacing {
+                sleep_ms: 0.0,
+                spin_ms: 0.0
+            }
+        );
+    }
+
+    #[test]
+    fn steady_frames_step_once_each() {
+        let mut time_step = TimeStep::new(FIXED_STEP);
+        let mut total = 0;
+        for _ in 0..60 {
+            let frame = time_step.advance(FIXED_STEP);
+            assert!(frame.steps.len() <= 2);
+            assert!((0.0..=1.0).contains(&frame.alpha));
+            total += frame.steps.len();
+        }
+        assert!((59..=60).contains(&total));
+    }
+
+    #[test]
+    fn bursty_frames_keep_the_average_rate() {
+        let mut time_step = TimeStep::new(FIXED_STEP);
+        let mut total = 0;
+        let mut single_steps = 0;
+        // Frames alternating between short and long, still averaging 60 fps
+        for frame in 0..240 {
+            let delta = FIXED_STEP * if frame % 2 == 0 { 0.75 } else { 1.25 };
+            let steps = time_step.advance(delta).steps;
+            assert!(steps.iter().all(|&step| step == FIXED_STEP));
+            assert!(steps.len() <= 2);
+            total += steps.len();
+            single_steps += (steps.len() == 1) as usize;
+        }
+        assert!((238..=240).contains(&total), "{} steps", total);
+        // Unsmoothed, nearly half the frames would step twice or not at all
+        assert!(single_steps > 200, "{} single steps", single_steps);
+    }
+
+    #[test]
+    fn stalls_do_not_spiral() {
+        let mut time_step = TimeStep::new(FIXED_STEP);
+        time_step.advance(FIXED_STEP);
+        let stall = time_step.advance(10.0);
+        assert!(stall.steps.len() <= MAX_STEPS_PER_FRAME);
+
+        // Even a run of slow frames never owes more than a step afterwards
+        for _ in 0..20 {
+            let frame = time_step.advance(MAX_FRAME_DELTA);
+            assert_eq!(frame.steps.len(), MAX_STEPS_PER_FRAME);
+            assert!(time_step.accumulator < FIXED_STEP);
+        }
+        // And back at speed it doesn't fast forward to make up for them
+        let recovered: usize = (0..30)
+            .map(|_| time_step.advance(FIXED_STEP).steps.len())
+            .sum();
+        assert!(recovered <= 31, "{} steps", recovered);
+    }
+
+    #[test]
+    fn short_frames_carry_over_into_alpha() {
+        let mut time_step = TimeStep::new(FIXED_STEP);
+        let frame = time_step.advance(FIXED_STEP / 4.0);
+        assert!(frame.steps.is_empty());
+        assert!((frame.alpha - 0.25).abs() < 1e-4);
+        assert_eq!(time_step.advance(-1.0).steps.len(), 0);
+    }
+}
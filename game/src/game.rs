@@ -1,115 +1,283 @@
-use core::{f32, num};
+use core::f32;
 use std::{collections::HashMap, rc::Rc};
 
+use game_build_tools::level::{CollisionKind, DoorDirection, TileGrid, TILE_SIZE};
+#[cfg(test)]
+use game_build_tools::level::{GeneratedRoom, LevelLayer};
 use glam::{Vec2, Vec3};
-use glyphon::{
-    cosmic_text::{ttf_parser::math, Align, CacheKeyFlags, FeatureTag, FontFeatures},
-    Attrs, Color as GlyphonColor,
-};
+use glyphon::{cosmic_text::Align, Attrs, Color as GlyphonColor, Metrics};
+use image::RgbaImage;
 use log::info;
 use rand::{rngs::StdRng, seq::IndexedRandom, Rng, SeedableRng};
 use wgpu::Color;
+#[cfg(test)]
 use winit::keyboard::KeyCode;
 
+#[cfg(test)]
+use crate::frame_pacing::FIXED_STEP;
+#[cfg(any(test, all(debug_assertions, target_arch = "wasm32")))]
+use crate::hot_reload::LevelHotReloader;
+#[cfg(test)]
+use crate::room_loading::check_tile_size;
 use crate::{
+    actions::Action,
+    assets::{self, AssetManager},
     audio::{AudioHandle, AudioSystem},
+    camera_controller::CameraController,
     collision::Collision,
     geometry::Transform,
     nimi::{convert_latin_to_ucsur, number_to_toki_pona},
     ortographic_camera::OrthoCamera,
     renderer::{
-        gizmo::{GizmoSprite, GizmoSpriteSheet},
-        text::FeaturedTextBuffer,
-        Drawer, EngineColor, RenderingSystem,
+        animation::{AnimationClip, AnimationEvent, AnimationPlayer, ClipHandle},
+        backend::RendererBackend,
+        gizmo::{BlendMode, GizmoSprite, GizmoSpriteSheet, MaterialHandle},
+        lighting::{Lighting, OcclusionMap},
+        material::{self, Material},
+        post::{identity_lut_image, PostEffect, PostEffectHandle},
+        text::{FeaturedTextBuffer, TextOptions, TextWrap},
+        tilemap::TilemapRenderer,
+        transition::{Transition, TransitionStyle},
+        DrawLayer, Drawer, EngineColor, RenderingSystem,
     },
-    InputSystem, InputSystemConfig, KeyPressGroupHandle,
+    room_loading::{BackgroundLevelLoader, DecodedLevel, LoadError, LoadResult},
+    spatial_hash::SpatialHash,
+    status_effects::{StatusEffectKind, StatusEffects},
+    tween::Tween,
+    ComboHandle, InputSystem, InputSystemConfig, KeyPressGroupHandle,
 };
 
 struct GameLevelSpec {
-    pub background: GizmoSpriteSheet,
+    name: &'static str,
+    // Drawn from the level tileset, `None` in rooms made up by tests
+    floor: Option<TilemapRenderer>,
     pub decoration: GizmoSpriteSheet,
-    collision: Vec<(Transform, u32)>, // (Transform, tile_id)
-    enemy_locations: Vec<Vec2>,
-    num_tiles: (usize, usize),
-    tile_size: f32,
+    collision: Vec<(Transform, CollisionKind)>,
+    // Enemy spawn points of each wave, from the values in the enemies csv
+    enemy_waves: Vec<Vec<Vec2>>,
+    doors: Vec<(DoorDirection, Vec2)>, // (direction, tile center)
+    grid: TileGrid,
+    // Tint over the whole room, white for none
+    ambient: EngineColor,
+    // The walls, blocking the room's light
+    occlusion: Option<OcclusionMap>,
+}
+
+/// How far inside the room, from the door tile's center, a character's feet
+/// end up after walking in through it
+const DOOR_ENTRY_DISTANCE: f32 = 0.75;
+
+/// Unit step in tile coordinates (y grows downwards) out of the room through a door
+fn door_outward(direction: DoorDirection) -> Vec2 {
+    match direction {
+        DoorDirection::Down => Vec2::new(0.0, 1.0),
+        DoorDirection::Right => Vec2::new(1.0, 0.0),
+        DoorDirection::Up => Vec2::new(0.0, -1.0),
+        DoorDirection::Left => Vec2::new(-1.0, 0.0),
+    }
+}
+
+pub struct GameLevelLoadData<'a> {
+    pub name: &'static str,
+    pub floor_csv: &'a str,
+    pub decoration_bytes: &'a [u8],
+    pub collision_csv: &'a str,
+    pub enemies_csv: &'a str,
+}
+
+fn level_sheet(
+    rendering_system: &mut RenderingSystem,
+    image: &RgbaImage,
+) -> Result<GizmoSpriteSheet, LoadError> {
+    let texture = rendering_system.gizmo_texture_from_image(image)?;
+    Ok(rendering_system.gizmo_sprite_sheet_from_texture(texture, [0.0, 0.0], [1.0, 1.0], [1, 1]))
 }
 
-struct GameLevelLoadData {
-    background_bytes: &'static [u8],
-    decoration_bytes: &'static [u8],
-    collision_csv: &'static str,
-    enemies_csv: &'static str,
+/// The floor of a level from `tileset`, `floor` holding the index of the tile
+/// of each cell counting along the tileset's rows
+fn level_floor(
+    rendering_system: &RenderingSystem,
+    tileset: &GizmoSpriteSheet,
+    floor: &[Vec<u32>],
+) -> TilemapRenderer {
+    let [columns, _] = tileset.num_tiles();
+    let rows: Vec<Vec<Option<[u32; 2]>>> = floor
+        .iter()
+        .map(|row| {
+            row.iter()
+                .map(|&index| Some([index % columns, index / columns]))
+                .collect()
+        })
+        .collect();
+    TilemapRenderer::new(rendering_system, tileset.clone(), &rows)
+}
+
+#[cfg(test)]
+fn layer_rows(layer: &LevelLayer) -> Vec<Vec<u32>> {
+    layer
+        .map_to(|value| value)
+        .rows()
+        .into_iter()
+        .map(|row| row.to_vec())
+        .collect()
 }
 
 impl GameLevelSpec {
     pub fn load(
-        load_data: GameLevelLoadData,
+        load_data: GameLevelLoadData<'_>,
+        tileset: &GizmoSpriteSheet,
         rendering_system: &mut RenderingSystem,
-    ) -> Result<Self, Box<dyn std::error::Error>> {
-        let background = rendering_system.gizmo_sprite_sheet_from_encoded_image(
-            load_data.background_bytes,
-            [0.0, 0.0],
-            [1.0, 1.0],
-            [1, 1],
-        );
+    ) -> Result<Self, LoadError> {
+        let decoded = DecodedLevel::decode(&load_data)?;
+        Self::from_decoded(&decoded, tileset, rendering_system)
+    }
 
-        let decoration = rendering_system.gizmo_sprite_sheet_from_encoded_image(
-            load_data.decoration_bytes,
-            [0.0, 0.0],
-            [1.0, 1.0],
-            [1, 1],
-        );
+    /// Uploads a level decoded by the `BackgroundLevelLoader`
+    pub fn from_decoded(
+        level: &DecodedLevel,
+        tileset: &GizmoSpriteSheet,
+        rendering_system: &mut RenderingSystem,
+    ) -> Result<Self, LoadError> {
+        let floor = level_floor(rendering_system, tileset, &level.floor);
+        let decoration = level_sheet(rendering_system, &level.decoration)?;
+        Self::from_grids(
+            level.name,
+            floor,
+            decoration,
+            &level.collision,
+            &level.enemies,
+            rendering_system,
+        )
+    }
+
+    /// Builds a room generated at runtime, skipping the encode/decode round trip
+    #[cfg(test)]
+    pub fn from_generated(
+        name: &'static str,
+        room: &GeneratedRoom,
+        tileset: &GizmoSpriteSheet,
+        rendering_system: &mut RenderingSystem,
+    ) -> Result<Self, LoadError> {
+        let collision = layer_rows(&room.collision);
+        check_tile_size(name, room.with_walls.dimensions(), &collision)?;
+        let floor = level_floor(rendering_system, tileset, &layer_rows(&room.floor_tiles));
+        let decoration = level_sheet(rendering_system, &room.with_walls)?;
+        Self::from_grids(
+            name,
+            floor,
+            decoration,
+            &collision,
+            &layer_rows(&room.enemies),
+            rendering_system,
+        )
+    }
+
+    fn from_grids(
+        name: &'static str,
+        floor: TilemapRenderer,
+        decoration: GizmoSpriteSheet,
+        collision: &[Vec<u32>],
+        enemies: &[Vec<u32>],
+        rendering_system: &RenderingSystem,
+    ) -> Result<Self, LoadError> {
+        let grid = TileGrid::of_rows(collision);
 
         // Let's do the 0 iq collisions for now
         let mut colliders = Vec::new();
-        for (y, row) in load_data.collision_csv.lines().enumerate() {
-            for (x, tile_id) in row.split(',').enumerate() {
-                let tile_id: u32 = tile_id.trim().parse().expect("Failed to parse tile ID");
+        let mut doors = Vec::new();
+        for (y, row) in collision.iter().enumerate() {
+            for (x, &tile_id) in row.iter().enumerate() {
                 if tile_id != 0 {
+                    let kind = CollisionKind::from_id(tile_id).ok_or_else(|| {
+                        format!("Unknown collision id {} at ({}, {})", tile_id, x, y)
+                    })?;
+                    let corner = grid.tile_to_world((x, y));
                     let transform = Transform::new()
-                        .translate(Vec3::new(x as f32, y as f32, 0.0))
+                        .translate(corner.extend(0.0))
                         .scale(Vec3::new(1.0, 1.0, 1.0));
-                    colliders.push((transform, tile_id));
+                    colliders.push((transform, kind));
+                    if let CollisionKind::Door(direction) = kind {
+                        doors.push((direction, grid.tile_center((x, y))));
+                    }
                 }
             }
         }
 
-        let mut enemy_locations = Vec::new();
-        for (y, row) in load_data.enemies_csv.lines().enumerate() {
-            for (x, tile_id) in row.split(',').enumerate() {
-                let tile_id: u32 = tile_id.trim().parse().expect("Failed to parse tile ID");
-                if tile_id != 0 {
-                    enemy_locations.push(Vec2::new(x as f32 + 0.5, y as f32 + 0.25));
+        let walls: Vec<Vec<bool>> = collision
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .map(|&id| CollisionKind::from_id(id) == Some(CollisionKind::Wall))
+                    .collect()
+            })
+            .collect();
+
+        // Nonzero values are the wave (starting at 1) the enemy spawns in
+        let mut enemy_waves: Vec<Vec<Vec2>> = Vec::new();
+        for (y, row) in enemies.iter().enumerate() {
+            for (x, &wave) in row.iter().enumerate() {
+                let wave = wave as usize;
+                if wave != 0 {
+                    if enemy_waves.len() < wave {
+                        enemy_waves.resize(wave, Vec::new());
+                    }
+                    // Feet a little above the center so the body fits in the tile
+                    enemy_waves[wave - 1].push(grid.tile_center((x, y)) - Vec2::new(0.0, 0.25));
                 }
             }
         }
 
         Ok(Self {
-            background,
+            name,
+            floor: Some(floor),
             decoration,
             collision: colliders,
-            enemy_locations,
-            num_tiles: (16, 16),
-            tile_size: 32.0,
+            enemy_waves,
+            doors,
+            grid,
+            ambient: EngineColor::WHITE,
+            occlusion: Some(rendering_system.create_occlusion_map(&walls)),
         })
     }
 
+    /// Where the feet of a character coming in through the `direction` door
+    /// land. `feet` only matters along the door, keeping wide doors from
+    /// shifting the character sideways. Rooms without such a door are
+    /// entered through the middle of that wall.
+    pub fn entry_position(&self, direction: DoorDirection, feet: Vec2) -> Vec2 {
+        let mut door_tiles = self
+            .doors
+            .iter()
+            .filter(|(door, _)| *door == direction)
+            .map(|(_, center)| *center);
+        let (min, max) = match door_tiles.next() {
+            Some(first) => door_tiles.fold((first, first), |(min, max), center| {
+                (min.min(center), max.max(center))
+            }),
+            None => {
+                let middle = self.grid.world_size() / 2.0;
+                let edge = middle + door_outward(direction) * (middle - 0.5);
+                (edge, edge)
+            }
+        };
+        feet.clamp(min, max) - door_outward(direction) * DOOR_ENTRY_DISTANCE
+    }
+
     pub fn get_local_space(&self, base_transform: &Transform) -> Transform {
-        let (width, height) = self.num_tiles;
-        base_transform.scale(Vec3::new(width as f32, height as f32, 1.0))
+        base_transform.scale(self.grid.world_size().extend(1.0))
     }
 
-    pub fn collides_with<CollisionHandler: FnMut(Collision, u32)>(
+    pub fn collides_with<CollisionHandler: FnMut(Collision, CollisionKind)>(
         &self,
         origin: &Transform,
         other_space: &Transform,
         handler: &mut CollisionHandler,
     ) {
-        for (collider, id) in &self.collision {
+        for (collider, kind) in &self.collision {
             if let Some(collision) =
                 Collision::do_spaces_collide(&origin.then(collider), other_space)
             {
-                handler(collision, *id);
+                handler(collision, *kind);
             }
         }
     }
@@ -120,9 +288,9 @@ impl GameLevelSpec {
         drawer: &mut Drawer,
         sprite: GizmoSprite,
     ) {
-        for (collider, id) in &self.collision {
+        for (collider, _) in &self.collision {
             let transform = origin.then(collider);
-            drawer.draw_square_slow(Some(&transform), Some(&EngineColor::RED), sprite.clone());
+            drawer.draw_square_slow(Some(&transform), Some(&EngineColor::RED), sprite);
         }
     }
 
@@ -131,7 +299,7 @@ impl GameLevelSpec {
         end: Vec2,
         level: &GameLevelSpec,
         level_origin: &Transform,
-        query_value: u32,
+        query: CollisionKind,
     ) -> bool {
         let direction = (end - start).normalize();
         let distance = start.distance(end);
@@ -144,15 +312,40 @@ impl GameLevelSpec {
             .set_origin(&Transform::new().translate(Vec3::new(0.0, 0.5, 0.0)));
 
         let mut collides = false;
-        level.collides_with(level_origin, &line_transform, &mut |_collision, id| {
-            if id == query_value {
+        level.collides_with(level_origin, &line_transform, &mut |_collision, kind| {
+            if kind == query {
                 collides = true;
             }
         });
         collides
     }
+
+    /// Whether something `width` wide at `start` can see `end`. Rays are
+    /// cast from across its width and at least half of them have to clear
+    /// the walls, so a wall edge grazing the middle of the view doesn't hide
+    /// what's beside it.
+    fn has_line_of_sight(&self, start: Vec2, end: Vec2, width: f32) -> bool {
+        let across = (end - start).normalize_or_zero().perp();
+        let clear_rays = (0..SIGHT_RAYS)
+            .filter(|&ray| {
+                let offset = across * width * (ray as f32 / (SIGHT_RAYS - 1) as f32 - 0.5);
+                !Self::line_collides_with_level(
+                    start + offset,
+                    end + offset,
+                    self,
+                    &Transform::new(),
+                    CollisionKind::Wall,
+                )
+            })
+            .count();
+        clear_rays * 2 >= SIGHT_RAYS
+    }
 }
 
+/// Rays `GameLevelSpec::has_line_of_sight` casts, spread evenly across the
+/// width of whoever is looking
+const SIGHT_RAYS: usize = 4;
+
 pub struct MovementController {
     pub position: Vec2,
     pub movement_speed: f32, // Default speed
@@ -168,10 +361,10 @@ struct MovementIntention {
 impl MovementIntention {
     pub fn from_input(input: &InputSystem) -> Self {
         Self {
-            up: input.is_physical_key_down(KeyCode::KeyW),
-            down: input.is_physical_key_down(KeyCode::KeyS),
-            left: input.is_physical_key_down(KeyCode::KeyA),
-            right: input.is_physical_key_down(KeyCode::KeyD),
+            up: input.is_action_down(Action::MoveUp),
+            down: input.is_action_down(Action::MoveDown),
+            left: input.is_action_down(Action::MoveLeft),
+            right: input.is_action_down(Action::MoveRight),
         }
     }
 
@@ -201,11 +394,16 @@ impl MovementController {
         }
     }
 
+    /// Moves along the intention, one axis at a time, undoing the moves
+    /// that run into the level or into one of the other characters' `bodies`.
+    /// Bodies already overlapping don't hold each other in place, so they
+    /// can still walk apart.
     fn update<F: Fn(&Transform) -> Option<Collision>>(
         &mut self,
         intention: &MovementIntention,
         delta_time: f32,
         check_collision: F,
+        bodies: &[Transform],
     ) {
         let speed = self.movement_speed * delta_time;
         let mut movement_vector = Vec2::ZERO;
@@ -228,41 +426,157 @@ impl MovementController {
         if movement_vector.length() > 0.0 {
             movement_vector = movement_vector.normalize();
             movement_vector *= speed;
+            self.move_by(movement_vector, check_collision, bodies);
+        }
+    }
 
-            //self.position += player_direction;
-            let previous_x = self.position.x;
-            self.position.x += movement_vector.x;
-            if check_collision(&self.collider(&Transform::new())).is_some() {
-                self.position.x = previous_x; // revert x movement if collision
-            }
-            let previous_y = self.position.y;
-            self.position.y += movement_vector.y;
-            if check_collision(&self.collider(&Transform::new())).is_some() {
-                self.position.y = previous_y; // revert y movement if collision
-            }
+    /// Moves by `movement_vector` with the same collisions as `update`, e.g.
+    /// for steering on top of what the character meant to do
+    fn move_by<F: Fn(&Transform) -> Option<Collision>>(
+        &mut self,
+        movement_vector: Vec2,
+        check_collision: F,
+        bodies: &[Transform],
+    ) {
+        let previous_x = self.position.x;
+        let before = self.collider(&Transform::new());
+        self.position.x += movement_vector.x;
+        if check_collision(&self.collider(&Transform::new())).is_some()
+            || self.moved_into_body(&before, bodies)
+        {
+            self.position.x = previous_x; // revert x movement if collision
+        }
+        let previous_y = self.position.y;
+        let before = self.collider(&Transform::new());
+        self.position.y += movement_vector.y;
+        if check_collision(&self.collider(&Transform::new())).is_some()
+            || self.moved_into_body(&before, bodies)
+        {
+            self.position.y = previous_y; // revert y movement if collision
         }
     }
 
+    fn moved_into_body(&self, before: &Transform, bodies: &[Transform]) -> bool {
+        let after = self.collider(&Transform::new());
+        bodies.iter().any(|body| {
+            Collision::do_spaces_collide(&after, body).is_some()
+                && Collision::do_spaces_collide(before, body).is_none()
+        })
+    }
+
     pub fn feet_position(&self) -> Vec2 {
         Vec2::new(self.position.x, self.position.y + 0.25) // Feet position is slightly above the center
     }
 
+    pub fn set_feet_position(&mut self, feet: Vec2) {
+        self.position = Vec2::new(feet.x, feet.y - 0.25);
+    }
+
     pub fn local_space(&self, base_transform: &Transform) -> Transform {
         base_transform
             .translate(Vec3::new(self.position.x, self.position.y, 0.0))
             .set_origin(&Transform::new().translate(Vec3::new(0.5, 0.5, 0.0)))
     }
 
+    /// Flat patch under the feet the character's shadow covers
+    pub fn shadow_space(&self, base_transform: &Transform) -> Transform {
+        self.local_space(base_transform)
+            .translate(Vec3::new(0.0, 0.4, 0.0))
+            .around_pivot(Vec2::splat(0.5), |t| t.scale(Vec3::new(0.6, 0.2, 1.0)))
+    }
+
     pub fn collider(&self, base_transform: &Transform) -> Transform {
-        base_transform
-            .translate(Vec3::new(self.position.x, self.position.y, 0.0))
+        self.local_space(base_transform)
             .translate(Vec3::new(0.0, 0.25, 0.0))
-            .scale(Vec3::new(0.5, 0.5, 1.0)) // half size for collider
-            .set_origin(&Transform::new().translate(Vec3::new(0.5, 0.5, 0.0)))
+            // half size for collider
+            .around_pivot(Vec2::splat(0.5), |t| {
+                t.scale(Vec3::new(BODY_SIZE, BODY_SIZE, 1.0))
+            })
+    }
+}
+
+/// Side of the square around a character's feet that other characters and
+/// the level collide with
+const BODY_SIZE: f32 = 0.5;
+/// How far from a character's feet other bodies are looked for, as far as
+/// one could be and still get in the way this frame
+const BODY_QUERY_RADIUS: f32 = 1.0;
+
+/// The collider of a character with its feet at `feet`
+fn body_at(feet: Vec2) -> Transform {
+    let mut controller = MovementController::new(Vec2::ZERO, 0.0);
+    controller.set_feet_position(feet);
+    controller.collider(&Transform::new())
+}
+
+/// The bodies of the living `enemies`, hashed by where their feet are
+fn enemy_bodies(enemies: &[Enemy]) -> SpatialHash {
+    let mut hash = SpatialHash::new(BODY_QUERY_RADIUS);
+    for (index, enemy) in enemies.iter().enumerate() {
+        if enemy.character.health > 0.0 {
+            hash.insert(index, enemy.character.controller.feet_position());
+        }
     }
+    hash
 }
 
-#[derive(Clone, Copy, Debug)]
+/// Enemies closer than this to each other steer apart
+const SEPARATION_RADIUS: f32 = 0.9;
+/// Speed of the separation steering, in tiles per second at full strength
+const SEPARATION_SPEED: f32 = 1.0;
+
+/// Boids-style separation: the way out of the crowd of `neighbors` (id and
+/// feet) for the character with id `me`, weighted by how close each one is.
+/// Neighbors on the very same spot are told apart by their ids.
+fn separation(me: usize, feet: Vec2, neighbors: impl IntoIterator<Item = (usize, Vec2)>) -> Vec2 {
+    let mut steering = Vec2::ZERO;
+    for (other, other_feet) in neighbors {
+        let away = feet - other_feet;
+        let distance = away.length();
+        if distance >= SEPARATION_RADIUS {
+            continue;
+        }
+        let direction = if distance > 1e-4 {
+            away / distance
+        } else if me < other {
+            Vec2::NEG_X
+        } else {
+            Vec2::X
+        };
+        steering += direction * (1.0 - distance / SEPARATION_RADIUS);
+    }
+    steering.clamp_length_max(1.0)
+}
+
+/// Pushes overlapping bodies apart, each moving half the way out along the
+/// axis they overlap the least on, and bodies in the very same spot apart
+/// sideways. Pushes into the level are dropped.
+fn separate_bodies<F: Fn(&Transform) -> bool>(bodies: &mut [&mut MovementController], blocked: F) {
+    for first in 0..bodies.len() {
+        for second in first + 1..bodies.len() {
+            let delta = bodies[second].feet_position() - bodies[first].feet_position();
+            let overlap = Vec2::splat(BODY_SIZE) - delta.abs();
+            if overlap.x <= 0.0 || overlap.y <= 0.0 {
+                continue;
+            }
+            let push = if overlap.x <= overlap.y {
+                Vec2::new(overlap.x * if delta.x < 0.0 { -0.5 } else { 0.5 }, 0.0)
+            } else {
+                Vec2::new(0.0, overlap.y * if delta.y < 0.0 { -0.5 } else { 0.5 })
+            };
+            for (index, push) in [(first, -push), (second, push)] {
+                let body = &mut bodies[index];
+                let previous = body.position;
+                body.position += push;
+                if blocked(&body.collider(&Transform::new())) {
+                    body.position = previous;
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 enum CharacterOrientation {
     Up,
     Down,
@@ -270,29 +584,27 @@ enum CharacterOrientation {
     Right,
 }
 
+/// A walk cycle per orientation, carrying the stride over when turning
 struct CharacterWalkAnimation {
-    sheet: GizmoSpriteSheet,
+    player: AnimationPlayer,
     orientation: CharacterOrientation,
-    current_frame: usize,
-    frame_duration: f32,
-    elapsed_time: f32,
-    speed: f32, // Speed of the animation
-}
-
-enum AnimationEvent {
-    FrameChanged(usize),
-    None,
+    // Indexed by `CharacterWalkAnimation::row`
+    clips: [ClipHandle; 4],
 }
 
 impl CharacterWalkAnimation {
     pub fn new(sheet: GizmoSpriteSheet, orientation: CharacterOrientation, speed: f32) -> Self {
+        let mut player = AnimationPlayer::new(sheet);
+        let clips = [0, 1, 2, 3].map(|row| {
+            let frames = [1, 2, 1, 0].map(|column| [column, row]).to_vec();
+            player.add_clip(AnimationClip::new(frames, 0.2, true))
+        });
+        player.set_speed(speed);
+        player.play(clips[Self::row(orientation) as usize]);
         Self {
-            sheet,
+            player,
             orientation,
-            current_frame: 0,
-            frame_duration: 0.2, // Duration for each frame
-            elapsed_time: 0.0,
-            speed, // Speed of the animation
+            clips,
         }
     }
 
@@ -301,90 +613,340 @@ impl CharacterWalkAnimation {
         delta_time: f32,
         orientation: Option<CharacterOrientation>,
     ) -> AnimationEvent {
-        let mut event = AnimationEvent::None;
         match orientation {
             Some(new_orientation) => {
                 self.orientation = new_orientation;
-                self.elapsed_time += delta_time * self.speed; // Adjust elapsed time by speed factor
-                if self.elapsed_time >= self.frame_duration {
-                    self.current_frame = (self.current_frame + 1) % 4; // Cycle through 4 frames
-                    event = AnimationEvent::FrameChanged(self.current_frame);
-                    self.elapsed_time = 0.0;
-                }
+                self.player
+                    .switch_to(self.clips[Self::row(new_orientation) as usize]);
+                self.player.update(delta_time)
             }
             None => {
-                self.current_frame = 0;
-                self.elapsed_time = 0.0;
+                self.player.restart();
+                AnimationEvent::None
             }
         }
-        event
     }
 
-    pub fn get_current_sprite(&self) -> GizmoSprite {
-        let walk_cycle = [1, 2, 1, 0];
-        let offset = walk_cycle[self.current_frame];
-        let sprite_index = match self.orientation {
-            CharacterOrientation::Up => [offset, 2],
-            CharacterOrientation::Down => [offset, 0],
-            CharacterOrientation::Left => [offset, 3],
-            CharacterOrientation::Right => [offset, 1],
+    pub fn get_current_sprite(&self) -> GizmoSprite<'_> {
+        self.player.current_sprite().expect("Sprite not found")
+    }
+
+    // The row of the sheet walking that way
+    fn row(orientation: CharacterOrientation) -> u32 {
+        match orientation {
+            CharacterOrientation::Up => 2,
+            CharacterOrientation::Down => 0,
+            CharacterOrientation::Left => 3,
+            CharacterOrientation::Right => 1,
+        }
+    }
+}
+
+const POISE_REGEN_PER_SECOND: f32 = 5.0;
+
+/// What happened to a character when it took a hit
+#[derive(Debug, Default, PartialEq)]
+struct DamageOutcome {
+    /// It wasn't staggered before the hit
+    newly_staggered: bool,
+    /// Its poise ran out, so it's staggered for longer and its poise refills
+    stance_broken: bool,
+    /// This hit took its last health
+    defeated: bool,
+}
+
+/// The parts players and enemies share: movement, animation, attacking and
+/// their health and poise
+struct Character {
+    controller: MovementController,
+    animation: CharacterWalkAnimation,
+    attack_controller: AttackController,
+    health: f32,
+    max_health: f32,
+    poise: f32,
+    max_poise: f32,
+    status_effects: StatusEffects,
+}
+
+impl Character {
+    pub fn new(
+        controller: MovementController,
+        animation: CharacterWalkAnimation,
+        max_health: f32,
+        max_poise: f32,
+    ) -> Self {
+        Self {
+            controller,
+            animation,
+            attack_controller: AttackController::new(),
+            health: max_health,
+            max_health,
+            poise: max_poise,
+            max_poise,
+            status_effects: StatusEffects::new(),
+        }
+    }
+
+    pub fn is_dead(&self) -> bool {
+        self.health <= 0.0
+    }
+
+    /// Deals `damage` to both health and poise and staggers for
+    /// `stagger_duration`, or for a full second if poise breaks. Damage with
+    /// no stagger, like status effect ticks, doesn't interrupt.
+    pub fn take_damage(&mut self, damage: f32, stagger_duration: f32) -> DamageOutcome {
+        let was_alive = !self.is_dead();
+        let mut outcome = DamageOutcome::default();
+        self.health -= damage;
+        self.poise -= damage;
+        if stagger_duration > 0.0 {
+            outcome.newly_staggered = self.attack_controller.make_staggered(stagger_duration);
+        }
+        if self.poise <= 0.0 {
+            self.poise = self.max_poise; // Refill poise once it breaks
+            self.attack_controller.make_staggered(1.0);
+            outcome.stance_broken = true;
+        }
+        if self.health <= 0.0 {
+            self.health = 0.0; // Prevent negative health
+            outcome.defeated = was_alive;
+        }
+        outcome
+    }
+
+    pub fn regen_poise(&mut self, delta_time: f32) {
+        self.poise = (self.poise + delta_time * POISE_REGEN_PER_SECOND).min(self.max_poise);
+    }
+
+    pub fn heal(&mut self, amount: f32) {
+        self.health = (self.health + amount).min(self.max_health);
+    }
+
+    /// Ticks status effects, dealing their damage like any other. Returns
+    /// the movement speed multiplier they impose and what the damage did.
+    pub fn update_status_effects(&mut self, delta_time: f32) -> (f32, DamageOutcome) {
+        let status = self.status_effects.update(delta_time);
+        let outcome = if status.damage > 0.0 {
+            self.take_damage(status.damage, 0.0)
+        } else {
+            DamageOutcome::default()
+        };
+        (status.speed_multiplier, outcome)
+    }
+
+    #[cfg(test)]
+    pub fn snapshot(&self) -> CharacterSnapshot {
+        CharacterSnapshot {
+            position: self.controller.position,
+            health: self.health,
+            max_health: self.max_health,
+            poise: self.poise,
+            max_poise: self.max_poise,
+        }
+    }
+
+    pub fn get_attack_space(&self, base_transform: &Transform) -> Option<(Transform, f32)> {
+        self.attack_controller.get_attack_space(
+            &self.controller,
+            base_transform,
+            self.animation.orientation,
+        )
+    }
+
+    pub fn health_bar_space(&self, base_transform: &Transform, full: bool) -> Transform {
+        let health_ratio = if !full {
+            self.health / self.max_health
+        } else {
+            1.0
+        };
+        let local_space = self.controller.local_space(base_transform);
+        local_space
+            .translate(Vec3::new(0.5, 0.0, 0.0)) // Position above the character
+            .translate(Vec3::new(0.0, -0.2, 0.0)) // Position above the character
+            .scale(Vec3::new(0.8, 0.1, 1.0))
+            .set_origin(&Transform::new().translate(Vec3::new(0.5, 0.5, 0.0)))
+            .scale(Vec3::new(health_ratio, 1.0, 1.0)) // Scale based on health
+    }
+
+    pub fn poise_bar_space(&self, base_transform: &Transform, full: bool) -> Transform {
+        let poise_ratio = if !full {
+            self.poise / self.max_poise
+        } else {
+            1.0
         };
-        self.sheet
-            .get_sprite(sprite_index)
-            .expect("Sprite not found")
+        let local_space = self.controller.local_space(base_transform);
+        local_space
+            .translate(Vec3::new(0.5, 0.0, 0.0)) // Position above the character
+            .translate(Vec3::new(0.0, -0.1, 0.0)) // Position above the character
+            .scale(Vec3::new(0.8, 0.1, 1.0))
+            .set_origin(&Transform::new().translate(Vec3::new(0.5, 0.5, 0.0)))
+            .scale(Vec3::new(poise_ratio, 1.0, 1.0)) // Scale based on poise
     }
 }
 
+#[derive(Debug)]
 enum EnemyAIState {
     Idle,
     Chasing(Vec2),
     Wandering(CharacterOrientation),
     Engaging,
+    /// Stands still and never notices the player, like a training dummy
+    #[cfg(test)]
+    Dormant,
+}
+
+/// How idle and wandering enemies pick what to do next
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct WanderConfig {
+    /// Average number of decisions per second
+    rate_per_second: f32,
+    /// Chance of standing still relative to each of the four directions,
+    /// which all weigh 1
+    idle_weight: f32,
+}
+
+impl Default for WanderConfig {
+    fn default() -> Self {
+        Self {
+            rate_per_second: 2.0,
+            idle_weight: 1.0,
+        }
+    }
+}
+
+impl WanderConfig {
+    /// Reinforcements in wave rooms, who change their mind more often and
+    /// hardly stand still
+    fn restless() -> Self {
+        Self {
+            rate_per_second: 3.0,
+            idle_weight: 0.25,
+        }
+    }
+}
+
+const WANDER_DIRECTIONS: [CharacterOrientation; 4] = [
+    CharacterOrientation::Up,
+    CharacterOrientation::Down,
+    CharacterOrientation::Left,
+    CharacterOrientation::Right,
+];
+
+/// The state an idle or wandering enemy switches to over the next
+/// `delta_time`, `None` to keep doing what it was doing
+fn wander_decision(
+    config: &WanderConfig,
+    delta_time: f32,
+    rng: &mut impl Rng,
+) -> Option<EnemyAIState> {
+    let chance = (config.rate_per_second * delta_time).clamp(0.0, 1.0);
+    if !rng.random_bool(chance as f64) {
+        return None;
+    }
+    let total_weight = WANDER_DIRECTIONS.len() as f32 + config.idle_weight.max(0.0);
+    let pick = rng.random_range(0.0..total_weight);
+    Some(match WANDER_DIRECTIONS.get(pick as usize) {
+        Some(&orientation) => EnemyAIState::Wandering(orientation),
+        None => EnemyAIState::Idle,
+    })
 }
 
 struct Enemy {
-    controller: MovementController,
+    character: Character,
     state: EnemyAIState,
-    animation: CharacterWalkAnimation,
-    attack_controller: AttackController,
-    health: f32,
-    max_health: f32,
-    poise: f32,
-    max_poise: f32,
+    wander: WanderConfig,
+    /// Seconds since it was defeated, to dissolve it over
+    defeated_for: f32,
+    /// Put on the player with every hit that staggers them
+    hit_effect: Option<(StatusEffectKind, f32)>,
+}
+
+const ENEMY_MOVEMENT_SPEED: f32 = 1.5;
+/// Crystals dropped by each defeated enemy
+const ENEMY_CRYSTAL_REWARD: std::ops::RangeInclusive<u32> = 10..=50;
+
+/// Closer than this to the player, enemies start to aim at the player rather
+/// than at their waypoint
+const PURSUIT_BLEND_START: f32 = 2.0;
+/// Closer than this (the last tile), enemies aim straight at the player
+const PURSUIT_BLEND_END: f32 = 1.0;
+
+/// Point a chasing enemy at `from` walks towards: the `waypoint` from afar,
+/// blending into the exact `target` position over the final approach so
+/// the enemy doesn't jitter between tile centers as the target moves.
+fn pursuit_point(from: Vec2, waypoint: Vec2, target: Vec2) -> Vec2 {
+    let distance = from.distance(target);
+    let blend = ((PURSUIT_BLEND_START - distance) / (PURSUIT_BLEND_START - PURSUIT_BLEND_END))
+        .clamp(0.0, 1.0);
+    waypoint.lerp(target, blend)
 }
 
 impl Enemy {
     pub fn new(position: Vec2, walking_sprite_sheet: GizmoSpriteSheet) -> Self {
         Self {
-            controller: MovementController::new(position, 1.5),
-            state: EnemyAIState::Idle,
-            animation: CharacterWalkAnimation::new(
-                walking_sprite_sheet,
-                CharacterOrientation::Down,
-                0.75, // Speed of the animation
+            character: Character::new(
+                MovementController::new(position, ENEMY_MOVEMENT_SPEED),
+                CharacterWalkAnimation::new(
+                    walking_sprite_sheet,
+                    CharacterOrientation::Down,
+                    0.75, // Speed of the animation
+                ),
+                20.0,
+                50.0,
             ),
-            attack_controller: AttackController::new(),
-            health: 20.0,
-            max_health: 20.0,
-            poise: 50.0,
-            max_poise: 50.0,
+            state: EnemyAIState::Idle,
+            wander: WanderConfig::default(),
+            defeated_for: 0.0,
+            hit_effect: None,
         }
     }
 
+    #[cfg(test)]
+    pub fn dormant(mut self) -> Self {
+        self.state = EnemyAIState::Dormant;
+        self
+    }
+
+    pub fn with_wander(mut self, wander: WanderConfig) -> Self {
+        self.wander = wander;
+        self
+    }
+
+    pub fn with_hit_effect(mut self, hit_effect: Option<(StatusEffectKind, f32)>) -> Self {
+        self.hit_effect = hit_effect;
+        self
+    }
+
+    /// Scales health and poise, e.g. for enemies in deeper rooms
+    pub fn with_stat_multiplier(mut self, multiplier: f32) -> Self {
+        self.character.max_health *= multiplier;
+        self.character.health = self.character.max_health;
+        self.character.max_poise *= multiplier;
+        self.character.poise = self.character.max_poise;
+        self
+    }
+
     pub fn update<CollidesWithWorld: Fn(&Transform) -> Option<Collision>>(
         &mut self,
         delta_time: f32,
         check_collision: CollidesWithWorld,
+        bodies: &[Transform],
         player: &MovementController,
         level: &GameLevelSpec,
         rng: &mut StdRng,
     ) -> CharacterEvent {
         let mut event = CharacterEvent::None;
 
-        // Recover some poise
-        self.poise = (self.poise + delta_time * 5.0).min(self.max_poise);
+        let (speed_multiplier, status_outcome) = self.character.update_status_effects(delta_time);
+        if status_outcome.defeated {
+            return CharacterEvent::Defeated;
+        }
+        self.character.controller.movement_speed = ENEMY_MOVEMENT_SPEED * speed_multiplier;
+
+        self.character.regen_poise(delta_time);
 
         let distance_to_player = self
+            .character
             .controller
             .feet_position()
             .distance(player.feet_position());
@@ -393,52 +955,43 @@ impl Enemy {
             EnemyAIState::Idle | EnemyAIState::Wandering(_) => {
                 let mut found_something = false;
                 if distance_to_player < 3.0 {
-                    let can_see = !GameLevelSpec::line_collides_with_level(
-                        self.controller.feet_position(),
-                        player.feet_position().floor() + 0.5,
-                        level,
-                        &Transform::new()
-                            .set_origin(&Transform::new().translate(Vec3::new(0.0, 0.0, 0.0))),
-                        1,
+                    let can_see = level.has_line_of_sight(
+                        self.character.controller.feet_position(),
+                        level.grid.snap_to_center(player.feet_position()),
+                        BODY_SIZE,
                     );
                     if can_see {
-                        self.state = EnemyAIState::Chasing(player.feet_position().floor() + 0.5);
+                        self.state = EnemyAIState::Chasing(pursuit_point(
+                            self.character.controller.feet_position(),
+                            level.grid.snap_to_center(player.feet_position()),
+                            player.feet_position(),
+                        ));
                         found_something = true;
                     }
                 }
 
-                if !found_something && rng.random_bool((2.0 * delta_time as f64).min(1.0)) {
-                    // Randomly decide to wander
-                    let direction = rng.random_range(0..5);
-                    let orientation = match direction {
-                        0 => Some(CharacterOrientation::Up),
-                        1 => Some(CharacterOrientation::Down),
-                        2 => Some(CharacterOrientation::Left),
-                        3 => Some(CharacterOrientation::Right),
-                        _ => None,
-                    };
-                    if let Some(orientation) = orientation {
-                        self.state = EnemyAIState::Wandering(orientation);
-                        info!("Enemy wandering in direction: {:?}", orientation);
-                    } else {
-                        self.state = EnemyAIState::Idle; // No valid direction, stay idle
-                        info!("Enemy idle, no valid wandering direction");
+                if !found_something {
+                    if let Some(state) = wander_decision(&self.wander, delta_time, rng) {
+                        info!("Enemy wander decision: {:?}", state);
+                        self.state = state;
                     }
                 }
             }
-            EnemyAIState::Chasing(target_position) => {
-                let can_see = !GameLevelSpec::line_collides_with_level(
-                    self.controller.feet_position(),
-                    player.feet_position().floor() + 0.5,
-                    level,
-                    &Transform::new()
-                        .set_origin(&Transform::new().translate(Vec3::new(0.0, 0.0, 0.0))),
-                    1,
+            EnemyAIState::Chasing(_) => {
+                let can_see = level.has_line_of_sight(
+                    self.character.controller.feet_position(),
+                    level.grid.snap_to_center(player.feet_position()),
+                    BODY_SIZE,
                 );
                 if can_see {
-                    self.state = EnemyAIState::Chasing(player.feet_position().floor() + 0.5);
+                    self.state = EnemyAIState::Chasing(pursuit_point(
+                        self.character.controller.feet_position(),
+                        level.grid.snap_to_center(player.feet_position()),
+                        player.feet_position(),
+                    ));
 
                     let distance_to_target = self
+                        .character
                         .controller
                         .feet_position()
                         .distance(player.feet_position());
@@ -450,14 +1003,16 @@ impl Enemy {
             }
             EnemyAIState::Engaging => {
                 let distance_to_target = self
+                    .character
                     .controller
                     .feet_position()
                     .distance(player.feet_position());
-                if distance_to_target > 1.0 && self.attack_controller.is_ready() {
+                if distance_to_target > 1.0 && self.character.attack_controller.is_ready() {
                     self.state = EnemyAIState::Idle;
                 }
             }
-            _ => {}
+            #[cfg(test)]
+            EnemyAIState::Dormant => {}
         };
 
         let mut intention = MovementIntention {
@@ -469,22 +1024,24 @@ impl Enemy {
 
         let mut desired_orientation = None;
 
-        if self.attack_controller.is_ready() {
+        if self.character.attack_controller.is_ready() {
             match self.state {
                 EnemyAIState::Chasing(target_position) => {
-                    if target_position.y < self.controller.feet_position().y - 0.02 {
+                    if target_position.y < self.character.controller.feet_position().y - 0.02 {
                         intention.up = true;
-                    } else if target_position.y > self.controller.feet_position().y + 0.02 {
+                    } else if target_position.y > self.character.controller.feet_position().y + 0.02
+                    {
                         intention.down = true;
                     }
-                    if target_position.x < self.controller.feet_position().x - 0.02 {
+                    if target_position.x < self.character.controller.feet_position().x - 0.02 {
                         intention.left = true;
-                    } else if target_position.x > self.controller.feet_position().x + 0.02 {
+                    } else if target_position.x > self.character.controller.feet_position().x + 0.02
+                    {
                         intention.right = true;
                     }
 
-                    let delta_x = target_position.x - self.controller.feet_position().x;
-                    let delta_y = target_position.y - self.controller.feet_position().y;
+                    let delta_x = target_position.x - self.character.controller.feet_position().x;
+                    let delta_y = target_position.y - self.character.controller.feet_position().y;
                     if delta_x.abs() > delta_y.abs() {
                         if delta_x < 0.0 {
                             desired_orientation = Some(CharacterOrientation::Left);
@@ -515,12 +1072,12 @@ impl Enemy {
 
                     let target_position = player.feet_position();
                     if distance_to_player < 0.6 {
-                        if target_position.x < self.controller.feet_position().x {
+                        if target_position.x < self.character.controller.feet_position().x {
                             intention.right = true;
                         } else {
                             intention.left = true;
                         }
-                        if target_position.y < self.controller.feet_position().y {
+                        if target_position.y < self.character.controller.feet_position().y {
                             intention.down = true;
                         } else {
                             intention.up = true;
@@ -528,8 +1085,10 @@ impl Enemy {
                     }
 
                     if intention.any() {
-                        let delta_x = target_position.x - self.controller.feet_position().x;
-                        let delta_y = target_position.y - self.controller.feet_position().y;
+                        let delta_x =
+                            target_position.x - self.character.controller.feet_position().x;
+                        let delta_y =
+                            target_position.y - self.character.controller.feet_position().y;
                         if delta_x.abs() > delta_y.abs() {
                             if delta_x < 0.0 {
                                 desired_orientation = Some(CharacterOrientation::Left);
@@ -547,19 +1106,23 @@ impl Enemy {
             }
         }
 
-        let animation_event = self.animation.update(delta_time, desired_orientation);
+        let animation_event = self
+            .character
+            .animation
+            .update(delta_time, desired_orientation);
         if let AnimationEvent::FrameChanged(frame) = animation_event {
             if frame == 0 || frame == 2 {
                 event = CharacterEvent::WalkCycle; // Trigger walk cycle event
             }
         };
 
-        let last_position = self.controller.position;
+        let last_position = self.character.controller.position;
 
-        self.controller
-            .update(&intention, delta_time, check_collision);
+        self.character
+            .controller
+            .update(&intention, delta_time, check_collision, bodies);
 
-        let attack_controller_event = self.attack_controller.update(
+        let attack_controller_event = self.character.attack_controller.update(
             delta_time,
             if matches!(self.state, EnemyAIState::Engaging) {
                 AttackIntention::Duration(0.2)
@@ -572,67 +1135,32 @@ impl Enemy {
         }
 
         match self.state {
-            EnemyAIState::Chasing(_) | EnemyAIState::Wandering(_) => {
-                if last_position == self.controller.position {
-                    self.state = EnemyAIState::Idle; // If we didn't move, go back to idle
-                    info!("Enemy idle, no movement detected");
-                }
+            EnemyAIState::Chasing(_) | EnemyAIState::Wandering(_)
+                if last_position == self.character.controller.position =>
+            {
+                self.state = EnemyAIState::Idle; // If we didn't move, go back to idle
+                info!("Enemy idle, no movement detected");
             }
-            EnemyAIState::Engaging => {
-                if self.attack_controller.is_ready() {
-                    self.state = EnemyAIState::Idle; // If we are ready to attack, go back to idle
-                    info!("Enemy idle, ready to attack");
-                }
+            EnemyAIState::Engaging if self.character.attack_controller.is_ready() => {
+                self.state = EnemyAIState::Idle; // If we are ready to attack, go back to idle
+                info!("Enemy idle, ready to attack");
             }
             _ => {}
         };
 
         event
     }
-
-    pub fn get_attack_space(&self, base_transform: &Transform) -> Option<(Transform, f32)> {
-        self.attack_controller.get_attack_space(
-            &self.controller,
-            base_transform,
-            self.animation.orientation,
-        )
-    }
-
-    pub fn health_bar_space(&self, base_transform: &Transform, full: bool) -> Transform {
-        let health_ratio = if !full {
-            self.health / self.max_health
-        } else {
-            1.0
-        };
-        let local_space = self.controller.local_space(base_transform);
-        local_space
-            .translate(Vec3::new(0.5, 0.0, 0.0)) // Position above the enemy
-            .translate(Vec3::new(0.0, -0.2, 0.0)) // Position above the enemy
-            .scale(Vec3::new(0.8, 0.1, 1.0))
-            .set_origin(&Transform::new().translate(Vec3::new(0.5, 0.5, 0.0)))
-            .scale(Vec3::new(health_ratio, 1.0, 1.0)) // Scale based on health
-    }
-
-    pub fn poise_bar_space(&self, base_transform: &Transform, full: bool) -> Transform {
-        let poise_ratio = if !full {
-            self.poise / self.max_poise
-        } else {
-            1.0
-        };
-        let local_space = self.controller.local_space(base_transform);
-        local_space
-            .translate(Vec3::new(0.5, 0.0, 0.0)) // Position above the enemy
-            .translate(Vec3::new(0.0, -0.1, 0.0)) // Position above the enemy
-            .scale(Vec3::new(0.8, 0.1, 1.0))
-            .set_origin(&Transform::new().translate(Vec3::new(0.5, 0.5, 0.0)))
-            .scale(Vec3::new(poise_ratio, 1.0, 1.0)) // Scale based on poise
-    }
 }
 
+const MIN_WINDUP_DURATION: f32 = 0.2;
+const ATTACK_DURATION: f32 = 0.2;
+const ATTACK_COOLDOWN: f32 = 0.1;
+
 enum AttackState {
     Ready,
     Windup {
         current_time: f32,
+        expected_duration: f32,
     },
     Attacking {
         duration_left: f32,
@@ -662,43 +1190,156 @@ enum AttackControllerEvent {
     None,
 }
 
-impl AttackController {
-    pub fn new() -> Self {
-        Self {
-            state: AttackState::Ready,
-        }
-    }
+/// Tint of an enemy winding up an attack, yellow when it starts and red once
+/// the attack is about to land
+fn windup_telegraph_color(progress: f32) -> EngineColor {
+    EngineColor::YELLOW.lerp(&EngineColor::RED, progress.clamp(0.0, 1.0))
+}
 
-    pub fn update(
-        &mut self,
-        delta_time: f32,
+const COOLDOWN_SWIRL_DOTS: u32 = 8;
+
+/// Light in the rooms away from the player's torch
+const ROOM_LIGHT: EngineColor = EngineColor {
+    r: 0.55,
+    g: 0.55,
+    b: 0.6,
+    a: 1.0,
+};
+/// Warm light around the player, reaching `PLAYER_TORCH_RADIUS` tiles
+const PLAYER_TORCH: EngineColor = EngineColor {
+    r: 1.0,
+    g: 0.85,
+    b: 0.6,
+    a: 0.5,
+};
+const PLAYER_TORCH_RADIUS: f32 = 6.0;
+
+const DAMAGE_FLASH_DURATION: f32 = 0.4;
+
+/// Camera shake when the player starts getting hurt
+const PLAYER_HIT_TRAUMA: f32 = 0.5;
+/// Camera shake when the player staggers an enemy, and breaks its stance
+const STAGGER_TRAUMA: f32 = 0.2;
+const STANCE_BREAK_TRAUMA: f32 = 0.4;
+
+/// Highest frequency heard while the player is staggered or low on health,
+/// in Hz, and the share of health left that counts as low
+const MUFFLED_CUTOFF: f32 = 800.0;
+const LOW_HEALTH_RATIO: f32 = 0.25;
+
+/// Seconds each half of the transition between rooms takes
+const ROOM_TRANSITION_DURATION: f32 = 0.25;
+
+/// The damage vignette, from 0 (gone) to 1 (just hurt)
+fn damage_vignette(intensity: f32) -> PostEffect {
+    PostEffect::Vignette {
+        color: EngineColor {
+            a: 0.6 * intensity.clamp(0.0, 1.0),
+            ..EngineColor::RED
+        },
+        radius: 0.4,
+    }
+}
+
+/// What shadows multiply the floor under characters by
+const SHADOW_COLOR: EngineColor = EngineColor {
+    r: 0.4,
+    g: 0.4,
+    b: 0.55,
+    a: 0.6,
+};
+
+/// Seconds defeated enemies take to dissolve away
+const DISSOLVE_DURATION: f32 = 0.6;
+
+/// `material::OUTLINE` parameters showing the player is under `kind`
+fn status_outline(kind: StatusEffectKind) -> [f32; 4] {
+    let [r, g, b] = match kind {
+        StatusEffectKind::Poison { .. } => [0.4, 1.0, 0.3],
+        StatusEffectKind::Burn { .. } => [1.0, 0.5, 0.1],
+        StatusEffectKind::Slow { .. } => [0.4, 0.7, 1.0],
+    };
+    [r, g, b, 1.0]
+}
+
+/// Color split of the damage flash, from 0 (gone) to 1 (just hurt)
+fn damage_aberration(intensity: f32) -> PostEffect {
+    PostEffect::ChromaticAberration {
+        strength: 0.01 * intensity.clamp(0.0, 1.0),
+    }
+}
+
+/// How much the screen bulges once the player is defeated
+const DEFEAT_CURVATURE: f32 = 0.15;
+
+/// Color grade once the player is defeated, gray with a dark red tint
+fn defeat_lut_image() -> RgbaImage {
+    let mut lut = identity_lut_image(16);
+    for pixel in lut.pixels_mut() {
+        let [r, g, b, a] = pixel.0.map(f32::from);
+        let gray = 0.3 * r + 0.59 * g + 0.11 * b;
+        pixel.0 = [gray, gray * 0.55, gray * 0.55, a].map(|value| value as u8);
+    }
+    lut
+}
+
+/// Which part of an attack an `AttackController` is in, without the timers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AttackPhase {
+    Ready,
+    Windup,
+    Attacking,
+    Cooldown,
+    Staggered,
+}
+
+impl AttackController {
+    pub fn new() -> Self {
+        Self {
+            state: AttackState::Ready,
+        }
+    }
+
+    pub fn update(
+        &mut self,
+        delta_time: f32,
         attack_intention: AttackIntention,
     ) -> AttackControllerEvent {
         let mut event = AttackControllerEvent::None;
         match self.state {
             AttackState::Ready => {
                 if !matches!(attack_intention, AttackIntention::None) {
-                    //self.state = AttackState::Attacking { duration_left: 0.2 };
-                    self.state = AttackState::Windup { current_time: 0.0 };
+                    let expected_duration = match attack_intention {
+                        AttackIntention::Duration(duration) => duration.max(MIN_WINDUP_DURATION),
+                        _ => MIN_WINDUP_DURATION,
+                    };
+                    self.state = AttackState::Windup {
+                        current_time: 0.0,
+                        expected_duration,
+                    };
                     event = AttackControllerEvent::StartWindup;
                 }
             }
-            AttackState::Windup { current_time } => {
+            AttackState::Windup {
+                current_time,
+                expected_duration,
+            } => {
                 let mut wants_to_finish_windup = match attack_intention {
                     AttackIntention::None => true,
                     AttackIntention::Perpetual => false,
                     AttackIntention::Duration(duration) => current_time + delta_time >= duration,
                 };
-                if current_time < 0.2 {
-                    wants_to_finish_windup = false; // Windup lasts 0.2 seconds
+                if current_time < MIN_WINDUP_DURATION {
+                    wants_to_finish_windup = false;
                 }
                 if !wants_to_finish_windup {
                     self.state = AttackState::Windup {
                         current_time: current_time + delta_time,
+                        expected_duration,
                     };
                 } else {
                     self.state = AttackState::Attacking {
-                        duration_left: 0.2,
+                        duration_left: ATTACK_DURATION,
                         windup_duration: current_time,
                     };
                     event = AttackControllerEvent::StartAttack;
@@ -709,7 +1350,9 @@ impl AttackController {
                 windup_duration,
             } => {
                 if duration_left <= 0.0 {
-                    self.state = AttackState::Cooldown { duration_left: 0.1 };
+                    self.state = AttackState::Cooldown {
+                        duration_left: ATTACK_COOLDOWN,
+                    };
                 } else {
                     self.state = AttackState::Attacking {
                         duration_left: duration_left - delta_time,
@@ -760,12 +1403,11 @@ impl AttackController {
             };
 
             Some((
-                local_space
-                    .translate(Vec3::new(0.5, 0.5, 0.0)) // Attack space is slightly above the center
-                    .rotate_2d(degrees)
-                    .scale(Vec3::new(1.0, 1.0, 1.0)) // Size of the attack space
-                    .translate(Vec3::new(0.0, 0.0, 0.0))
-                    .set_origin(&Transform::new().translate(Vec3::new(0.5, 1.0, 0.0))),
+                // Rotate about the character's center, with the attack space
+                // sitting one tile in front of it
+                local_space.around_pivot(Vec2::splat(0.5), |t| {
+                    t.rotate_2d(degrees).translate(Vec3::new(0.0, -0.5, 0.0))
+                }),
                 windup_duration,
             ))
         } else {
@@ -777,6 +1419,39 @@ impl AttackController {
         matches!(self.state, AttackState::Ready)
     }
 
+    pub fn state_kind(&self) -> AttackPhase {
+        match self.state {
+            AttackState::Ready => AttackPhase::Ready,
+            AttackState::Windup { .. } => AttackPhase::Windup,
+            AttackState::Attacking { .. } => AttackPhase::Attacking,
+            AttackState::Cooldown { .. } => AttackPhase::Cooldown,
+            AttackState::Staggered { .. } => AttackPhase::Staggered,
+        }
+    }
+
+    /// How far along the windup is, from 0 to 1. Held windups stay at 1 once
+    /// the attack could be released.
+    pub fn windup_progress(&self) -> Option<f32> {
+        if let AttackState::Windup {
+            current_time,
+            expected_duration,
+        } = self.state
+        {
+            Some((current_time / expected_duration).min(1.0))
+        } else {
+            None
+        }
+    }
+
+    /// How much of the cooldown after an attack has passed, from 0 to 1
+    pub fn cooldown_progress(&self) -> Option<f32> {
+        if let AttackState::Cooldown { duration_left } = self.state {
+            Some((1.0 - duration_left / ATTACK_COOLDOWN).clamp(0.0, 1.0))
+        } else {
+            None
+        }
+    }
+
     pub fn make_staggered(&mut self, duration: f32) -> bool {
         if let AttackState::Staggered { duration_left } = self.state {
             self.state = AttackState::Staggered {
@@ -793,12 +1468,11 @@ impl AttackController {
 }
 
 struct Player {
-    controller: MovementController,
-    animation: CharacterWalkAnimation,
+    character: Character,
     direction_group_handle: KeyPressGroupHandle,
-    attack_controller: AttackController,
-    health: f32,
-    poise: f32,
+    // Double tapping each direction
+    dash_combos: [ComboHandle; 4],
+    dash_time_left: f32,
 
     healing_flasks: u32,
     max_healing_flasks: u32,
@@ -812,8 +1486,18 @@ enum CharacterEvent {
     None,
     AttackControllerEvent(AttackControllerEvent),
     WalkCycle,
+    /// Status effects took its last health
+    Defeated,
 }
 
+const HEALING_DURATION: f32 = 1.0;
+
+/// Double tapping a direction within `DASH_WINDOW` seconds speeds the player
+/// up this much for `DASH_DURATION` seconds
+const DASH_SPEED_MULTIPLIER: f32 = 2.5;
+const DASH_DURATION: f32 = 0.2;
+const DASH_WINDOW: f32 = 0.25;
+
 enum HealingState {
     Ready,
     Healing { current_time: f32 },
@@ -832,11 +1516,20 @@ impl HealingState {
         *self = HealingState::Ready;
     }
 
+    /// How far along the current heal is, from 0 to 1
+    #[cfg(test)]
+    pub fn progress(&self) -> Option<f32> {
+        if let HealingState::Healing { current_time } = self {
+            Some((current_time / HEALING_DURATION).min(1.0))
+        } else {
+            None
+        }
+    }
+
     pub fn update(&mut self, delta_time: f32) -> bool {
         if let HealingState::Healing { current_time } = self {
             *current_time += delta_time;
-            if *current_time >= 1.0 {
-                // Healing takes 1 second
+            if *current_time >= HEALING_DURATION {
                 *self = HealingState::Ready;
             }
             true
@@ -853,25 +1546,34 @@ impl Player {
         input_config: &mut InputSystemConfig,
     ) -> Self {
         Self {
-            controller: MovementController::new(position, 2.0),
-            animation: CharacterWalkAnimation::new(
-                walking_sprite_sheet,
-                CharacterOrientation::Down,
-                1.0, // Speed of the animation
+            character: Character::new(
+                MovementController::new(position, 2.0),
+                CharacterWalkAnimation::new(
+                    walking_sprite_sheet,
+                    CharacterOrientation::Down,
+                    1.0, // Speed of the animation
+                ),
+                100.0,
+                50.0,
             ),
             direction_group_handle: input_config.allocate_group(&[
-                KeyCode::KeyW,
-                KeyCode::KeyS,
-                KeyCode::KeyA,
-                KeyCode::KeyD,
+                Action::MoveUp,
+                Action::MoveDown,
+                Action::MoveLeft,
+                Action::MoveRight,
             ]),
-            attack_controller: AttackController::new(),
-            health: 100.0, // Default health
-            poise: 50.0,
+            dash_combos: [
+                Action::MoveUp,
+                Action::MoveDown,
+                Action::MoveLeft,
+                Action::MoveRight,
+            ]
+            .map(|direction| input_config.register_combo(&[direction, direction], DASH_WINDOW)),
+            dash_time_left: 0.0,
             healing_flasks: 5,
             max_healing_flasks: 5,
             healing_state: HealingState::Ready,
-            healing_group_handle: input_config.allocate_group(&[KeyCode::KeyH]),
+            healing_group_handle: input_config.allocate_group(&[Action::Heal]),
             num_crystals: 0, // Default number of crystals
         }
     }
@@ -881,61 +1583,84 @@ impl Player {
         input: &mut InputSystem,
         delta_time: f32,
         check_collision: CollidesWithWorld,
+        bodies: &[Transform],
     ) -> CharacterEvent {
         let mut event = CharacterEvent::None;
 
-        let wants_to_attack = input.is_physical_key_down(KeyCode::KeyL);
+        let wants_to_attack = input.is_action_down(Action::Attack);
         let wants_to_heal = input
-            .get_last_key_pressed(&self.healing_group_handle)
+            .get_last_action_pressed(&self.healing_group_handle)
             .is_some()
             && self.healing_flasks > 0
-            && self.attack_controller.is_ready();
+            && self.character.attack_controller.is_ready();
         input.debounce(&self.healing_group_handle);
 
         if wants_to_heal {
             self.healing_flasks -= 1;
             self.healing_state.start_healing();
+            self.character.status_effects.cure();
         }
 
         if self.healing_state.update(delta_time) {
-            self.health = (self.health + delta_time * 40.0).min(100.0);
-            self.controller.movement_speed = 1.0;
+            self.character.heal(delta_time * 40.0);
+            self.character.controller.movement_speed = 1.0;
         } else {
-            self.controller.movement_speed = 2.0;
+            self.character.controller.movement_speed = 2.0;
+        }
+
+        let dashing = self
+            .dash_combos
+            .iter()
+            .any(|combo| input.was_combo_triggered(combo));
+        if dashing && self.healing_state.is_ready() && self.character.attack_controller.is_ready() {
+            self.dash_time_left = DASH_DURATION;
+        }
+        if self.dash_time_left > 0.0 {
+            self.dash_time_left -= delta_time;
+            self.character.controller.movement_speed *= DASH_SPEED_MULTIPLIER;
+        }
+
+        let (speed_multiplier, status_outcome) = self.character.update_status_effects(delta_time);
+        if status_outcome.defeated {
+            return CharacterEvent::Defeated;
         }
+        self.character.controller.movement_speed *= speed_multiplier;
 
-        // Recover some poise
-        self.poise = (self.poise + delta_time * 5.0).min(50.0);
+        self.character.regen_poise(delta_time);
 
-        let movement_intention = if self.attack_controller.is_ready() {
+        let movement_intention = if self.character.attack_controller.is_ready() {
             MovementIntention::from_input(input)
         } else {
             MovementIntention::idle()
         };
 
-        self.controller
-            .update(&movement_intention, delta_time, check_collision);
+        self.character
+            .controller
+            .update(&movement_intention, delta_time, check_collision, bodies);
 
         let desired_orientation = if movement_intention.is_idle() {
             None
         } else {
-            match input.get_last_key_pressed(&self.direction_group_handle) {
-                Some(KeyCode::KeyW) => Some(CharacterOrientation::Up),
-                Some(KeyCode::KeyS) => Some(CharacterOrientation::Down),
-                Some(KeyCode::KeyA) => Some(CharacterOrientation::Left),
-                Some(KeyCode::KeyD) => Some(CharacterOrientation::Right),
+            match input.get_last_action_pressed(&self.direction_group_handle) {
+                Some(Action::MoveUp) => Some(CharacterOrientation::Up),
+                Some(Action::MoveDown) => Some(CharacterOrientation::Down),
+                Some(Action::MoveLeft) => Some(CharacterOrientation::Left),
+                Some(Action::MoveRight) => Some(CharacterOrientation::Right),
                 _ => None,
             }
         };
 
-        let animation_event = self.animation.update(delta_time, desired_orientation);
+        let animation_event = self
+            .character
+            .animation
+            .update(delta_time, desired_orientation);
         if let AnimationEvent::FrameChanged(frame) = animation_event {
             if frame == 0 || frame == 2 {
                 event = CharacterEvent::WalkCycle;
             }
         }
 
-        let attack_event = self.attack_controller.update(
+        let attack_event = self.character.attack_controller.update(
             delta_time,
             if wants_to_attack {
                 self.healing_state.cancel_healing();
@@ -950,35 +1675,155 @@ impl Player {
         }
         event
     }
+}
 
-    pub fn get_attack_space(&self, base_transform: &Transform) -> Option<(Transform, f32)> {
-        self.attack_controller.get_attack_space(
-            &self.controller,
-            base_transform,
-            self.animation.orientation,
-        )
+/// How much tougher enemies get the further a room is from spawn. Depth is
+/// the manhattan distance of the room coordinate to (0, 0, 0), so the spawn
+/// room is always at base difficulty.
+#[derive(Clone, Copy)]
+pub struct DifficultyCurve {
+    /// Extra fraction of base health and poise per room of depth
+    pub stats_per_depth: f32,
+    pub max_stat_multiplier: f32,
+}
+
+impl DifficultyCurve {
+    pub fn new() -> Self {
+        Self {
+            stats_per_depth: 0.15,
+            max_stat_multiplier: 3.0,
+        }
+    }
+
+    pub fn depth(room: (i32, i32, i32)) -> u32 {
+        room.0.unsigned_abs() + room.1.unsigned_abs() + room.2.unsigned_abs()
     }
 
-    pub fn stagger(&mut self, duration: f32) -> bool {
-        self.healing_state.cancel_healing();
-        self.attack_controller.make_staggered(duration)
+    pub fn stat_multiplier(&self, depth: u32) -> f32 {
+        (1.0 + self.stats_per_depth * depth as f32).min(self.max_stat_multiplier)
     }
 }
 
 struct ActiveRoom {
     spec: Rc<GameLevelSpec>,
     enemies: Vec<Enemy>,
+    next_wave: usize,
+    enemy_sprite_sheet: GizmoSpriteSheet,
+    stat_multiplier: f32,
+    hit_effect: Option<(StatusEffectKind, f32)>,
+    ambient: EngineColor,
+}
+
+/// Ambient tint of rooms `AMBIENT_FULL_DEPTH` or more rooms away from spawn,
+/// the deeper the colder
+const DEEP_AMBIENT: EngineColor = EngineColor {
+    r: 0.7,
+    g: 0.8,
+    b: 1.0,
+    a: 1.0,
+};
+const AMBIENT_FULL_DEPTH: f32 = 10.0;
+
+fn depth_ambient(depth: u32) -> EngineColor {
+    EngineColor::WHITE.lerp(&DEEP_AMBIENT, (depth as f32 / AMBIENT_FULL_DEPTH).min(1.0))
+}
+
+/// Rooms this many or more rooms away from spawn have enemies whose hits
+/// slow, then poison, then burn
+const SLOWING_DEPTH: u32 = 3;
+const POISONING_DEPTH: u32 = 6;
+const BURNING_DEPTH: u32 = 9;
+
+/// Status effect enemies at `depth` put on the player with their hits, and
+/// for how long
+fn depth_hit_effect(depth: u32) -> Option<(StatusEffectKind, f32)> {
+    if depth >= BURNING_DEPTH {
+        Some((
+            StatusEffectKind::Burn {
+                damage_per_second: 3.0,
+            },
+            2.0,
+        ))
+    } else if depth >= POISONING_DEPTH {
+        Some((
+            StatusEffectKind::Poison {
+                damage_per_second: 4.0,
+            },
+            3.0,
+        ))
+    } else if depth >= SLOWING_DEPTH {
+        Some((StatusEffectKind::Slow { speed_factor: 0.6 }, 1.5))
+    } else {
+        None
+    }
 }
 
 impl ActiveRoom {
-    pub fn from_spec(spec: Rc<GameLevelSpec>, enemy_sprite_sheet: GizmoSpriteSheet) -> Self {
-        let mut enemies = Vec::new();
-        for enemy_position in &spec.enemy_locations {
-            let enemy = Enemy::new(*enemy_position, enemy_sprite_sheet.clone());
-            enemies.push(enemy);
+    pub fn from_spec(
+        spec: Rc<GameLevelSpec>,
+        enemy_sprite_sheet: GizmoSpriteSheet,
+        depth: u32,
+        difficulty: &DifficultyCurve,
+    ) -> Self {
+        let mut room = Self {
+            enemies: Vec::new(),
+            next_wave: 0,
+            enemy_sprite_sheet,
+            stat_multiplier: difficulty.stat_multiplier(depth),
+            hit_effect: depth_hit_effect(depth),
+            ambient: spec.ambient.multiply(&depth_ambient(depth)),
+            spec,
+        };
+        room.spawn_next_wave();
+        room
+    }
+
+    fn spawn_next_wave(&mut self) {
+        let Some(wave) = self.spec.enemy_waves.get(self.next_wave) else {
+            return;
+        };
+        // Whatever is left of the previous wave is dead by now
+        self.enemies.clear();
+        let wander = if self.next_wave == 0 {
+            WanderConfig::default()
+        } else {
+            WanderConfig::restless()
+        };
+        for enemy_position in wave {
+            let enemy = Enemy::new(*enemy_position, self.enemy_sprite_sheet.clone())
+                .with_stat_multiplier(self.stat_multiplier)
+                .with_hit_effect(self.hit_effect)
+                .with_wander(wander);
+            self.enemies.push(enemy);
         }
+        self.next_wave += 1;
+    }
+
+    /// Rooms with more than one wave keep their doors shut until the last
+    /// wave is cleared
+    pub fn is_wave_room(&self) -> bool {
+        self.spec.enemy_waves.len() > 1
+    }
+
+    fn is_cleared(&self) -> bool {
+        self.enemies
+            .iter()
+            .all(|enemy| enemy.character.health <= 0.0)
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.is_wave_room() && (self.next_wave < self.spec.enemy_waves.len() || !self.is_cleared())
+    }
 
-        Self { spec, enemies }
+    /// Spawns the next wave once the current one is cleared. Returns whether
+    /// a wave was spawned.
+    pub fn update_waves(&mut self) -> bool {
+        if self.is_cleared() && self.next_wave < self.spec.enemy_waves.len() {
+            self.spawn_next_wave();
+            true
+        } else {
+            false
+        }
     }
 }
 
@@ -988,29 +1833,54 @@ struct RoomManager {
     current_room: (i32, i32, i32),
     rng: StdRng,
     enemy_sprite_sheet: GizmoSpriteSheet,
+    difficulty: DifficultyCurve,
 }
 
 impl RoomManager {
-    pub fn new(spawn_spec: GameLevelSpec, enemy_sprite_sheet: GizmoSpriteSheet) -> Self {
+    pub fn new(
+        spawn_spec: GameLevelSpec,
+        enemy_sprite_sheet: GizmoSpriteSheet,
+        rng: StdRng,
+    ) -> Self {
+        let difficulty = DifficultyCurve::new();
         let mut rooms = HashMap::new();
         rooms.insert(
             (0, 0, 0),
-            ActiveRoom::from_spec(Rc::new(spawn_spec), enemy_sprite_sheet.clone()),
+            ActiveRoom::from_spec(
+                Rc::new(spawn_spec),
+                enemy_sprite_sheet.clone(),
+                0,
+                &difficulty,
+            ),
         );
         Self {
             room_pool: Vec::new(),
             rooms,
-            current_room: (0, 0, 0),         // Starting room
-            rng: StdRng::from_seed([0; 32]), // Seed with zeros for reproducibility
+            current_room: (0, 0, 0), // Starting room
+            rng,
             enemy_sprite_sheet: enemy_sprite_sheet.clone(),
+            difficulty,
         }
     }
 
+    /// Rooms created from now on use `difficulty`
+    #[cfg(test)]
+    pub fn with_difficulty(mut self, difficulty: DifficultyCurve) -> Self {
+        self.difficulty = difficulty;
+        self
+    }
+
+    #[cfg(test)]
     pub fn add_room_spec(mut self, spec: GameLevelSpec) -> Self {
-        self.room_pool.push(Rc::new(spec));
+        self.push_room_spec(spec);
         self
     }
 
+    /// Like `add_room_spec`, for rooms that finish loading later
+    pub fn push_room_spec(&mut self, spec: GameLevelSpec) {
+        self.room_pool.push(Rc::new(spec));
+    }
+
     pub fn get_current_room(&self) -> &ActiveRoom {
         self.rooms
             .get(&self.current_room)
@@ -1023,88 +1893,251 @@ impl RoomManager {
             .expect("Current room not found")
     }
 
-    pub fn change_room(&mut self, position: (i32, i32, i32)) {
+    /// Moves to the room at `position`, making it up from the room pool if
+    /// it's new. Returns whether it moved: with no level loaded to make the
+    /// room from, e.g. because they all failed to load, it stays put.
+    pub fn change_room(&mut self, position: (i32, i32, i32)) -> bool {
         if let std::collections::hash_map::Entry::Vacant(e) = self.rooms.entry(position) {
-            let new_room_spec = self
-                .room_pool
-                .choose(&mut self.rng)
-                .expect("No room available for spawning");
+            let Some(new_room_spec) = self.room_pool.choose(&mut self.rng) else {
+                return false;
+            };
 
-            let new_room =
-                ActiveRoom::from_spec(new_room_spec.clone(), self.enemy_sprite_sheet.clone());
+            let new_room = ActiveRoom::from_spec(
+                new_room_spec.clone(),
+                self.enemy_sprite_sheet.clone(),
+                DifficultyCurve::depth(position),
+                &self.difficulty,
+            );
             e.insert(new_room);
-            self.current_room = position; // Update current room to the newly created one
-        } else {
-            self.current_room = position;
         }
+        self.current_room = position;
+        true
     }
-}
 
-enum CrystalCountState {
-    None,
-    Counting { duration: f32 },
+    /// Swaps every use of the spec sharing `spec`'s name for `spec`. Active
+    /// rooms keep their enemies. The textures of the replaced spec are freed
+    /// unless something else still holds on to it.
+    #[cfg(any(test, all(debug_assertions, target_arch = "wasm32")))]
+    pub fn replace_spec(&mut self, spec: GameLevelSpec, rendering_system: &mut RenderingSystem) {
+        let spec = Rc::new(spec);
+        let mut replaced = Vec::new();
+        for pooled in self.room_pool.iter_mut() {
+            if pooled.name == spec.name {
+                replaced.push(std::mem::replace(pooled, spec.clone()));
+            }
+        }
+        for room in self.rooms.values_mut() {
+            if room.spec.name == spec.name {
+                replaced.push(std::mem::replace(&mut room.spec, spec.clone()));
+            }
+        }
+        // Only the last of the uses dropped here unwraps
+        for old in replaced {
+            let Ok(old) = Rc::try_unwrap(old) else {
+                continue;
+            };
+            // The floor's tileset is shared by every level
+            if let Some(texture) = old.decoration.into_texture() {
+                rendering_system.free_gizmo_texture(&texture);
+            }
+        }
+    }
+
+    /// Swaps in the levels `reloader` found changed on disk
+    #[cfg(any(test, all(debug_assertions, target_arch = "wasm32")))]
+    fn reload_changed(
+        &mut self,
+        reloader: &mut LevelHotReloader,
+        tileset: &GizmoSpriteSheet,
+        rendering_system: &mut RenderingSystem,
+        delta_time: f32,
+    ) {
+        for level in reloader.update(delta_time) {
+            match GameLevelSpec::load(level.as_load_data(), tileset, rendering_system) {
+                Ok(spec) => {
+                    info!("Reloaded level {}", level.name);
+                    self.replace_spec(spec, rendering_system);
+                }
+                Err(err) => log::error!("Failed to reload level {}: {}", level.name, err),
+            }
+        }
+    }
 }
 
+/// The animated crystal counter on the HUD
 struct CrystalCountBuffer {
-    target_num: f32,
-    current_num: f32,
-    state: CrystalCountState,
-    speed: f32,
+    count: Tween,
 }
 
 impl CrystalCountBuffer {
     pub fn new(current_num: f32, speed: f32) -> Self {
         Self {
-            target_num: current_num,
-            current_num,
-            state: CrystalCountState::None,
-            speed,
+            count: Tween::new(current_num, speed),
+        }
+    }
+
+    /// Crystal sprite to show, filling up the longer the counter runs
+    pub fn sprite_index(&self) -> u32 {
+        (self.count.active_duration() as u32).min(4)
+    }
+}
+
+#[cfg(test)]
+#[derive(Debug, Clone, PartialEq)]
+pub struct CharacterSnapshot {
+    pub position: Vec2,
+    pub health: f32,
+    pub max_health: f32,
+    pub poise: f32,
+    pub max_poise: f32,
+}
+
+#[cfg(test)]
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlayerSnapshot {
+    pub character: CharacterSnapshot,
+    pub healing_flasks: u32,
+    pub num_crystals: u32,
+}
+
+/// Read-only copy of the game state, with nothing tied to the GPU, for
+/// tests to assert on
+#[cfg(test)]
+#[derive(Debug, Clone, PartialEq)]
+pub struct GameSnapshot {
+    pub player: PlayerSnapshot,
+    pub enemies: Vec<CharacterSnapshot>,
+    pub room: (i32, i32, i32),
+    pub room_locked: bool,
+}
+
+#[cfg(test)]
+impl GameSnapshot {
+    fn capture(player: &Player, manager: &RoomManager) -> Self {
+        let room = manager.get_current_room();
+        Self {
+            player: PlayerSnapshot {
+                character: player.character.snapshot(),
+                healing_flasks: player.healing_flasks,
+                num_crystals: player.num_crystals,
+            },
+            enemies: room
+                .enemies
+                .iter()
+                .map(|enemy| enemy.character.snapshot())
+                .collect(),
+            room: manager.current_room,
+            room_locked: room.is_locked(),
         }
     }
+}
+
+/// What happened over a run of `Game::simulate_until`
+#[cfg(test)]
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimResult {
+    pub steps: usize,
+    /// Whether the condition was met, rather than running out of steps
+    pub reached: bool,
+    /// Health taken from enemies, not counting overkill
+    pub damage_dealt: f32,
+    pub damage_taken: f32,
+}
 
-    pub fn update(&mut self, delta_time: f32) {
-        let speed_per_second = self.speed * delta_time;
-        let delta = self.target_num - self.current_num;
-        if delta.abs() < speed_per_second {
-            self.current_num = self.target_num; // Snap to target if within speed range
-        } else if delta > 0.0 {
-            self.current_num += speed_per_second * delta.signum(); // Increment towards target
+#[cfg(test)]
+impl SimResult {
+    fn record_step(&mut self, before: &GameSnapshot, after: &GameSnapshot) {
+        let lost = |before: &CharacterSnapshot, after: &CharacterSnapshot| {
+            (before.health.max(0.0) - after.health.max(0.0)).max(0.0)
+        };
+        self.damage_taken += lost(&before.player.character, &after.player.character);
+        // Enemies are only comparable while staying in the same room
+        if before.room == after.room && before.enemies.len() == after.enemies.len() {
+            self.damage_dealt += before
+                .enemies
+                .iter()
+                .zip(&after.enemies)
+                .map(|(before, after)| lost(before, after))
+                .sum::<f32>();
         }
+        self.steps += 1;
+    }
+}
 
-        match self.state {
-            CrystalCountState::None => {
-                if self.current_num != self.target_num {
-                    self.state = CrystalCountState::Counting {
-                        duration: 0.0, // Start counting down from 0.5 seconds
-                    };
-                }
-            }
-            CrystalCountState::Counting { duration } => {
-                if self.current_num == self.target_num {
-                    self.state = CrystalCountState::None; // Stop counting if we reached the target
-                } else {
-                    self.state = CrystalCountState::Counting {
-                        duration: duration + delta_time,
-                    };
+const MASTER_SEED: u64 = 0; // Fixed for reproducibility
+
+/// Separate random streams derived from one master seed, so drawing more from
+/// one consumer (say, pitching a new sound) doesn't change what the others see
+pub struct RngStreams {
+    pub audio: StdRng,
+    pub loot: StdRng,
+    pub ai: StdRng,
+}
+
+impl RngStreams {
+    pub fn new(master_seed: u64) -> Self {
+        Self {
+            audio: Self::named(master_seed, "audio"),
+            loot: Self::named(master_seed, "loot"),
+            ai: Self::named(master_seed, "ai"),
+        }
+    }
+
+    /// Stream for picking rooms, owned by the room manager
+    pub fn generation(master_seed: u64) -> StdRng {
+        Self::named(master_seed, "generation")
+    }
+
+    fn named(master_seed: u64, name: &str) -> StdRng {
+        // FNV-1a, so seeds don't depend on the std hasher
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for byte in name.bytes() {
+            hash = (hash ^ byte as u64).wrapping_mul(0x100000001b3);
+        }
+        StdRng::seed_from_u64(master_seed ^ hash)
+    }
+}
+
+/// Everything that failed to load while initializing the game
+#[derive(Debug, Default)]
+pub struct GameInitError {
+    /// What failed, e.g. `asset ui` or `level spawn`, and why
+    pub failures: Vec<(String, LoadError)>,
+}
+
+impl GameInitError {
+    /// Records `result` if it failed. An asset that fails more than once is
+    /// only recorded the first time.
+    fn check<T>(&mut self, what: String, result: Result<T, LoadError>) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err(err) => {
+                if self.failures.iter().all(|(failed, _)| *failed != what) {
+                    self.failures.push((what, err));
                 }
+                None
             }
         }
     }
+}
 
-    pub fn get_load(&self) -> f32 {
-        // duration is the load
-        match self.state {
-            CrystalCountState::None => 0.0,
-            CrystalCountState::Counting { duration } => duration,
+impl std::fmt::Display for GameInitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Failed to initialize the game:")?;
+        for (what, err) in &self.failures {
+            write!(f, "\n  {}: {}", what, err)?;
         }
+        Ok(())
     }
 }
 
+impl std::error::Error for GameInitError {}
+
 pub struct Game {
     player: Player,
-    camera: OrthoCamera,
+    camera: CameraController,
     walk_audio: AudioHandle,
-    rng: StdRng,
+    rng: RngStreams,
 
     windup_audio: AudioHandle,
     attack_audio: AudioHandle,
@@ -1112,6 +2145,8 @@ pub struct Game {
     stance_broken_audio: AudioHandle,
 
     manager: RoomManager,
+    level_loader: BackgroundLevelLoader,
+    level_tileset: GizmoSpriteSheet,
 
     ui_sheet_32: GizmoSpriteSheet,
     ui_sheet_16: GizmoSpriteSheet,
@@ -1121,6 +2156,34 @@ pub struct Game {
     crystal_count_buffer: CrystalCountBuffer,
 
     test_sheet: GizmoSpriteSheet,
+
+    // Defeated enemies break up with `dissolve`, the defeated player turns
+    // gray and status effects outline the player in their color
+    dissolve: MaterialHandle,
+    grayscale: MaterialHandle,
+    outline: MaterialHandle,
+
+    // Red around the edges after the player gets hurt, with the colors
+    // split apart
+    damage_vignette: PostEffectHandle,
+    damage_aberration: PostEffectHandle,
+    // The screen bulges and drains of color once the player is defeated
+    defeat_curvature: PostEffectHandle,
+    defeat_grading: PostEffectHandle,
+    // Seconds left of the vignette
+    damage_flash: f32,
+    // Player health at the end of the last update, to tell when it drops
+    last_health: f32,
+
+    transition: Transition,
+    // The door the player walked into, gone through once the transition
+    // covers the screen
+    pending_door: Option<DoorDirection>,
+    // Whether the player has stepped off the door they came in through
+    off_door: bool,
+
+    #[cfg(all(debug_assertions, target_arch = "wasm32"))]
+    level_reloader: LevelHotReloader,
 }
 
 impl Game {
@@ -1128,121 +2191,294 @@ impl Game {
         (320, 240)
     }
 
-    pub fn alignment_hint() -> u32 {
-        32
+    /// Samples per pixel, so rotated quads like the attack hitboxes don't
+    /// alias
+    pub fn sample_count() -> u32 {
+        4
+    }
+
+    /// Frames queued up before presenting blocks. As few as possible, so
+    /// attacks land on screen right as they're timed.
+    pub fn frame_latency() -> u32 {
+        1
+    }
+
+    /// Frames per second to cap to when presenting without vsync, so the
+    /// game doesn't draw flat out
+    pub fn frame_cap() -> f32 {
+        60.0
+    }
+
+    /// Graphics API to render with, unless the page's URL overrides it
+    pub fn renderer_backend() -> RendererBackend {
+        RendererBackend::Auto
+    }
+
+    #[cfg(test)]
+    pub fn snapshot(&self) -> GameSnapshot {
+        GameSnapshot::capture(&self.player, &self.manager)
+    }
+
+    /// Steps the game at `FIXED_STEP` until `condition` holds or `max_steps`
+    /// have run, holding down the keys `input_policy` picks for each step.
+    /// Runs are deterministic, so balance changes can be measured with them.
+    #[cfg(test)]
+    pub fn simulate_until(
+        &mut self,
+        input: &mut InputSystem,
+        audio_system: &mut AudioSystem,
+        rendering_system: &mut RenderingSystem,
+        mut condition: impl FnMut(&GameSnapshot) -> bool,
+        max_steps: usize,
+        mut input_policy: impl FnMut(&GameSnapshot) -> Vec<KeyCode>,
+    ) -> SimResult {
+        let mut result = SimResult {
+            steps: 0,
+            reached: false,
+            damage_dealt: 0.0,
+            damage_taken: 0.0,
+        };
+        let mut held: Vec<KeyCode> = Vec::new();
+        let mut before = self.snapshot();
+        while result.steps < max_steps && !condition(&before) {
+            let keys = input_policy(&before);
+            for &key in held.iter().filter(|key| !keys.contains(key)) {
+                input.release_key(key);
+            }
+            for &key in keys.iter().filter(|key| !held.contains(key)) {
+                input.press_key(key);
+            }
+            held = keys;
+
+            self.update(input, audio_system, rendering_system, FIXED_STEP);
+            input.end_frame(FIXED_STEP);
+            let after = self.snapshot();
+            result.record_step(&before, &after);
+            before = after;
+        }
+        for key in held {
+            input.release_key(key);
+        }
+        result.reached = condition(&before);
+        result
     }
 
+    /// Loads every asset the game starts with. Fails with all of the assets
+    /// that couldn't be loaded instead of stopping at the first one.
+    #[cfg(test)]
     pub fn init(
         rendering_system: &mut RenderingSystem,
         audio_system: &mut AudioSystem,
         input_config: &mut InputSystemConfig,
-    ) -> Self {
-        let ui_sheet_32 = rendering_system.gizmo_sprite_sheet_from_encoded_image(
-            include_bytes!("assets/ui.png"),
-            [0.0, 0.0],
-            [1.0, 1.0],
-            [2, 5],
-        );
-        let ui_sheet_16 = rendering_system.gizmo_sprite_sheet_from_encoded_image(
-            include_bytes!("assets/ui.png"),
-            [0.0, 0.0],
-            [1.0, 1.0],
-            [4, 10],
+    ) -> Result<Self, GameInitError> {
+        let mut assets = AssetManager::new().with_source(assets::AssetSource::for_platform());
+        assets.request_preload(audio_system);
+        assets.wait(rendering_system, audio_system);
+        Self::init_with_assets(rendering_system, audio_system, input_config, assets)
+    }
+
+    /// Like `init`, with the assets in `assets::PRELOAD` already requested
+    /// from `assets`, e.g. streamed in behind `render_loading_screen`
+    pub fn init_with_assets(
+        rendering_system: &mut RenderingSystem,
+        audio_system: &mut AudioSystem,
+        input_config: &mut InputSystemConfig,
+        assets: AssetManager,
+    ) -> Result<Self, GameInitError> {
+        Self::init_with_levels(
+            rendering_system,
+            audio_system,
+            input_config,
+            assets,
+            assets::embedded_level("spawn"),
+            assets::embedded_level("base_0"),
+        )
+    }
+
+    /// Like `init_with_assets`, starting in `spawn` with `first_room` to go
+    /// to. `first_room` is only checked here and decoded in the background.
+    fn init_with_levels(
+        rendering_system: &mut RenderingSystem,
+        audio_system: &mut AudioSystem,
+        input_config: &mut InputSystemConfig,
+        mut assets: AssetManager,
+        spawn: Result<GameLevelLoadData<'_>, LoadError>,
+        first_room: Result<GameLevelLoadData<'static>, LoadError>,
+    ) -> Result<Self, GameInitError> {
+        let mut errors = GameInitError::default();
+        for (id, err) in assets.take_failures() {
+            errors.check::<()>(format!("asset {}", id), Err(err));
+        }
+
+        let mut sprite_sheet = |id: &str, num_tiles: [u32; 2]| {
+            let sheet =
+                assets.sprite_sheet(rendering_system, id, [0.0, 0.0], [1.0, 1.0], num_tiles);
+            errors.check(format!("asset {}", id), sheet)
+        };
+        let ui_sheet_32 = sprite_sheet("ui", [2, 5]);
+        let ui_sheet_16 = sprite_sheet("ui", [4, 10]);
+        let char_sheet = sprite_sheet("char_template", [3, 4]);
+        let test_sheet = sprite_sheet("fountain_test", [1, 1]);
+        let level_tileset = sprite_sheet("level/tileset", [16, 16]);
+
+        let mut sound = |id: &str| {
+            let sound = assets.sound(audio_system, id);
+            errors.check(format!("asset {}", id), sound)
+        };
+        let walk_audio = sound("sfx/walk");
+        let windup_audio = sound("sfx/windup");
+        let attack_audio = sound("sfx/attack");
+        let staggered_audio = sound("sfx/staggered");
+        let stance_broken_audio = sound("sfx/stance_broken");
+        let drone_audio = sound("music/drone");
+
+        let spawn_name = spawn.as_ref().map_or("spawn", |spawn| spawn.name);
+        // Without the tileset the failure is already down
+        let spawn = level_tileset.as_ref().and_then(|tileset| {
+            errors.check(
+                format!("level {}", spawn_name),
+                spawn.and_then(|spawn| GameLevelSpec::load(spawn, tileset, rendering_system)),
+            )
+        });
+        let first_room_name = first_room.as_ref().map_or("base_0", |level| level.name);
+        let first_room = errors.check(
+            format!("level {}", first_room_name),
+            first_room.and_then(|level| DecodedLevel::validate(&level).map(|()| level)),
         );
 
-        rendering_system.load_font(include_bytes!("assets/leko majuna.ttf"));
+        let (
+            Some(ui_sheet_32),
+            Some(ui_sheet_16),
+            Some(char_sheet),
+            Some(test_sheet),
+            Some(level_tileset),
+            Some(walk_audio),
+            Some(windup_audio),
+            Some(attack_audio),
+            Some(staggered_audio),
+            Some(stance_broken_audio),
+            Some(drone_audio),
+            Some(spawn),
+            Some(first_room),
+        ) = (
+            ui_sheet_32,
+            ui_sheet_16,
+            char_sheet,
+            test_sheet,
+            level_tileset,
+            walk_audio,
+            windup_audio,
+            attack_audio,
+            staggered_audio,
+            stance_broken_audio,
+            drone_audio,
+            spawn,
+            first_room,
+        )
+        else {
+            return Err(errors);
+        };
+        if !errors.failures.is_empty() {
+            return Err(errors);
+        }
+        // The same track in every room, for now
+        audio_system.play_music(&drone_audio, None);
+
+        // Only the first room is needed right away
+        let mut level_loader = BackgroundLevelLoader::new();
+        level_loader.request(first_room);
 
         let num_flasks_text = rendering_system.create_text_buffer(
-            16.0,
-            17.0,
-            200.0,
-            16.0,
+            Metrics::new(16.0, 17.0),
+            (200.0, 16.0),
             "ala",
             Attrs::new().family(glyphon::Family::SansSerif),
             Align::Left,
+            TextOptions::default(),
         );
 
         let num_crystals_text = rendering_system.create_text_buffer(
-            8.0,
-            9.0,
-            128.0,
-            8.0,
+            Metrics::new(8.0, 9.0),
+            (128.0, 8.0),
             "ala",
             Attrs::new().family(glyphon::Family::SansSerif),
             Align::Right,
+            // Long numbers shrink to stay next to the crystal
+            TextOptions {
+                wrap: TextWrap::None,
+                shrink_to_fit: true,
+            },
         );
 
-        let rng = StdRng::from_seed([0; 32]); // Seed with zeros for reproducibility
-        Self {
-            player: Player::new(
-                Vec2::new(8.0, 8.0),
-                rendering_system.gizmo_sprite_sheet_from_encoded_image(
-                    include_bytes!("assets/char_template.png"),
-                    [0.0, 0.0],
-                    [1.0, 1.0],
-                    [3, 4],
-                ),
-                input_config,
-            ),
+        let damage_vignette = rendering_system.add_post_effect(damage_vignette(0.0));
+        let damage_aberration = rendering_system.add_post_effect(damage_aberration(0.0));
+        let defeat_curvature = rendering_system.add_post_effect(PostEffect::CrtCurvature {
+            curvature: DEFEAT_CURVATURE,
+        });
+        let defeat_lut = rendering_system
+            .create_color_lut(&defeat_lut_image())
+            .expect("Laid out like identity_lut_image");
+        let defeat_grading = rendering_system.add_post_effect(PostEffect::ColorGrading(defeat_lut));
+        for effect in [
+            &damage_vignette,
+            &damage_aberration,
+            &defeat_curvature,
+            &defeat_grading,
+        ] {
+            rendering_system.set_post_effect_enabled(effect, false);
+        }
+
+        let player = Player::new(Vec2::new(8.0, 8.0), char_sheet.clone(), input_config);
+        let last_health = player.character.health;
+
+        Ok(Self {
             camera: {
                 let (width, height) = Game::target_size();
-                OrthoCamera::new(width as f32, height as f32, 32.0)
+                let mut camera = CameraController::new(OrthoCamera::new(
+                    width as f32,
+                    height as f32,
+                    TILE_SIZE.0 as f32,
+                ));
+                camera.snap_to(player.character.controller.position);
+                camera
             },
-            walk_audio: audio_system.load_buffer(include_bytes!("assets/walk.wav")),
-            rng: StdRng::from_seed([0; 32]), // Seed with zeros for reproducibility
-            windup_audio: audio_system.load_buffer(include_bytes!("assets/windup_2.wav")),
-            attack_audio: audio_system.load_buffer(include_bytes!("assets/attack_1.wav")),
-            staggered_audio: audio_system.load_buffer(include_bytes!("assets/staggered_1.wav")),
-            stance_broken_audio: audio_system
-                .load_buffer(include_bytes!("assets/stance_broken_1.wav")),
-
-            manager: RoomManager::new(
-                GameLevelSpec::load(
-                    GameLevelLoadData {
-                        background_bytes: include_bytes!("assets/level_generated/spawn_floor.png"),
-                        decoration_bytes: include_bytes!(
-                            "assets/level_generated/spawn_with_walls.png"
-                        ),
-                        collision_csv: include_str!("assets/level_generated/spawn_collision.csv"),
-                        enemies_csv: include_str!("assets/level_generated/spawn_enemies.csv"),
-                    },
-                    rendering_system,
-                )
-                .expect("Failed to load spawn level"),
-                rendering_system.gizmo_sprite_sheet_from_encoded_image(
-                    include_bytes!("assets/char_template.png"),
-                    [0.0, 0.0],
-                    [1.0, 1.0],
-                    [3, 4],
-                ),
-            )
-            .add_room_spec(
-                GameLevelSpec::load(
-                    GameLevelLoadData {
-                        background_bytes: include_bytes!("assets/level_generated/base_0_floor.png"),
-                        decoration_bytes: include_bytes!(
-                            "assets/level_generated/base_0_with_walls.png"
-                        ),
-                        collision_csv: include_str!("assets/level_generated/base_0_collision.csv"),
-                        enemies_csv: include_str!("assets/level_generated/base_0_enemies.csv"),
-                    },
-                    rendering_system,
-                )
-                .expect("Failed to load level"),
-            ),
+            player,
+            walk_audio,
+            rng: RngStreams::new(MASTER_SEED),
+            windup_audio,
+            attack_audio,
+            staggered_audio,
+            stance_broken_audio,
+
+            manager: RoomManager::new(spawn, char_sheet, RngStreams::generation(MASTER_SEED)),
+            level_loader,
+            level_tileset,
 
             ui_sheet_16,
             ui_sheet_32,
             num_flasks_text,
             num_crystals_text,
             crystal_count_buffer: CrystalCountBuffer::new(0.0, 10.0),
-            test_sheet: rendering_system.gizmo_sprite_sheet_from_encoded_image(
-                include_bytes!("assets/fountain/test_processed.png"),
-                [0.0, 0.0],
-                [1.0, 1.0],
-                [1, 1],
-            ),
-        }
+            test_sheet,
+
+            dissolve: rendering_system.add_material(material::DISSOLVE),
+            grayscale: rendering_system.add_material(material::GRAYSCALE),
+            outline: rendering_system.add_material(material::OUTLINE),
+
+            damage_vignette,
+            damage_aberration,
+            defeat_curvature,
+            defeat_grading,
+            damage_flash: 0.0,
+            last_health,
+
+            transition: Transition::finished(),
+            pending_door: None,
+            off_door: true,
+
+            #[cfg(all(debug_assertions, target_arch = "wasm32"))]
+            level_reloader: LevelHotReloader::new(&["spawn", "base_0"]),
+        })
     }
 
     pub fn update(
@@ -1252,27 +2488,68 @@ impl Game {
         rendering_system: &mut RenderingSystem,
         delta_time: f32,
     ) {
+        let loaded = self.level_loader.poll();
+        self.add_loaded_levels(loaded, rendering_system);
+
+        #[cfg(all(debug_assertions, target_arch = "wasm32"))]
+        self.manager.reload_changed(
+            &mut self.level_reloader,
+            &self.level_tileset,
+            rendering_system,
+            delta_time,
+        );
+
         self.num_flasks_text.set_text(
             rendering_system,
             &convert_latin_to_ucsur(&number_to_toki_pona(self.player.healing_flasks)),
         );
 
-        self.crystal_count_buffer.target_num = self.player.num_crystals as f32;
-        self.crystal_count_buffer.update(delta_time);
+        self.crystal_count_buffer.count.target = self.player.num_crystals as f32;
+        self.crystal_count_buffer.count.update(delta_time);
         self.num_crystals_text.set_text(
             rendering_system,
             &convert_latin_to_ucsur(&number_to_toki_pona(
-                self.crystal_count_buffer.current_num as u32,
+                self.crystal_count_buffer.count.current as u32,
             )),
         );
 
+        if self.transition.update(delta_time) {
+            if let Some(direction) = self.pending_door.take() {
+                self.go_through_door(direction, rendering_system);
+            }
+        }
+
         let level_origin =
             Transform::new().set_origin(&Transform::new().translate(Vec3::new(0.0, 0.0, 0.0)));
 
+        audio_system.set_listener(self.player.character.controller.feet_position());
+
         let room = self.manager.get_current_room_mut();
 
         for enemy in room.enemies.iter_mut() {
-            if enemy.health > 0.0 {
+            if enemy.character.is_dead() {
+                enemy.defeated_for += delta_time;
+            }
+        }
+
+        let hashed_enemies = enemy_bodies(&room.enemies);
+        let enemy_feet: Vec<Vec2> = room
+            .enemies
+            .iter()
+            .map(|enemy| enemy.character.controller.feet_position())
+            .collect();
+
+        for (index, enemy) in room.enemies.iter_mut().enumerate() {
+            if enemy.character.health > 0.0 {
+                let mut bodies: Vec<Transform> = hashed_enemies
+                    .within(enemy_feet[index], BODY_QUERY_RADIUS)
+                    .into_iter()
+                    .filter(|&other| other != index)
+                    .map(|other| body_at(enemy_feet[other]))
+                    .collect();
+                if self.player.character.health > 0.0 {
+                    bodies.push(self.player.character.controller.collider(&level_origin));
+                }
                 let enemy_event = enemy.update(
                     delta_time,
                     |enemy_space| {
@@ -1280,61 +2557,83 @@ impl Game {
                         room.spec.collides_with(
                             &level_origin,
                             enemy_space,
-                            &mut |collision, id| {
-                                if id == 1 {
+                            &mut |collision, kind| {
+                                if kind == CollisionKind::Wall {
                                     collision_result = Some(collision);
                                 }
                             },
                         );
                         collision_result
                     },
-                    &self.player.controller,
+                    &bodies,
+                    &self.player.character.controller,
                     &room.spec,
-                    &mut self.rng,
+                    &mut self.rng.ai,
                 );
 
+                // Heard from where the enemy is, off screen too
+                let enemy_position = enemy.character.controller.feet_position();
                 match enemy_event {
                     CharacterEvent::None => {}
                     CharacterEvent::AttackControllerEvent(attack_event) => match attack_event {
                         AttackControllerEvent::StartWindup => {
-                            audio_system.play(&self.windup_audio, self.rng.random_range(0.6..1.0));
+                            audio_system.play_at(
+                                &self.windup_audio,
+                                enemy_position,
+                                self.rng.audio.random_range(0.6..1.0),
+                            );
                         }
                         AttackControllerEvent::StartAttack => {
-                            audio_system.play(&self.attack_audio, self.rng.random_range(0.6..1.0));
+                            audio_system.play_at(
+                                &self.attack_audio,
+                                enemy_position,
+                                self.rng.audio.random_range(0.6..1.0),
+                            );
                         }
                         AttackControllerEvent::None => {}
                     },
                     CharacterEvent::WalkCycle => {
-                        audio_system.play(&self.walk_audio, self.rng.random_range(0.6..1.0));
+                        audio_system.play_at(
+                            &self.walk_audio,
+                            enemy_position,
+                            self.rng.audio.random_range(0.6..1.0),
+                        );
+                    }
+                    CharacterEvent::Defeated => {
+                        info!("Enemy defeated!");
+                        self.player.num_crystals +=
+                            self.rng.loot.random_range(ENEMY_CRYSTAL_REWARD);
+                        continue;
                     }
                 }
 
-                if let Some((attack_space, windup_duration)) = enemy.get_attack_space(&level_origin)
+                if let Some((attack_space, windup_duration)) =
+                    enemy.character.get_attack_space(&level_origin)
                 {
                     if Collision::do_spaces_collide(
                         &attack_space,
-                        &self.player.controller.collider(&level_origin),
+                        &self.player.character.controller.collider(&level_origin),
                     )
                     .is_some()
                     {
-                        self.player.health -= 400.0 * delta_time * windup_duration; // Deal damage to the player
-                        self.player.poise -= 400.0 * delta_time * windup_duration; // Deal poise damage to the player
-                        if self
+                        let outcome = self
                             .player
-                            .attack_controller
-                            .make_staggered(windup_duration)
-                        {
+                            .character
+                            .take_damage(400.0 * delta_time * windup_duration, windup_duration);
+                        if outcome.newly_staggered {
                             audio_system
-                                .play(&self.staggered_audio, self.rng.random_range(0.8..1.2));
+                                .play(&self.staggered_audio, self.rng.audio.random_range(0.8..1.2));
+                            if let Some((kind, duration)) = enemy.hit_effect {
+                                self.player.character.status_effects.apply(kind, duration);
+                            }
                         }
-                        if self.player.poise <= 0.0 {
-                            self.player.poise = 50.0; // Prevent negative poise
-                            self.player.attack_controller.make_staggered(1.0);
-                            audio_system
-                                .play(&self.stance_broken_audio, self.rng.random_range(0.8..1.2));
+                        if outcome.stance_broken {
+                            audio_system.play(
+                                &self.stance_broken_audio,
+                                self.rng.audio.random_range(0.8..1.2),
+                            );
                         }
-                        if self.player.health <= 0.0 {
-                            self.player.health = 0.0; // Prevent negative health
+                        if outcome.defeated {
                             info!("Player defeated!");
                         }
                     }
@@ -1342,130 +2641,310 @@ impl Game {
             }
         }
 
-        if self.player.health > 0.0 {
+        // Spread groups out so they don't merge into one sprite
+        let hashed_enemies = enemy_bodies(&room.enemies);
+        let enemy_feet: Vec<Vec2> = room
+            .enemies
+            .iter()
+            .map(|enemy| enemy.character.controller.feet_position())
+            .collect();
+        for (index, enemy) in room.enemies.iter_mut().enumerate() {
+            if enemy.character.health <= 0.0 {
+                continue;
+            }
+            let neighbors = hashed_enemies
+                .within(enemy_feet[index], SEPARATION_RADIUS)
+                .into_iter()
+                .filter(|&other| other != index)
+                .map(|other| (other, enemy_feet[other]));
+            let steering = separation(index, enemy_feet[index], neighbors);
+            enemy.character.controller.move_by(
+                steering * SEPARATION_SPEED * delta_time,
+                |enemy_space| {
+                    let mut collision_result = None;
+                    room.spec
+                        .collides_with(&level_origin, enemy_space, &mut |collision, kind| {
+                            if kind == CollisionKind::Wall {
+                                collision_result = Some(collision);
+                            }
+                        });
+                    collision_result
+                },
+                &[],
+            );
+        }
+
+        // Enemies that ended up on top of each other, e.g. spawned there
+        let mut living: Vec<&mut MovementController> = room
+            .enemies
+            .iter_mut()
+            .filter(|enemy| enemy.character.health > 0.0)
+            .map(|enemy| &mut enemy.character.controller)
+            .collect();
+        separate_bodies(&mut living, |body| {
+            let mut blocked = false;
+            room.spec
+                .collides_with(&level_origin, body, &mut |_, kind| {
+                    blocked |= kind == CollisionKind::Wall
+                });
+            blocked
+        });
+
+        let player_feet = self.player.character.controller.feet_position();
+        let player_bodies: Vec<Transform> = enemy_bodies(&room.enemies)
+            .within(player_feet, BODY_QUERY_RADIUS)
+            .into_iter()
+            .map(|index| {
+                room.enemies[index]
+                    .character
+                    .controller
+                    .collider(&level_origin)
+            })
+            .collect();
+
+        if self.player.character.health > 0.0 {
             for enemy in room.enemies.iter_mut() {
-                if enemy.health <= 0.0 {
+                if enemy.character.health <= 0.0 {
                     continue; // Skip dead enemies
                 }
                 if let Some((attack_space, windup_duration)) =
-                    self.player.get_attack_space(&level_origin)
+                    self.player.character.get_attack_space(&level_origin)
                 {
                     let attacking_enemy = Collision::do_spaces_collide(
                         &attack_space,
-                        &enemy.controller.collider(&level_origin),
+                        &enemy.character.controller.collider(&level_origin),
                     )
                     .is_some();
                     if attacking_enemy {
-                        enemy.health -= 100.0 * delta_time * windup_duration; // Deal damage to the enemy
-                        enemy.poise -= 100.0 * delta_time * windup_duration; // Deal poise damage to the enemy
-                        if enemy
-                            .attack_controller
-                            .make_staggered(windup_duration * 0.25)
-                        {
+                        let outcome = enemy.character.take_damage(
+                            100.0 * delta_time * windup_duration,
+                            windup_duration * 0.25,
+                        );
+                        if outcome.newly_staggered {
                             audio_system
-                                .play(&self.staggered_audio, self.rng.random_range(0.6..1.0));
+                                .play(&self.staggered_audio, self.rng.audio.random_range(0.6..1.0));
+                            self.camera.add_trauma(STAGGER_TRAUMA);
                         }
-                        if enemy.poise <= 0.0 {
-                            enemy.poise = 50.0; // Prevent negative poise
-                            enemy.attack_controller.make_staggered(1.0);
-                            audio_system
-                                .play(&self.stance_broken_audio, self.rng.random_range(0.6..1.0));
+                        if outcome.stance_broken {
+                            audio_system.play(
+                                &self.stance_broken_audio,
+                                self.rng.audio.random_range(0.6..1.0),
+                            );
+                            self.camera.add_trauma(STANCE_BREAK_TRAUMA);
                         }
-                        if enemy.health <= 0.0 {
-                            enemy.health = 0.0; // Prevent negative health
+                        if outcome.defeated {
                             info!("Enemy defeated!");
-                            self.player.num_crystals += self.rng.random_range(10..=50);
+                            self.player.num_crystals +=
+                                self.rng.loot.random_range(ENEMY_CRYSTAL_REWARD);
                         }
                     }
                 }
             }
 
-            let player_event = self.player.update(input, delta_time, |player_space| {
-                let mut collision_result = None;
-                self.manager.get_current_room().spec.collides_with(
-                    &level_origin,
-                    player_space,
-                    &mut |collision, id| {
-                        if id == 1 {
-                            collision_result = Some(collision);
-                        }
-                    },
-                );
-                collision_result
-            });
+            let player_event = self.player.update(
+                input,
+                delta_time,
+                |player_space| {
+                    let mut collision_result = None;
+                    self.manager.get_current_room().spec.collides_with(
+                        &level_origin,
+                        player_space,
+                        &mut |collision, kind| {
+                            if kind == CollisionKind::Wall {
+                                collision_result = Some(collision);
+                            }
+                        },
+                    );
+                    collision_result
+                },
+                &player_bodies,
+            );
 
             match player_event {
                 CharacterEvent::None => {}
                 CharacterEvent::AttackControllerEvent(attack_event) => match attack_event {
                     AttackControllerEvent::StartWindup => {
-                        audio_system.play(&self.windup_audio, self.rng.random_range(0.8..1.2));
+                        audio_system
+                            .play(&self.windup_audio, self.rng.audio.random_range(0.8..1.2));
                     }
                     AttackControllerEvent::StartAttack => {
-                        audio_system.play(&self.attack_audio, self.rng.random_range(0.8..1.2));
+                        audio_system
+                            .play(&self.attack_audio, self.rng.audio.random_range(0.8..1.2));
                     }
                     AttackControllerEvent::None => {}
                 },
                 CharacterEvent::WalkCycle => {
-                    audio_system.play(&self.walk_audio, self.rng.random_range(0.8..1.2));
+                    audio_system.play(&self.walk_audio, self.rng.audio.random_range(0.8..1.2));
                 }
+                CharacterEvent::Defeated => {
+                    info!("Player defeated!");
+                }
+            }
+
+            let room = self.manager.get_current_room_mut();
+            if room.update_waves() {
+                info!("Wave {} incoming!", room.next_wave);
             }
+            let doors_locked = room.is_locked();
 
-            // Level advancing:
-            // collides with:
-            // 2 -> move down
-            // 3 -> move right
-            // 4 -> move up
-            // 5 -> move left
-            let player_space = self.player.controller.collider(&level_origin);
-            let mut collision_result = None;
+            // Level advancing
+            let player_space = self.player.character.controller.collider(&level_origin);
+            let mut door = None;
             self.manager.get_current_room().spec.collides_with(
                 &level_origin,
                 &player_space,
-                &mut |collision, id| {
-                    if id == 2 || id == 3 || id == 4 || id == 5 {
-                        collision_result = Some((collision, id));
+                &mut |_, kind| {
+                    if let CollisionKind::Door(direction) = kind {
+                        if !doors_locked {
+                            door = Some(direction);
+                        }
                     }
                 },
             );
-            if let Some((collision, id)) = collision_result {
-                let current_position = self.manager.current_room;
-                let new_position = match id {
-                    2 => (
-                        current_position.0,
-                        current_position.1 - 1,
-                        current_position.2,
-                    ), // Move down
-                    3 => (
-                        current_position.0 + 1,
-                        current_position.1,
-                        current_position.2,
-                    ), // Move right
-                    4 => (
-                        current_position.0,
-                        current_position.1 + 1,
-                        current_position.2,
-                    ), // Move up
-                    5 => (
-                        current_position.0 - 1,
-                        current_position.1,
-                        current_position.2,
-                    ), // Move left
-                    _ => current_position,
-                };
-                self.manager.change_room(new_position);
-                info!("Changed room to: {:?}", new_position);
-                // Move player position accordingly
-                match id {
-                    2 => self.player.controller.position.y = 1.0, // Move down
-                    3 => self.player.controller.position.x = 1.25, // Move right
-                    4 => self.player.controller.position.y = 14.5, // Move up
-                    5 => self.player.controller.position.x = 14.75, // Move left
-                    _ => {}
+            // The room changes once the screen is covered
+            self.off_door |= door.is_none();
+            if let Some(direction) = door {
+                if self.transition.is_finished() && self.off_door {
+                    self.transition =
+                        Transition::new(TransitionStyle::Iris, ROOM_TRANSITION_DURATION);
+                    self.pending_door = Some(direction);
+                }
+            }
+        }
+
+        self.update_damage_feedback(rendering_system, delta_time);
+        self.update_muffle(audio_system);
+
+        let room_size = self.manager.get_current_room().spec.grid.world_size();
+        self.camera.set_bounds(Vec2::ZERO, room_size);
+        self.camera
+            .update(self.player.character.controller.position, delta_time);
+    }
+
+    /// Moves the player into the room behind the `direction` door
+    fn go_through_door(
+        &mut self,
+        direction: DoorDirection,
+        rendering_system: &mut RenderingSystem,
+    ) {
+        let current_position = self.manager.current_room;
+        let offset = direction.room_offset();
+        let new_position = (
+            current_position.0 + offset.0,
+            current_position.1 + offset.1,
+            current_position.2 + offset.2,
+        );
+        // The next room may be picked from levels still loading
+        if self.level_loader.is_loading() {
+            let loaded = self.level_loader.wait();
+            self.add_loaded_levels(loaded, rendering_system);
+        }
+        if !self.manager.change_room(new_position) {
+            log::error!("No level to make room {:?} from, staying", new_position);
+            // Walking onto the door again tries again
+            self.off_door = false;
+            return;
+        }
+        info!(
+            "Changed room to {:?}, made from level {}",
+            new_position,
+            self.manager.get_current_room().spec.name
+        );
+        // Come out of the matching door on the other side
+        let controller = &mut self.player.character.controller;
+        let entry = self
+            .manager
+            .get_current_room()
+            .spec
+            .entry_position(direction.opposite(), controller.feet_position());
+        controller.set_feet_position(entry);
+        self.off_door = false;
+
+        // No panning over from where the last room was
+        let room_size = self.manager.get_current_room().spec.grid.world_size();
+        self.camera.set_bounds(Vec2::ZERO, room_size);
+        self.camera
+            .snap_to(self.player.character.controller.position);
+    }
+
+    /// Flashes the damage vignette whenever the player loses health, fading
+    /// it out over `DAMAGE_FLASH_DURATION`, and shakes the camera on new hits
+    fn update_damage_feedback(&mut self, rendering_system: &mut RenderingSystem, delta_time: f32) {
+        let health = self.player.character.health;
+        if health < self.last_health {
+            if self.damage_flash == 0.0 {
+                self.camera.add_trauma(PLAYER_HIT_TRAUMA);
+            }
+            self.damage_flash = DAMAGE_FLASH_DURATION;
+        } else {
+            self.damage_flash = (self.damage_flash - delta_time).max(0.0);
+        }
+        self.last_health = health;
+
+        let flash = self.damage_flash / DAMAGE_FLASH_DURATION;
+        rendering_system.set_post_effect_enabled(&self.damage_vignette, self.damage_flash > 0.0);
+        rendering_system.set_post_effect(&self.damage_vignette, damage_vignette(flash));
+        rendering_system.set_post_effect_enabled(&self.damage_aberration, self.damage_flash > 0.0);
+        rendering_system.set_post_effect(&self.damage_aberration, damage_aberration(flash));
+
+        let defeated = self.player.character.is_dead();
+        rendering_system.set_post_effect_enabled(&self.defeat_curvature, defeated);
+        rendering_system.set_post_effect_enabled(&self.defeat_grading, defeated);
+    }
+
+    /// Muffles the audio while the player is staggered or low on health
+    fn update_muffle(&self, audio_system: &mut AudioSystem) {
+        let character = &self.player.character;
+        let staggered = character.attack_controller.state_kind() == AttackPhase::Staggered;
+        let low_health = character.health < character.max_health * LOW_HEALTH_RATIO;
+        audio_system.set_filter((staggered || low_health).then_some(MUFFLED_CUTOFF));
+    }
+
+    /// Uploads decoded levels and makes them available for new rooms
+    fn add_loaded_levels(
+        &mut self,
+        loaded: Vec<LoadResult>,
+        rendering_system: &mut RenderingSystem,
+    ) {
+        for (name, result) in loaded {
+            let spec = result.and_then(|level| {
+                GameLevelSpec::from_decoded(&level, &self.level_tileset, rendering_system)
+            });
+            match spec {
+                Ok(spec) => {
+                    info!("Loaded level {}", name);
+                    self.manager.push_room_spec(spec);
                 }
+                Err(err) => log::error!("Failed to load level {}: {}", name, err),
             }
         }
     }
 
+    /// Draws how far along `progress`, from 0 to 1, the assets are while
+    /// there's no game to draw yet
+    pub fn render_loading_screen(drawer: &mut Drawer, progress: f32) {
+        drawer.clear_slow(Color::BLACK);
+        let size = drawer.internal_size();
+        let bar_size = Vec2::new(size.x * 0.5, 6.0);
+        let corner = (size - bar_size) * 0.5;
+        let white_sprite = drawer.white_sprite();
+        let bar = |width: f32| {
+            drawer
+                .ortho
+                .translate(corner.extend(0.0))
+                .scale(Vec3::new(width, bar_size.y, 1.0))
+        };
+        let (track, filled) = (bar(bar_size.x), bar(bar_size.x * progress.clamp(0.0, 1.0)));
+        let track_color = EngineColor {
+            r: 0.2,
+            g: 0.2,
+            b: 0.2,
+            a: 1.0,
+        };
+        drawer.draw_square_slow(Some(&track), Some(&track_color), white_sprite);
+        drawer.draw_square_slow(Some(&filled), Some(&EngineColor::WHITE), white_sprite);
+    }
+
     pub fn render(&self, drawer: &mut Drawer) {
         drawer.clear_slow(Color {
             r: 0.0,
@@ -1474,22 +2953,21 @@ impl Game {
             a: 255.0,
         });
 
-        let view_transform = self.camera.get_transform().set_origin(
-            &self
-                .player
-                .controller
-                .local_space(&Transform::new().translate(Vec3::new(0.5, 0.5, 0.0))),
-        );
+        let view_transform = self.camera.view_transform();
 
         let current_level = self.manager.get_current_room();
+        drawer.set_ambient(current_level.ambient);
+        drawer.set_layer(DrawLayer::LEVEL);
         let level_transform = current_level.spec.get_local_space(
             &view_transform.set_origin(&Transform::new().translate(Vec3::new(0.0, 0.0, 0.0))),
         );
-        drawer.draw_square_slow(
-            Some(&level_transform),
-            Some(&EngineColor::WHITE),
-            current_level.spec.background.get_sprite([0, 0]).unwrap(),
-        );
+        if let Some(floor) = &current_level.spec.floor {
+            drawer.draw_tilemap_slow(
+                floor,
+                Some(&view_transform.set_origin(&Transform::new())),
+                Some(&EngineColor::WHITE),
+            );
+        }
         drawer.draw_square_slow(
             Some(&level_transform),
             Some(&EngineColor::WHITE),
@@ -1506,73 +2984,168 @@ impl Game {
             self.test_sheet.get_sprite([0, 0]).unwrap(),
         );
 
-        // Draw enemies
+        // Shadows darken the floor under everyone still standing
+        drawer.set_blend_mode(BlendMode::Multiply);
+        let white_sprite = drawer.white_sprite();
+        let standing = current_level
+            .enemies
+            .iter()
+            .map(|enemy| &enemy.character)
+            .chain([&self.player.character])
+            .filter(|character| !character.is_dead());
+        for character in standing {
+            drawer.draw_square_slow(
+                Some(&character.controller.shadow_space(&view_transform)),
+                Some(&SHADOW_COLOR),
+                white_sprite,
+            );
+        }
+        drawer.set_blend_mode(BlendMode::Alpha);
+
+        // Draw enemies, with their bars over every character
         for enemy in &current_level.enemies {
-            if enemy.health > 0.0 {
-                let color = if let EnemyAIState::Chasing(_) = enemy.state {
-                    EngineColor::RED
-                } else {
-                    EngineColor::BLUE
-                };
+            if enemy.character.health > 0.0 {
+                let color =
+                    if let Some(progress) = enemy.character.attack_controller.windup_progress() {
+                        windup_telegraph_color(progress)
+                    } else if let EnemyAIState::Chasing(_) = enemy.state {
+                        EngineColor::RED
+                    } else {
+                        EngineColor::BLUE
+                    };
 
+                drawer.set_layer(DrawLayer::WORLD);
                 drawer.draw_square_slow(
-                    Some(&enemy.controller.local_space(&view_transform)),
+                    Some(&enemy.character.controller.local_space(&view_transform)),
                     Some(&color),
-                    enemy.animation.get_current_sprite(),
+                    enemy.character.animation.get_current_sprite(),
                 );
 
+                drawer.set_layer(DrawLayer::OVERLAY);
                 let white_sprite = drawer.white_sprite();
 
-                if let Some((attack_space, _)) = enemy.get_attack_space(&view_transform) {
+                if let Some((attack_space, _)) = enemy.character.get_attack_space(&view_transform) {
+                    drawer.set_blend_mode(BlendMode::Additive);
                     drawer.draw_square_slow(
                         Some(&attack_space),
                         Some(&EngineColor::GREEN),
                         white_sprite,
                     );
+                    drawer.set_blend_mode(BlendMode::Alpha);
                 }
 
                 // Draw enemy health bar
                 drawer.draw_square_slow(
-                    Some(&enemy.health_bar_space(&view_transform, true)),
+                    Some(&enemy.character.health_bar_space(&view_transform, true)),
                     Some(&EngineColor::RED.additive_darken(0.7)),
                     white_sprite,
                 );
                 drawer.draw_square_slow(
-                    Some(&enemy.health_bar_space(&view_transform, false)),
+                    Some(&enemy.character.health_bar_space(&view_transform, false)),
                     Some(&EngineColor::RED),
                     white_sprite,
                 );
 
                 // Draw enemy poise bar
                 drawer.draw_square_slow(
-                    Some(&enemy.poise_bar_space(&view_transform, true)),
+                    Some(&enemy.character.poise_bar_space(&view_transform, true)),
                     Some(&EngineColor::YELLOW.additive_darken(0.7)),
                     white_sprite,
                 );
                 drawer.draw_square_slow(
-                    Some(&enemy.poise_bar_space(&view_transform, false)),
+                    Some(&enemy.character.poise_bar_space(&view_transform, false)),
                     Some(&EngineColor::YELLOW),
                     white_sprite,
                 );
+            } else if enemy.defeated_for < DISSOLVE_DURATION {
+                let progress = enemy.defeated_for / DISSOLVE_DURATION;
+                drawer.set_layer(DrawLayer::WORLD);
+                drawer.set_material(Some(Material::new(
+                    self.dissolve,
+                    [progress, 1.0, 0.6, 0.2],
+                )));
+                drawer.draw_square_slow(
+                    Some(&enemy.character.controller.local_space(&view_transform)),
+                    Some(&EngineColor::BLUE),
+                    enemy.character.animation.get_current_sprite(),
+                );
+                drawer.set_material(None);
             }
         }
 
-        let color = if self.player.health > 0.0 {
-            EngineColor::WHITE
+        let player_material = if self.player.character.is_dead() {
+            Some(Material::new(self.grayscale, [1.0, 0.0, 0.0, 0.0]))
         } else {
-            EngineColor::BLACK
+            let status = self.player.character.status_effects.newest();
+            status.map(|kind| Material::new(self.outline, status_outline(kind)))
         };
+        drawer.set_layer(DrawLayer::WORLD);
+        drawer.set_material(player_material);
         drawer.draw_square_slow(
-            Some(&self.player.controller.local_space(&view_transform)),
-            Some(&color),
-            self.player.animation.get_current_sprite(),
+            Some(
+                &self
+                    .player
+                    .character
+                    .controller
+                    .local_space(&view_transform),
+            ),
+            Some(&EngineColor::WHITE),
+            self.player.character.animation.get_current_sprite(),
         );
+        drawer.set_material(None);
 
+        drawer.set_layer(DrawLayer::OVERLAY);
         let white_sprite = drawer.white_sprite();
 
-        if let Some((attack_space, _)) = self.player.get_attack_space(&view_transform) {
+        // Attacks glow over what they hit
+        if let Some((attack_space, _)) = self.player.character.get_attack_space(&view_transform) {
+            drawer.set_blend_mode(BlendMode::Additive);
             drawer.draw_square_slow(Some(&attack_space), Some(&EngineColor::GREEN), white_sprite);
+            drawer.set_blend_mode(BlendMode::Alpha);
+        }
+
+        // Cooldown swirl, dots filling in clockwise around the player
+        if let Some(progress) = self.player.character.attack_controller.cooldown_progress() {
+            let player_space = self
+                .player
+                .character
+                .controller
+                .local_space(&view_transform);
+            let dots = (progress * COOLDOWN_SWIRL_DOTS as f32).ceil() as u32;
+            for dot in 0..dots {
+                let angle = dot as f32 / COOLDOWN_SWIRL_DOTS as f32 * f32::consts::TAU;
+                drawer.draw_square_slow(
+                    Some(&player_space.around_pivot(Vec2::splat(0.5), |t| {
+                        t.rotate_2d(angle)
+                            .translate(Vec3::new(0.0, -0.6, 0.0))
+                            .scale(Vec3::new(0.12, 0.12, 1.0))
+                    })),
+                    Some(&EngineColor::WHITE),
+                    white_sprite,
+                );
+            }
+        }
+
+        // Walls cast shadows from the player's torch
+        drawer.set_layer(DrawLayer::LIGHTING);
+        let mut lighting = Lighting::new(&view_transform, ROOM_LIGHT);
+        if let Some(occlusion) = &current_level.spec.occlusion {
+            lighting = lighting.with_occlusion(occlusion);
         }
+        lighting.attach_light(
+            &self
+                .player
+                .character
+                .controller
+                .local_space(&view_transform),
+            PLAYER_TORCH_RADIUS,
+            PLAYER_TORCH,
+        );
+        drawer.draw_lighting_slow(&lighting);
+
+        // The HUD isn't part of the room
+        drawer.set_ambient(EngineColor::WHITE);
+        drawer.set_layer(DrawLayer::UI);
 
         // Draw player health
         let ui_transform = drawer.ortho;
@@ -1591,7 +3164,7 @@ impl Game {
             Some(
                 &ui_transform
                     .translate(Vec3::new(16.0, 16.0, 0.0))
-                    .scale(Vec3::new(self.player.health, 16.0, 1.0)),
+                    .scale(Vec3::new(self.player.character.health, 16.0, 1.0)),
             ),
             Some(&EngineColor::RED),
             white_sprite,
@@ -1611,7 +3184,7 @@ impl Game {
         //    Some(
         //        &ui_transform
         //            .translate(Vec3::new(16.0, 32.0, 0.0))
-        //            .scale(Vec3::new(self.player.poise * 2.0, 16.0, 1.0)),
+        //            .scale(Vec3::new(self.player.character.poise * 2.0, 16.0, 1.0)),
         //    ),
         //    Some(&EngineColor::YELLOW),
         //    white_sprite,
@@ -1639,8 +3212,7 @@ impl Game {
         );
 
         // Render crystals
-        let crystal_load = self.crystal_count_buffer.get_load();
-        let crystal_index = (crystal_load as u32).min(4);
+        let crystal_index = self.crystal_count_buffer.sprite_index();
         let crystal_sprite = self.ui_sheet_16.get_sprite([2, crystal_index]).unwrap();
         drawer.draw_square_slow(
             Some(
@@ -1658,5 +3230,955 @@ impl Game {
             1.0,
             GlyphonColor::rgba(255, 255, 255, 255),
         );
+
+        self.transition.draw(drawer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        headless::{self, HeadlessGame},
+        hot_reload::POLL_INTERVAL,
+        renderer::gizmo::{GizmoBindableTexture, TextureAlpha, TextureSampling},
+    };
+
+    fn test_sheet() -> GizmoSpriteSheet {
+        let texture = GizmoBindableTexture {
+            layer: 0,
+            width: 96,
+            height: 128,
+            alpha: TextureAlpha::Straight,
+            sampling: TextureSampling::default(),
+        };
+        GizmoSpriteSheet::new(Rc::new(texture), [0.0, 0.0], [1.0, 1.0], [3, 4])
+    }
+
+    fn test_spec(enemy_waves: Vec<Vec<Vec2>>) -> Rc<GameLevelSpec> {
+        Rc::new(test_level(enemy_waves))
+    }
+
+    fn test_level(enemy_waves: Vec<Vec<Vec2>>) -> GameLevelSpec {
+        GameLevelSpec {
+            name: "test",
+            floor: None,
+            decoration: test_sheet(),
+            collision: Vec::new(),
+            enemy_waves,
+            doors: Vec::new(),
+            grid: TileGrid::new((16, 16)),
+            ambient: EngineColor::WHITE,
+            occlusion: None,
+        }
+    }
+
+    #[test]
+    fn deeper_rooms_have_tougher_enemies() {
+        let spec = test_spec(vec![vec![Vec2::new(4.0, 4.0), Vec2::new(8.0, 8.0)]]);
+        let difficulty = DifficultyCurve::new();
+        let room_at = |position| {
+            ActiveRoom::from_spec(
+                spec.clone(),
+                test_sheet(),
+                DifficultyCurve::depth(position),
+                &difficulty,
+            )
+        };
+
+        let spawn = room_at((0, 0, 0));
+        let near = room_at((1, 0, 0));
+        let far = room_at((2, -3, 0));
+
+        assert_eq!(spawn.enemies[0].character.max_health, 20.0);
+        assert_eq!(spawn.enemies[0].character.max_poise, 50.0);
+        assert_eq!(far.enemies.len(), near.enemies.len());
+        for (near, far) in near.enemies.iter().zip(&far.enemies) {
+            assert!(far.character.max_health > near.character.max_health);
+            assert!(far.character.max_poise > near.character.max_poise);
+            assert_eq!(far.character.health, far.character.max_health);
+        }
+    }
+
+    #[test]
+    fn only_the_last_wave_unlocks_the_doors() {
+        let spec = test_spec(vec![
+            vec![Vec2::new(4.0, 4.0)],
+            vec![Vec2::new(8.0, 8.0), Vec2::new(10.0, 8.0)],
+        ]);
+        let mut room = ActiveRoom::from_spec(spec, test_sheet(), 0, &DifficultyCurve::new());
+        assert_eq!(room.enemies.len(), 1);
+        assert!(room.is_locked());
+        assert!(!room.update_waves());
+
+        room.enemies[0].character.health = 0.0;
+        assert!(room.is_locked());
+        assert!(room.update_waves());
+        assert_eq!(room.enemies.len(), 2);
+        assert!(room.is_locked());
+
+        room.enemies[0].character.health = 0.0;
+        assert!(room.is_locked());
+        room.enemies[1].character.health = 0.0;
+        assert!(!room.update_waves());
+        assert!(!room.is_locked());
+    }
+
+    #[test]
+    fn reinforcements_wander_restlessly() {
+        let spec = test_spec(vec![vec![Vec2::new(4.0, 4.0)], vec![Vec2::new(8.0, 8.0)]]);
+        let mut room = ActiveRoom::from_spec(spec, test_sheet(), 0, &DifficultyCurve::new());
+        assert_eq!(room.enemies[0].wander, WanderConfig::default());
+
+        room.enemies[0].character.health = 0.0;
+        assert!(room.update_waves());
+        assert_eq!(room.enemies[0].wander, WanderConfig::restless());
+    }
+
+    #[test]
+    fn single_wave_rooms_never_lock() {
+        let spec = test_spec(vec![vec![Vec2::new(4.0, 4.0)]]);
+        let room = ActiveRoom::from_spec(spec, test_sheet(), 0, &DifficultyCurve::new());
+        assert!(!room.is_wave_room());
+        assert!(!room.is_locked());
+    }
+
+    #[test]
+    fn crystal_count_animates_down_to_a_lower_target() {
+        let mut buffer = CrystalCountBuffer::new(30.0, 10.0);
+        buffer.count.target = 10.0;
+
+        let mut previous = buffer.count.current;
+        buffer.count.update(0.1);
+        assert!(buffer.count.current < previous);
+        assert!(buffer.count.is_active());
+
+        for _ in 0..40 {
+            previous = buffer.count.current;
+            buffer.count.update(0.1);
+            assert!(buffer.count.current <= previous);
+            assert!(buffer.count.current >= 10.0);
+        }
+        assert_eq!(buffer.count.current, 10.0);
+        assert_eq!(buffer.sprite_index(), 0);
+    }
+
+    #[test]
+    fn rng_streams_do_not_perturb_each_other() {
+        let mut quiet = RngStreams::new(7);
+        let mut noisy = RngStreams::new(7);
+        let _: f32 = noisy.audio.random_range(0.6..1.0);
+
+        for _ in 0..16 {
+            assert_eq!(
+                quiet.ai.random_range(0..1000),
+                noisy.ai.random_range(0..1000)
+            );
+        }
+        assert_ne!(
+            quiet.loot.random::<u64>(),
+            RngStreams::generation(7).random::<u64>()
+        );
+    }
+
+    fn test_character() -> Character {
+        Character::new(
+            MovementController::new(Vec2::ZERO, 1.0),
+            CharacterWalkAnimation::new(test_sheet(), CharacterOrientation::Down, 1.0),
+            20.0,
+            50.0,
+        )
+    }
+
+    #[test]
+    fn breaking_poise_refills_it_and_staggers() {
+        let mut character = test_character();
+        let outcome = character.take_damage(10.0, 0.1);
+        assert_eq!(
+            outcome,
+            DamageOutcome {
+                newly_staggered: true,
+                stance_broken: false,
+                defeated: false,
+            }
+        );
+        assert_eq!(character.poise, 40.0);
+
+        character.health = 100.0;
+        let outcome = character.take_damage(45.0, 0.1);
+        assert!(outcome.stance_broken);
+        assert!(!outcome.newly_staggered); // Still staggered from the first hit
+        assert_eq!(character.poise, character.max_poise);
+        assert!(matches!(
+            character.attack_controller.state,
+            AttackState::Staggered { duration_left } if duration_left == 1.0
+        ));
+    }
+
+    #[test]
+    fn health_stops_at_zero_and_defeat_is_reported_once() {
+        let mut character = test_character();
+        assert!(character.take_damage(25.0, 0.1).defeated);
+        assert_eq!(character.health, 0.0);
+        assert!(character.is_dead());
+        assert!(!character.take_damage(5.0, 0.1).defeated);
+        assert_eq!(character.health, 0.0);
+    }
+
+    #[test]
+    fn status_damage_wears_down_without_staggering() {
+        let mut character = test_character();
+        character.status_effects.apply(
+            StatusEffectKind::Poison {
+                damage_per_second: 10.0,
+            },
+            5.0,
+        );
+
+        let (speed_multiplier, outcome) = character.update_status_effects(1.0);
+        assert_eq!(speed_multiplier, 1.0);
+        assert_eq!(outcome, DamageOutcome::default());
+        assert_eq!(character.health, 10.0);
+        assert!(character.attack_controller.is_ready());
+
+        let (_, outcome) = character.update_status_effects(1.0);
+        assert!(outcome.defeated);
+        assert!(character.is_dead());
+    }
+
+    #[test]
+    fn deeper_enemies_hit_with_status_effects() {
+        let spec = test_spec(vec![vec![Vec2::new(4.0, 4.0)]]);
+        let difficulty = DifficultyCurve::new();
+        let hit_effect = |depth| {
+            let room = ActiveRoom::from_spec(spec.clone(), test_sheet(), depth, &difficulty);
+            room.enemies[0].hit_effect.map(|(kind, _)| kind)
+        };
+
+        assert_eq!(hit_effect(0), None);
+        assert!(matches!(
+            hit_effect(SLOWING_DEPTH),
+            Some(StatusEffectKind::Slow { .. })
+        ));
+        assert!(matches!(
+            hit_effect(POISONING_DEPTH),
+            Some(StatusEffectKind::Poison { .. })
+        ));
+        assert!(matches!(
+            hit_effect(20),
+            Some(StatusEffectKind::Burn { .. })
+        ));
+    }
+
+    #[test]
+    fn snapshot_reflects_hits_on_the_player() {
+        let mut player = Player::new(
+            Vec2::new(8.0, 8.0),
+            test_sheet(),
+            &mut InputSystemConfig::new(),
+        );
+        let manager = RoomManager::new(
+            test_level(vec![vec![Vec2::new(4.0, 4.0)]]),
+            test_sheet(),
+            RngStreams::generation(0),
+        );
+
+        let before = GameSnapshot::capture(&player, &manager);
+        assert_eq!(before.player.character.health, 100.0);
+        assert_eq!(before.enemies.len(), 1);
+        assert_eq!(before.room, (0, 0, 0));
+
+        player.character.take_damage(30.0, 0.1);
+        let after = GameSnapshot::capture(&player, &manager);
+        assert_eq!(after.player.character.health, 70.0);
+        assert_eq!(after.player.character.poise, 20.0);
+        assert_eq!(after.enemies, before.enemies);
+    }
+
+    #[test]
+    fn empty_room_pool_keeps_the_player_in_the_room() {
+        let mut manager = RoomManager::new(
+            test_level(Vec::new()),
+            test_sheet(),
+            RngStreams::generation(0),
+        );
+
+        assert!(!manager.change_room((1, 0, 0)));
+        assert_eq!(manager.current_room, (0, 0, 0));
+        assert!(!manager.rooms.contains_key(&(1, 0, 0)));
+
+        // Rooms already made up can still be gone back to
+        assert!(manager.change_room((0, 0, 0)));
+    }
+
+    #[test]
+    fn new_rooms_follow_the_managers_difficulty() {
+        let difficulty = DifficultyCurve {
+            stats_per_depth: 1.0,
+            max_stat_multiplier: 10.0,
+        };
+        let mut manager = RoomManager::new(
+            test_level(Vec::new()),
+            test_sheet(),
+            RngStreams::generation(0),
+        )
+        .with_difficulty(difficulty)
+        .add_room_spec(test_level(vec![vec![Vec2::new(4.0, 4.0)]]));
+
+        assert!(manager.change_room((2, 0, 0)));
+        let enemies = &manager.get_current_room().enemies;
+        assert_eq!(enemies[0].character.max_health, 60.0);
+    }
+
+    #[test]
+    fn right_door_leads_to_the_left_door_of_the_next_room() {
+        let door_rows = [6.5, 7.5, 8.5];
+        let mut next_room = test_level(Vec::new());
+        next_room.grid = TileGrid::new((24, 12));
+        for y in door_rows {
+            next_room
+                .doors
+                .push((DoorDirection::Left, Vec2::new(0.5, y)));
+            next_room
+                .doors
+                .push((DoorDirection::Right, Vec2::new(23.5, y)));
+        }
+
+        let through = DoorDirection::Right;
+        let entry = next_room.entry_position(through.opposite(), Vec2::new(15.5, 7.25));
+        assert_eq!(entry, Vec2::new(0.5 + DOOR_ENTRY_DISTANCE, 7.25));
+
+        // Lined up with the door even when the rooms' doors don't line up
+        let entry = next_room.entry_position(through.opposite(), Vec2::new(15.5, 2.0));
+        assert_eq!(entry, Vec2::new(0.5 + DOOR_ENTRY_DISTANCE, 6.5));
+
+        // Same spots as the ones 16x16 rooms always used
+        let room = test_level(Vec::new());
+        let feet = Vec2::new(8.0, 8.0);
+        assert_eq!(room.entry_position(DoorDirection::Left, feet).x, 1.25);
+        assert_eq!(room.entry_position(DoorDirection::Right, feet).x, 14.75);
+        assert_eq!(room.entry_position(DoorDirection::Up, feet).y - 0.25, 1.0);
+        assert_eq!(
+            room.entry_position(DoorDirection::Down, feet).y - 0.25,
+            14.5
+        );
+    }
+
+    #[test]
+    fn enemies_close_in_on_the_exact_player_position() {
+        let player = Vec2::new(5.9, 3.2);
+        let tile_center = player.floor() + 0.5;
+
+        let near = Vec2::new(5.2, 3.4);
+        assert_eq!(pursuit_point(near, tile_center, player), player);
+
+        let far = Vec2::new(1.0, 3.0);
+        assert_eq!(pursuit_point(far, tile_center, player), tile_center);
+    }
+
+    #[test]
+    fn attack_progress_tracks_the_timers() {
+        let mut attack = AttackController::new();
+        assert_eq!(attack.state_kind(), AttackPhase::Ready);
+        assert_eq!(attack.windup_progress(), None);
+
+        attack.update(0.0, AttackIntention::Duration(0.4));
+        attack.update(0.1, AttackIntention::Duration(0.4));
+        assert_eq!(attack.state_kind(), AttackPhase::Windup);
+        assert_eq!(attack.windup_progress(), Some(0.25));
+        attack.update(0.1, AttackIntention::Duration(0.4));
+        assert_eq!(attack.windup_progress(), Some(0.5));
+
+        while attack.state_kind() != AttackPhase::Cooldown {
+            attack.update(0.05, AttackIntention::None);
+        }
+        assert_eq!(attack.cooldown_progress(), Some(0.0));
+        attack.update(0.05, AttackIntention::None);
+        assert_eq!(attack.cooldown_progress(), Some(0.5));
+        assert_eq!(attack.windup_progress(), None);
+
+        let mut healing = HealingState::Ready;
+        assert_eq!(healing.progress(), None);
+        healing.start_healing();
+        healing.update(0.25);
+        assert_eq!(healing.progress(), Some(0.25));
+        healing.update(1.0);
+        assert_eq!(healing.progress(), None);
+    }
+
+    #[test]
+    fn windup_telegraph_shifts_from_yellow_to_red() {
+        let mut attack = AttackController::new();
+        attack.update(0.0, AttackIntention::Duration(0.2));
+        assert_eq!(
+            windup_telegraph_color(attack.windup_progress().unwrap()),
+            EngineColor::YELLOW
+        );
+
+        attack.update(0.1, AttackIntention::Duration(0.2));
+        let halfway = windup_telegraph_color(attack.windup_progress().unwrap());
+        assert_eq!((halfway.r, halfway.g, halfway.b), (1.0, 0.5, 0.0));
+
+        attack.update(0.1, AttackIntention::Duration(0.2));
+        assert_eq!(
+            windup_telegraph_color(attack.windup_progress().unwrap()),
+            EngineColor::RED
+        );
+    }
+
+    #[test]
+    fn walking_into_a_wall_stops_the_player() {
+        let Some(mut run) = HeadlessGame::new() else {
+            return;
+        };
+        let start = run.game.snapshot().player.character.position;
+
+        run.hold(&[KeyCode::KeyA], 30);
+        let walked = run.game.snapshot().player.character.position;
+        assert!(walked.x < start.x);
+        assert_eq!(walked.y, start.y);
+
+        // Far longer than crossing the room takes
+        run.hold(&[KeyCode::KeyA], 600);
+        let at_wall = run.game.snapshot();
+        run.hold(&[KeyCode::KeyA], 60);
+        let still_at_wall = run.game.snapshot();
+        assert_eq!(
+            still_at_wall.player.character.position,
+            at_wall.player.character.position
+        );
+        assert!(at_wall.player.character.position.x >= 0.5);
+        assert_eq!(still_at_wall.room, (0, 0, 0));
+
+        let frame = run.frame();
+        assert_eq!(frame.dimensions(), Game::target_size());
+        let corner = *frame.get_pixel(0, 0);
+        assert!(frame.pixels().any(|pixel| *pixel != corner));
+    }
+
+    #[test]
+    fn double_tapping_a_direction_dashes() {
+        let walked = |gap: usize| {
+            let mut run = HeadlessGame::new()?;
+            let start = run.game.player.character.controller.position;
+            run.hold(&[KeyCode::KeyA], 2);
+            run.run(gap);
+            run.hold(&[KeyCode::KeyA], 6);
+            Some(start.x - run.game.player.character.controller.position.x)
+        };
+        let (Some(dashed), Some(walked)) = (walked(2), walked(30)) else {
+            return;
+        };
+        assert!(dashed > walked * 1.5, "{} vs {}", dashed, walked);
+    }
+
+    #[test]
+    fn attacking_an_adjacent_enemy_damages_it() {
+        let Some(mut run) = HeadlessGame::new() else {
+            return;
+        };
+        // The player starts out facing down, so put the enemy right below
+        let below = run.game.player.character.controller.position + Vec2::new(0.0, 0.75);
+        let enemy = Enemy::new(below, run.game.manager.enemy_sprite_sheet.clone());
+        run.game.manager.get_current_room_mut().enemies.push(enemy);
+        let before = run.game.snapshot();
+
+        // A quick tap, so the attack lands before the enemy's own does
+        run.hold(&[KeyCode::KeyL], 10);
+        run.run(30);
+        let after = run.game.snapshot();
+        assert_eq!(after.enemies.len(), 1);
+        assert!(after.enemies[0].health < before.enemies[0].health);
+        assert_eq!(
+            after.player.character.position,
+            before.player.character.position
+        );
+    }
+
+    #[test]
+    fn enemies_killed_by_poison_drop_crystals() {
+        let Some(mut run) = HeadlessGame::new() else {
+            return;
+        };
+        let mut enemy = Enemy::new(
+            Vec2::new(4.0, 4.0),
+            run.game.manager.enemy_sprite_sheet.clone(),
+        );
+        enemy.character.status_effects.apply(
+            StatusEffectKind::Poison {
+                damage_per_second: 100.0,
+            },
+            1.0,
+        );
+        run.game.manager.get_current_room_mut().enemies.push(enemy);
+
+        run.run(30);
+        let poisoned = run.game.manager.get_current_room().enemies.last().unwrap();
+        assert!(poisoned.character.is_dead());
+        assert!(run.game.player.num_crystals >= *ENEMY_CRYSTAL_REWARD.start());
+    }
+
+    #[test]
+    fn defeated_enemies_dissolve_away() {
+        let Some(mut run) = HeadlessGame::new() else {
+            return;
+        };
+        let mut enemy = Enemy::new(
+            Vec2::new(4.0, 4.0),
+            run.game.manager.enemy_sprite_sheet.clone(),
+        );
+        enemy.character.take_damage(1000.0, 0.1);
+        run.game.manager.get_current_room_mut().enemies = vec![enemy];
+        // The same frame, with the enemy gone altogether
+        let without_enemy = |run: &mut HeadlessGame| {
+            let enemies = std::mem::take(&mut run.game.manager.get_current_room_mut().enemies);
+            run.render();
+            let frame = run.frame();
+            run.game.manager.get_current_room_mut().enemies = enemies;
+            frame
+        };
+
+        run.run(1);
+        let dissolving = run.frame();
+        assert_ne!(dissolving, without_enemy(&mut run));
+
+        run.run((DISSOLVE_DURATION / headless::STEP) as usize + 1);
+        let enemy = &run.game.manager.get_current_room().enemies[0];
+        assert!(enemy.defeated_for >= DISSOLVE_DURATION);
+        let dissolved = run.frame();
+        assert_eq!(dissolved, without_enemy(&mut run));
+    }
+
+    #[test]
+    fn deeper_rooms_are_colder() {
+        assert_eq!(depth_ambient(0), EngineColor::WHITE);
+        let shallow = depth_ambient(3);
+        let deep = depth_ambient(30);
+        assert_eq!(deep, DEEP_AMBIENT);
+        assert!(shallow.r < 1.0 && shallow.r > deep.r);
+        assert_eq!(shallow.b, 1.0);
+    }
+
+    #[test]
+    fn blue_ambient_shifts_the_scene_towards_blue() {
+        let Some(mut run) = HeadlessGame::new() else {
+            return;
+        };
+        let blueness = |frame: &image::RgbaImage| {
+            let (mut blue, mut total) = (0.0, 0.0);
+            for pixel in frame.pixels() {
+                blue += pixel[2] as f32;
+                total += (pixel[0] as u32 + pixel[1] as u32 + pixel[2] as u32) as f32;
+            }
+            blue / total
+        };
+
+        run.render();
+        let neutral = blueness(&run.frame());
+
+        run.game.manager.get_current_room_mut().ambient = EngineColor {
+            r: 0.5,
+            g: 0.5,
+            b: 1.0,
+            a: 1.0,
+        };
+        run.render();
+        let tinted = blueness(&run.frame());
+        assert!(tinted > neutral + 0.05, "{} vs {}", tinted, neutral);
+    }
+
+    #[test]
+    fn enemies_on_the_same_spot_are_pushed_apart() {
+        let mut first = MovementController::new(Vec2::new(4.0, 4.0), ENEMY_MOVEMENT_SPEED);
+        let mut second = MovementController::new(Vec2::new(4.0, 4.0), ENEMY_MOVEMENT_SPEED);
+        let mut third = MovementController::new(Vec2::new(4.1, 4.3), ENEMY_MOVEMENT_SPEED);
+        separate_bodies(&mut [&mut first, &mut second, &mut third], |_| false);
+
+        let controllers = [&first, &second, &third];
+        for (index, a) in controllers.iter().enumerate() {
+            for b in &controllers[index + 1..] {
+                let delta = (a.feet_position() - b.feet_position()).abs();
+                assert!(
+                    delta.x >= BODY_SIZE - 1e-4 || delta.y >= BODY_SIZE - 1e-4,
+                    "{:?} overlaps {:?}",
+                    a.position,
+                    b.position
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn characters_cannot_walk_through_bodies() {
+        let no_walls = |_: &Transform| None;
+        let right = MovementIntention {
+            up: false,
+            down: false,
+            left: false,
+            right: true,
+        };
+        let mut walker = MovementController::new(Vec2::new(4.0, 4.0), 2.0);
+        let in_the_way = [body_at(walker.feet_position() + Vec2::new(0.75, 0.0))];
+        for _ in 0..60 {
+            walker.update(&right, 1.0 / 60.0, no_walls, &in_the_way);
+        }
+        assert!(walker.position.x > 4.0);
+        assert!(walker.position.x <= 4.25 + 1e-4);
+
+        // Starting out overlapping doesn't keep them stuck together
+        let mut stuck = MovementController::new(Vec2::new(4.0, 4.0), 2.0);
+        let on_top = [body_at(stuck.feet_position())];
+        stuck.update(&right, 0.1, no_walls, &on_top);
+        assert!(stuck.position.x > 4.0);
+    }
+
+    #[test]
+    fn coincident_enemies_steer_apart() {
+        let start = Vec2::new(4.0, 4.0);
+        let mut feet = [start, start];
+        for _ in 0..30 {
+            let steering: Vec<Vec2> = (0..feet.len())
+                .map(|me| {
+                    let neighbors = (0..feet.len())
+                        .filter(|&other| other != me)
+                        .map(|other| (other, feet[other]));
+                    separation(me, feet[me], neighbors)
+                })
+                .collect();
+            for (feet, steering) in feet.iter_mut().zip(steering) {
+                *feet += steering * SEPARATION_SPEED / 60.0;
+            }
+        }
+        assert!(feet[0].distance(feet[1]) > 0.5);
+        assert_eq!(feet[0].y, start.y);
+        assert!(feet[0].x < start.x && feet[1].x > start.x);
+
+        // Far apart enough, they're left alone
+        let apart = separation(0, start, [(1, start + Vec2::new(SEPARATION_RADIUS, 0.0))]);
+        assert_eq!(apart, Vec2::ZERO);
+    }
+
+    #[test]
+    fn corrupt_spawn_level_fails_init_with_why() {
+        let Some(mut renderer) = headless::renderer(320, 240) else {
+            return;
+        };
+        let mut audio = AudioSystem::silent();
+        let mut input_config = InputSystemConfig::new();
+        let spawn = GameLevelLoadData {
+            collision_csv: "0,1,0\n0,wall,0",
+            ..assets::embedded_level("spawn").unwrap()
+        };
+        let Err(err) = Game::init_with_levels(
+            &mut renderer,
+            &mut audio,
+            &mut input_config,
+            AssetManager::new(),
+            Ok(spawn),
+            assets::embedded_level("base_0"),
+        ) else {
+            panic!("A corrupt level shouldn't load");
+        };
+        assert_eq!(err.failures.len(), 1);
+        let message = err.to_string();
+        assert!(message.contains("level spawn"), "{}", message);
+        assert!(message.contains("invalid digit"), "{}", message);
+    }
+
+    #[test]
+    fn corrupt_first_room_fails_init_before_it_loads() {
+        let Some(mut renderer) = headless::renderer(320, 240) else {
+            return;
+        };
+        let mut audio = AudioSystem::silent();
+        let mut input_config = InputSystemConfig::new();
+        let base_0 = GameLevelLoadData {
+            enemies_csv: "0,0\n0,x",
+            ..assets::embedded_level("base_0").unwrap()
+        };
+        let Err(err) = Game::init_with_levels(
+            &mut renderer,
+            &mut audio,
+            &mut input_config,
+            AssetManager::new(),
+            assets::embedded_level("spawn"),
+            Ok(base_0),
+        ) else {
+            panic!("A corrupt first room shouldn't wait to fail until it's decoded");
+        };
+        assert_eq!(err.failures.len(), 1);
+        let message = err.to_string();
+        assert!(message.contains("level base_0"), "{}", message);
+        assert!(message.contains("invalid digit"), "{}", message);
+    }
+
+    #[test]
+    fn editing_a_collision_csv_reloads_the_level() {
+        let Some(mut renderer) = headless::renderer(320, 240) else {
+            return;
+        };
+        let spawn = assets::embedded_level("spawn").unwrap();
+        let root = std::env::temp_dir().join(format!("level_reload_{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let write = |suffix: &str, bytes: &[u8]| {
+            std::fs::write(root.join(format!("spawn{}", suffix)), bytes).unwrap();
+        };
+        write("_floor.csv", spawn.floor_csv.as_bytes());
+        write("_with_walls.png", spawn.decoration_bytes);
+        write("_collision.csv", spawn.collision_csv.as_bytes());
+        write("_enemies.csv", spawn.enemies_csv.as_bytes());
+
+        let tileset = level_tileset(&mut renderer);
+        let spawn_spec = assets::embedded_level("spawn").unwrap();
+        let spec = GameLevelSpec::load(spawn_spec, &tileset, &mut renderer).unwrap();
+        let mut manager = RoomManager::new(spec, test_sheet(), StdRng::seed_from_u64(0));
+        let mut reloader = LevelHotReloader::watching(root.to_str().unwrap(), &["spawn"]);
+        // The first fetch is the baseline, nothing changed yet
+        manager.reload_changed(&mut reloader, &tileset, &mut renderer, POLL_INTERVAL);
+        let before = manager.get_current_room().spec.clone();
+        let layer = before.decoration.get_sprite([0, 0]).unwrap().texture.layer;
+        let colliders = before.collision.len();
+        drop(before);
+
+        // A wall in the first open cell
+        let edited = spawn.collision_csv.replacen("\n1,0,", "\n1,1,", 1);
+        write("_collision.csv", edited.as_bytes());
+        manager.reload_changed(&mut reloader, &tileset, &mut renderer, POLL_INTERVAL);
+        std::fs::remove_dir_all(&root).unwrap();
+
+        let after = &manager.get_current_room().spec;
+        assert_eq!(after.collision.len(), colliders + 1);
+        // The replaced level gave its texture layer back
+        let reused = renderer
+            .gizmo_texture_from_image(&RgbaImage::new(1, 1))
+            .unwrap();
+        assert_eq!(reused.layer, layer);
+    }
+
+    /// The level tileset, as `Game::init` loads it
+    fn level_tileset(renderer: &mut RenderingSystem) -> GizmoSpriteSheet {
+        let bytes = assets::embedded_bytes("level/tileset").unwrap();
+        let image = image::load_from_memory(bytes).unwrap().to_rgba8();
+        let texture = renderer.gizmo_texture_from_image(&image).unwrap();
+        renderer.gizmo_sprite_sheet_from_texture(texture, [0.0, 0.0], [1.0, 1.0], [16, 16])
+    }
+
+    #[test]
+    fn level_floors_are_tiles_of_the_tileset() {
+        let Some(mut renderer) = headless::renderer(320, 240) else {
+            return;
+        };
+        let tileset = level_tileset(&mut renderer);
+        let spawn = assets::embedded_level("spawn").unwrap();
+        let spec = GameLevelSpec::load(spawn, &tileset, &mut renderer).unwrap();
+        let floor = spec.floor.as_ref().unwrap();
+        assert_eq!(floor.grid().dimensions, spec.grid.dimensions);
+        let (width, height) = spec.grid.dimensions;
+        for cell in (0..height).flat_map(|y| (0..width).map(move |x| (x, y))) {
+            // The floor variants, down the first column
+            let tile = floor.tile(cell);
+            assert!(matches!(tile, Some([0, 4 | 5])), "{:?} at {:?}", tile, cell);
+        }
+    }
+
+    #[test]
+    fn generated_rooms_match_the_ones_built_ahead() {
+        let Some(mut renderer) = headless::renderer(320, 240) else {
+            return;
+        };
+        let tileset = level_tileset(&mut renderer);
+        let layout = image::load_from_memory(include_bytes!("assets/level_specs/spawn_layout.png"))
+            .unwrap()
+            .to_rgb8();
+        let environment =
+            image::load_from_memory(include_bytes!("assets/level_specs/environment.png"))
+                .unwrap()
+                .to_rgba8();
+        let room = game_build_tools::level::generate_room(
+            &layout,
+            &environment,
+            &game_build_tools::level::RoomConfig::default(),
+        )
+        .unwrap();
+
+        let generated =
+            GameLevelSpec::from_generated("spawn", &room, &tileset, &mut renderer).unwrap();
+        let built = GameLevelSpec::load(
+            assets::embedded_level("spawn").unwrap(),
+            &tileset,
+            &mut renderer,
+        )
+        .unwrap();
+        assert_eq!(generated.grid.dimensions, built.grid.dimensions);
+        assert_eq!(generated.collision.len(), built.collision.len());
+        assert_eq!(generated.doors, built.doors);
+        assert_eq!(generated.enemy_waves, built.enemy_waves);
+    }
+
+    #[test]
+    fn wander_directions_follow_their_weights() {
+        let config = WanderConfig {
+            rate_per_second: 2.0,
+            idle_weight: 4.0,
+        };
+        let mut rng = StdRng::seed_from_u64(7);
+        let (mut decisions, mut idle) = (0, 0);
+        let mut per_direction = [0; 4];
+        for _ in 0..40_000 {
+            match wander_decision(&config, 0.25, &mut rng) {
+                None => continue,
+                Some(EnemyAIState::Idle) => idle += 1,
+                Some(EnemyAIState::Wandering(orientation)) => {
+                    let index = WANDER_DIRECTIONS.iter().position(|&o| o == orientation);
+                    per_direction[index.unwrap()] += 1;
+                }
+                Some(state) => panic!("Wandering can't lead to {:?}", state),
+            }
+            decisions += 1;
+        }
+        // Half of the samples decide something, at 2 per second over 0.25s
+        assert!((decisions as f32 / 40_000.0 - 0.5).abs() < 0.02);
+        // Idle weighs 4 out of 8, and each direction 1 out of 8
+        assert!((idle as f32 / decisions as f32 - 0.5).abs() < 0.02);
+        for count in per_direction {
+            assert!((count as f32 / decisions as f32 - 0.125).abs() < 0.02);
+        }
+
+        // Never deciding when the rate is zero
+        let still = WanderConfig {
+            rate_per_second: 0.0,
+            ..config
+        };
+        assert!((0..100).all(|_| wander_decision(&still, 1.0, &mut rng).is_none()));
+    }
+
+    #[test]
+    fn line_of_sight_looks_past_grazing_walls() {
+        let mut level = test_level(Vec::new());
+        let wall_at = |x: f32, y: f32| {
+            (
+                Transform::new().translate(Vec3::new(x, y, 0.0)),
+                CollisionKind::Wall,
+            )
+        };
+        level.collision.push(wall_at(2.0, 0.0));
+        let (start, end) = (Vec2::new(0.5, 1.0), Vec2::new(4.5, 1.0));
+
+        // The middle of the view runs along the wall's edge, which a single
+        // thin line can't see past
+        assert!(GameLevelSpec::line_collides_with_level(
+            start,
+            end,
+            &level,
+            &Transform::new(),
+            CollisionKind::Wall
+        ));
+        assert!(level.has_line_of_sight(start, end, BODY_SIZE));
+
+        // Walled off all the way across, nothing gets through
+        level.collision.push(wall_at(2.0, 1.0));
+        assert!(!level.has_line_of_sight(start, end, BODY_SIZE));
+    }
+
+    #[test]
+    fn simulated_player_kills_an_enemy_in_bounded_steps() {
+        let Some(mut run) = HeadlessGame::new() else {
+            return;
+        };
+        let below = run.game.player.character.controller.position + Vec2::new(0.0, 0.75);
+        let enemy = Enemy::new(below, run.game.manager.enemy_sprite_sheet.clone()).dormant();
+        let room = run.game.manager.get_current_room_mut();
+        room.enemies.clear();
+        room.enemies.push(enemy);
+        let max_health = run.game.snapshot().enemies[0].max_health;
+
+        // Quick taps of the attack key, as in a real fight
+        let mut step = 0;
+        let tap_attack = |_: &GameSnapshot| {
+            step += 1;
+            if step % 20 < 10 {
+                vec![KeyCode::KeyL]
+            } else {
+                Vec::new()
+            }
+        };
+        let enemy_dead = |snapshot: &GameSnapshot| snapshot.enemies[0].health <= 0.0;
+        // Five hits of the shortest windup take well under 300 steps
+        let result = run.simulate_until(enemy_dead, 300, tap_attack);
+        assert!(result.reached, "{:?}", result);
+        assert!(result.damage_dealt >= max_health - 1e-3);
+        // A dormant enemy never hits back
+        assert_eq!(result.damage_taken, 0.0);
+    }
+
+    #[test]
+    fn getting_hurt_flashes_a_red_vignette() {
+        let Some(mut run) = HeadlessGame::new() else {
+            return;
+        };
+        let corner_red = |run: &mut HeadlessGame| {
+            run.render();
+            let [r, g, _, _] = run.frame().get_pixel(0, 0).0;
+            r as i32 - g as i32
+        };
+        run.run(1);
+        let calm = corner_red(&mut run);
+
+        run.game.player.character.take_damage(10.0, 0.1);
+        run.run(1);
+        assert!(corner_red(&mut run) > calm + 50);
+
+        // Long gone after a second
+        run.run(60);
+        assert_eq!(corner_red(&mut run), calm);
+    }
+
+    #[test]
+    fn defeat_grades_the_screen_red_and_gray() {
+        let Some(mut run) = HeadlessGame::new() else {
+            return;
+        };
+        let graded = |frame: &image::RgbaImage| {
+            frame.pixels().all(|pixel| {
+                let [r, g, b, _] = pixel.0.map(i32::from);
+                (g - b).abs() <= 2 && r + 2 >= g
+            })
+        };
+        run.run(1);
+        assert!(!graded(&run.frame()));
+
+        run.game.player.character.take_damage(1000.0, 0.1);
+        // Past the damage flash
+        run.run(60);
+        let frame = run.frame();
+        assert!(graded(&frame));
+        // Bent off the screen in the corners
+        assert_eq!(frame.get_pixel(0, 0).0, [0, 0, 0, 255]);
+    }
+
+    #[test]
+    fn doors_change_rooms_once_the_transition_covers_the_screen() {
+        let Some(mut run) = HeadlessGame::new() else {
+            return;
+        };
+        let room = run.game.manager.get_current_room_mut();
+        room.enemies.clear();
+        room.next_wave = room.spec.enemy_waves.len();
+        let door = room.spec.doors.first().expect("The spawn room has doors").1;
+        run.game.player.character.controller.set_feet_position(door);
+        let start = run.game.manager.current_room;
+
+        run.run(1);
+        assert!(!run.game.transition.is_finished());
+        assert_eq!(run.game.manager.current_room, start);
+
+        // Halfway through, the new room is behind the cover
+        run.run(20);
+        assert_ne!(run.game.manager.current_room, start);
+        assert!(!run.game.transition.is_finished());
+        // Coming out on the other side's door doesn't lead straight back
+        run.run(60);
+        assert!(run.game.transition.is_finished());
+        assert_ne!(run.game.manager.current_room, start);
     }
 }
@@ -1,6 +1,6 @@
-use glam::Vec3;
-use wgpu::{Buffer, Queue};
+use glam::{Vec2, Vec3};
 
+#[derive(Clone)]
 pub struct Transform {
     matrix: glam::Mat4,
     raw: [[f32; 4]; 4],
@@ -52,26 +52,24 @@ impl Transform {
         }
     }
 
-    pub fn shear(&self, shear_x: f32, shear_y: f32) -> Self {
-        let shear_matrix = glam::Mat4::from_cols(
-            glam::Vec4::new(1.0, shear_y, 0.0, 0.0),
-            glam::Vec4::new(shear_x, 1.0, 0.0, 0.0),
-            glam::Vec4::new(0.0, 0.0, 1.0, 0.0),
-            glam::Vec4::new(0.0, 0.0, 0.0, 1.0),
-        );
-        let mat = self.matrix * shear_matrix;
-        Self {
-            matrix: mat,
-            raw: mat.to_cols_array_2d(),
-        }
+    /// Applies `f` with the origin temporarily moved to `pivot`, so rotations
+    /// and scales done inside `f` happen about that point.
+    pub fn around_pivot(&self, pivot: Vec2, f: impl FnOnce(Transform) -> Transform) -> Self {
+        let pivot = pivot.extend(0.0);
+        f(self.translate(pivot)).translate(-pivot)
     }
 
-    pub fn as_bytes(&self) -> &[u8] {
-        bytemuck::cast_slice(&self.raw)
+    /// The transform that undoes this one
+    pub fn inverse(&self) -> Self {
+        Self::from_matrix(self.matrix.inverse())
+    }
+
+    pub fn matrix(&self) -> glam::Mat4 {
+        self.matrix
     }
 
-    pub fn write_buffer(&self, buffer: &Buffer, queue: &Queue) {
-        queue.write_buffer(buffer, 0, self.as_bytes());
+    pub fn as_bytes(&self) -> &[u8] {
+        bytemuck::cast_slice(&self.raw)
     }
 
     pub fn ortographic_size_invariant() -> Self {
@@ -104,6 +102,10 @@ impl Transform {
         }
     }
 
+    /// Composes `self` with `other`, with `other` applied to points first.
+    ///
+    /// `a.then(b).project(p)` is `a.project(b.project(p))`: `other` is a child
+    /// space expressed in the coordinates of `self`, as in `origin.then(collider)`.
     pub fn then(&self, other: &Self) -> Self {
         let mat = self.matrix * other.matrix;
         Self {
@@ -111,4 +113,80 @@ impl Transform {
             raw: mat.to_cols_array_2d(),
         }
     }
+
+    /// Chains `transforms` with [`Transform::then`], outermost space first, so
+    /// the last transform is applied to points first.
+    #[cfg(test)]
+    pub fn compose_all(transforms: &[Transform]) -> Self {
+        transforms
+            .iter()
+            .fold(Self::new(), |composed, transform| composed.then(transform))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn then_applies_other_first() {
+        let translate = Transform::new().translate(Vec3::new(2.0, 3.0, 0.0));
+        let scale = Transform::new().scale(Vec3::new(4.0, 5.0, 1.0));
+        let point = Vec3::new(1.0, 1.0, 0.0);
+
+        // Scale first, then translate: (1 * 4 + 2, 1 * 5 + 3)
+        let projected = translate.then(&scale).project(point);
+        assert!(projected.abs_diff_eq(Vec3::new(6.0, 8.0, 0.0), 1e-5));
+
+        // Translate first, then scale: ((1 + 2) * 4, (1 + 3) * 5)
+        let projected = scale.then(&translate).project(point);
+        assert!(projected.abs_diff_eq(Vec3::new(12.0, 20.0, 0.0), 1e-5));
+    }
+
+    #[test]
+    fn compose_all_matches_chained_then() {
+        let transforms = [
+            Transform::new().translate(Vec3::new(2.0, 3.0, 0.0)),
+            Transform::new().scale(Vec3::new(4.0, 5.0, 1.0)),
+            Transform::new().rotate_2d(std::f32::consts::FRAC_PI_2),
+        ];
+        let point = Vec3::new(1.0, 0.0, 0.0);
+
+        let composed = Transform::compose_all(&transforms).project(point);
+        let chained = transforms[0]
+            .then(&transforms[1])
+            .then(&transforms[2])
+            .project(point);
+        assert!(composed.abs_diff_eq(chained, 1e-5));
+        // Rotating (1, 0) gives (0, 1), scaled to (0, 5), translated to (2, 8)
+        assert!(composed.abs_diff_eq(Vec3::new(2.0, 8.0, 0.0), 1e-5));
+    }
+
+    #[test]
+    fn around_pivot_rotates_the_unit_square_onto_itself() {
+        let center = Vec2::new(0.5, 0.5);
+        let rotated = Transform::new().around_pivot(center, |transform| {
+            transform.rotate_2d(std::f32::consts::FRAC_PI_2)
+        });
+        let corners = [
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(1.0, 1.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+        ];
+
+        // Each corner lands on the next one, counterclockwise
+        for (index, &corner) in corners.iter().enumerate() {
+            let next = corners[(index + 1) % corners.len()];
+            assert!(rotated.project(corner).abs_diff_eq(next, 1e-5));
+        }
+        let pivot = center.extend(0.0);
+        assert!(rotated.project(pivot).abs_diff_eq(pivot, 1e-5));
+    }
+
+    #[test]
+    fn compose_all_of_nothing_is_identity() {
+        let point = Vec3::new(1.5, -2.0, 0.0);
+        assert_eq!(Transform::compose_all(&[]).project(point), point);
+    }
 }
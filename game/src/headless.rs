@@ -0,0 +1,124 @@
+//! Runs the whole game without a window: a `Game` on an offscreen renderer,
+//! with silent audio and scripted input, stepped at a fixed rate. Scenarios
+//! built on it check that update and render still hold together end to end.
+
+use image::RgbaImage;
+use winit::keyboard::KeyCode;
+
+use crate::{
+    audio::AudioSystem,
+    frame_pacing::FIXED_STEP,
+    game::{Game, GameSnapshot, SimResult},
+    renderer::RenderingSystem,
+    InputSystem, InputSystemConfig,
+};
+
+/// Seconds each step advances the game by, the same as the real loop's
+pub const STEP: f32 = FIXED_STEP;
+
+/// Set to skip the tests that need an adapter instead of failing them
+pub const SKIP_GPU_TESTS: &str = "SKIP_GPU_TESTS";
+
+/// An offscreen renderer for a test, `None` only when there's no adapter and
+/// `SKIP_GPU_TESTS` is set. Without the variable a missing adapter fails the test
+pub fn renderer(width: u32, height: u32) -> Option<RenderingSystem> {
+    multisampled_renderer(width, height, 1)
+}
+
+/// Like `renderer`, with `sample_count` samples per pixel
+pub fn multisampled_renderer(
+    width: u32,
+    height: u32,
+    sample_count: u32,
+) -> Option<RenderingSystem> {
+    let renderer = pollster::block_on(RenderingSystem::new_headless_multisampled(
+        width,
+        height,
+        sample_count,
+    ));
+    if renderer.is_none() {
+        assert!(
+            std::env::var_os(SKIP_GPU_TESTS).is_some(),
+            "No adapter to render with, set {SKIP_GPU_TESTS} to skip the tests that need one"
+        );
+        eprintln!("No adapter to render with, skipping");
+    }
+    renderer
+}
+
+pub struct HeadlessGame {
+    pub game: Game,
+    renderer: RenderingSystem,
+    audio: AudioSystem,
+    input: InputSystem,
+}
+
+impl HeadlessGame {
+    /// `None` where there's no adapter and `SKIP_GPU_TESTS` is set
+    pub fn new() -> Option<Self> {
+        let (width, height) = Game::target_size();
+        let mut renderer = renderer(width, height)?;
+        let mut audio = AudioSystem::silent();
+        let mut input_config = InputSystemConfig::new();
+        let game =
+            Game::init(&mut renderer, &mut audio, &mut input_config).expect("Embedded assets load");
+        Some(Self {
+            game,
+            renderer,
+            audio,
+            input: InputSystem::new(input_config),
+        })
+    }
+
+    /// Updates and renders `steps` frames with the keys as they are
+    pub fn run(&mut self, steps: usize) {
+        for _ in 0..steps {
+            self.game
+                .update(&mut self.input, &mut self.audio, &mut self.renderer, STEP);
+            self.input.end_frame(STEP);
+            self.render();
+        }
+    }
+
+    /// Renders a frame without advancing the game
+    pub fn render(&mut self) {
+        self.renderer
+            .render(&self.game)
+            .expect("Offscreen frames can't be lost");
+    }
+
+    /// Runs `steps` frames with `keys` held down, releasing them after
+    pub fn hold(&mut self, keys: &[KeyCode], steps: usize) {
+        for &key in keys {
+            self.input.press_key(key);
+        }
+        self.run(steps);
+        for &key in keys {
+            self.input.release_key(key);
+        }
+    }
+
+    /// `Game::simulate_until` on this game's systems, without rendering
+    pub fn simulate_until(
+        &mut self,
+        condition: impl FnMut(&GameSnapshot) -> bool,
+        max_steps: usize,
+        input_policy: impl FnMut(&GameSnapshot) -> Vec<KeyCode>,
+    ) -> SimResult {
+        self.game.simulate_until(
+            &mut self.input,
+            &mut self.audio,
+            &mut self.renderer,
+            condition,
+            max_steps,
+            input_policy,
+        )
+    }
+
+    /// The last rendered frame
+    pub fn frame(&self) -> RgbaImage {
+        self.renderer
+            .read_frame()
+            .expect("Headless renderers render offscreen")
+    }
+}
@@ -0,0 +1,178 @@
+//! Debug-only reloading of the generated level assets in the browser build.
+//! The browser cannot watch the filesystem, so the files copied next to the
+//! game by trunk are polled instead, and levels rebuilt by `build.rs` replace
+//! the embedded ones without reloading the page. Outside the browser, as in
+//! tests, the files are read straight off the disk.
+
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+};
+
+#[cfg(target_arch = "wasm32")]
+use wasm_bindgen::{JsCast, JsValue};
+#[cfg(target_arch = "wasm32")]
+use wasm_bindgen_futures::JsFuture;
+#[cfg(target_arch = "wasm32")]
+use web_sys::{
+    js_sys::{Date, Uint8Array},
+    Response,
+};
+
+use crate::game::GameLevelLoadData;
+
+pub const POLL_INTERVAL: f32 = 1.0;
+#[cfg(target_arch = "wasm32")]
+const ASSET_ROOT: &str = "level_generated";
+
+#[derive(PartialEq)]
+pub struct FetchedLevel {
+    pub name: &'static str,
+    floor: String,
+    decoration: Vec<u8>,
+    collision: String,
+    enemies: String,
+}
+
+impl FetchedLevel {
+    pub fn as_load_data(&self) -> GameLevelLoadData<'_> {
+        GameLevelLoadData {
+            name: self.name,
+            floor_csv: &self.floor,
+            decoration_bytes: &self.decoration,
+            collision_csv: &self.collision,
+            enemies_csv: &self.enemies,
+        }
+    }
+}
+
+struct WatchedLevel {
+    name: &'static str,
+    latest: Rc<RefCell<Option<FetchedLevel>>>,
+    in_flight: Rc<Cell<bool>>,
+    current: Option<FetchedLevel>,
+}
+
+pub struct LevelHotReloader {
+    // Where the levels are fetched from, next to the page
+    root: Rc<str>,
+    levels: Vec<WatchedLevel>,
+    elapsed: f32,
+}
+
+impl LevelHotReloader {
+    #[cfg(target_arch = "wasm32")]
+    pub fn new(names: &[&'static str]) -> Self {
+        Self::watching(ASSET_ROOT, names)
+    }
+
+    /// Polls the levels in `root` rather than where trunk puts them
+    pub fn watching(root: &str, names: &[&'static str]) -> Self {
+        Self {
+            root: root.into(),
+            levels: names
+                .iter()
+                .map(|&name| WatchedLevel {
+                    name,
+                    latest: Rc::new(RefCell::new(None)),
+                    in_flight: Rc::new(Cell::new(false)),
+                    current: None,
+                })
+                .collect(),
+            elapsed: POLL_INTERVAL,
+        }
+    }
+
+    /// Polls the served assets and returns the levels that changed since they
+    /// were last fetched. The first fetch of each level is only a baseline.
+    pub fn update(&mut self, delta_time: f32) -> Vec<&FetchedLevel> {
+        self.elapsed += delta_time;
+        let poll = self.elapsed >= POLL_INTERVAL;
+        if poll {
+            self.elapsed = 0.0;
+        }
+
+        let mut changed = Vec::new();
+        for (index, level) in self.levels.iter_mut().enumerate() {
+            if poll && !level.in_flight.get() {
+                let (root, name) = (self.root.clone(), level.name);
+                let latest = level.latest.clone();
+                let in_flight = level.in_flight.clone();
+                in_flight.set(true);
+                let fetch = async move {
+                    match fetch_level(&root, name).await {
+                        Ok(fetched) => *latest.borrow_mut() = Some(fetched),
+                        Err(err) => log::warn!("Failed to fetch level {}: {}", name, err),
+                    }
+                    in_flight.set(false);
+                };
+                #[cfg(target_arch = "wasm32")]
+                wasm_bindgen_futures::spawn_local(fetch);
+                #[cfg(not(target_arch = "wasm32"))]
+                pollster::block_on(fetch);
+            }
+
+            // Fetches finish later in the browser, on the next updates
+            if let Some(fetched) = level.latest.borrow_mut().take() {
+                match &level.current {
+                    Some(current) if *current == fetched => {}
+                    Some(_) => {
+                        level.current = Some(fetched);
+                        changed.push(index);
+                    }
+                    None => level.current = Some(fetched),
+                }
+            }
+        }
+
+        changed
+            .into_iter()
+            .filter_map(|index| self.levels[index].current.as_ref())
+            .collect()
+    }
+}
+
+async fn fetch_level(root: &str, name: &'static str) -> Result<FetchedLevel, String> {
+    let fetch_file = |suffix| fetch_bytes(format!("{}/{}{}", root, name, suffix));
+    let fetch_text = |suffix| async move {
+        String::from_utf8(fetch_file(suffix).await?).map_err(|err| err.to_string())
+    };
+    Ok(FetchedLevel {
+        name,
+        floor: fetch_text("_floor.csv").await?,
+        decoration: fetch_file("_with_walls.png").await?,
+        collision: fetch_text("_collision.csv").await?,
+        enemies: fetch_text("_enemies.csv").await?,
+    })
+}
+
+#[cfg(target_arch = "wasm32")]
+async fn fetch_bytes(path: String) -> Result<Vec<u8>, String> {
+    fetch_served(&path)
+        .await
+        .map_err(|err| format!("{:?}", err))
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+async fn fetch_bytes(path: String) -> Result<Vec<u8>, String> {
+    std::fs::read(&path).map_err(|err| format!("{}: {}", path, err))
+}
+
+#[cfg(target_arch = "wasm32")]
+async fn fetch_served(path: &str) -> Result<Vec<u8>, JsValue> {
+    let window = web_sys::window().ok_or("No window available")?;
+    // The timestamp keeps the browser from answering with a cached copy
+    let url = format!("{}?t={}", path, Date::now());
+    let response: Response = JsFuture::from(window.fetch_with_str(&url))
+        .await?
+        .dyn_into()?;
+    if !response.ok() {
+        return Err(JsValue::from_str(&format!(
+            "{} returned status {}",
+            url,
+            response.status()
+        )));
+    }
+    let buffer = JsFuture::from(response.array_buffer()?).await?;
+    Ok(Uint8Array::new(&buffer).to_vec())
+}
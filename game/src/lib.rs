@@ -1,31 +1,51 @@
+mod actions;
+mod assets;
 mod audio;
+mod camera_controller;
 mod collision;
+mod frame_pacing;
 mod game;
 mod geometry;
+#[cfg(test)]
+mod headless;
+#[cfg(any(test, all(debug_assertions, target_arch = "wasm32")))]
+mod hot_reload;
 mod nimi;
 mod ortographic_camera;
 mod renderer;
-
+mod room_loading;
+mod spatial_hash;
+mod status_effects;
+mod tween;
 use core::panic;
+use frame_pacing::{FramePacing, TimeStep};
 use game::Game;
+use glam::Vec2;
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::rc::Rc;
 use std::sync::Arc;
-use std::sync::Mutex;
+use std::time::Duration;
 use wasm_bindgen::prelude::*;
+#[cfg(target_arch = "wasm32")]
 use web_sys::HtmlCanvasElement;
-use winit::event::{ElementState, KeyEvent, MouseButton};
+use winit::event::{ElementState, KeyEvent, MouseButton, MouseScrollDelta, StartCause};
 use winit::keyboard::{KeyCode, PhysicalKey};
+#[cfg(target_arch = "wasm32")]
+use winit::platform::web::WindowExtWebSys;
 use winit::{
     application::ApplicationHandler,
     event::WindowEvent,
-    event_loop::{ActiveEventLoop, EventLoop},
-    platform::web::WindowExtWebSys,
-    window::{Window as WinitWindow, WindowId},
+    event_loop::{ActiveEventLoop, ControlFlow, EventLoop},
+    monitor::MonitorHandle,
+    window::{Fullscreen, Window as WinitWindow, WindowId},
 };
 
+use crate::actions::{Action, ActionBindings, Binding, GamepadButton};
+use crate::assets::{AssetManager, AssetSource};
 use crate::audio::AudioSystem;
-use crate::renderer::RenderingSystem;
+use crate::renderer::{backend::RendererBackend, RenderingSystem};
 
 #[wasm_bindgen(start)]
 pub fn main() {
@@ -42,93 +62,429 @@ async fn run() {
     event_loop.run_app(&mut app).unwrap();
 }
 
+/// Puts `message` up in the page's error box in place of the game's canvas
+fn show_error(window: &WinitWindow, message: &str) {
+    #[cfg(target_arch = "wasm32")]
+    if let Some(canvas) = window.canvas() {
+        canvas.set_hidden(true);
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    let _ = window;
+    let Some(document) = web_sys::window().and_then(|window| window.document()) else {
+        return;
+    };
+    if let Some(error_div) = document.get_element_by_id("error") {
+        error_div.set_text_content(Some(message));
+        let _ = error_div.set_attribute("style", "display: block");
+    }
+}
+
+/// Puts the window's canvas in the page, in place of the loading status
+#[cfg(target_arch = "wasm32")]
+fn attach_canvas(window: &WinitWindow) {
+    let web_window = web_sys::window().unwrap();
+    let document = web_window.document().unwrap();
+    let canvas: HtmlCanvasElement = window.canvas().unwrap();
+
+    let container = document
+        .get_element_by_id("webengine-container")
+        .unwrap_or_else(|| {
+            let body = document.body().unwrap();
+            let container = document.create_element("div").unwrap();
+            container.set_id("webengine-container");
+            body.append_child(&container).unwrap();
+            container
+        });
+
+    container.append_child(&canvas).unwrap();
+
+    let status_div = document.get_element_by_id("status").unwrap();
+    status_div.set_text_content(Some(""));
+}
+
 enum AppState {
+    /// Filled in by a future on the same thread, see `WebApp::resumed`
     Loading {
-        game: Arc<Mutex<Option<Game>>>,
-        renderer: Arc<Mutex<Option<RenderingSystem>>>,
-        window: Arc<Mutex<Option<Arc<WinitWindow>>>>,
-        audio: Arc<Mutex<Option<AudioSystem>>>,
-        input_config: Arc<Mutex<Option<InputSystemConfig>>>,
+        renderer: Rc<RefCell<Option<RenderingSystem>>>,
+        window: Rc<RefCell<Option<Arc<WinitWindow>>>>,
+        audio: Rc<RefCell<Option<AudioSystem>>>,
+        assets: Rc<RefCell<Option<AssetManager>>>,
+    },
+    /// Drawing a loading screen while the assets stream in
+    LoadingAssets {
+        renderer: RenderingSystem,
+        window: Arc<WinitWindow>,
+        audio: AudioSystem,
+        assets: AssetManager,
     },
     Loaded {
-        game: Game,
+        game: Box<Game>,
         renderer: RenderingSystem,
         window: Arc<WinitWindow>,
         input: InputSystem,
         audio: AudioSystem,
     },
+    /// The game couldn't be initialized, there's nothing left to run
+    Failed,
 }
 
 struct KeyPressGroup {
-    keys: HashSet<KeyCode>,
-    stack: Vec<KeyCode>,
+    actions: HashSet<Action>,
+    stack: Vec<Action>,
 }
 
 pub struct KeyPressGroupHandle {
     index: usize,
 }
 
+/// Actions to press in order, each within `window` seconds of the first
+struct Combo {
+    actions: Vec<Action>,
+    window: f64,
+    progress: usize,
+    started_at: f64,
+    triggered: bool,
+}
+
+impl Combo {
+    fn on_action_pressed(&mut self, action: Action, now: f64) {
+        if self.progress > 0 && now - self.started_at > self.window {
+            self.progress = 0;
+        }
+        if self.actions[self.progress] != action {
+            // A wrong action may still start the combo over
+            self.progress = 0;
+            if self.actions[0] != action {
+                return;
+            }
+        }
+        if self.progress == 0 {
+            self.started_at = now;
+        }
+        self.progress += 1;
+        if self.progress == self.actions.len() {
+            self.triggered = true;
+            self.progress = 0;
+        }
+    }
+}
+
+pub struct ComboHandle {
+    index: usize,
+}
+
+/// Key cancelling `InputSystem::capture_binding`
+const CANCEL_CAPTURE_KEY: KeyCode = KeyCode::Escape;
+
+/// A binding captured for an action, see `InputSystem::capture_binding`
+#[derive(Debug, Clone, PartialEq)]
+struct Rebound {
+    action: Action,
+    binding: Binding,
+    /// Other actions the binding triggers too, for a settings screen to
+    /// point out
+    conflicts: Vec<Action>,
+}
+
 struct InputSystemConfig {
+    bindings: ActionBindings,
     key_press_groups: Vec<KeyPressGroup>,
+    combos: Vec<Combo>,
 }
 
 impl InputSystemConfig {
+    /// Starts from the default bindings, see `with_bindings`
     fn new() -> Self {
         Self {
+            bindings: ActionBindings::default(),
             key_press_groups: Vec::new(),
+            combos: Vec::new(),
         }
     }
 
-    fn allocate_group(&mut self, keys: &[KeyCode]) -> KeyPressGroupHandle {
+    fn with_bindings(mut self, bindings: ActionBindings) -> Self {
+        self.bindings = bindings;
+        self
+    }
+
+    /// Registers `actions` as a combo completed by pressing them in order
+    /// within `window` seconds, e.g. dash then attack for a lunge.
+    fn register_combo(&mut self, actions: &[Action], window: f32) -> ComboHandle {
+        assert!(!actions.is_empty(), "A combo needs at least one action");
+        let index = self.combos.len();
+        self.combos.push(Combo {
+            actions: actions.to_vec(),
+            window: window as f64,
+            progress: 0,
+            started_at: 0.0,
+            triggered: false,
+        });
+        ComboHandle { index }
+    }
+
+    fn allocate_group(&mut self, actions: &[Action]) -> KeyPressGroupHandle {
         let index = self.key_press_groups.len();
         self.key_press_groups.push(KeyPressGroup {
-            keys: keys.iter().cloned().collect(),
+            actions: actions.iter().cloned().collect(),
             stack: Vec::new(),
         });
         KeyPressGroupHandle { index }
     }
 }
 
+/// Pixels browsers scroll by for a notch of the mouse wheel, for wheels that
+/// report pixels instead of lines
+const PIXELS_PER_WHEEL_LINE: f64 = 100.0;
+
 struct InputSystem {
     mouse_position: (f64, f64),
+    // Where the cursor is in the internal resolution, if it's over the view
+    mouse_internal: Option<Vec2>,
     mouse_buttons: HashMap<MouseButton, ElementState>,
+    // Buttons pressed and lines scrolled since the last `end_frame`
+    mouse_presses: HashSet<MouseButton>,
+    mouse_wheel: Vec2,
     physical_key_states: HashMap<KeyCode, ElementState>,
+    gamepad_buttons: HashSet<GamepadButton>,
+    bindings: ActionBindings,
+    // The action the next key or button pressed is bound to, and the last
+    // one bound that way
+    capturing: Option<Action>,
+    rebound: Option<Rebound>,
     key_press_groups: Vec<KeyPressGroup>,
+    combos: Vec<Combo>,
+    // Seconds of frames ended so far, which key presses are timed with
+    clock: f64,
 }
 
 impl InputSystem {
     pub fn new(config: InputSystemConfig) -> Self {
         Self {
             mouse_position: (0.0, 0.0),
+            mouse_internal: None,
             mouse_buttons: HashMap::new(),
+            mouse_presses: HashSet::new(),
+            mouse_wheel: Vec2::ZERO,
             physical_key_states: HashMap::new(),
+            gamepad_buttons: HashSet::new(),
+            bindings: config.bindings,
+            capturing: None,
+            rebound: None,
             key_press_groups: config.key_press_groups,
+            combos: config.combos,
+            clock: 0.0,
         }
     }
-    fn is_mouse_down(&self, button: MouseButton) -> bool {
-        matches!(self.mouse_buttons.get(&button), Some(ElementState::Pressed))
+
+    fn press_key(&mut self, code: KeyCode) {
+        let repeated = self.is_physical_key_down(code);
+        self.physical_key_states.insert(code, ElementState::Pressed);
+        if !repeated && self.capture(Binding::Key(code)) {
+            return;
+        }
+        self.press(Binding::Key(code), repeated);
+    }
+
+    fn release_key(&mut self, code: KeyCode) {
+        self.physical_key_states
+            .insert(code, ElementState::Released);
+        self.release(Binding::Key(code));
     }
-    fn is_mouse_up(&self, button: MouseButton) -> bool {
-        match self.mouse_buttons.get(&button) {
-            Some(ElementState::Pressed) => false,
-            Some(ElementState::Released) => true,
-            None => false,
+
+    /// Presses and releases gamepad buttons to match the ones `held` now,
+    /// see `actions::held_gamepad_buttons`
+    fn update_gamepad(&mut self, held: HashSet<GamepadButton>) {
+        let pressed: Vec<_> = held.difference(&self.gamepad_buttons).copied().collect();
+        let released: Vec<_> = self.gamepad_buttons.difference(&held).copied().collect();
+        self.gamepad_buttons = held;
+        for button in pressed {
+            if !self.capture(Binding::Gamepad(button)) {
+                self.press(Binding::Gamepad(button), false);
+            }
+        }
+        for button in released {
+            self.release(Binding::Gamepad(button));
         }
     }
+
+    fn press(&mut self, binding: Binding, repeated: bool) {
+        let actions: Vec<Action> = self.bindings.actions_of(binding).collect();
+        for action in actions {
+            // Check if this action is part of any key press group
+            for group in &mut self.key_press_groups {
+                if group.actions.contains(&action) {
+                    group.stack.push(action);
+                }
+            }
+            if !repeated {
+                for combo in &mut self.combos {
+                    combo.on_action_pressed(action, self.clock);
+                }
+            }
+        }
+    }
+
+    fn release(&mut self, binding: Binding) {
+        let actions: Vec<Action> = self.bindings.actions_of(binding).collect();
+        for action in actions {
+            // Still held through another binding
+            if self.is_action_down(action) {
+                continue;
+            }
+            for group in &mut self.key_press_groups {
+                group.stack.retain(|&x| x != action);
+            }
+        }
+    }
+
+    fn is_action_down(&self, action: Action) -> bool {
+        self.bindings
+            .bindings_of(action)
+            .any(|binding| match binding {
+                Binding::Key(code) => self.is_physical_key_down(code),
+                Binding::Gamepad(button) => self.gamepad_buttons.contains(&button),
+            })
+    }
+
+    #[cfg(test)]
+    fn bindings(&self) -> &ActionBindings {
+        &self.bindings
+    }
+
+    /// Binds the next key or gamepad button pressed to `action` instead of
+    /// pressing it, in place of the action's bindings on the same device, and
+    /// saves the bindings. `CANCEL_CAPTURE_KEY` cancels, and keys that can't
+    /// be bound are passed over. See `poll_rebound`.
+    #[cfg(test)]
+    fn capture_binding(&mut self, action: Action) {
+        self.capturing = Some(action);
+    }
+
+    #[cfg(test)]
+    fn is_capturing(&self) -> bool {
+        self.capturing.is_some()
+    }
+
+    /// The binding captured since the last poll, if any
+    #[cfg(test)]
+    fn poll_rebound(&mut self) -> Option<Rebound> {
+        self.rebound.take()
+    }
+
+    /// Binds `binding` if capturing, returns whether it was taken for that
+    fn capture(&mut self, binding: Binding) -> bool {
+        let Some(action) = self.capturing else {
+            return false;
+        };
+        if binding == Binding::Key(CANCEL_CAPTURE_KEY) {
+            self.capturing = None;
+            return true;
+        }
+        if binding.name().is_none() {
+            return true;
+        }
+        let mut bindings = self.bindings.clone();
+        bindings.rebind(action, binding);
+        actions::save_bindings(&bindings);
+        let conflicts = self
+            .set_bindings(bindings)
+            .into_iter()
+            .find(|(conflicting, _)| *conflicting == binding)
+            .map(|(_, actions)| actions)
+            .unwrap_or_default();
+        self.capturing = None;
+        self.rebound = Some(Rebound {
+            action,
+            binding,
+            conflicts: conflicts
+                .into_iter()
+                .filter(|other| *other != action)
+                .collect(),
+        });
+        true
+    }
+
+    /// Rebinds every action. Actions held through their old bindings are let
+    /// go of. Returns the bindings that now trigger more than one action,
+    /// see `ActionBindings::conflicts`.
+    fn set_bindings(&mut self, bindings: ActionBindings) -> Vec<(Binding, Vec<Action>)> {
+        self.bindings = bindings;
+        for group in &mut self.key_press_groups {
+            group.stack.clear();
+        }
+        self.bindings.conflicts()
+    }
+
+    /// Whether the combo was completed since the last `end_frame`
+    fn was_combo_triggered(&self, handle: &ComboHandle) -> bool {
+        self.combos
+            .get(handle.index)
+            .is_some_and(|combo| combo.triggered)
+    }
+
+    /// Advances the input clock past a frame that took `delta_time` seconds
+    fn end_frame(&mut self, delta_time: f32) {
+        self.clock += delta_time as f64;
+        for combo in &mut self.combos {
+            combo.triggered = false;
+        }
+        self.mouse_presses.clear();
+        self.mouse_wheel = Vec2::ZERO;
+    }
+
+    /// Moves the cursor to `window` physical pixels, `internal` in the
+    /// internal resolution as `RenderingSystem::window_to_internal` maps it
+    fn move_mouse(&mut self, window: (f64, f64), internal: Option<Vec2>) {
+        self.mouse_position = window;
+        self.mouse_internal = internal;
+    }
+
+    fn set_mouse_button(&mut self, button: MouseButton, state: ElementState) {
+        if state == ElementState::Pressed && !self.is_mouse_down(button) {
+            self.mouse_presses.insert(button);
+        }
+        self.mouse_buttons.insert(button, state);
+    }
+
+    fn scroll(&mut self, delta: MouseScrollDelta) {
+        self.mouse_wheel += match delta {
+            MouseScrollDelta::LineDelta(x, y) => Vec2::new(x, y),
+            MouseScrollDelta::PixelDelta(pixels) => Vec2::new(
+                (pixels.x / PIXELS_PER_WHEEL_LINE) as f32,
+                (pixels.y / PIXELS_PER_WHEEL_LINE) as f32,
+            ),
+        };
+    }
+
+    /// Where the cursor is in the internal resolution, from the view's
+    /// top-left corner, or `None` if it's over a letterbox bar. Cameras map
+    /// it to the world, see `CameraController::screen_to_world`.
+    #[cfg(test)]
+    fn mouse_position(&self) -> Option<Vec2> {
+        self.mouse_internal
+    }
+
+    /// Whether `button` went down since the last `end_frame`, e.g. for clicks
+    #[cfg(test)]
+    fn was_mouse_pressed(&self, button: MouseButton) -> bool {
+        self.mouse_presses.contains(&button)
+    }
+
+    /// Lines scrolled since the last `end_frame`, positive up and left
+    #[cfg(test)]
+    fn mouse_wheel(&self) -> Vec2 {
+        self.mouse_wheel
+    }
+    fn is_mouse_down(&self, button: MouseButton) -> bool {
+        matches!(self.mouse_buttons.get(&button), Some(ElementState::Pressed))
+    }
     fn is_physical_key_down(&self, key: KeyCode) -> bool {
         matches!(
             self.physical_key_states.get(&key),
             Some(ElementState::Pressed)
         )
     }
-    fn is_physical_key_up(&self, key: KeyCode) -> bool {
-        match self.physical_key_states.get(&key) {
-            Some(ElementState::Pressed) => false,
-            Some(ElementState::Released) => true,
-            None => false,
-        }
-    }
-    fn get_last_key_pressed(&self, group_handle: &KeyPressGroupHandle) -> Option<KeyCode> {
+    fn get_last_action_pressed(&self, group_handle: &KeyPressGroupHandle) -> Option<Action> {
         self.key_press_groups
             .get(group_handle.index)
             .and_then(|group| group.stack.last().cloned())
@@ -141,52 +497,138 @@ impl InputSystem {
 }
 
 impl AppState {
-    fn is_loading(&self) -> bool {
-        matches!(self, AppState::Loading { .. })
-    }
-
-    fn is_loaded(&self) -> bool {
-        matches!(self, AppState::Loaded { .. })
-    }
-
     // Mutably advances the state in place, returns true if advancement happened
     fn advance_in_place(&mut self) -> bool {
         match self {
             AppState::Loading {
-                game,
                 renderer,
                 window,
                 audio,
-                input_config,
+                assets,
             } => {
                 // Check if all components are ready
-                let renderer_ready = renderer.lock().unwrap().is_some();
-                let game_ready = game.lock().unwrap().is_some();
-                let window_ready = window.lock().unwrap().is_some();
-                let audio_ready = audio.lock().unwrap().is_some();
+                let renderer_ready = renderer.borrow().is_some();
+                let window_ready = window.borrow().is_some();
+                let audio_ready = audio.borrow().is_some();
+                let assets_ready = assets.borrow().is_some();
 
-                if renderer_ready && game_ready && window_ready && audio_ready {
+                if renderer_ready && window_ready && audio_ready && assets_ready {
                     // Take the values out
-                    let renderer = renderer.lock().unwrap().take().unwrap();
-                    let game = game.lock().unwrap().take().unwrap();
-                    let window = window.lock().unwrap().take().unwrap();
-                    let audio = audio.lock().unwrap().take().unwrap();
-                    let input_config = input_config.lock().unwrap().take().unwrap();
-
-                    // Replace self with the new state
-                    *self = AppState::Loaded {
-                        game,
+                    let renderer = renderer.borrow_mut().take().unwrap();
+                    let window = window.borrow_mut().take().unwrap();
+                    let audio = audio.borrow_mut().take().unwrap();
+                    let assets = assets.borrow_mut().take().unwrap();
+
+                    // Polled and drawn on every redraw from now on
+                    window.request_redraw();
+                    *self = AppState::LoadingAssets {
                         renderer,
                         window,
-                        input: InputSystem::new(input_config),
                         audio,
+                        assets,
                     };
                     true
                 } else {
                     false
                 }
             }
-            AppState::Loaded { .. } => false,
+            AppState::LoadingAssets { assets, .. } if !assets.is_loading() => {
+                let AppState::LoadingAssets {
+                    mut renderer,
+                    window,
+                    mut audio,
+                    assets,
+                } = std::mem::replace(self, AppState::Failed)
+                else {
+                    unreachable!("Matched above");
+                };
+                let mut input_config = InputSystemConfig::new()
+                    .with_bindings(actions::load_bindings().unwrap_or_default());
+                match Game::init_with_assets(&mut renderer, &mut audio, &mut input_config, assets) {
+                    Ok(game) => {
+                        *self = AppState::Loaded {
+                            game: Box::new(game),
+                            renderer,
+                            window,
+                            input: InputSystem::new(input_config),
+                            audio,
+                        };
+                    }
+                    Err(err) => log::error!("{}", err),
+                }
+                true
+            }
+            AppState::LoadingAssets { .. } | AppState::Loaded { .. } | AppState::Failed => false,
+        }
+    }
+}
+
+/// Key cycling between a window, borderless and exclusive fullscreen
+const FULLSCREEN_KEY: KeyCode = KeyCode::F11;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum WindowMode {
+    #[default]
+    Windowed,
+    BorderlessFullscreen,
+    /// Fullscreen in the monitor's biggest video mode
+    ExclusiveFullscreen,
+}
+
+impl WindowMode {
+    /// The mode a window is in, e.g. after the browser left fullscreen on
+    /// its own when escape was pressed
+    fn of(fullscreen: Option<Fullscreen>) -> Self {
+        match fullscreen {
+            Some(Fullscreen::Borderless(_)) => WindowMode::BorderlessFullscreen,
+            Some(Fullscreen::Exclusive(_)) => WindowMode::ExclusiveFullscreen,
+            None => WindowMode::Windowed,
+        }
+    }
+
+    /// The mode once the window was resized while in `fullscreen`. Only
+    /// whether it's fullscreen is taken from the window, which fullscreen
+    /// it's in is kept, as exclusive may have fallen back to borderless.
+    fn after_resize(self, fullscreen: Option<Fullscreen>) -> Self {
+        match (self, fullscreen) {
+            (_, None) => WindowMode::Windowed,
+            (WindowMode::Windowed, Some(fullscreen)) => Self::of(Some(fullscreen)),
+            (mode, Some(_)) => mode,
+        }
+    }
+
+    /// Switches to the next mode, returning it. Without video modes there's
+    /// no exclusive fullscreen to switch to, as in most browsers.
+    fn toggle(&mut self, has_video_modes: bool) -> Self {
+        *self = match self {
+            WindowMode::Windowed => WindowMode::BorderlessFullscreen,
+            WindowMode::BorderlessFullscreen if has_video_modes => WindowMode::ExclusiveFullscreen,
+            WindowMode::BorderlessFullscreen | WindowMode::ExclusiveFullscreen => {
+                WindowMode::Windowed
+            }
+        };
+        *self
+    }
+
+    /// What to set the window to for this mode on `monitor`, the one it's
+    /// on. Monitors without video modes to pick from, like most browsers',
+    /// get borderless fullscreen instead of exclusive.
+    fn fullscreen(self, monitor: Option<MonitorHandle>) -> Option<Fullscreen> {
+        match self {
+            WindowMode::Windowed => None,
+            WindowMode::BorderlessFullscreen => Some(Fullscreen::Borderless(monitor)),
+            WindowMode::ExclusiveFullscreen => {
+                let video_mode = monitor.as_ref().and_then(|monitor| {
+                    monitor.video_modes().max_by_key(|mode| {
+                        let size = mode.size();
+                        (size.width * size.height, mode.refresh_rate_millihertz())
+                    })
+                });
+                Some(match video_mode {
+                    Some(video_mode) => Fullscreen::Exclusive(video_mode),
+                    None => Fullscreen::Borderless(monitor),
+                })
+            }
         }
     }
 }
@@ -194,24 +636,43 @@ impl AppState {
 struct WebApp {
     state: Box<AppState>,
     last_time: Option<f64>,
+    time_step: TimeStep,
+    window_mode: WindowMode,
+    // When the next capped frame is due, while the event loop waits for it
+    next_frame_at: Option<f64>,
 }
 
 impl WebApp {
     fn new() -> Self {
         Self {
             state: Box::new(AppState::Loading {
-                game: Arc::new(Mutex::new(None)),
-                renderer: Arc::new(Mutex::new(None)),
-                window: Arc::new(Mutex::new(None)),
-                audio: Arc::new(Mutex::new(None)),
-                input_config: Arc::new(Mutex::new(None)),
+                renderer: Rc::new(RefCell::new(None)),
+                window: Rc::new(RefCell::new(None)),
+                audio: Rc::new(RefCell::new(None)),
+                assets: Rc::new(RefCell::new(None)),
             }),
             last_time: None,
+            time_step: TimeStep::new(frame_pacing::FIXED_STEP),
+            window_mode: WindowMode::default(),
+            next_frame_at: None,
         }
     }
 }
 
 impl ApplicationHandler for WebApp {
+    fn new_events(&mut self, event_loop: &ActiveEventLoop, cause: StartCause) {
+        if !matches!(cause, StartCause::ResumeTimeReached { .. }) {
+            return;
+        }
+        if let (Some(next_frame_at), AppState::Loaded { window, .. }) =
+            (self.next_frame_at.take(), &*self.state)
+        {
+            frame_pacing::spin_until(next_frame_at);
+            event_loop.set_control_flow(ControlFlow::Wait);
+            window.request_redraw();
+        }
+    }
+
     fn resumed(&mut self, event_loop: &ActiveEventLoop) {
         let window = std::sync::Arc::new(
             event_loop
@@ -219,27 +680,10 @@ impl ApplicationHandler for WebApp {
                 .unwrap(),
         );
 
-        let web_window = web_sys::window().unwrap();
-        let document = web_window.document().unwrap();
-        let canvas: HtmlCanvasElement = window.canvas().unwrap();
-
-        let container = document
-            .get_element_by_id("webengine-container")
-            .unwrap_or_else(|| {
-                let body = document.body().unwrap();
-                let container = document.create_element("div").unwrap();
-                container.set_id("webengine-container");
-                body.append_child(&container).unwrap();
-                container
-            });
-
-        container.append_child(&canvas).unwrap();
-
-        let status_div = document.get_element_by_id("status").unwrap();
-        status_div.set_text_content(Some(""));
+        #[cfg(target_arch = "wasm32")]
+        attach_canvas(&window);
 
         let (target_w, target_h) = Game::target_size();
-        let alignment_hint = Game::alignment_hint();
 
         //canvas.set_width(target_w);
         //canvas.set_height(target_h);
@@ -247,32 +691,64 @@ impl ApplicationHandler for WebApp {
         //let _ = window.request_inner_size(winit::dpi::PhysicalSize::new(target_w, target_h));
 
         if let AppState::Loading {
-            game,
             renderer,
             window: window_state,
             audio,
-            input_config,
+            assets,
         } = &mut *self.state
         {
             // Store the window in the state
-            *window_state.lock().unwrap() = Some(window.clone());
+            *window_state.borrow_mut() = Some(window.clone());
 
-            let renderer_clone = Arc::clone(renderer);
-            let game_clone = Arc::clone(game);
-            let audio_clone = Arc::clone(audio);
-            let input_config_clone = Arc::clone(input_config);
+            let renderer_clone = Rc::clone(renderer);
+            let audio_clone = Rc::clone(audio);
+            let assets_clone = Rc::clone(assets);
             wasm_bindgen_futures::spawn_local(async move {
-                let mut renderer =
-                    RenderingSystem::new(window.clone(), target_w, target_h, alignment_hint).await;
+                let backend = RendererBackend::from_override().unwrap_or(Game::renderer_backend());
+                let renderer = RenderingSystem::new(
+                    window.clone(),
+                    target_w,
+                    target_h,
+                    Game::sample_count(),
+                    backend,
+                )
+                .await;
+                let mut renderer = match renderer {
+                    Ok(renderer) => renderer,
+                    Err(err) => {
+                        // Stays loading for good, with the error up instead
+                        log::error!("{}", err);
+                        show_error(
+                            &window,
+                            &format!(
+                                "{}. Try another browser, or turning on hardware acceleration.",
+                                err
+                            ),
+                        );
+                        return;
+                    }
+                };
+                // The window may have been sized before there was a renderer
+                // to hear about it
+                renderer.resize(window.inner_size());
+                renderer.set_frame_latency(Game::frame_latency());
+                if renderer.present_mode() != wgpu::PresentMode::Fifo {
+                    renderer.set_frame_cap(Some(Game::frame_cap()));
+                }
+                log::info!(
+                    "Presenting with {:?}, up to {} frames queued",
+                    renderer.present_mode(),
+                    renderer.frame_latency()
+                );
                 let mut audio_system = AudioSystem::new();
 
-                let mut input_config = InputSystemConfig::new();
-                let game = Game::init(&mut renderer, &mut audio_system, &mut input_config);
+                // Streamed in behind the loading screen
+                let mut assets = AssetManager::new().with_source(AssetSource::for_platform());
+                assets.request_preload(&mut audio_system);
 
-                *renderer_clone.lock().unwrap() = Some(renderer);
-                *game_clone.lock().unwrap() = Some(game);
-                *audio_clone.lock().unwrap() = Some(audio_system);
-                *input_config_clone.lock().unwrap() = Some(input_config);
+                *renderer_clone.borrow_mut() = Some(renderer);
+                *audio_clone.borrow_mut() = Some(audio_system);
+                *assets_clone.borrow_mut() = Some(assets);
             });
         } else {
             panic!("AppState is not Loading");
@@ -288,6 +764,35 @@ impl ApplicationHandler for WebApp {
         // Try to advance the state
         self.state.advance_in_place();
 
+        if let AppState::LoadingAssets {
+            renderer,
+            window,
+            audio,
+            assets,
+        } = &mut *self.state
+        {
+            match event {
+                WindowEvent::CloseRequested => event_loop.exit(),
+                WindowEvent::Resized(physical_size) => renderer.resize(physical_size),
+                WindowEvent::RedrawRequested => {
+                    assets.poll(renderer, audio);
+                    let progress = assets.progress();
+                    let rendered = renderer
+                        .render_with(|drawer| Game::render_loading_screen(drawer, progress));
+                    if let Err(err) = rendered {
+                        log::error!("{:?}", err);
+                    }
+                    window.request_redraw();
+                }
+                // Unlocks audio early, so the game starts with sound
+                WindowEvent::KeyboardInput { .. }
+                | WindowEvent::MouseInput { .. }
+                | WindowEvent::Touch(_) => audio.on_user_interaction(),
+                _ => {}
+            }
+            return;
+        }
+
         // Handle events if we're loaded
         if let AppState::Loaded {
             game,
@@ -305,63 +810,102 @@ impl ApplicationHandler for WebApp {
                     // Handle resize - you'll need to implement this method on your renderer
                     // renderer.resize(physical_size.width, physical_size.height);
                     renderer.resize(physical_size);
+                    // The view may have moved under the cursor
+                    let (x, y) = input.mouse_position;
+                    let internal = renderer.window_to_internal(Vec2::new(x as f32, y as f32));
+                    input.move_mouse((x, y), internal);
+                    // Switching modes resizes the window, and so does leaving
+                    // fullscreen some other way
+                    self.window_mode = self.window_mode.after_resize(window.fullscreen());
                 }
+                WindowEvent::ScaleFactorChanged { .. } => {
+                    // The monitor's resolution changed, or the window moved
+                    // to another one, which doesn't always come with a
+                    // `Resized` of its own
+                    renderer.resize(window.inner_size());
+                }
+                // Nothing to hear from a game that can't be seen, like in a
+                // tab in the background
+                WindowEvent::Occluded(occluded) => audio.set_muted(occluded),
                 WindowEvent::RedrawRequested => {
                     // Handle render - you'll need to implement this method
                     // match renderer.render(&game) {
                     //     Ok(_) => {}
                     //     Err(e) => log::error!("Render error: {:?}", e),
                     // }
-                    let now = web_sys::window().unwrap().performance().unwrap().now();
+                    let now = frame_pacing::now_ms();
                     // Only call update if we have a last time
+                    input.update_gamepad(actions::held_gamepad_buttons());
                     if let Some(last_time) = self.last_time {
                         let delta_time = (now - last_time) as f32 / 1000.0; // Convert to seconds
-                        game.update(input, audio, renderer, delta_time);
+                        for step in self.time_step.advance(delta_time).steps {
+                            game.update(input, audio, renderer, step);
+                            input.end_frame(step);
+                        }
                     }
                     self.last_time = Some(now);
+                    audio.update();
 
                     match renderer.render(game) {
                         Ok(_) => {}
-                        Err(wgpu::SurfaceError::Lost) => {
-                            renderer.canonical_resize();
-                        }
                         Err(wgpu::SurfaceError::OutOfMemory) => event_loop.exit(),
                         Err(e) => log::error!("{:?}", e),
                     }
 
-                    window.request_redraw();
+                    match renderer.target_frame_ms() {
+                        Some(target_ms) => {
+                            let elapsed_ms = frame_pacing::now_ms() - now;
+                            let pacing = FramePacing::new(target_ms, elapsed_ms);
+                            if pacing.sleep_ms > 0.0 {
+                                // Wait out the frame; new_events spins the rest and redraws
+                                self.next_frame_at = Some(now + target_ms);
+                                event_loop.set_control_flow(ControlFlow::wait_duration(
+                                    Duration::from_secs_f64(pacing.sleep_ms / 1000.0),
+                                ));
+                            } else {
+                                frame_pacing::spin_until(now + target_ms);
+                                window.request_redraw();
+                            }
+                        }
+                        None => window.request_redraw(),
+                    }
                 }
                 WindowEvent::MouseInput { button, state, .. } => {
-                    // Update mouse input state
-                    input.mouse_buttons.insert(button, state);
+                    input.set_mouse_button(button, state);
+                    audio.on_user_interaction();
+                }
+                WindowEvent::Touch(_) => {
+                    // Browsers only let audio start from a gesture, on
+                    // phones that's a touch
                     audio.on_user_interaction();
                 }
                 WindowEvent::CursorMoved { position, .. } => {
-                    // Update mouse position
-                    input.mouse_position = (position.x, position.y);
+                    let window_position = Vec2::new(position.x as f32, position.y as f32);
+                    let internal = renderer.window_to_internal(window_position);
+                    input.move_mouse((position.x, position.y), internal);
                 }
+                WindowEvent::MouseWheel { delta, .. } => input.scroll(delta),
                 WindowEvent::KeyboardInput { event, .. } => {
                     // Handle keyboard input if needed
                     let KeyEvent {
                         physical_key,
                         state,
+                        repeat,
                         ..
                     } = event;
-                    if let PhysicalKey::Code(code) = physical_key {
-                        input.physical_key_states.insert(code, state);
-                        if state == ElementState::Pressed {
-                            // Check if this key is part of any key press group
-                            for group in &mut input.key_press_groups {
-                                if group.keys.contains(&code) {
-                                    group.stack.push(code);
-                                }
-                            }
-                        } else if state == ElementState::Released {
-                            for group in &mut input.key_press_groups {
-                                if group.keys.contains(&code) {
-                                    group.stack.retain(|&x| x != code);
-                                }
-                            }
+                    if physical_key == PhysicalKey::Code(FULLSCREEN_KEY) {
+                        if state == ElementState::Pressed && !repeat {
+                            let monitor = window.current_monitor();
+                            let has_video_modes = monitor
+                                .as_ref()
+                                .is_some_and(|monitor| monitor.video_modes().next().is_some());
+                            let mode = self.window_mode.toggle(has_video_modes);
+                            window.set_fullscreen(mode.fullscreen(monitor));
+                        }
+                    } else if let PhysicalKey::Code(code) = physical_key {
+                        match state {
+                            ElementState::Pressed => input.press_key(code),
+                            ElementState::Released => input.release_key(code),
                         }
                     }
                     audio.on_user_interaction();
@@ -371,3 +915,193 @@ impl ApplicationHandler for WebApp {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn combos_trigger_only_within_their_window() {
+        let mut config = InputSystemConfig::new();
+        let lunge = config.register_combo(&[Action::Heal, Action::Attack], 0.3);
+        let mut input = InputSystem::new(config);
+
+        input.press_key(KeyCode::KeyH);
+        input.end_frame(0.1);
+        input.release_key(KeyCode::KeyH);
+        input.press_key(KeyCode::KeyL);
+        assert!(input.was_combo_triggered(&lunge));
+        input.end_frame(0.1);
+        assert!(!input.was_combo_triggered(&lunge));
+        input.release_key(KeyCode::KeyL);
+
+        input.press_key(KeyCode::KeyH);
+        input.end_frame(0.5);
+        input.release_key(KeyCode::KeyH);
+        input.press_key(KeyCode::KeyL);
+        assert!(!input.was_combo_triggered(&lunge));
+        input.release_key(KeyCode::KeyL);
+
+        // Out of order doesn't count either
+        input.press_key(KeyCode::KeyL);
+        input.press_key(KeyCode::KeyH);
+        assert!(!input.was_combo_triggered(&lunge));
+    }
+
+    #[test]
+    fn actions_follow_whatever_they_are_bound_to() {
+        let mut config = InputSystemConfig::new();
+        let heal = config.allocate_group(&[Action::Heal]);
+        let mut input = InputSystem::new(config);
+
+        input.press_key(KeyCode::KeyW);
+        input.update_gamepad(HashSet::from([GamepadButton::DpadUp]));
+        input.release_key(KeyCode::KeyW);
+        // Still held on the gamepad
+        assert!(input.is_action_down(Action::MoveUp));
+        input.update_gamepad(HashSet::new());
+        assert!(!input.is_action_down(Action::MoveUp));
+
+        // Rebinding leaves the old key doing nothing
+        let mut bindings = input.bindings().clone();
+        bindings.clear(Action::Heal);
+        bindings.bind(Action::Heal, Binding::Key(KeyCode::KeyJ));
+        assert!(input.set_bindings(bindings).is_empty());
+        input.press_key(KeyCode::KeyH);
+        assert_eq!(input.get_last_action_pressed(&heal), None);
+        input.press_key(KeyCode::KeyJ);
+        assert_eq!(input.get_last_action_pressed(&heal), Some(Action::Heal));
+    }
+
+    #[test]
+    fn captured_keys_are_bound_instead_of_pressed() {
+        let mut config = InputSystemConfig::new();
+        let heal = config.allocate_group(&[Action::Heal]);
+        let mut input = InputSystem::new(config);
+
+        input.capture_binding(Action::Heal);
+        input.press_key(KeyCode::F11);
+        assert!(input.is_capturing());
+        input.press_key(KeyCode::KeyL);
+        assert!(!input.is_capturing());
+        assert_eq!(input.get_last_action_pressed(&heal), None);
+        assert_eq!(
+            input.poll_rebound(),
+            Some(Rebound {
+                action: Action::Heal,
+                binding: Binding::Key(KeyCode::KeyL),
+                conflicts: vec![Action::Attack],
+            })
+        );
+        assert_eq!(input.poll_rebound(), None);
+        assert_eq!(
+            input.bindings().conflicts(),
+            vec![(
+                Binding::Key(KeyCode::KeyL),
+                vec![Action::Attack, Action::Heal]
+            )]
+        );
+        input.release_key(KeyCode::KeyL);
+        input.press_key(KeyCode::KeyL);
+        assert_eq!(input.get_last_action_pressed(&heal), Some(Action::Heal));
+
+        // Cancelling leaves the bindings be
+        input.capture_binding(Action::Attack);
+        input.press_key(CANCEL_CAPTURE_KEY);
+        assert!(!input.is_capturing());
+        assert_eq!(input.poll_rebound(), None);
+        assert_eq!(input.bindings().bindings_of(Action::Attack).count(), 2);
+
+        // Taking the key back off attacking settles the conflict
+        input.capture_binding(Action::Attack);
+        input.press_key(KeyCode::KeyK);
+        assert_eq!(input.poll_rebound().unwrap().conflicts, Vec::new());
+        assert!(input.bindings().conflicts().is_empty());
+    }
+
+    #[test]
+    fn mouse_presses_and_scrolling_last_a_frame() {
+        let mut input = InputSystem::new(InputSystemConfig::new());
+        input.set_mouse_button(MouseButton::Left, ElementState::Pressed);
+        input.scroll(MouseScrollDelta::LineDelta(0.0, 1.0));
+        input.scroll(MouseScrollDelta::PixelDelta(
+            winit::dpi::PhysicalPosition::new(0.0, -50.0),
+        ));
+        assert!(input.was_mouse_pressed(MouseButton::Left));
+        assert_eq!(input.mouse_wheel(), Vec2::new(0.0, 0.5));
+        input.end_frame(0.1);
+        assert!(!input.was_mouse_pressed(MouseButton::Left));
+        assert!(input.is_mouse_down(MouseButton::Left));
+        assert_eq!(input.mouse_wheel(), Vec2::ZERO);
+
+        // Held down isn't pressed again
+        input.set_mouse_button(MouseButton::Left, ElementState::Pressed);
+        assert!(!input.was_mouse_pressed(MouseButton::Left));
+
+        input.move_mouse((10.0, 540.0), None);
+        assert_eq!(input.mouse_position(), None);
+    }
+
+    #[test]
+    fn fullscreen_toggles_back_to_a_window_without_video_modes() {
+        let mut mode = WindowMode::default();
+        assert_eq!(mode.toggle(false), WindowMode::BorderlessFullscreen);
+        let fullscreen = mode.fullscreen(None);
+        // Entering fullscreen resizes the window
+        mode = mode.after_resize(fullscreen.clone());
+        assert_eq!(mode, WindowMode::BorderlessFullscreen);
+        assert_eq!(mode.toggle(false), WindowMode::Windowed);
+        assert_eq!(mode.fullscreen(None), None);
+        assert_eq!(mode.after_resize(None), WindowMode::Windowed);
+
+        // Exclusive reported back as borderless stays exclusive
+        let exclusive = WindowMode::ExclusiveFullscreen;
+        assert_eq!(exclusive.after_resize(fullscreen), exclusive);
+        // Escape left fullscreen without the key
+        assert_eq!(exclusive.after_resize(None), WindowMode::Windowed);
+    }
+
+    #[test]
+    fn fullscreen_toggle_keeps_the_internal_resolution() {
+        let mut mode = WindowMode::default();
+        assert_eq!(mode.toggle(true), WindowMode::BorderlessFullscreen);
+        assert_eq!(mode.fullscreen(None), Some(Fullscreen::Borderless(None)));
+        assert_eq!(mode.toggle(true), WindowMode::ExclusiveFullscreen);
+        // Nothing to pick a video mode from without a monitor
+        assert_eq!(mode.fullscreen(None), Some(Fullscreen::Borderless(None)));
+        assert_eq!(mode.toggle(true), WindowMode::Windowed);
+        assert_eq!(mode.fullscreen(None), None);
+        assert_eq!(
+            WindowMode::of(Some(Fullscreen::Borderless(None))),
+            WindowMode::BorderlessFullscreen
+        );
+
+        let (width, height) = Game::target_size();
+        let Some(mut renderer) = headless::renderer(width, height) else {
+            return;
+        };
+        // Going fullscreen on a wide monitor, then back to a window
+        for (monitor_width, monitor_height) in [(1920.0, 1080.0), (640.0, 480.0)] {
+            renderer.resize(winit::dpi::PhysicalSize::new(
+                monitor_width as u32,
+                monitor_height as u32,
+            ));
+            let monitor = glam::Vec2::new(monitor_width, monitor_height);
+            let internal = glam::Vec2::new(width as f32, height as f32);
+            let center = renderer.window_to_internal(monitor * 0.5).unwrap();
+            assert!((center - internal * 0.5).length() < 0.5);
+            // Scaled up by a whole number of pixels, with bars all around
+            // if it doesn't fill the monitor
+            let scale = (monitor / internal).min_element().floor();
+            let corner = (monitor + internal * scale) * 0.5 - 1.0;
+            let bottom_right = renderer.window_to_internal(corner).unwrap();
+            assert!((bottom_right - internal).length() < 2.0);
+            assert!(renderer.window_to_internal(corner + 2.0).is_none());
+        }
+        // The 4:3 view is letterboxed on the sides of a 16:9 monitor
+        renderer.resize(winit::dpi::PhysicalSize::new(1920, 1080));
+        assert!(renderer
+            .window_to_internal(glam::Vec2::new(10.0, 540.0))
+            .is_none());
+    }
+}
@@ -1,6 +1,4 @@
-use std::collections::HashMap;
-
-use log::info;
+use std::cmp::Reverse;
 
 const MAP: &[(&str, &str)] = &[
     ("a", "󱤀"),
@@ -167,12 +165,26 @@ const MAP: &[(&str, &str)] = &[
     ("'", "󱦔"),
 ];
 
+/// How `convert_latin_to_ucsur_with` lays out what it converts
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UcsurOptions {
+    /// Keeps a single space between words and every line break, for running
+    /// text. Otherwise all whitespace is dropped, which suits single words
+    /// and counters.
+    pub preserve_spaces: bool,
+}
+
+/// Writes toki pona in sitelen pona. Words with no glyph are kept in latin
+/// letters inside a cartouche, the way names and other foreign words are
+/// written in sitelen pona.
 pub fn convert_latin_to_ucsur(text: &str) -> String {
-    // Create HashMap from the const array and sort by key length (descending)
-    let mut table: Vec<(&str, &str)> = MAP.iter().copied().collect();
-    table.sort_by(|a, b| b.0.len().cmp(&a.0.len()));
+    convert_latin_to_ucsur_with(text, &UcsurOptions::default())
+}
 
-    let table_map: HashMap<&str, &str> = table.into_iter().collect();
+pub fn convert_latin_to_ucsur_with(text: &str, options: &UcsurOptions) -> String {
+    // Longest words first
+    let mut table = MAP.to_vec();
+    table.sort_by_key(|&(latin, _)| Reverse(latin.len()));
 
     let mut current_text = text;
     let mut output_text = String::new();
@@ -181,7 +193,7 @@ pub fn convert_latin_to_ucsur(text: &str) -> String {
         let mut matched = false;
 
         // Try to match the longest possible word first
-        for &(latin, ucsur) in MAP.iter() {
+        for &(latin, ucsur) in table.iter() {
             if current_text.starts_with(latin) {
                 // Check if this is a complete word boundary
                 let after_match = &current_text[latin.len()..];
@@ -198,7 +210,21 @@ pub fn convert_latin_to_ucsur(text: &str) -> String {
             // Take the first character and add it to output
             if let Some(ch) = current_text.chars().next() {
                 if ch.is_whitespace() {
-                    // Skip whitespace (as per your Python version)
+                    let rest = current_text.trim_start();
+                    if options.preserve_spaces {
+                        let run = &current_text[..current_text.len() - rest.len()];
+                        push_whitespace(&mut output_text, run);
+                    }
+                    current_text = rest;
+                    continue;
+                } else if ch.is_alphabetic() {
+                    // A whole word with no glyph, not just its first letter
+                    let end = current_text
+                        .find(|c: char| !c.is_alphabetic())
+                        .unwrap_or(current_text.len());
+                    push_unknown_word(&mut output_text, &current_text[..end]);
+                    current_text = &current_text[end..];
+                    continue;
                 } else {
                     output_text.push(ch);
                 }
@@ -212,6 +238,24 @@ pub fn convert_latin_to_ucsur(text: &str) -> String {
     output_text
 }
 
+fn push_unknown_word(output: &mut String, word: &str) {
+    let glyph = |latin| MAP.iter().find(|(l, _)| *l == latin).unwrap().1;
+    output.push_str(glyph("["));
+    output.push_str(word);
+    output.push_str(glyph("]"));
+}
+
+/// Collapses a run of whitespace into its line breaks, or a single space if
+/// it has none
+fn push_whitespace(output: &mut String, run: &str) {
+    let newlines = run.matches('\n').count();
+    if newlines == 0 {
+        output.push(' ');
+    } else {
+        output.extend(std::iter::repeat_n('\n', newlines));
+    }
+}
+
 fn factorize_mixed_radix(number: i32) -> String {
     let basis = vec![100, 20, 5, 2, 1];
 
@@ -297,27 +341,142 @@ fn format_result(terms: Vec<Vec<i32>>) -> String {
     parts.join("+")
 }
 
+/// The words numbers are spelled with, e.g. to follow a dialect or a course
+/// that teaches `ali` instead of `ale`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NumberWords<'a> {
+    pub hundred: &'a str,
+    pub twenty: &'a str,
+    pub five: &'a str,
+    pub two: &'a str,
+    pub one: &'a str,
+    pub zero: &'a str,
+    /// Goes between the terms that are added together
+    pub separator: &'a str,
+}
+
+impl Default for NumberWords<'static> {
+    fn default() -> Self {
+        Self {
+            hundred: "ale",
+            twenty: "mute",
+            five: "luka",
+            two: "tu",
+            one: "wan",
+            zero: "ala",
+            separator: "en",
+        }
+    }
+}
+
+impl NumberWords<'_> {
+    fn word_for(&self, unit: &str) -> &str {
+        match unit {
+            "100" => self.hundred,
+            "20" => self.twenty,
+            "5" => self.five,
+            "2" => self.two,
+            "1" => self.one,
+            _ => self.zero,
+        }
+    }
+}
+
 pub fn number_to_toki_pona(number: u32) -> String {
+    number_to_toki_pona_with(number, &NumberWords::default())
+}
+
+/// Like `number_to_toki_pona`, spelled with `words`
+pub fn number_to_toki_pona_with(number: u32, words: &NumberWords) -> String {
     let partial = factorize_mixed_radix(number as i32);
-    // replace 100 with ale, 20 with mute, 5 with luka, 2 with tu, and 1 with wan
-    // then * with a space
-    // and + with "en"
-    let mut result = partial
-        .replace("100", "ale")
-        .replace("20", "mute")
-        .replace("5", "luka")
-        .replace("2", "tu")
-        .replace("1", "wan")
-        .replace("0", "ala")
-        .replace("*", " ")
-        .replace("+", " en ");
+    // Units multiplied together are spelled next to each other, and the
+    // terms they make up are added with the separator in between
+    let separator = format!(" {} ", words.separator);
+    let result = partial
+        .split('+')
+        .map(|term| {
+            term.split('*')
+                .map(|unit| words.word_for(unit))
+                .collect::<Vec<_>>()
+                .join(" ")
+        })
+        .collect::<Vec<_>>()
+        .join(&separator);
 
     // remove any trailing spaces
-    result = result.trim().to_string();
-    // if the result is empty, return "ala"
+    let result = result.trim();
+    // if the result is empty, return the word for zero
     if result.is_empty() {
-        result = "ala".to_string();
+        words.zero.to_string()
+    } else {
+        result.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spells_numbers_with_the_default_words() {
+        assert_eq!(number_to_toki_pona(0), "ala");
+        assert_eq!(number_to_toki_pona(3), "tu en wan");
+        assert_eq!(number_to_toki_pona(100), "ale");
+        assert_eq!(number_to_toki_pona(40), "mute tu");
     }
 
-    result
+    #[test]
+    fn spells_numbers_with_custom_words() {
+        let words = NumberWords {
+            hundred: "ali",
+            separator: "anu",
+            ..NumberWords::default()
+        };
+        assert_eq!(number_to_toki_pona_with(100, &words), "ali");
+        assert_eq!(number_to_toki_pona_with(3, &words), "tu anu wan");
+        assert_eq!(
+            number_to_toki_pona_with(
+                0,
+                &NumberWords {
+                    zero: "weka",
+                    ..words
+                }
+            ),
+            "weka"
+        );
+    }
+
+    #[test]
+    fn drops_whitespace_by_default() {
+        assert_eq!(
+            convert_latin_to_ucsur("mi moku\ne kili"),
+            "\u{F1934}\u{F1936}\u{F1909}\u{F191A}"
+        );
+    }
+
+    #[test]
+    fn preserves_spaces_and_line_breaks() {
+        let options = UcsurOptions {
+            preserve_spaces: true,
+        };
+        assert_eq!(
+            convert_latin_to_ucsur_with("mi moku\ne kili", &options),
+            "\u{F1934} \u{F1936}\n\u{F1909} \u{F191A}"
+        );
+        // Runs of spaces collapse, blank lines stay
+        assert_eq!(
+            convert_latin_to_ucsur_with("mi   moku\n\n  e", &options),
+            "\u{F1934} \u{F1936}\n\n\u{F1909}"
+        );
+    }
+
+    #[test]
+    fn wraps_unknown_words_in_a_cartouche() {
+        assert_eq!(convert_latin_to_ucsur("github"), "\u{F1990}github\u{F1991}");
+        // Known words around it are left alone, even inside the unknown one
+        assert_eq!(
+            convert_latin_to_ucsur("mi githubmi"),
+            "\u{F1934}\u{F1990}githubmi\u{F1991}"
+        );
+    }
 }
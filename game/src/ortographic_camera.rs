@@ -1,4 +1,4 @@
-use glam::Vec3;
+use glam::{Vec2, Vec3};
 
 use crate::geometry::Transform;
 
@@ -17,6 +17,18 @@ impl OrthoCamera {
         }
     }
 
+    /// How much of the world fits on the screen, in world units
+    pub fn view_size(&self) -> Vec2 {
+        Vec2::new(self.screen_width, self.screen_height) / self.zoom
+    }
+
+    /// World offset from the middle of the view of a point `screen` pixels
+    /// from the screen's top-left corner
+    #[cfg(test)]
+    pub fn screen_to_view(&self, screen: Vec2) -> Vec2 {
+        (screen - Vec2::new(self.screen_width, self.screen_height) / 2.0) / self.zoom
+    }
+
     pub fn get_transform(&self) -> Transform {
         Transform::ortographic_size_invariant()
             .translate(Vec3::new(0.5, 0.5, 0.0))
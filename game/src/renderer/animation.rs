@@ -0,0 +1,275 @@
+//! Sprite animation: clips of tiles from a sprite sheet shown one after the
+//! other, and a player stepping through them as time goes by.
+
+use crate::renderer::gizmo::{GizmoSprite, GizmoSpriteSheet};
+
+/// Tiles of a sheet shown in order, each for the same time
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnimationClip {
+    pub frames: Vec<[u32; 2]>,
+    /// Seconds each frame is shown for, at a speed of 1
+    pub frame_duration: f32,
+    /// Whether it starts over after the last frame, instead of holding it
+    pub looping: bool,
+}
+
+impl AnimationClip {
+    pub fn new(frames: Vec<[u32; 2]>, frame_duration: f32, looping: bool) -> Self {
+        Self {
+            frames,
+            frame_duration,
+            looping,
+        }
+    }
+}
+
+/// Refers to a clip added with `AnimationPlayer::add_clip`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClipHandle {
+    index: usize,
+}
+
+/// What happened during an `AnimationPlayer::update`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnimationEvent {
+    None,
+    /// Moved on to the frame at this index of the clip, e.g. to play a
+    /// footstep on the frames a foot lands. Looping clips go back to 0.
+    FrameChanged(usize),
+    /// A clip that doesn't loop got through its last frame, and holds it
+    Finished,
+}
+
+pub struct AnimationPlayer {
+    sheet: GizmoSpriteSheet,
+    clips: Vec<AnimationClip>,
+    current: usize,
+    frame: usize,
+    // Into the current frame, in clip seconds
+    elapsed: f32,
+    speed: f32,
+    paused: bool,
+    finished: bool,
+}
+
+impl AnimationPlayer {
+    /// A player of clips of `sheet`, playing the first one added
+    pub fn new(sheet: GizmoSpriteSheet) -> Self {
+        Self {
+            sheet,
+            clips: Vec::new(),
+            current: 0,
+            frame: 0,
+            elapsed: 0.0,
+            speed: 1.0,
+            paused: false,
+            finished: false,
+        }
+    }
+
+    pub fn add_clip(&mut self, clip: AnimationClip) -> ClipHandle {
+        self.clips.push(clip);
+        ClipHandle {
+            index: self.clips.len() - 1,
+        }
+    }
+
+    /// Plays `clip` from its first frame, unless it's already playing. A
+    /// clip that finished plays again.
+    pub fn play(&mut self, clip: ClipHandle) {
+        if clip.index != self.current || self.finished {
+            self.current = clip.index;
+            self.restart();
+        }
+    }
+
+    /// Carries on in `clip` from the same frame and time into it, e.g. a
+    /// walk turning to face another way mid-stride. After a clip that
+    /// finished there's nothing to carry on from, so `clip` starts over.
+    pub fn switch_to(&mut self, clip: ClipHandle) {
+        self.current = clip.index;
+        if self.finished {
+            self.restart();
+            return;
+        }
+        let last_frame = self.clips[clip.index].frames.len().saturating_sub(1);
+        if self.frame > last_frame {
+            self.frame = last_frame;
+            self.elapsed = 0.0;
+        }
+    }
+
+    /// Goes back to the first frame of the current clip
+    pub fn restart(&mut self) {
+        self.frame = 0;
+        self.elapsed = 0.0;
+        self.finished = false;
+    }
+
+    #[cfg(test)]
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    #[cfg(test)]
+    pub fn resume(&mut self) {
+        self.paused = false;
+    }
+
+    /// Plays faster (above 1) or slower (below 1). Negative speeds are
+    /// treated as 0.
+    pub fn set_speed(&mut self, speed: f32) {
+        self.speed = speed.max(0.0);
+    }
+
+    #[cfg(test)]
+    pub fn speed(&self) -> f32 {
+        self.speed
+    }
+
+    /// Moves the clip along by `delta_time` seconds, at the player's speed
+    pub fn update(&mut self, delta_time: f32) -> AnimationEvent {
+        let Some(clip) = self.clips.get(self.current) else {
+            return AnimationEvent::None;
+        };
+        if self.paused || self.finished || clip.frames.is_empty() || clip.frame_duration <= 0.0 {
+            return AnimationEvent::None;
+        }
+        self.elapsed += delta_time * self.speed;
+        let mut event = AnimationEvent::None;
+        while self.elapsed >= clip.frame_duration {
+            self.elapsed -= clip.frame_duration;
+            if self.frame + 1 < clip.frames.len() {
+                self.frame += 1;
+            } else if clip.looping {
+                self.frame = 0;
+            } else {
+                self.finished = true;
+                self.elapsed = 0.0;
+                return AnimationEvent::Finished;
+            }
+            event = AnimationEvent::FrameChanged(self.frame);
+        }
+        event
+    }
+
+    #[cfg(test)]
+    pub fn current_clip(&self) -> ClipHandle {
+        ClipHandle {
+            index: self.current,
+        }
+    }
+
+    /// Index of the frame showing in the current clip
+    #[cfg(test)]
+    pub fn frame(&self) -> usize {
+        self.frame
+    }
+
+    /// Whether a clip that doesn't loop is holding its last frame
+    #[cfg(test)]
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// The tile showing, `None` without clips or if the clip's frames aren't
+    /// on the sheet
+    pub fn current_sprite(&self) -> Option<GizmoSprite<'_>> {
+        let tile = self.clips.get(self.current)?.frames.get(self.frame)?;
+        self.sheet.get_sprite(*tile)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::renderer::gizmo::{GizmoBindableTexture, TextureAlpha, TextureSampling};
+
+    fn player() -> AnimationPlayer {
+        let texture = GizmoBindableTexture {
+            layer: 0,
+            width: 48,
+            height: 32,
+            alpha: TextureAlpha::Straight,
+            sampling: TextureSampling::default(),
+        };
+        AnimationPlayer::new(GizmoSpriteSheet::new(
+            Rc::new(texture),
+            [0.0, 0.0],
+            [1.0, 1.0],
+            [3, 2],
+        ))
+    }
+
+    #[test]
+    fn looping_clips_cycle_and_others_finish() {
+        let mut player = player();
+        let walk = player.add_clip(AnimationClip::new(vec![[0, 0], [1, 0], [2, 0]], 0.1, true));
+        let attack = player.add_clip(AnimationClip::new(vec![[0, 1], [1, 1]], 0.1, false));
+        assert_eq!(player.current_clip(), walk);
+
+        assert_eq!(player.update(0.05), AnimationEvent::None);
+        assert_eq!(player.update(0.06), AnimationEvent::FrameChanged(1));
+        // Time left over carries into the next frames
+        assert_eq!(player.update(0.2), AnimationEvent::FrameChanged(0));
+        assert_eq!(player.frame(), 0);
+
+        player.play(attack);
+        assert_eq!(player.frame(), 0);
+        assert_eq!(player.update(0.1), AnimationEvent::FrameChanged(1));
+        assert_eq!(player.update(0.1), AnimationEvent::Finished);
+        assert!(player.is_finished());
+        assert_eq!(player.update(1.0), AnimationEvent::None);
+        let sprite = player.current_sprite().unwrap();
+        assert_eq!(sprite.sprite_spec.selected_tile, [1, 1]);
+
+        // Playing what's already playing doesn't start it over, unless it
+        // finished
+        player.play(attack);
+        assert!(!player.is_finished());
+        assert_eq!(player.frame(), 0);
+        assert_eq!(player.update(0.05), AnimationEvent::None);
+        player.play(attack);
+        assert_eq!(player.update(0.05), AnimationEvent::FrameChanged(1));
+    }
+
+    #[test]
+    fn switching_from_a_finished_clip_starts_over() {
+        let mut player = player();
+        let attack = player.add_clip(AnimationClip::new(vec![[0, 1], [1, 1]], 0.1, false));
+        let walk = player.add_clip(AnimationClip::new(vec![[0, 0], [1, 0], [2, 0]], 0.1, true));
+        player.play(attack);
+        assert_eq!(player.update(0.2), AnimationEvent::Finished);
+
+        player.switch_to(walk);
+        assert!(!player.is_finished());
+        assert_eq!(player.frame(), 0);
+        assert_eq!(player.update(0.1), AnimationEvent::FrameChanged(1));
+    }
+
+    #[test]
+    fn pausing_and_speed_scale_time() {
+        let mut player = player();
+        let right = player.add_clip(AnimationClip::new(vec![[0, 0], [1, 0], [2, 0]], 0.1, true));
+        let left = player.add_clip(AnimationClip::new(vec![[0, 1], [1, 1], [2, 1]], 0.1, true));
+        player.pause();
+        assert_eq!(player.update(1.0), AnimationEvent::None);
+        player.resume();
+
+        player.set_speed(2.0);
+        assert_eq!(player.update(0.05), AnimationEvent::FrameChanged(1));
+        player.set_speed(-1.0);
+        assert_eq!(player.speed(), 0.0);
+        assert_eq!(player.update(1.0), AnimationEvent::None);
+
+        // Turning around keeps the stride
+        player.switch_to(left);
+        assert_eq!(player.frame(), 1);
+        assert_eq!(player.current_clip(), left);
+        assert_ne!(player.current_clip(), right);
+        let sprite = player.current_sprite().unwrap();
+        assert_eq!(sprite.sprite_spec.selected_tile, [1, 1]);
+    }
+}
@@ -0,0 +1,202 @@
+//! Picking the graphics API to render with: the platform's primary one where
+//! it works, WebGPU in browsers and Vulkan, Metal or DX12 natively, falling
+//! back to GL.
+
+use wgpu::{Adapter, Device, Queue, Surface};
+
+/// Query parameter of the page's URL overriding the backend, e.g.
+/// `?backend=gl` to get around a broken driver
+#[cfg(target_arch = "wasm32")]
+pub const BACKEND_QUERY: &str = "backend";
+/// Environment variable overriding the backend natively
+#[cfg(not(target_arch = "wasm32"))]
+pub const BACKEND_ENV: &str = "WEBENGINE_BACKEND";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RendererBackend {
+    /// The primary backends, then GL if none of them has an adapter
+    #[default]
+    Auto,
+    /// Only the primary backends
+    Primary,
+    /// Only GL, WebGL2 in browsers
+    Gl,
+}
+
+impl RendererBackend {
+    /// From its name in an override, `auto`, `primary` or `gl`
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "auto" => Some(Self::Auto),
+            "primary" | "webgpu" => Some(Self::Primary),
+            "gl" | "webgl" | "webgl2" => Some(Self::Gl),
+            _ => None,
+        }
+    }
+
+    /// The backend set with `BACKEND_QUERY` or `BACKEND_ENV`, if any
+    pub fn from_override() -> Option<Self> {
+        #[cfg(target_arch = "wasm32")]
+        let name = {
+            let search = web_sys::window()?.location().search().ok()?;
+            search
+                .trim_start_matches('?')
+                .split('&')
+                .find_map(|pair| pair.strip_prefix(BACKEND_QUERY)?.strip_prefix('='))
+                .map(str::to_owned)
+        };
+        #[cfg(not(target_arch = "wasm32"))]
+        let name = std::env::var(BACKEND_ENV).ok();
+        Self::from_name(&name?)
+    }
+
+    /// The sets of backends to look for an adapter in, in order
+    pub fn attempts(self) -> &'static [wgpu::Backends] {
+        match self {
+            Self::Auto => &[wgpu::Backends::PRIMARY, wgpu::Backends::GL],
+            Self::Primary => &[wgpu::Backends::PRIMARY],
+            Self::Gl => &[wgpu::Backends::GL],
+        }
+    }
+}
+
+/// An instance over `backends`, leaving WebGPU out in browsers without it
+pub async fn create_instance(backends: wgpu::Backends) -> wgpu::Instance {
+    wgpu::util::new_instance_with_webgpu_detection(&wgpu::InstanceDescriptor {
+        backends,
+        ..Default::default()
+    })
+    .await
+}
+
+/// What the renderer needs of a device on `adapter`. WebGL2 can do the
+/// least, so GL sticks to its limits; elsewhere textures can be as big as
+/// the adapter allows.
+pub fn required_limits(adapter: &Adapter) -> wgpu::Limits {
+    match adapter.get_info().backend {
+        wgpu::Backend::Gl => wgpu::Limits::downlevel_webgl2_defaults(),
+        _ => wgpu::Limits::downlevel_defaults().using_resolution(adapter.limits()),
+    }
+}
+
+/// Why the renderer couldn't start, shown to the player instead of a game
+#[derive(Debug)]
+pub enum RendererInitError {
+    /// No backend tried could render to the window
+    Surface(wgpu::CreateSurfaceError),
+    /// No backend tried had an adapter, not even a software one
+    NoAdapter,
+    /// Every adapter found refused to create a device
+    Device(wgpu::RequestDeviceError),
+    /// The window's surface has no format to present in
+    NoSurfaceFormat,
+}
+
+impl std::fmt::Display for RendererInitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Surface(err) => write!(f, "Couldn't draw to the window: {}", err),
+            Self::NoAdapter => write!(f, "No compatible graphics adapter was found"),
+            Self::Device(err) => write!(f, "The graphics adapter couldn't be used: {}", err),
+            Self::NoSurfaceFormat => write!(f, "The window can't show any known color format"),
+        }
+    }
+}
+
+impl std::error::Error for RendererInitError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Surface(err) => Some(err),
+            Self::Device(err) => Some(err),
+            Self::NoAdapter | Self::NoSurfaceFormat => None,
+        }
+    }
+}
+
+impl RendererInitError {
+    /// Whether `self` says more about what went wrong than `other`, so it's
+    /// the one reported when every attempt fails
+    pub fn is_more_specific_than(&self, other: &Self) -> bool {
+        !matches!(self, Self::NoAdapter) || matches!(other, Self::NoAdapter)
+    }
+}
+
+/// An adapter of `instance` and a device on it, compatible with `surface` if
+/// there is one. Falls back to a software adapter when the hardware one is
+/// missing or can't create a device.
+pub async fn request_adapter_and_device(
+    instance: &wgpu::Instance,
+    surface: Option<&Surface<'_>>,
+) -> Result<(Adapter, Device, Queue), RendererInitError> {
+    let mut error = RendererInitError::NoAdapter;
+    for force_fallback_adapter in [false, true] {
+        let adapter = instance
+            .request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::default(),
+                compatible_surface: surface,
+                force_fallback_adapter,
+            })
+            .await;
+        let Ok(adapter) = adapter else {
+            continue;
+        };
+        match request_device(&adapter).await {
+            Ok((device, queue)) => return Ok((adapter, device, queue)),
+            Err(err) => {
+                log::warn!("No device on {:?}: {}", adapter.get_info().name, err);
+                error = RendererInitError::Device(err);
+            }
+        }
+    }
+    Err(error)
+}
+
+pub async fn request_device(
+    adapter: &Adapter,
+) -> Result<(Device, Queue), wgpu::RequestDeviceError> {
+    adapter
+        .request_device(&wgpu::DeviceDescriptor {
+            label: None,
+            required_features: wgpu::Features::empty(),
+            required_limits: required_limits(adapter),
+            memory_hints: wgpu::MemoryHints::Performance,
+            trace: wgpu::Trace::default(),
+        })
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backends_by_name_fall_back_to_gl() {
+        assert_eq!(
+            RendererBackend::from_name(" GL "),
+            Some(RendererBackend::Gl)
+        );
+        assert_eq!(
+            RendererBackend::from_name("webgpu"),
+            Some(RendererBackend::Primary)
+        );
+        assert_eq!(RendererBackend::from_name("vulkan"), None);
+        assert_eq!(
+            RendererBackend::default().attempts(),
+            [wgpu::Backends::PRIMARY, wgpu::Backends::GL]
+        );
+        assert_eq!(RendererBackend::Gl.attempts(), [wgpu::Backends::GL]);
+    }
+
+    #[test]
+    fn failures_without_an_adapter_are_least_specific() {
+        let no_adapter = RendererInitError::NoAdapter;
+        let no_format = RendererInitError::NoSurfaceFormat;
+        assert!(no_format.is_more_specific_than(&no_adapter));
+        assert!(!no_adapter.is_more_specific_than(&no_format));
+        assert!(no_adapter.is_more_specific_than(&RendererInitError::NoAdapter));
+        assert_eq!(
+            no_adapter.to_string(),
+            "No compatible graphics adapter was found"
+        );
+    }
+}
@@ -0,0 +1,180 @@
+//! Fonts drawn from a grid of glyph tiles instead of outlines, for UI text
+//! that stays crisp at the internal resolution, see
+//! `RenderingSystem::create_bitmap_text_buffer`.
+
+use std::collections::HashMap;
+
+use glyphon::cosmic_text::Align;
+
+use crate::renderer::{
+    gizmo::{GizmoSprite, GizmoSpriteSheet},
+    markup::MarkupSpan,
+};
+
+pub struct BitmapFont {
+    sheet: GizmoSpriteSheet,
+    glyphs: HashMap<char, [u32; 2]>,
+    glyph_size: [f32; 2],
+    // Pixels from one glyph to the next, and from one line to the next
+    advance: f32,
+    line_height: f32,
+}
+
+/// A glyph of laid out text, `position` pixels from the text's top-left
+/// corner
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PlacedGlyph {
+    pub tile: [u32; 2],
+    pub position: [f32; 2],
+    /// sRGB, or the color the text is drawn with if `None`
+    pub color: Option<[u8; 4]>,
+}
+
+impl BitmapFont {
+    /// A font with a glyph per tile of `sheet` for each of `chars`, from the
+    /// top-left tile, left to right and row by row. Glyphs are as far apart
+    /// as the tiles are big.
+    #[cfg(test)]
+    pub fn from_grid(sheet: GizmoSpriteSheet, chars: &str) -> Self {
+        let [columns, rows] = sheet.num_tiles();
+        let glyphs = chars
+            .chars()
+            .zip(0..columns * rows)
+            .map(|(c, i)| (c, [i % columns, i / columns]))
+            .collect();
+        let glyph_size = sheet
+            .get_sprite([0, 0])
+            .map_or([0.0; 2], |sprite| sprite.pixel_size());
+        Self {
+            sheet,
+            glyphs,
+            glyph_size,
+            advance: glyph_size[0],
+            line_height: glyph_size[1],
+        }
+    }
+
+    /// Places glyphs `advance` pixels apart and lines `line_height` pixels
+    /// apart, e.g. 1 more than the tiles for a gap between them
+    #[cfg(test)]
+    pub fn with_spacing(mut self, advance: f32, line_height: f32) -> Self {
+        self.advance = advance;
+        self.line_height = line_height;
+        self
+    }
+
+    /// Pixels each glyph is drawn over
+    pub fn glyph_size(&self) -> [f32; 2] {
+        self.glyph_size
+    }
+
+    #[cfg(test)]
+    pub fn line_height(&self) -> f32 {
+        self.line_height
+    }
+
+    /// The glyph's sprite, `None` if `tile` isn't on the sheet
+    pub fn sprite(&self, tile: [u32; 2]) -> Option<GizmoSprite<'_>> {
+        self.sheet.get_sprite(tile)
+    }
+
+    /// Lays `spans` out in lines broken at `\n`, each aligned by `align`
+    /// within `width` pixels. Characters the font doesn't have leave a gap.
+    /// Only the spans' colors apply, bitmap glyphs don't scale or embolden.
+    pub fn layout(&self, spans: &[MarkupSpan], width: f32, align: Align) -> Vec<PlacedGlyph> {
+        let mut glyphs = Vec::new();
+        let mut line_start = 0;
+        let mut column = 0;
+        let mut line = 0;
+        for span in spans {
+            for c in span.text.chars() {
+                if c == '\n' {
+                    self.align_line(&mut glyphs[line_start..], column, width, align);
+                    line_start = glyphs.len();
+                    column = 0;
+                    line += 1;
+                    continue;
+                }
+                if let Some(tile) = self.glyphs.get(&c) {
+                    glyphs.push(PlacedGlyph {
+                        tile: *tile,
+                        position: [column as f32 * self.advance, line as f32 * self.line_height],
+                        color: span.color,
+                    });
+                }
+                column += 1;
+            }
+        }
+        self.align_line(&mut glyphs[line_start..], column, width, align);
+        glyphs
+    }
+
+    /// Moves the glyphs of a line `columns` glyphs long over for `align`,
+    /// by whole pixels
+    fn align_line(&self, glyphs: &mut [PlacedGlyph], columns: usize, width: f32, align: Align) {
+        let left_over = width - columns as f32 * self.advance;
+        let offset = match align {
+            Align::Right | Align::End => left_over,
+            Align::Center => left_over * 0.5,
+            Align::Left | Align::Justified => 0.0,
+        };
+        for glyph in glyphs {
+            glyph.position[0] += offset.floor();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::renderer::{
+        gizmo::{GizmoBindableTexture, TextureAlpha, TextureSampling},
+        markup::parse_markup,
+    };
+
+    fn digits() -> BitmapFont {
+        let texture = GizmoBindableTexture {
+            layer: 0,
+            width: 20,
+            height: 14,
+            alpha: TextureAlpha::Straight,
+            sampling: TextureSampling::default(),
+        };
+        let sheet = GizmoSpriteSheet::new(Rc::new(texture), [0.0, 0.0], [1.0, 1.0], [5, 2]);
+        BitmapFont::from_grid(sheet, "0123456789")
+    }
+
+    #[test]
+    fn glyphs_come_from_the_grid_in_order() {
+        let font = digits();
+        assert_eq!(font.glyph_size(), [4.0, 7.0]);
+        let glyphs = font.layout(&parse_markup("07\n5"), 20.0, Align::Left);
+        let tiles: Vec<_> = glyphs.iter().map(|glyph| glyph.tile).collect();
+        assert_eq!(tiles, [[0, 0], [2, 1], [0, 1]]);
+        let positions: Vec<_> = glyphs.iter().map(|glyph| glyph.position).collect();
+        assert_eq!(positions, [[0.0, 0.0], [4.0, 0.0], [0.0, 7.0]]);
+    }
+
+    #[test]
+    fn lines_align_by_whole_pixels() {
+        let font = digits().with_spacing(5.0, 8.0);
+        let glyphs = font.layout(
+            &parse_markup("1[color=yellow]2[/color] 3\n4"),
+            20.0,
+            Align::Right,
+        );
+        // The space leaves a gap, and the last glyph ends at the right edge
+        let positions: Vec<_> = glyphs.iter().map(|glyph| glyph.position).collect();
+        assert_eq!(
+            positions,
+            [[0.0, 0.0], [5.0, 0.0], [15.0, 0.0], [15.0, 8.0]]
+        );
+        assert_eq!(glyphs[1].color, Some([255, 255, 0, 255]));
+        assert_eq!(glyphs[2].color, None);
+
+        let centered = font.layout(&parse_markup("12"), 15.0, Align::Center);
+        assert_eq!(centered[0].position, [2.0, 0.0]);
+    }
+}
@@ -1,17 +1,179 @@
-use std::{mem, rc::Rc};
+use std::{cell::RefCell, collections::HashMap, mem, rc::Rc};
 
 use wgpu::{
     BindGroup, BindGroupLayout, BindGroupLayoutEntry, Buffer, Device, Queue, RenderPipeline,
     SurfaceConfiguration, Texture,
 };
 
-use crate::{geometry::Transform, renderer::EngineColor};
+use crate::{
+    geometry::Transform,
+    renderer::{material, mipmap::MipmapGenerator, EngineColor},
+};
+
+/// Side length of every layer in the shared sprite texture array. Textures
+/// smaller than this occupy the top-left corner of their layer.
+pub const TEXTURE_ARRAY_LAYER_SIZE: u32 = 512;
+
+// GL backends treat single-layer textures as plain 2D textures, so the array
+// always starts with more than one layer.
+const TEXTURE_ARRAY_INITIAL_LAYERS: u32 = 4;
+
+// Every layer has the full chain of mips, down to a single pixel
+const TEXTURE_ARRAY_MIP_LEVELS: u32 = TEXTURE_ARRAY_LAYER_SIZE.ilog2() + 1;
+
+// Sizes of the uniforms as declared in shader.wgsl. The bind group layouts
+// ask for them, so a buffer that's too small fails validation up front, and
+// the structs written into the buffers are checked against them below.
+/// `Transform`, a `mat4x4<f32>`
+const TRANSFORM_UNIFORM_SIZE: u64 = 64;
+/// `EngineColor`, a `vec4<f32>`
+const COLOR_UNIFORM_SIZE: u64 = 16;
+/// `SpriteSpec`, five `vec4`s
+pub const SPRITE_SPEC_UNIFORM_SIZE: u64 = 80;
+/// `OutputSettings`, a `vec4<f32>`
+const OUTPUT_UNIFORM_SIZE: u64 = 16;
+/// `MaterialParams`, a `vec4<f32>`
+const MATERIAL_UNIFORM_SIZE: u64 = 16;
+/// `DrawSettings`, a `vec4<u32>`
+const DRAW_SETTINGS_UNIFORM_SIZE: u64 = 16;
+
+/// Gizmo draws whose uniforms fit in the pipeline's buffers at once, a slot
+/// each, so they can be submitted together
+pub const DRAWS_PER_BATCH: u32 = 256;
+
+/// Colors in a palette, as many as an indexed sprite's red channel can pick
+pub const PALETTE_SIZE: u32 = 256;
+/// Palettes that fit in the palette texture, a row each
+pub const MAX_PALETTES: u32 = 64;
+
+/// Format of the depth buffer the depth tested pipelines draw with
+pub const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth24Plus;
+
+const _: () = assert!(mem::size_of::<[[f32; 4]; 4]>() as u64 == TRANSFORM_UNIFORM_SIZE);
+const _: () = assert!(mem::size_of::<EngineColor>() as u64 == COLOR_UNIFORM_SIZE);
+const _: () = assert!(mem::size_of::<SpriteSpecPadded>() as u64 == SPRITE_SPEC_UNIFORM_SIZE);
+const _: () = assert!(mem::size_of::<[f32; 4]>() as u64 == OUTPUT_UNIFORM_SIZE);
+const _: () = assert!(mem::size_of::<[f32; 4]>() as u64 == MATERIAL_UNIFORM_SIZE);
+const _: () = assert!(mem::size_of::<[u32; 4]>() as u64 == DRAW_SETTINGS_UNIFORM_SIZE);
+
+const SHADER_SOURCE: &str = include_str!("../assets/shader.wgsl");
+
+/// How the color channels of a texture relate to its alpha.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextureAlpha {
+    /// Color is stored as is, and blended with `BlendState::ALPHA_BLENDING`.
+    Straight,
+    /// Color is stored already multiplied by alpha, so filtering doesn't
+    /// bleed the color of transparent pixels into the edges of a sprite.
+    Premultiplied,
+}
+
+/// How a texture's texels are blended together when it's drawn at another
+/// size than its own.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TextureFilter {
+    /// Each pixel takes the closest texel, so pixel art stays crisp.
+    #[default]
+    Nearest,
+    /// Each pixel blends the closest texels, for smooth images like
+    /// backgrounds. Sheets of tiles may bleed into each other a little.
+    Linear,
+}
+
+/// What's sampled past the edges of a texture.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TextureWrap {
+    /// The texels at the edge.
+    #[default]
+    ClampToEdge,
+    /// The texture again, e.g. a uv rect from 0 to 3 fits three of it
+    /// side by side, for backdrops and parallax layers.
+    Repeat,
+}
 
+/// How sprites sample a texture.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TextureSampling {
+    pub filter: TextureFilter,
+    pub wrap: TextureWrap,
+    /// Whether the smaller mip levels of the texture are generated, so it
+    /// doesn't shimmer when it's drawn scaled down. Only takes effect when
+    /// the texture is uploaded.
+    pub mipmaps: bool,
+}
+
+/// A texture living in one layer of the pipeline's shared texture array.
 pub struct GizmoBindableTexture {
-    pub texture: wgpu::Texture,
-    pub view: wgpu::TextureView,
-    pub sampler: wgpu::Sampler,
-    pub bind_group: BindGroup,
+    pub layer: u32,
+    pub width: u32,
+    pub height: u32,
+    pub alpha: TextureAlpha,
+    pub sampling: TextureSampling,
+}
+
+impl GizmoBindableTexture {
+    /// Portion of the array layer covered by this texture, in UV units.
+    pub fn uv_extent(&self) -> [f32; 2] {
+        [
+            self.width as f32 / TEXTURE_ARRAY_LAYER_SIZE as f32,
+            self.height as f32 / TEXTURE_ARRAY_LAYER_SIZE as f32,
+        ]
+    }
+}
+
+struct GizmoTextureArray {
+    texture: Texture,
+    bind_group: BindGroup,
+    num_layers: u32,
+    used_layers: u32,
+    // Layers below `used_layers` given back with `free_texture`
+    free_layers: Vec<u32>,
+}
+
+/// Why a texture couldn't be put in the texture array
+#[derive(Debug)]
+pub enum TextureError {
+    Decode(image::ImageError),
+    /// Bigger than a layer, which is `TEXTURE_ARRAY_LAYER_SIZE` square
+    TooLarge {
+        width: u32,
+        height: u32,
+    },
+    /// Every layer the device allows is taken
+    ArrayFull {
+        layers: u32,
+    },
+}
+
+impl std::fmt::Display for TextureError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Decode(err) => write!(f, "Couldn't decode the image: {}", err),
+            Self::TooLarge { width, height } => write!(
+                f,
+                "Texture of size {}x{} does not fit in a {}x{} texture array layer",
+                width, height, TEXTURE_ARRAY_LAYER_SIZE, TEXTURE_ARRAY_LAYER_SIZE
+            ),
+            Self::ArrayFull { layers } => {
+                write!(f, "Gizmo texture array is full ({} layers)", layers)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TextureError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Decode(err) => Some(err),
+            Self::TooLarge { .. } | Self::ArrayFull { .. } => None,
+        }
+    }
+}
+
+impl From<image::ImageError> for TextureError {
+    fn from(err: image::ImageError) -> Self {
+        Self::Decode(err)
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -20,6 +182,44 @@ pub struct GizmoSprite<'a> {
     pub sprite_spec: SpriteSpec,
 }
 
+impl<'a> GizmoSprite<'a> {
+    /// The part of `texture` between `uv_min` and `uv_max`, for sprites
+    /// that don't sit on a regular grid, like the ones in a packed atlas
+    #[cfg(test)]
+    pub fn from_uv_rect(
+        texture: &'a GizmoBindableTexture,
+        uv_min: [f32; 2],
+        uv_max: [f32; 2],
+    ) -> Self {
+        Self {
+            texture,
+            sprite_spec: SpriteSpec {
+                use_texture: 1,
+                region_start: [0.0, 0.0],
+                region_end: [1.0, 1.0],
+                num_tiles: [1, 1],
+                selected_tile: [0, 0],
+                uv_rect: Some([uv_min, uv_max]),
+            },
+        }
+    }
+
+    /// Size of the sprite in the pixels of its texture
+    #[cfg(test)]
+    pub fn pixel_size(&self) -> [f32; 2] {
+        let spec = &self.sprite_spec;
+        let [start, end] = spec.uv_rect.unwrap_or([spec.region_start, spec.region_end]);
+        let tiles = match spec.uv_rect {
+            Some(_) => [1, 1],
+            None => spec.num_tiles,
+        };
+        [
+            (end[0] - start[0]) * self.texture.width as f32 / tiles[0] as f32,
+            (end[1] - start[1]) * self.texture.height as f32 / tiles[1] as f32,
+        ]
+    }
+}
+
 #[repr(C)]
 #[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct Vertex {
@@ -54,6 +254,256 @@ impl Vertex {
     }
 }
 
+/// A filled arc as a triangle fan around `center`, covering `sweep` radians
+/// from `start_angle`. Angles grow from +x towards +y, which is clockwise on
+/// screen. A full turn takes `segments` triangles and shorter sweeps take
+/// as many as their share of it, at least one.
+#[cfg(test)]
+pub fn arc_geometry(
+    center: [f32; 2],
+    radius: f32,
+    start_angle: f32,
+    sweep: f32,
+    segments: u32,
+) -> (Vec<Vertex>, Vec<u16>) {
+    let turns = (sweep.abs() / std::f32::consts::TAU).min(1.0);
+    let count = ((segments as f32 * turns).ceil() as u32).max(1);
+    let sweep = sweep.clamp(-std::f32::consts::TAU, std::f32::consts::TAU);
+
+    let vertex = |offset: [f32; 2]| Vertex {
+        position: [
+            center[0] + offset[0] * radius,
+            center[1] + offset[1] * radius,
+            0.0,
+        ],
+        color: [1.0, 1.0, 1.0],
+        uv: [0.5 + offset[0] * 0.5, 0.5 + offset[1] * 0.5],
+    };
+    let mut vertices = vec![vertex([0.0, 0.0])];
+    for i in 0..=count {
+        let angle = start_angle + sweep * i as f32 / count as f32;
+        vertices.push(vertex([angle.cos(), angle.sin()]));
+    }
+
+    // Wound like the quad, so they face the camera the same way
+    let mut indices = Vec::with_capacity(count as usize * 3);
+    for i in 1..=count as u16 {
+        if sweep >= 0.0 {
+            indices.extend([0, i + 1, i]);
+        } else {
+            indices.extend([0, i, i + 1]);
+        }
+    }
+    (vertices, indices)
+}
+
+/// A filled ring around `center`, between `inner_radius` and `outer_radius`,
+/// as `segments` quads. An inner radius of 0 fills the whole circle.
+pub fn ring_geometry(
+    center: [f32; 2],
+    inner_radius: f32,
+    outer_radius: f32,
+    segments: u32,
+) -> (Vec<Vertex>, Vec<u16>) {
+    let segments = segments.max(3);
+    let vertex = |angle: f32, radius: f32| Vertex {
+        position: [
+            center[0] + angle.cos() * radius,
+            center[1] + angle.sin() * radius,
+            0.0,
+        ],
+        color: [1.0, 1.0, 1.0],
+        uv: [
+            0.5 + angle.cos() * radius / outer_radius * 0.5,
+            0.5 + angle.sin() * radius / outer_radius * 0.5,
+        ],
+    };
+    // Inner and outer vertex of each spoke, the last one closing the ring
+    let mut vertices = Vec::with_capacity(segments as usize * 2 + 2);
+    for i in 0..=segments {
+        let angle = std::f32::consts::TAU * i as f32 / segments as f32;
+        vertices.push(vertex(angle, inner_radius));
+        vertices.push(vertex(angle, outer_radius));
+    }
+
+    // Wound like the arcs
+    let mut indices = Vec::with_capacity(segments as usize * 6);
+    for i in 0..segments as u16 {
+        let (inner, outer) = (i * 2, i * 2 + 1);
+        let (next_inner, next_outer) = (inner + 2, outer + 2);
+        indices.extend([inner, next_outer, outer, inner, next_inner, next_outer]);
+    }
+    (vertices, indices)
+}
+
+/// The triangle `a`, `b`, `c` wound like the quad, whichever way round its
+/// corners are given, so the back face culling keeps it
+#[cfg(test)]
+fn wound_like_quad(vertices: &[Vertex], [a, b, c]: [u16; 3]) -> [u16; 3] {
+    let corner = |i: u16| {
+        let [x, y, _] = vertices[i as usize].position;
+        glam::Vec2::new(x, y)
+    };
+    let (a_corner, b_corner, c_corner) = (corner(a), corner(b), corner(c));
+    if (b_corner - a_corner).perp_dot(c_corner - a_corner) > 0.0 {
+        [a, c, b]
+    } else {
+        [a, b, c]
+    }
+}
+
+#[cfg(test)]
+fn flat_vertex(position: glam::Vec2, uv: [f32; 2]) -> Vertex {
+    Vertex {
+        position: [position.x, position.y, 0.0],
+        color: [1.0, 1.0, 1.0],
+        uv,
+    }
+}
+
+/// A `thickness` wide segment from `start` to `end`, as a quad
+#[cfg(test)]
+pub fn line_geometry(start: [f32; 2], end: [f32; 2], thickness: f32) -> (Vec<Vertex>, Vec<u16>) {
+    let (start, end) = (glam::Vec2::from(start), glam::Vec2::from(end));
+    let side = (end - start).normalize_or_zero().perp() * thickness / 2.0;
+    let vertices = vec![
+        flat_vertex(start + side, [0.0, 0.0]),
+        flat_vertex(start - side, [0.0, 1.0]),
+        flat_vertex(end - side, [1.0, 1.0]),
+        flat_vertex(end + side, [1.0, 0.0]),
+    ];
+    let mut indices = Vec::with_capacity(6);
+    indices.extend(wound_like_quad(&vertices, [0, 1, 2]));
+    indices.extend(wound_like_quad(&vertices, [3, 0, 2]));
+    (vertices, indices)
+}
+
+/// A filled convex polygon with corners `points`, as a triangle fan
+#[cfg(test)]
+pub fn polygon_geometry(points: &[[f32; 2]]) -> (Vec<Vertex>, Vec<u16>) {
+    if points.len() < 3 {
+        return (Vec::new(), Vec::new());
+    }
+    let corners: Vec<glam::Vec2> = points.iter().copied().map(glam::Vec2::from).collect();
+    // The uvs spread the texture over the bounding box
+    let min = corners.iter().copied().fold(corners[0], glam::Vec2::min);
+    let max = corners.iter().copied().fold(corners[0], glam::Vec2::max);
+    let extent = (max - min).max(glam::Vec2::splat(f32::EPSILON));
+    let vertices: Vec<Vertex> = corners
+        .iter()
+        .map(|corner| flat_vertex(*corner, ((*corner - min) / extent).into()))
+        .collect();
+    let mut indices = Vec::with_capacity((points.len() - 2) * 3);
+    for i in 1..points.len() as u16 - 1 {
+        indices.extend(wound_like_quad(&vertices, [0, i, i + 1]));
+    }
+    (vertices, indices)
+}
+
+/// The outline of the polygon with corners `points`, `thickness` wide and
+/// centered on its edges, with mitered corners. Open outlines stop at the
+/// first and last points instead of closing back to the first.
+#[cfg(test)]
+pub fn outline_geometry(
+    points: &[[f32; 2]],
+    thickness: f32,
+    closed: bool,
+) -> (Vec<Vertex>, Vec<u16>) {
+    let corners: Vec<glam::Vec2> = points.iter().copied().map(glam::Vec2::from).collect();
+    let count = corners.len();
+    if count < 2 {
+        return (Vec::new(), Vec::new());
+    }
+    let half = thickness / 2.0;
+    let direction = |from: usize, to: usize| (corners[to] - corners[from]).normalize_or_zero();
+
+    // An inner and outer vertex per corner, pushed out along the miter
+    let mut vertices = Vec::with_capacity(count * 2);
+    for (i, corner) in corners.iter().enumerate() {
+        let before = (i > 0 || closed).then(|| direction((i + count - 1) % count, i));
+        let after = (i + 1 < count || closed).then(|| direction(i, (i + 1) % count));
+        let (normal, length) = match (before, after) {
+            (Some(before), Some(after)) => {
+                let miter = (before.perp() + after.perp()).normalize_or(after.perp());
+                // Sharp corners would spike out far, so they're capped at 4
+                // times the width
+                let length = half / miter.dot(after.perp()).max(0.25);
+                (miter, length)
+            }
+            (Some(edge), None) | (None, Some(edge)) => (edge.perp(), half),
+            (None, None) => (glam::Vec2::ZERO, half),
+        };
+        let t = i as f32 / (count - 1) as f32;
+        vertices.push(flat_vertex(*corner - normal * length, [t, 0.0]));
+        vertices.push(flat_vertex(*corner + normal * length, [t, 1.0]));
+    }
+
+    let edges = if closed { count } else { count - 1 };
+    let mut indices = Vec::with_capacity(edges * 6);
+    for i in 0..edges {
+        let (inner, outer) = (i as u16 * 2, i as u16 * 2 + 1);
+        let next = ((i + 1) % count) as u16 * 2;
+        let (next_inner, next_outer) = (next, next + 1);
+        indices.extend(wound_like_quad(&vertices, [inner, next_outer, outer]));
+        indices.extend(wound_like_quad(&vertices, [inner, next_inner, next_outer]));
+    }
+    (vertices, indices)
+}
+
+/// A rectangle from `min` to `max` as a 3 by 3 grid of quads, for a sprite
+/// with `uv_border` of it along each side kept at `border` wide, and the rest
+/// stretched in between. Borders wider than half the rectangle shrink to fit.
+#[cfg(test)]
+pub fn nine_slice_geometry(
+    min: [f32; 2],
+    max: [f32; 2],
+    border: [f32; 2],
+    uv_border: [f32; 2],
+) -> (Vec<Vertex>, Vec<u16>) {
+    // Where the grid lines are along one axis, on screen and in the sprite
+    let lines = |axis: usize| {
+        let half = ((max[axis] - min[axis]) / 2.0).max(0.0);
+        let border = border[axis].min(half);
+        [
+            (min[axis], 0.0),
+            (min[axis] + border, uv_border[axis]),
+            (max[axis] - border, 1.0 - uv_border[axis]),
+            (max[axis], 1.0),
+        ]
+    };
+    let (columns, rows) = (lines(0), lines(1));
+
+    let mut vertices = Vec::with_capacity(16);
+    for (y, v) in rows {
+        for (x, u) in columns {
+            vertices.push(Vertex {
+                position: [x, y, 0.0],
+                color: [1.0, 1.0, 1.0],
+                uv: [u, v],
+            });
+        }
+    }
+
+    // Wound like the quad
+    let mut indices = Vec::with_capacity(9 * 6);
+    for row in 0..3u16 {
+        for column in 0..3u16 {
+            let top_left = row * 4 + column;
+            let (bottom_left, top_right) = (top_left + 4, top_left + 1);
+            let bottom_right = bottom_left + 1;
+            indices.extend([
+                top_left,
+                bottom_left,
+                bottom_right,
+                top_right,
+                top_left,
+                bottom_right,
+            ]);
+        }
+    }
+    (vertices, indices)
+}
+
 //#[repr(C)]
 //#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
 #[derive(Clone, Copy)]
@@ -63,32 +513,57 @@ pub struct SpriteSpec {
     pub region_end: [f32; 2],
     pub num_tiles: [u32; 2],
     pub selected_tile: [u32; 2],
+    /// `uv_min` and `uv_max` of the sprite within the texture. When set, it's
+    /// sampled directly and the grid above is ignored.
+    pub uv_rect: Option<[[f32; 2]; 2]>,
 }
 
 #[repr(C)]
 #[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct SpriteSpecPadded {
-    pub use_texture_and_padding: [u32; 4], // use_texture in [0], rest unused
-    pub region_start_and_end: [f32; 4],    // start in [0,1], end in [2,3]
+    pub use_texture_and_padding: [u32; 4], // use_texture in [0], array layer in [1], premultiplied in [2], uv rect in [3]
+    pub region_start_and_end: [f32; 4],    // start in [0,1], end in [2,3], or uv_min and uv_max
     pub tiles_info: [u32; 4],              // num_tiles in [0,1], selected in [2,3]
+    pub sampling_info: [u32; 4],           // linear in [0], repeat in [1], mipmapped in [2]
+    pub texture_extent: [f32; 4],          // uv extent of the texture in its layer in [0,1]
+}
+
+impl SpriteSpecPadded {
+    /// Pads the spec and remaps its region into the array layer of `texture`.
+    pub fn for_texture(spec: SpriteSpec, texture: &GizmoBindableTexture) -> Self {
+        let [u, v] = texture.uv_extent();
+        let mut padded = Self::from(spec);
+        padded.use_texture_and_padding[1] = texture.layer;
+        padded.use_texture_and_padding[2] = (texture.alpha == TextureAlpha::Premultiplied) as u32;
+        let sampling = texture.sampling;
+        padded.sampling_info = [
+            (sampling.filter == TextureFilter::Linear) as u32,
+            (sampling.wrap == TextureWrap::Repeat) as u32,
+            sampling.mipmaps as u32,
+            0,
+        ];
+        padded.texture_extent = [u, v, 0.0, 0.0];
+        for (i, coordinate) in padded.region_start_and_end.iter_mut().enumerate() {
+            *coordinate *= if i % 2 == 0 { u } else { v };
+        }
+        padded
+    }
 }
 
 impl From<SpriteSpec> for SpriteSpecPadded {
     fn from(spec: SpriteSpec) -> Self {
+        let [start, end] = spec.uv_rect.unwrap_or([spec.region_start, spec.region_end]);
         Self {
-            use_texture_and_padding: [spec.use_texture, 0, 0, 0],
-            region_start_and_end: [
-                spec.region_start[0],
-                spec.region_start[1],
-                spec.region_end[0],
-                spec.region_end[1],
-            ],
+            use_texture_and_padding: [spec.use_texture, 0, 0, spec.uv_rect.is_some() as u32],
+            region_start_and_end: [start[0], start[1], end[0], end[1]],
             tiles_info: [
                 spec.num_tiles[0],
                 spec.num_tiles[1],
                 spec.selected_tile[0],
                 spec.selected_tile[1],
             ],
+            sampling_info: [0; 4],
+            texture_extent: [1.0, 1.0, 0.0, 0.0],
         }
     }
 }
@@ -116,7 +591,18 @@ impl GizmoSpriteSheet {
         }
     }
 
-    pub fn get_sprite(&self, selected_tile: [u32; 2]) -> Option<GizmoSprite> {
+    /// Columns and rows of tiles
+    pub fn num_tiles(&self) -> [u32; 2] {
+        self.num_tiles
+    }
+
+    /// The texture, unless other sheets still share it, e.g. to free it
+    #[cfg(any(test, all(debug_assertions, target_arch = "wasm32")))]
+    pub fn into_texture(self) -> Option<GizmoBindableTexture> {
+        Rc::try_unwrap(self.texture).ok()
+    }
+
+    pub fn get_sprite(&self, selected_tile: [u32; 2]) -> Option<GizmoSprite<'_>> {
         if selected_tile[0] >= self.num_tiles[0] || selected_tile[1] >= self.num_tiles[1] {
             return None; // Invalid tile selection
         }
@@ -128,39 +614,346 @@ impl GizmoSpriteSheet {
                 region_end: self.region_end,
                 num_tiles: self.num_tiles,
                 selected_tile,
+                uv_rect: None,
             },
         })
     }
 }
 
+/// One tile of a tilemap, an instance of the quad at `position`. Empty cells
+/// have `EMPTY_TILE` for a tile.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct TileInstance {
+    pub position: [f32; 2],
+    pub tile: [u32; 2],
+}
+
+pub const EMPTY_TILE: [u32; 2] = [u32::MAX, u32::MAX];
+
+impl TileInstance {
+    fn desc() -> wgpu::VertexBufferLayout<'static> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<TileInstance>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Instance,
+            attributes: &[
+                wgpu::VertexAttribute {
+                    offset: 0,
+                    shader_location: 3,
+                    format: wgpu::VertexFormat::Float32x2,
+                },
+                wgpu::VertexAttribute {
+                    offset: std::mem::size_of::<[f32; 2]>() as wgpu::BufferAddress,
+                    shader_location: 4,
+                    format: wgpu::VertexFormat::Uint32x2,
+                },
+            ],
+        }
+    }
+}
+
+/// How a draw's colors combine with what's already in the frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum BlendMode {
+    /// Covers what's under it as much as it's opaque
+    #[default]
+    Alpha,
+    /// Adds its color, scaled by its alpha, e.g. glows and sparks
+    Additive,
+    /// Multiplies what's under it by its color, as much as it's opaque,
+    /// e.g. shadows
+    Multiply,
+}
+
+impl BlendMode {
+    /// The modes besides `Alpha` blend premultiplied colors, so the shader
+    /// premultiplies sprites with straight alpha for them
+    fn premultiplies(self) -> bool {
+        self != BlendMode::Alpha
+    }
+
+    fn blend_state(self, alpha: TextureAlpha) -> wgpu::BlendState {
+        // Only covering the frame changes its alpha
+        let keep_alpha = wgpu::BlendComponent {
+            src_factor: wgpu::BlendFactor::Zero,
+            dst_factor: wgpu::BlendFactor::One,
+            operation: wgpu::BlendOperation::Add,
+        };
+        match (self, alpha) {
+            (BlendMode::Alpha, TextureAlpha::Straight) => wgpu::BlendState::ALPHA_BLENDING,
+            (BlendMode::Alpha, TextureAlpha::Premultiplied) => {
+                wgpu::BlendState::PREMULTIPLIED_ALPHA_BLENDING
+            }
+            (BlendMode::Additive, _) => wgpu::BlendState {
+                color: wgpu::BlendComponent {
+                    src_factor: wgpu::BlendFactor::One,
+                    dst_factor: wgpu::BlendFactor::One,
+                    operation: wgpu::BlendOperation::Add,
+                },
+                alpha: keep_alpha,
+            },
+            // The frame times the color where it's opaque, untouched where
+            // it's transparent
+            (BlendMode::Multiply, _) => wgpu::BlendState {
+                color: wgpu::BlendComponent {
+                    src_factor: wgpu::BlendFactor::Dst,
+                    dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
+                    operation: wgpu::BlendOperation::Add,
+                },
+                alpha: keep_alpha,
+            },
+        }
+    }
+}
+
+/// What a single gizmo draw sets the shader's uniforms to
+pub struct DrawUniforms {
+    pub transform: Transform,
+    pub color: EngineColor,
+    pub sprite_spec: SpriteSpecPadded,
+    pub material_params: [f32; 4],
+    pub draw_settings: [u32; 4],
+}
+
+/// `DrawSettings` for a draw looking its colors up in `palette`, or drawing
+/// its texels as they are with `None`, and blending with `blend`
+pub fn draw_settings(palette: Option<&PaletteHandle>, blend: BlendMode) -> [u32; 4] {
+    let (indexed, row) = match palette {
+        Some(palette) => (1, palette.index as u32),
+        None => (0, 0),
+    };
+    [indexed, row, blend.premultiplies() as u32, 0]
+}
+
+/// The uniforms of up to `DRAWS_PER_BATCH` draws, laid out like the slots of
+/// the pipeline's buffers to be written in one go
+pub struct DrawUniformBatch {
+    stride: usize,
+    len: u32,
+    // Uniforms of the last draw pushed, end to end
+    last: Vec<u8>,
+    transforms: Vec<u8>,
+    colors: Vec<u8>,
+    sprite_specs: Vec<u8>,
+    material_params: Vec<u8>,
+    draw_settings: Vec<u8>,
+}
+
+impl DrawUniformBatch {
+    fn new(stride: usize) -> Self {
+        Self {
+            stride,
+            len: 0,
+            last: Vec::new(),
+            transforms: Vec::new(),
+            colors: Vec::new(),
+            sprite_specs: Vec::new(),
+            material_params: Vec::new(),
+            draw_settings: Vec::new(),
+        }
+    }
+
+    /// Adds a draw, returning the slot its uniforms go in, or `None` if the
+    /// batch is full. Draws in a row with the same uniforms, like the same
+    /// sprite drawn over and over, share a slot.
+    pub fn push(&mut self, uniforms: &DrawUniforms) -> Option<u32> {
+        let fields: [&[u8]; 5] = [
+            uniforms.transform.as_bytes(),
+            bytemuck::bytes_of(&uniforms.color),
+            bytemuck::bytes_of(&uniforms.sprite_spec),
+            bytemuck::cast_slice(&uniforms.material_params),
+            bytemuck::cast_slice(&uniforms.draw_settings),
+        ];
+        let all = fields.concat();
+        if !self.is_empty() && all == self.last {
+            return Some(self.len - 1);
+        }
+        if self.is_full() {
+            return None;
+        }
+        self.last = all;
+        let stride = self.stride;
+        let push = |bytes: &mut Vec<u8>, data: &[u8]| {
+            bytes.extend_from_slice(data);
+            bytes.resize(bytes.len().next_multiple_of(stride), 0);
+        };
+        let [transform, color, sprite_spec, material_params, draw_settings] = fields;
+        push(&mut self.transforms, transform);
+        push(&mut self.colors, color);
+        push(&mut self.sprite_specs, sprite_spec);
+        push(&mut self.material_params, material_params);
+        push(&mut self.draw_settings, draw_settings);
+        self.len += 1;
+        Some(self.len - 1)
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> u32 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == DRAWS_PER_BATCH
+    }
+
+    /// Empties the batch, keeping its memory for the next one
+    pub fn clear(&mut self) {
+        self.len = 0;
+        self.last.clear();
+        self.transforms.clear();
+        self.colors.clear();
+        self.sprite_specs.clear();
+        self.material_params.clear();
+        self.draw_settings.clear();
+    }
+}
+
+/// What's already set on a render pass, so that draws in a row sharing a
+/// pipeline, uniforms or geometry don't set them again. Starts out empty for
+/// every pass.
+#[derive(Default)]
+pub struct GizmoPassState {
+    pipeline: Option<PipelineKey>,
+    textures_bound: bool,
+    slot: Option<u32>,
+    // The mesh in [0], the tilemap instances in [1]
+    vertex_buffers: [Option<Buffer>; 2],
+    index_buffer: Option<Buffer>,
+}
+
+impl GizmoPassState {
+    /// Sets `buffer` as vertex buffer `slot` of `render_pass`, unless it
+    /// already is
+    pub fn set_vertex_buffer(
+        &mut self,
+        render_pass: &mut wgpu::RenderPass,
+        slot: u32,
+        buffer: &Buffer,
+    ) {
+        let bound = &mut self.vertex_buffers[slot as usize];
+        if bound.as_ref() != Some(buffer) {
+            render_pass.set_vertex_buffer(slot, buffer.slice(..));
+            *bound = Some(buffer.clone());
+        }
+    }
+
+    /// Sets `buffer` of `u16` indices as the index buffer of `render_pass`,
+    /// unless it already is
+    pub fn set_index_buffer(&mut self, render_pass: &mut wgpu::RenderPass, buffer: &Buffer) {
+        if self.index_buffer.as_ref() != Some(buffer) {
+            render_pass.set_index_buffer(buffer.slice(..), wgpu::IndexFormat::Uint16);
+            self.index_buffer = Some(buffer.clone());
+        }
+    }
+}
+
+/// Everything that sets apart the pipelines gizmo draws go through
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PipelineKey {
+    pub blend: wgpu::BlendState,
+    /// Whether it tests against and writes to a depth buffer
+    pub depth_tested: bool,
+    pub material: Option<MaterialHandle>,
+    /// Whether it draws the quad once per `TileInstance` in the second
+    /// vertex buffer, for tilemaps
+    pub instanced: bool,
+}
+
+impl PipelineKey {
+    pub fn new(blend: BlendMode, alpha: TextureAlpha) -> Self {
+        Self {
+            blend: blend.blend_state(alpha),
+            depth_tested: false,
+            material: None,
+            instanced: false,
+        }
+    }
+}
+
+/// Refers to a material added with `RenderingSystem::add_material`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MaterialHandle {
+    index: usize,
+}
+
+/// Refers to a palette added with `RenderingSystem::add_palette`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PaletteHandle {
+    index: usize,
+}
+
 pub struct GizmoRenderPipeline {
-    pipeline: RenderPipeline,
+    shader: wgpu::ShaderModule,
+    // One per material, in the order they were added
+    material_shaders: Vec<wgpu::ShaderModule>,
+    // Made the first time a draw needs them, see `pipeline`
+    pipelines: RefCell<HashMap<PipelineKey, RenderPipeline>>,
+    pipeline_layout: wgpu::PipelineLayout,
+    format: wgpu::TextureFormat,
+    sample_count: u32,
+    // Bytes between the slots of the per-draw uniform buffers, which hold
+    // `DRAWS_PER_BATCH` draws each
+    uniform_stride: u64,
     transform_buffer: Buffer,
     transform_bind_group: BindGroup,
     color_buffer: Buffer,
+    // Settings applied to everything drawn, like the brightness
+    #[cfg(test)]
+    output_buffer: Buffer,
+    material_buffer: Buffer,
+    draw_settings_buffer: Buffer,
     color_bind_group: BindGroup,
     // For pre-baked geometry:
     square_vertex_buffer: Buffer,
     square_index_buffer: Buffer,
     texture_bind_group_layout: BindGroupLayout,
+    texture_sampler: wgpu::Sampler,
+    linear_texture_sampler: wgpu::Sampler,
+    texture_array: GizmoTextureArray,
+    mipmap_generator: MipmapGenerator,
+    // A palette per row, picked by indexed sprites
+    #[cfg(test)]
+    palette_texture: Texture,
+    palette_view: wgpu::TextureView,
+    #[cfg(test)]
+    num_palettes: u32,
     sprite_spec_bind_group: BindGroup,
     sprite_spec_buffer: Buffer,
 }
 
 impl GizmoRenderPipeline {
-    pub fn new(device: &Device, config: &SurfaceConfiguration) -> Self {
-        let shader_source = include_str!("../assets/shader.wgsl");
+    pub fn new(device: &Device, config: &SurfaceConfiguration, sample_count: u32) -> Self {
         let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
             label: Some("Shader"),
-            source: wgpu::ShaderSource::Wgsl(shader_source.into()),
+            source: wgpu::ShaderSource::Wgsl(SHADER_SOURCE.into()),
         });
 
-        let transform_buffer = device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("Transform Buffer"),
-            size: 4 * 4 * mem::size_of::<f32>() as u64, // 4x4 matrix
-            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
-            mapped_at_creation: false,
-        });
+        // Dynamic offsets have to be aligned
+        let uniform_stride = TRANSFORM_UNIFORM_SIZE
+            .max(SPRITE_SPEC_UNIFORM_SIZE)
+            .next_multiple_of(device.limits().min_uniform_buffer_offset_alignment as u64);
+        let per_draw_buffer = |label| {
+            device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some(label),
+                size: uniform_stride * DRAWS_PER_BATCH as u64,
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            })
+        };
+        // A slot of `uniform_stride` bytes per draw, picked with dynamic offsets
+        let per_draw_binding = |buffer, size| {
+            wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                buffer,
+                offset: 0,
+                size: wgpu::BufferSize::new(size),
+            })
+        };
+
+        let transform_buffer = per_draw_buffer("Transform Buffer");
 
         let transform_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
@@ -170,33 +963,76 @@ impl GizmoRenderPipeline {
                     visibility: wgpu::ShaderStages::VERTEX,
                     ty: wgpu::BindingType::Buffer {
                         ty: wgpu::BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
+                        has_dynamic_offset: true,
+                        min_binding_size: wgpu::BufferSize::new(TRANSFORM_UNIFORM_SIZE),
                     },
                     count: None,
                 }],
             });
 
-        let color_buffer = device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("Color Buffer"),
-            size: mem::size_of::<EngineColor>() as u64, // 4 bytes for RGBA
+        let color_buffer = per_draw_buffer("Color Buffer");
+
+        let output_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Output Buffer"),
+            size: OUTPUT_UNIFORM_SIZE,
             usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
-            mapped_at_creation: false,
+            mapped_at_creation: true,
         });
+        // Neutral brightness until it's set
+        output_buffer
+            .slice(..)
+            .get_mapped_range_mut()
+            .copy_from_slice(bytemuck::cast_slice(&[1.0f32, 0.0, 0.0, 0.0]));
+        output_buffer.unmap();
+
+        let material_buffer = per_draw_buffer("Material Buffer");
+        let draw_settings_buffer = per_draw_buffer("Draw Settings Buffer");
 
         let color_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
                 label: Some("Color Bind Group Layout"),
-                entries: &[wgpu::BindGroupLayoutEntry {
-                    binding: 1,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: true,
+                            min_binding_size: wgpu::BufferSize::new(COLOR_UNIFORM_SIZE),
+                        },
+                        count: None,
                     },
-                    count: None,
-                }],
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 5,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: wgpu::BufferSize::new(OUTPUT_UNIFORM_SIZE),
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 6,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: true,
+                            min_binding_size: wgpu::BufferSize::new(MATERIAL_UNIFORM_SIZE),
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 7,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: true,
+                            min_binding_size: wgpu::BufferSize::new(DRAW_SETTINGS_UNIFORM_SIZE),
+                        },
+                        count: None,
+                    },
+                ],
             });
 
         let texture_bind_group_layout =
@@ -208,7 +1044,7 @@ impl GizmoRenderPipeline {
                         visibility: wgpu::ShaderStages::FRAGMENT,
                         ty: wgpu::BindingType::Texture {
                             sample_type: wgpu::TextureSampleType::Float { filterable: true },
-                            view_dimension: wgpu::TextureViewDimension::D2,
+                            view_dimension: wgpu::TextureViewDimension::D2Array,
                             multisampled: false,
                         },
                         count: None,
@@ -219,27 +1055,49 @@ impl GizmoRenderPipeline {
                         ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
                         count: None,
                     },
+                    BindGroupLayoutEntry {
+                        binding: 8,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            multisampled: false,
+                        },
+                        count: None,
+                    },
+                    BindGroupLayoutEntry {
+                        binding: 9,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                        count: None,
+                    },
+                    // The texture array again, as GL can't sample a texture
+                    // binding through two samplers
+                    BindGroupLayoutEntry {
+                        binding: 10,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                            view_dimension: wgpu::TextureViewDimension::D2Array,
+                            multisampled: false,
+                        },
+                        count: None,
+                    },
                 ],
             });
 
-        let sprite_spec_buffer = device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("Sprite Spec Buffer"),
-            //size: mem::size_of::<SpriteSpecPadded>() as u64, // Ensure alignment
-            size: mem::size_of::<SpriteSpecPadded>() as u64, // Ensure alignment
-            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
-            mapped_at_creation: false,
-        });
+        let sprite_spec_buffer = per_draw_buffer("Sprite Spec Buffer");
 
         let sprite_spec_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
                 label: Some("Sprite Spec Bind Group Layout"),
                 entries: &[wgpu::BindGroupLayoutEntry {
                     binding: 4,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    visibility: wgpu::ShaderStages::VERTEX_FRAGMENT,
                     ty: wgpu::BindingType::Buffer {
                         ty: wgpu::BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
+                        has_dynamic_offset: true,
+                        min_binding_size: wgpu::BufferSize::new(SPRITE_SPEC_UNIFORM_SIZE),
                     },
                     count: None,
                 }],
@@ -257,68 +1115,40 @@ impl GizmoRenderPipeline {
                 push_constant_ranges: &[],
             });
 
-        let render_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-            label: Some("Render Pipeline"),
-            layout: Some(&render_pipeline_layout),
-            vertex: wgpu::VertexState {
-                module: &shader,
-                entry_point: Some("vs_main"),
-                buffers: &[Vertex::desc()],
-                compilation_options: wgpu::PipelineCompilationOptions::default(),
-            },
-            fragment: Some(wgpu::FragmentState {
-                module: &shader,
-                entry_point: Some("fs_main"),
-                targets: &[Some(wgpu::ColorTargetState {
-                    format: config.format,
-                    blend: Some(wgpu::BlendState::ALPHA_BLENDING),
-                    write_mask: wgpu::ColorWrites::ALL,
-                })],
-                compilation_options: wgpu::PipelineCompilationOptions::default(),
-            }),
-            primitive: wgpu::PrimitiveState {
-                topology: wgpu::PrimitiveTopology::TriangleList,
-                strip_index_format: None,
-                front_face: wgpu::FrontFace::Ccw,
-                cull_mode: Some(wgpu::Face::Back),
-                polygon_mode: wgpu::PolygonMode::Fill,
-                unclipped_depth: false,
-                conservative: false,
-            },
-            depth_stencil: None,
-            multisample: wgpu::MultisampleState {
-                count: 1,
-                mask: !0,
-                alpha_to_coverage_enabled: false,
-            },
-            multiview: None,
-            cache: None,
-        });
-
         let transform_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
             label: Some("Transform Bind Group"),
             layout: &transform_bind_group_layout,
             entries: &[wgpu::BindGroupEntry {
                 binding: 0,
-                resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
-                    buffer: &transform_buffer,
-                    offset: 0,
-                    size: None,
-                }),
+                resource: per_draw_binding(&transform_buffer, TRANSFORM_UNIFORM_SIZE),
             }],
         });
 
         let color_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
             label: Some("Color Bind Group"),
             layout: &color_bind_group_layout,
-            entries: &[wgpu::BindGroupEntry {
-                binding: 1,
-                resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
-                    buffer: &color_buffer,
-                    offset: 0,
-                    size: None,
-                }),
-            }],
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: per_draw_binding(&color_buffer, COLOR_UNIFORM_SIZE),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                        buffer: &output_buffer,
+                        offset: 0,
+                        size: None,
+                    }),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 6,
+                    resource: per_draw_binding(&material_buffer, MATERIAL_UNIFORM_SIZE),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 7,
+                    resource: per_draw_binding(&draw_settings_buffer, DRAW_SETTINGS_UNIFORM_SIZE),
+                },
+            ],
         });
 
         let sprite_spec_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
@@ -326,11 +1156,7 @@ impl GizmoRenderPipeline {
             layout: &sprite_spec_bind_group_layout,
             entries: &[wgpu::BindGroupEntry {
                 binding: 4,
-                resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
-                    buffer: &sprite_spec_buffer,
-                    offset: 0,
-                    size: None,
-                }),
+                resource: per_draw_binding(&sprite_spec_buffer, SPRITE_SPEC_UNIFORM_SIZE),
             }],
         });
 
@@ -362,20 +1188,194 @@ impl GizmoRenderPipeline {
         let square_vertex_buffer = Self::create_vertex_buffer_internal(device, &square_vertices);
         let square_index_buffer = Self::create_index_buffer_internal(device, square_indices);
 
+        let texture_sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Gizmo Sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Nearest,
+            min_filter: wgpu::FilterMode::Nearest,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+        // For textures with `TextureFilter::Linear`, picked in the shader
+        let linear_texture_sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Gizmo Linear Sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+
+        let palette_texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Gizmo Palette Texture"),
+            size: wgpu::Extent3d {
+                width: PALETTE_SIZE,
+                height: MAX_PALETTES,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba8UnormSrgb,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            view_formats: &[],
+        });
+        let palette_view = palette_texture.create_view(&Default::default());
+
+        let texture_array = Self::create_texture_array(
+            device,
+            &texture_bind_group_layout,
+            [&texture_sampler, &linear_texture_sampler],
+            &palette_view,
+            TEXTURE_ARRAY_INITIAL_LAYERS,
+        );
+        let mipmap_generator = MipmapGenerator::new(device, wgpu::TextureFormat::Rgba8UnormSrgb);
+
         Self {
-            pipeline: render_pipeline,
+            shader,
+            material_shaders: Vec::new(),
+            pipelines: RefCell::new(HashMap::new()),
+            pipeline_layout: render_pipeline_layout,
+            format: config.format,
+            sample_count,
+            uniform_stride,
             transform_buffer,
             transform_bind_group,
             color_buffer,
+            #[cfg(test)]
+            output_buffer,
+            material_buffer,
+            draw_settings_buffer,
             color_bind_group,
             square_vertex_buffer,
             square_index_buffer,
             texture_bind_group_layout,
+            texture_sampler,
+            linear_texture_sampler,
+            texture_array,
+            mipmap_generator,
+            #[cfg(test)]
+            palette_texture,
+            palette_view,
+            #[cfg(test)]
+            num_palettes: 0,
             sprite_spec_bind_group,
             sprite_spec_buffer,
         }
     }
 
+    fn create_texture_array(
+        device: &Device,
+        layout: &BindGroupLayout,
+        [sampler, linear_sampler]: [&wgpu::Sampler; 2],
+        palette_view: &wgpu::TextureView,
+        num_layers: u32,
+    ) -> GizmoTextureArray {
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Gizmo Texture Array"),
+            size: wgpu::Extent3d {
+                width: TEXTURE_ARRAY_LAYER_SIZE,
+                height: TEXTURE_ARRAY_LAYER_SIZE,
+                depth_or_array_layers: num_layers,
+            },
+            mip_level_count: TEXTURE_ARRAY_MIP_LEVELS,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba8UnormSrgb,
+            // Rendered to for the mips
+            usage: wgpu::TextureUsages::TEXTURE_BINDING
+                | wgpu::TextureUsages::COPY_DST
+                | wgpu::TextureUsages::COPY_SRC
+                | wgpu::TextureUsages::RENDER_ATTACHMENT,
+            view_formats: &[],
+        });
+        let view = texture.create_view(&wgpu::TextureViewDescriptor {
+            dimension: Some(wgpu::TextureViewDimension::D2Array),
+            ..Default::default()
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Gizmo Texture Bind Group"),
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(&view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::Sampler(sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 8,
+                    resource: wgpu::BindingResource::TextureView(palette_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 9,
+                    resource: wgpu::BindingResource::Sampler(linear_sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 10,
+                    resource: wgpu::BindingResource::TextureView(&view),
+                },
+            ],
+        });
+        GizmoTextureArray {
+            texture,
+            bind_group,
+            num_layers,
+            used_layers: 0,
+            free_layers: Vec::new(),
+        }
+    }
+
+    fn grow_texture_array(&mut self, device: &Device, queue: &Queue) -> Result<(), TextureError> {
+        let max_layers = device.limits().max_texture_array_layers;
+        let old = &self.texture_array;
+        if old.num_layers >= max_layers {
+            return Err(TextureError::ArrayFull { layers: max_layers });
+        }
+
+        let mut new = Self::create_texture_array(
+            device,
+            &self.texture_bind_group_layout,
+            [&self.texture_sampler, &self.linear_texture_sampler],
+            &self.palette_view,
+            (old.num_layers * 2).min(max_layers),
+        );
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Texture Array Grow Encoder"),
+        });
+        for mip_level in 0..TEXTURE_ARRAY_MIP_LEVELS {
+            let size = TEXTURE_ARRAY_LAYER_SIZE >> mip_level;
+            encoder.copy_texture_to_texture(
+                wgpu::TexelCopyTextureInfo {
+                    mip_level,
+                    ..old.texture.as_image_copy()
+                },
+                wgpu::TexelCopyTextureInfo {
+                    mip_level,
+                    ..new.texture.as_image_copy()
+                },
+                wgpu::Extent3d {
+                    width: size,
+                    height: size,
+                    depth_or_array_layers: old.used_layers,
+                },
+            );
+        }
+        queue.submit(std::iter::once(encoder.finish()));
+
+        new.used_layers = old.used_layers;
+        new.free_layers = std::mem::take(&mut self.texture_array.free_layers);
+        self.texture_array = new;
+        Ok(())
+    }
+
     pub fn create_vertex_buffer_internal(device: &Device, vertices: &[Vertex]) -> wgpu::Buffer {
         let align = wgpu::COPY_BUFFER_ALIGNMENT;
         let vertex_size = std::mem::size_of_val(vertices) as u64;
@@ -420,70 +1420,288 @@ impl GizmoRenderPipeline {
         index_buffer
     }
 
-    pub fn write_transform(&self, queue: &Queue, transform: &Transform) {
-        transform.write_buffer(&self.transform_buffer, queue);
+    /// An empty batch of draws to fill in and write with
+    /// `write_draw_uniforms`
+    pub fn draw_uniform_batch(&self) -> DrawUniformBatch {
+        DrawUniformBatch::new(self.uniform_stride as usize)
     }
 
-    pub fn write_color(&self, queue: &Queue, color: EngineColor) {
-        queue.write_buffer(&self.color_buffer, 0, bytemuck::cast_slice(&[color]));
+    /// Writes the uniforms of every draw in `batch` into their slots, all
+    /// at once
+    pub fn write_draw_uniforms(&self, queue: &Queue, batch: &DrawUniformBatch) {
+        if batch.is_empty() {
+            return;
+        }
+        queue.write_buffer(&self.transform_buffer, 0, &batch.transforms);
+        queue.write_buffer(&self.color_buffer, 0, &batch.colors);
+        queue.write_buffer(&self.sprite_spec_buffer, 0, &batch.sprite_specs);
+        queue.write_buffer(&self.material_buffer, 0, &batch.material_params);
+        queue.write_buffer(&self.draw_settings_buffer, 0, &batch.draw_settings);
     }
 
-    pub fn write_sprite_spec(&self, queue: &Queue, sprite_spec: SpriteSpec) {
+    /// Settings for everything drawn. `brightness` is the gamma colors are
+    /// raised to the inverse of in linear space: 1 leaves them as they are,
+    /// and higher values lift the darks more than the lights.
+    /// `discard_transparent` drops fully transparent fragments, so they don't
+    /// hide what's under them from the depth test.
+    #[cfg(test)]
+    pub fn write_output(&self, queue: &Queue, brightness: f32, discard_transparent: bool) {
+        let discard = if discard_transparent { 1.0 } else { 0.0 };
         queue.write_buffer(
-            &self.sprite_spec_buffer,
+            &self.output_buffer,
             0,
-            //bytemuck::cast_slice(&[sprite_spec]),
-            // we need to pad it
-            bytemuck::cast_slice(&[SpriteSpecPadded::from(sprite_spec)]),
+            bytemuck::cast_slice(&[brightness, discard, 0.0, 0.0]),
         );
     }
 
-    pub fn bind_texture(&self, render_pass: &mut wgpu::RenderPass, texture: &GizmoBindableTexture) {
-        render_pass.set_bind_group(2, &texture.bind_group, &[]);
+    /// Sets up `render_pass` to draw with the pipeline for `key` and the
+    /// uniforms in `slot` of the last batch written. What `state` says is
+    /// already set on the pass isn't set again.
+    pub fn setup_pass(
+        &self,
+        device: &Device,
+        render_pass: &mut wgpu::RenderPass,
+        state: &mut GizmoPassState,
+        key: PipelineKey,
+        slot: u32,
+    ) {
+        if state.pipeline != Some(key) {
+            render_pass.set_pipeline(&self.pipeline(device, key));
+            state.pipeline = Some(key);
+        }
+        if !state.textures_bound {
+            render_pass.set_bind_group(2, &self.texture_array.bind_group, &[]);
+            state.textures_bound = true;
+        }
+        if state.slot != Some(slot) {
+            self.set_uniform_bind_groups(render_pass, slot);
+            state.slot = Some(slot);
+        }
+    }
+
+    /// The pipeline for `key`, made the first time it's asked for and
+    /// reused from then on
+    pub fn pipeline(&self, device: &Device, key: PipelineKey) -> RenderPipeline {
+        self.pipelines
+            .borrow_mut()
+            .entry(key)
+            .or_insert_with(|| self.create_pipeline(device, &key))
+            .clone()
     }
 
-    pub fn setup_pass(&self, render_pass: &mut wgpu::RenderPass) {
-        render_pass.set_pipeline(&self.pipeline);
-        render_pass.set_bind_group(0, &self.transform_bind_group, &[]);
-        render_pass.set_bind_group(1, &self.color_bind_group, &[]);
-        render_pass.set_bind_group(3, &self.sprite_spec_bind_group, &[]);
+    /// How many pipelines have been made so far
+    #[cfg(test)]
+    pub fn num_pipelines(&self) -> usize {
+        self.pipelines.borrow().len()
+    }
+
+    fn create_pipeline(&self, device: &Device, key: &PipelineKey) -> RenderPipeline {
+        let (shader, fragment_entry) = match key.material {
+            Some(material) => (&self.material_shaders[material.index], "fs_material"),
+            None => (&self.shader, "fs_main"),
+        };
+        let (vertex_entry, buffers): (_, &[wgpu::VertexBufferLayout]) = if key.instanced {
+            ("vs_tilemap", &[Vertex::desc(), TileInstance::desc()])
+        } else {
+            ("vs_main", &[Vertex::desc()])
+        };
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Gizmo Render Pipeline"),
+            layout: Some(&self.pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: shader,
+                entry_point: Some(vertex_entry),
+                buffers,
+                compilation_options: wgpu::PipelineCompilationOptions::default(),
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: shader,
+                entry_point: Some(fragment_entry),
+                targets: &[Some(wgpu::ColorTargetState {
+                    format: self.format,
+                    blend: Some(key.blend),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+                compilation_options: wgpu::PipelineCompilationOptions::default(),
+            }),
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                strip_index_format: None,
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: Some(wgpu::Face::Back),
+                polygon_mode: wgpu::PolygonMode::Fill,
+                unclipped_depth: false,
+                conservative: false,
+            },
+            depth_stencil: key.depth_tested.then(|| wgpu::DepthStencilState {
+                format: DEPTH_FORMAT,
+                depth_write_enabled: true,
+                depth_compare: wgpu::CompareFunction::LessEqual,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+            }),
+            multisample: wgpu::MultisampleState {
+                count: self.sample_count,
+                mask: !0,
+                alpha_to_coverage_enabled: false,
+            },
+            multiview: None,
+            cache: None,
+        })
+    }
+
+    /// Compiles a material's fragment shader, see
+    /// `material::material_shader_source`. Its pipelines are made as it's
+    /// drawn with.
+    pub fn add_material(&mut self, device: &Device, source: &str) -> MaterialHandle {
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Material Shader"),
+            source: wgpu::ShaderSource::Wgsl(
+                material::material_shader_source(SHADER_SOURCE, source).into(),
+            ),
+        });
+        self.material_shaders.push(shader);
+        MaterialHandle {
+            index: self.material_shaders.len() - 1,
+        }
+    }
+
+    /// Stores `colors` as a new palette, `None` once there are
+    /// `MAX_PALETTES` of them or if there are more than `PALETTE_SIZE` colors
+    #[cfg(test)]
+    pub fn add_palette(&mut self, queue: &Queue, colors: &[[u8; 4]]) -> Option<PaletteHandle> {
+        if self.num_palettes >= MAX_PALETTES || colors.len() > PALETTE_SIZE as usize {
+            return None;
+        }
+        let palette = PaletteHandle {
+            index: self.num_palettes as usize,
+        };
+        self.num_palettes += 1;
+        self.set_palette(queue, &palette, colors);
+        Some(palette)
+    }
+
+    /// Replaces the colors of `palette`. Colors past the end of `colors` are
+    /// transparent, and the ones past `PALETTE_SIZE` are ignored.
+    #[cfg(test)]
+    pub fn set_palette(&self, queue: &Queue, palette: &PaletteHandle, colors: &[[u8; 4]]) {
+        let mut row = vec![0u8; PALETTE_SIZE as usize * 4];
+        for (texel, color) in row.chunks_exact_mut(4).zip(colors) {
+            texel.copy_from_slice(color);
+        }
+        queue.write_texture(
+            wgpu::TexelCopyTextureInfo {
+                texture: &self.palette_texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d {
+                    x: 0,
+                    y: palette.index as u32,
+                    z: 0,
+                },
+                aspect: wgpu::TextureAspect::All,
+            },
+            &row,
+            wgpu::TexelCopyBufferLayout {
+                offset: 0,
+                bytes_per_row: Some(PALETTE_SIZE * 4),
+                rows_per_image: None,
+            },
+            wgpu::Extent3d {
+                width: PALETTE_SIZE,
+                height: 1,
+                depth_or_array_layers: 1,
+            },
+        );
+    }
+
+    fn set_uniform_bind_groups(&self, render_pass: &mut wgpu::RenderPass, slot: u32) {
+        let offset = slot * self.uniform_stride as u32;
+        render_pass.set_bind_group(0, &self.transform_bind_group, &[offset]);
+        // The color, material and draw settings, the output is the same for
+        // every draw
+        render_pass.set_bind_group(1, &self.color_bind_group, &[offset; 3]);
+        render_pass.set_bind_group(3, &self.sprite_spec_bind_group, &[offset]);
     }
 
     pub fn with_quad_geometry<F: FnOnce(&Buffer, &Buffer, u32)>(&self, f: F) {
         f(&self.square_vertex_buffer, &self.square_index_buffer, 6);
     }
 
-    pub fn make_texture_bindable(&self, device: &Device, texture: Texture) -> GizmoBindableTexture {
-        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
-        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
-            label: Some("Gizmo Sampler"),
-            address_mode_u: wgpu::AddressMode::ClampToEdge,
-            address_mode_v: wgpu::AddressMode::ClampToEdge,
-            address_mode_w: wgpu::AddressMode::ClampToEdge,
-            mag_filter: wgpu::FilterMode::Nearest,
-            min_filter: wgpu::FilterMode::Nearest,
-            mipmap_filter: wgpu::FilterMode::Nearest,
-            ..Default::default()
+    /// Copies `texture` into a free layer of the shared texture array,
+    /// generating its mips if `sampling` asks for them. Fails if it's bigger
+    /// than a layer or the device can't fit another layer in the array.
+    pub fn make_texture_bindable(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        texture: Texture,
+        sampling: TextureSampling,
+    ) -> Result<GizmoBindableTexture, TextureError> {
+        let (width, height) = (texture.width(), texture.height());
+        if width > TEXTURE_ARRAY_LAYER_SIZE || height > TEXTURE_ARRAY_LAYER_SIZE {
+            return Err(TextureError::TooLarge { width, height });
+        }
+
+        let layer = match self.texture_array.free_layers.pop() {
+            Some(layer) => layer,
+            None => {
+                if self.texture_array.used_layers == self.texture_array.num_layers {
+                    self.grow_texture_array(device, queue)?;
+                }
+                self.texture_array.used_layers += 1;
+                self.texture_array.used_layers - 1
+            }
+        };
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Texture Array Upload Encoder"),
         });
-        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: Some("Gizmo Texture Bind Group"),
-            layout: &self.texture_bind_group_layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 2,
-                    resource: wgpu::BindingResource::TextureView(&view),
+        encoder.copy_texture_to_texture(
+            texture.as_image_copy(),
+            wgpu::TexelCopyTextureInfo {
+                texture: &self.texture_array.texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d {
+                    x: 0,
+                    y: 0,
+                    z: layer,
                 },
-                wgpu::BindGroupEntry {
-                    binding: 3,
-                    resource: wgpu::BindingResource::Sampler(&sampler),
-                },
-            ],
-        });
-        GizmoBindableTexture {
-            texture,
-            view,
-            sampler,
-            bind_group,
+                aspect: wgpu::TextureAspect::All,
+            },
+            wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+        );
+        queue.submit(std::iter::once(encoder.finish()));
+
+        let texture = GizmoBindableTexture {
+            layer,
+            width,
+            height,
+            alpha: TextureAlpha::Straight,
+            sampling,
+        };
+        if sampling.mipmaps {
+            self.mipmap_generator.generate(
+                device,
+                queue,
+                &self.texture_array.texture,
+                layer,
+                texture.uv_extent(),
+            );
+        }
+        Ok(texture)
+    }
+
+    /// Gives the layer of `texture` back for the next texture made bindable
+    /// to reuse. Sprites still drawn from it would show that texture instead.
+    #[cfg(any(test, all(debug_assertions, target_arch = "wasm32")))]
+    pub fn free_texture(&mut self, texture: &GizmoBindableTexture) {
+        let array = &mut self.texture_array;
+        if texture.layer < array.used_layers && !array.free_layers.contains(&texture.layer) {
+            array.free_layers.push(texture.layer);
         }
     }
 }
@@ -0,0 +1,346 @@
+//! A lighting pass multiplied over the frame: everything is darkened to an
+//! ambient color and lit back up around point lights, whose light is blocked
+//! by the tiles of an occlusion map, like a level's walls.
+
+use std::mem;
+
+use glam::{Vec2, Vec3};
+use wgpu::{BindGroup, BindGroupLayout, Buffer, Device, Queue, RenderPipeline};
+
+use crate::{geometry::Transform, renderer::EngineColor};
+
+/// Lights past this many are left out of a `Lighting`
+pub const MAX_LIGHTS: usize = 16;
+
+/// `Lighting` in lighting.wgsl: a `mat4x4`, two `vec4`s and the lights
+const LIGHTING_UNIFORM_SIZE: u64 = 64 + 16 + 16 + MAX_LIGHTS as u64 * 32;
+
+const _: () = assert!(mem::size_of::<LightingUniform>() as u64 == LIGHTING_UNIFORM_SIZE);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PointLight {
+    /// Center of the light, in the space of the `Lighting` it's in
+    pub position: Vec2,
+    /// How far the light reaches, fading out on the way
+    pub radius: f32,
+    /// Scaled by its alpha, so that's the light's intensity
+    pub color: EngineColor,
+}
+
+/// What to light a frame with, drawn with `Drawer::draw_lighting_slow`
+pub struct Lighting<'a> {
+    /// Maps the space the lights and the occlusion map are in, e.g. a
+    /// level's tiles, to the screen
+    pub space: &'a Transform,
+    /// Light everywhere, even away from every light
+    pub ambient: EngineColor,
+    pub lights: Vec<PointLight>,
+    pub occlusion: Option<&'a OcclusionMap>,
+}
+
+impl<'a> Lighting<'a> {
+    pub fn new(space: &'a Transform, ambient: EngineColor) -> Self {
+        Self {
+            space,
+            ambient,
+            lights: Vec::new(),
+            occlusion: None,
+        }
+    }
+
+    /// Blocks the light with the tiles of `occlusion`, tile `(x, y)` covering
+    /// `[x, x + 1) × [y, y + 1)` in the lighting's space
+    pub fn with_occlusion(mut self, occlusion: &'a OcclusionMap) -> Self {
+        self.occlusion = Some(occlusion);
+        self
+    }
+
+    /// Adds a light in the middle of the unit square `transform` maps to the
+    /// screen, e.g. a character's sprite, so the light follows it around
+    pub fn attach_light(&mut self, transform: &Transform, radius: f32, color: EngineColor) {
+        let center = transform.project(Vec3::new(0.5, 0.5, 0.0));
+        let position = self.space.inverse().project(center).truncate();
+        self.lights.push(PointLight {
+            position,
+            radius,
+            color,
+        });
+    }
+
+    fn uniform(&self) -> LightingUniform {
+        let mut uniform: LightingUniform = bytemuck::Zeroable::zeroed();
+        uniform.screen_to_space = self.space.inverse().matrix().to_cols_array_2d();
+        uniform.ambient = bytemuck::cast(self.ambient);
+        let count = self.lights.len().min(MAX_LIGHTS);
+        uniform.info[0] = count as u32;
+        if let Some(occlusion) = self.occlusion {
+            uniform.info[1] = 1;
+            uniform.info[2] = occlusion.size.0;
+            uniform.info[3] = occlusion.size.1;
+        }
+        for (light, slot) in self.lights[..count].iter().zip(&mut uniform.lights) {
+            *slot = LightUniform {
+                position_radius: [light.position.x, light.position.y, light.radius, 0.0],
+                color: bytemuck::cast(light.color),
+            };
+        }
+        uniform
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct LightUniform {
+    position_radius: [f32; 4],
+    color: [f32; 4],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct LightingUniform {
+    screen_to_space: [[f32; 4]; 4],
+    ambient: [f32; 4],
+    info: [u32; 4], // light count in [0], occlusion in [1], occlusion map size in [2, 3]
+    lights: [LightUniform; MAX_LIGHTS],
+}
+
+/// Which tiles block the light, uploaded as a texture of one texel per tile
+pub struct OcclusionMap {
+    bind_group: BindGroup,
+    size: (u32, u32),
+}
+
+pub struct LightingPipeline {
+    pipeline: RenderPipeline,
+    uniform_buffer: Buffer,
+    uniform_bind_group: BindGroup,
+    occlusion_layout: BindGroupLayout,
+    // Bound when there's no occlusion map, as something has to be
+    no_occlusion: OcclusionMap,
+}
+
+impl LightingPipeline {
+    pub fn new(
+        device: &Device,
+        queue: &Queue,
+        format: wgpu::TextureFormat,
+        sample_count: u32,
+    ) -> Self {
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Lighting Shader"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("../assets/lighting.wgsl").into()),
+        });
+
+        let uniform_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Lighting Buffer"),
+            size: LIGHTING_UNIFORM_SIZE,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let uniform_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Lighting Bind Group Layout"),
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: wgpu::BufferSize::new(LIGHTING_UNIFORM_SIZE),
+                },
+                count: None,
+            }],
+        });
+        let uniform_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Lighting Bind Group"),
+            layout: &uniform_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: uniform_buffer.as_entire_binding(),
+            }],
+        });
+
+        let occlusion_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Occlusion Bind Group Layout"),
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Texture {
+                    sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    multisampled: false,
+                },
+                count: None,
+            }],
+        });
+
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Lighting Pipeline Layout"),
+            bind_group_layouts: &[&uniform_layout, &occlusion_layout],
+            push_constant_ranges: &[],
+        });
+        // Multiplies the frame by the light, leaving its alpha alone
+        let multiply = wgpu::BlendState {
+            color: wgpu::BlendComponent {
+                src_factor: wgpu::BlendFactor::Dst,
+                dst_factor: wgpu::BlendFactor::Zero,
+                operation: wgpu::BlendOperation::Add,
+            },
+            alpha: wgpu::BlendComponent {
+                src_factor: wgpu::BlendFactor::Zero,
+                dst_factor: wgpu::BlendFactor::One,
+                operation: wgpu::BlendOperation::Add,
+            },
+        };
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Lighting Pipeline"),
+            layout: Some(&layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: Some("vs_main"),
+                buffers: &[],
+                compilation_options: wgpu::PipelineCompilationOptions::default(),
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: Some("fs_main"),
+                targets: &[Some(wgpu::ColorTargetState {
+                    format,
+                    blend: Some(multiply),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+                compilation_options: wgpu::PipelineCompilationOptions::default(),
+            }),
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState {
+                count: sample_count,
+                ..Default::default()
+            },
+            multiview: None,
+            cache: None,
+        });
+
+        let no_occlusion =
+            Self::create_occlusion_map_internal(device, queue, &occlusion_layout, &[vec![false]]);
+
+        Self {
+            pipeline,
+            uniform_buffer,
+            uniform_bind_group,
+            occlusion_layout,
+            no_occlusion,
+        }
+    }
+
+    /// An occlusion map of rows of tiles, `true` for the ones that block light
+    pub fn create_occlusion_map(
+        &self,
+        device: &Device,
+        queue: &Queue,
+        occluders: &[Vec<bool>],
+    ) -> OcclusionMap {
+        Self::create_occlusion_map_internal(device, queue, &self.occlusion_layout, occluders)
+    }
+
+    fn create_occlusion_map_internal(
+        device: &Device,
+        queue: &Queue,
+        layout: &BindGroupLayout,
+        occluders: &[Vec<bool>],
+    ) -> OcclusionMap {
+        let width = occluders.first().map_or(0, Vec::len).max(1) as u32;
+        let height = occluders.len().max(1) as u32;
+        let mut texels = vec![0u8; (width * height) as usize];
+        for (y, row) in occluders.iter().enumerate() {
+            for (x, &occludes) in row.iter().enumerate().take(width as usize) {
+                texels[y * width as usize + x] = if occludes { 255 } else { 0 };
+            }
+        }
+
+        let size = wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        };
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Occlusion Map"),
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::R8Unorm,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            view_formats: &[],
+        });
+        queue.write_texture(
+            texture.as_image_copy(),
+            &texels,
+            wgpu::TexelCopyBufferLayout {
+                offset: 0,
+                bytes_per_row: Some(width),
+                rows_per_image: Some(height),
+            },
+            size,
+        );
+        let view = texture.create_view(&Default::default());
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Occlusion Bind Group"),
+            layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(&view),
+            }],
+        });
+        OcclusionMap {
+            bind_group,
+            size: (width, height),
+        }
+    }
+
+    /// What `draw` needs from `lighting`, taken when it's drawn
+    pub fn prepare(lighting: &Lighting) -> (LightingUniform, Option<BindGroup>) {
+        (
+            lighting.uniform(),
+            lighting.occlusion.map(|map| map.bind_group.clone()),
+        )
+    }
+
+    pub fn write_uniform(&self, queue: &Queue, uniform: &LightingUniform) {
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::cast_slice(&[*uniform]));
+    }
+
+    /// Multiplies the light over everything `render_pass` draws into
+    pub fn draw(&self, render_pass: &mut wgpu::RenderPass, occlusion: Option<&BindGroup>) {
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(0, &self.uniform_bind_group, &[]);
+        render_pass.set_bind_group(1, occlusion.unwrap_or(&self.no_occlusion.bind_group), &[]);
+        render_pass.draw(0..3, 0..1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attached_lights_land_in_the_lighting_space() {
+        // Tiles 8 units wide, with the view moved 2 tiles to the right
+        let space = Transform::new()
+            .translate(Vec3::new(16.0, 0.0, 0.0))
+            .scale(Vec3::new(8.0, 8.0, 1.0));
+        let mut lighting = Lighting::new(&space, EngineColor::BLACK);
+        // A sprite one tile big, its corner on tile (3, 1)
+        let sprite = space
+            .translate(Vec3::new(3.0, 1.0, 0.0))
+            .scale(Vec3::new(1.0, 1.0, 1.0));
+        lighting.attach_light(&sprite, 4.0, EngineColor::WHITE);
+        assert!((lighting.lights[0].position - Vec2::new(3.5, 1.5)).length() < 1e-4);
+
+        for _ in 0..MAX_LIGHTS + 4 {
+            lighting.attach_light(&sprite, 1.0, EngineColor::WHITE);
+        }
+        let uniform = lighting.uniform();
+        assert_eq!(uniform.info, [MAX_LIGHTS as u32, 0, 0, 0]);
+        assert_eq!(uniform.lights[0].position_radius, [3.5, 1.5, 4.0, 0.0]);
+    }
+}
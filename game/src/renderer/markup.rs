@@ -0,0 +1,260 @@
+//! Inline markup for text buffers, so a single buffer can highlight words:
+//! `o lukin e [color=yellow]ilo[/color]`. Tags are `[color=...]` with a
+//! name or `#rrggbb(aa)`, `[b]` for bold and `[scale=...]` for a size
+//! relative to the buffer's, each closed by `[/color]`, `[/b]` or
+//! `[/scale]`, and they nest. `[icon=name]` puts an icon registered
+//! with the text pipeline in the text. `[[` is a literal `[`,
+//! and anything that isn't a tag is left in the text as written.
+
+/// A run of text drawn the same way
+#[derive(Debug, Clone, PartialEq)]
+pub struct MarkupSpan {
+    pub text: String,
+    /// sRGB, or the color the text is drawn with if `None`
+    pub color: Option<[u8; 4]>,
+    pub bold: bool,
+    /// Of the buffer's font size and line height
+    pub scale: f32,
+    /// The icon this span stands for, with no text of its own
+    pub icon: Option<String>,
+}
+
+impl MarkupSpan {
+    fn style(&self) -> MarkupStyle {
+        MarkupStyle {
+            color: self.color,
+            bold: self.bold,
+            scale: self.scale,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct MarkupStyle {
+    color: Option<[u8; 4]>,
+    bold: bool,
+    scale: f32,
+}
+
+impl Default for MarkupStyle {
+    fn default() -> Self {
+        Self {
+            color: None,
+            bold: false,
+            scale: 1.0,
+        }
+    }
+}
+
+/// Splits `markup` into spans of text with the styles its tags give them.
+/// Spans next to each other with the same style are merged.
+pub fn parse_markup(markup: &str) -> Vec<MarkupSpan> {
+    let mut spans = Vec::new();
+    // Open tags, innermost last, with the style inside them
+    let mut open: Vec<(&str, MarkupStyle)> = Vec::new();
+    let mut rest = markup;
+    while !rest.is_empty() {
+        let style = open.last().map(|(_, style)| *style).unwrap_or_default();
+        if let Some(after) = rest.strip_prefix("[[") {
+            push_text(&mut spans, "[", style);
+            rest = after;
+            continue;
+        }
+        match tag_at(rest) {
+            Some((Tag::Open(name, tag), len)) => {
+                open.push((name, tag.apply(style)));
+                rest = &rest[len..];
+                continue;
+            }
+            Some((Tag::Icon(name), len)) => {
+                spans.push(MarkupSpan {
+                    text: String::new(),
+                    color: style.color,
+                    bold: style.bold,
+                    scale: style.scale,
+                    icon: Some(name.to_string()),
+                });
+                rest = &rest[len..];
+                continue;
+            }
+            Some((Tag::Close(name), len)) => {
+                // A tag closed without being opened is just text
+                if let Some(index) = open.iter().rposition(|(open_name, _)| *open_name == name) {
+                    open.truncate(index);
+                    rest = &rest[len..];
+                    continue;
+                }
+            }
+            None => {}
+        }
+        // Up to the next thing that may be a tag
+        let len = rest
+            .char_indices()
+            .skip(1)
+            .find(|(_, c)| *c == '[')
+            .map_or(rest.len(), |(i, _)| i);
+        push_text(&mut spans, &rest[..len], style);
+        rest = &rest[len..];
+    }
+    spans
+}
+
+fn push_text(spans: &mut Vec<MarkupSpan>, text: &str, style: MarkupStyle) {
+    match spans.last_mut() {
+        Some(last) if last.icon.is_none() && last.style() == style => last.text.push_str(text),
+        _ => spans.push(MarkupSpan {
+            text: text.to_string(),
+            color: style.color,
+            bold: style.bold,
+            scale: style.scale,
+            icon: None,
+        }),
+    }
+}
+
+/// What a tag changes in the style it's opened in
+#[derive(Debug, Clone, Copy)]
+enum TagStyle {
+    Color([u8; 4]),
+    Bold,
+    Scale(f32),
+}
+
+impl TagStyle {
+    fn apply(self, mut style: MarkupStyle) -> MarkupStyle {
+        match self {
+            Self::Color(color) => style.color = Some(color),
+            Self::Bold => style.bold = true,
+            Self::Scale(scale) => style.scale *= scale,
+        }
+        style
+    }
+}
+
+#[derive(Debug)]
+enum Tag<'a> {
+    Open(&'a str, TagStyle),
+    Close(&'a str),
+    Icon(&'a str),
+}
+
+/// The tag `text` starts with and its length, if it's a valid one
+fn tag_at(text: &str) -> Option<(Tag<'_>, usize)> {
+    let inner = text.strip_prefix('[')?;
+    let end = inner.find(']')?;
+    let len = end + 2;
+    let tag = &inner[..end];
+    if let Some(name) = tag.strip_prefix('/') {
+        return matches!(name, "color" | "b" | "scale").then_some((Tag::Close(name), len));
+    }
+    let (name, value) = tag.split_once('=').unwrap_or((tag, ""));
+    if name == "icon" && !value.is_empty() {
+        return Some((Tag::Icon(value), len));
+    }
+    let style = match name {
+        "color" => TagStyle::Color(parse_color(value)?),
+        "b" if value.is_empty() => TagStyle::Bold,
+        "scale" => TagStyle::Scale(value.parse().ok().filter(|scale: &f32| *scale > 0.0)?),
+        _ => return None,
+    };
+    Some((Tag::Open(name, style), len))
+}
+
+/// A color by name, or as `#rrggbb` or `#rrggbbaa`
+fn parse_color(value: &str) -> Option<[u8; 4]> {
+    let named = match value {
+        "white" => Some([255, 255, 255, 255]),
+        "black" => Some([0, 0, 0, 255]),
+        "red" => Some([255, 0, 0, 255]),
+        "green" => Some([0, 255, 0, 255]),
+        "blue" => Some([0, 0, 255, 255]),
+        "yellow" => Some([255, 255, 0, 255]),
+        "purple" => Some([128, 0, 128, 255]),
+        _ => None,
+    };
+    if named.is_some() {
+        return named;
+    }
+    let hex = value.strip_prefix('#')?;
+    if !matches!(hex.len(), 6 | 8) || !hex.is_ascii() {
+        return None;
+    }
+    let mut color = [255; 4];
+    for (i, channel) in color.iter_mut().enumerate().take(hex.len() / 2) {
+        *channel = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(color)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(text: &str, color: Option<[u8; 4]>, bold: bool, scale: f32) -> MarkupSpan {
+        MarkupSpan {
+            text: text.to_string(),
+            color,
+            bold,
+            scale,
+            icon: None,
+        }
+    }
+
+    #[test]
+    fn tags_style_the_text_they_wrap() {
+        const YELLOW: Option<[u8; 4]> = Some([255, 255, 0, 255]);
+        assert_eq!(
+            parse_markup("o lukin e [color=yellow]ilo [b]pona[/b][/color]!"),
+            vec![
+                span("o lukin e ", None, false, 1.0),
+                span("ilo ", YELLOW, false, 1.0),
+                span("pona", YELLOW, true, 1.0),
+                span("!", None, false, 1.0),
+            ]
+        );
+        // Scales multiply, and closing an outer tag closes what's inside it
+        assert_eq!(
+            parse_markup("[scale=2][color=#ff000080][scale=1.5]a[/scale]b[/scale]c"),
+            vec![
+                span("a", Some([255, 0, 0, 128]), false, 3.0),
+                span("b", Some([255, 0, 0, 128]), false, 2.0),
+                span("c", None, false, 1.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn anything_but_a_tag_stays_text() {
+        assert_eq!(
+            parse_markup("[[b]] [x] [color=mauve]é[/b][scale=-1]"),
+            vec![span(
+                "[b]] [x] [color=mauve]é[/b][scale=-1]",
+                None,
+                false,
+                1.0
+            )]
+        );
+        assert_eq!(parse_markup(""), vec![]);
+        // Left open until the end
+        assert_eq!(parse_markup("[b]ale"), vec![span("ale", None, true, 1.0)]);
+    }
+
+    #[test]
+    fn icons_are_spans_of_their_own() {
+        let icon = |name: &str, bold| MarkupSpan {
+            icon: Some(name.to_string()),
+            ..span("", None, bold, 1.0)
+        };
+        assert_eq!(
+            parse_markup("[icon=crystal] x[b]luka[icon=flask][icon=flask][/b][icon=]"),
+            vec![
+                icon("crystal", false),
+                span(" x", None, false, 1.0),
+                span("luka", None, true, 1.0),
+                icon("flask", true),
+                icon("flask", true),
+                span("[icon=]", None, false, 1.0),
+            ]
+        );
+    }
+}
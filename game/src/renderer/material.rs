@@ -0,0 +1,42 @@
+//! Materials: extra fragment shaders for the gizmo pipeline, picked per
+//! draw with `Drawer::set_material`.
+//!
+//! A material's WGSL defines `fn material(in: VertexOutput) -> vec4<f32>`,
+//! returning the texel to draw in place of the sprite's. It's compiled along
+//! with shader.wgsl, so it can use everything in there, like
+//! `sprite_texel(in, uv)` to sample the sprite anywhere, `sprite_pixel_size`
+//! and the four floats in `material_params.params`. The tint, brightness and
+//! alpha handling are applied to its result like to any other sprite.
+
+use crate::renderer::gizmo::MaterialHandle;
+
+/// Fades the sprite to gray, by `params[0]` from 0 to 1
+pub const GRAYSCALE: &str = include_str!("../assets/materials/grayscale.wgsl");
+/// Breaks the sprite up as `params[0]` goes from 0 to 1, the edge glowing
+/// in the color in `params[1..4]`
+pub const DISSOLVE: &str = include_str!("../assets/materials/dissolve.wgsl");
+/// Outlines the sprite in the color in `params[0..3]`, `params[3]` of its
+/// pixels wide
+pub const OUTLINE: &str = include_str!("../assets/materials/outline.wgsl");
+
+/// A material and the parameters to draw with it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Material {
+    pub handle: MaterialHandle,
+    pub params: [f32; 4],
+}
+
+impl Material {
+    pub fn new(handle: MaterialHandle, params: [f32; 4]) -> Self {
+        Self { handle, params }
+    }
+}
+
+/// The gizmo shader with the material appended, and a fragment shader
+/// running it as `fs_material`
+pub fn material_shader_source(shader: &str, material: &str) -> String {
+    format!(
+        "{}\n{}\n@fragment\nfn fs_material(in: VertexOutput) -> @location(0) vec4<f32> {{\n    return shade(in, material(in));\n}}\n",
+        shader, material
+    )
+}
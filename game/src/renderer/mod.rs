@@ -1,30 +1,64 @@
+pub mod animation;
+pub mod backend;
+pub mod bitmap_font;
 pub mod gizmo;
+pub mod lighting;
+pub mod markup;
+pub mod material;
+pub mod mipmap;
+pub mod post;
 pub mod text;
+pub mod tilemap;
+pub mod transition;
+pub mod upscale;
 
-use glam::Mat4;
-use glyphon::{Color as GlyphonColor, Resolution};
-use image::GenericImageView;
-use std::{cell::RefCell, mem, rc::Rc, sync::Arc};
+use glam::{Mat4, Vec2, Vec3, Vec4};
+use glyphon::Color as GlyphonColor;
+use image::RgbaImage;
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    hash::{DefaultHasher, Hash, Hasher},
+    mem,
+    rc::Rc,
+    sync::Arc,
+};
 use wgpu::{
-    wgc::device, Buffer, Color, CommandBuffer, Device, Queue, Surface, SurfaceConfiguration,
-    TexelCopyBufferLayout, Texture, TextureDescriptor, TextureView,
+    BindGroup, Buffer, Color, Device, Queue, Surface, SurfaceConfiguration, TexelCopyBufferLayout,
+    Texture, TextureDescriptor, TextureView,
 };
 use winit::window::Window;
 
+#[cfg(test)]
+use crate::renderer::{
+    bitmap_font::BitmapFont,
+    gizmo::{arc_geometry, line_geometry, nine_slice_geometry, outline_geometry, polygon_geometry},
+};
 use crate::{
     game::Game,
     geometry::Transform,
     renderer::{
+        backend::{RendererBackend, RendererInitError},
         gizmo::{
-            GizmoBindableTexture, GizmoRenderPipeline, GizmoSprite, GizmoSpriteSheet, SpriteSpec,
+            ring_geometry, BlendMode, DrawUniformBatch, DrawUniforms, GizmoBindableTexture,
+            GizmoPassState, GizmoRenderPipeline, GizmoSprite, GizmoSpriteSheet, MaterialHandle,
+            PaletteHandle, PipelineKey, SpriteSpec, SpriteSpecPadded, TextureAlpha, TextureError,
+            TextureSampling, Vertex, DEPTH_FORMAT,
         },
-        text::{FeaturedTextBuffer, TextRenderPipeline},
+        lighting::{Lighting, LightingPipeline, LightingUniform, OcclusionMap},
+        material::Material,
+        post::{ColorLut, PostEffect, PostEffectHandle, PostProcessor},
+        text::{FeaturedTextBuffer, TextOptions, TextRenderPipeline},
+        tilemap::TilemapRenderer,
+        upscale::{Tonemap, Upscaler},
     },
 };
 
 #[repr(C)]
-#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
-/// Represents a color in RGBA format.
+#[derive(Copy, Clone, Debug, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
+/// Represents a color in RGBA format. Channels can go past 1, brighter than
+/// white, which shows once the frame is tone mapped, see
+/// `RenderingSystem::set_tonemap`.
 pub struct EngineColor {
     pub r: f32,
     pub g: f32,
@@ -63,12 +97,6 @@ impl EngineColor {
         b: 1.0,
         a: 1.0,
     };
-    pub const PURPLE: Self = Self {
-        r: 0.5,
-        g: 0.0,
-        b: 0.5,
-        a: 1.0,
-    };
     pub const YELLOW: Self = Self {
         r: 1.0,
         g: 1.0,
@@ -76,6 +104,24 @@ impl EngineColor {
         a: 1.0,
     };
 
+    /// From sRGB encoded bytes, e.g. a color picked in an image editor
+    pub fn from_srgb8(color: [u8; 4]) -> Self {
+        let to_linear = |c: u8| {
+            let c = c as f32 / 255.0;
+            if c <= 0.04045 {
+                c / 12.92
+            } else {
+                ((c + 0.055) / 1.055).powf(2.4)
+            }
+        };
+        Self {
+            r: to_linear(color[0]),
+            g: to_linear(color[1]),
+            b: to_linear(color[2]),
+            a: color[3] as f32 / 255.0,
+        }
+    }
+
     pub fn additive_darken(&self, factor: f32) -> Self {
         Self {
             r: (self.r - factor).max(0.0),
@@ -84,66 +130,262 @@ impl EngineColor {
             a: self.a,
         }
     }
+
+    /// Scales the color towards black, keeping its hue. `amount` goes from 0
+    /// (unchanged) to 1 (black).
+    #[cfg(test)]
+    pub fn darken(&self, amount: f32) -> Self {
+        let scale = 1.0 - amount.clamp(0.0, 1.0);
+        self.map_rgb(|channel| channel * scale)
+    }
+
+    /// Blends the color towards white, from 0 (unchanged) to 1 (white)
+    #[cfg(test)]
+    pub fn lighten(&self, amount: f32) -> Self {
+        let amount = amount.clamp(0.0, 1.0);
+        self.map_rgb(|channel| channel + (1.0 - channel) * amount)
+    }
+
+    /// Perceived brightness, with the Rec. 709 weights
+    #[cfg(test)]
+    pub fn luminance(&self) -> f32 {
+        0.2126 * self.r + 0.7152 * self.g + 0.0722 * self.b
+    }
+
+    /// The gray of the same luminance
+    #[cfg(test)]
+    pub fn grayscale(&self) -> Self {
+        let gray = self.luminance();
+        self.map_rgb(|_| gray)
+    }
+
+    /// Blends the color towards its grayscale, from 0 (unchanged) to 1 (gray)
+    #[cfg(test)]
+    pub fn desaturate(&self, amount: f32) -> Self {
+        self.lerp(&self.grayscale(), amount.clamp(0.0, 1.0))
+    }
+
+    /// Pushes the color away from its grayscale, by `amount` times its
+    /// current distance from it. Channels are clamped to 0..=1.
+    #[cfg(test)]
+    pub fn saturate(&self, amount: f32) -> Self {
+        let gray = self.luminance();
+        let scale = 1.0 + amount.max(0.0);
+        self.map_rgb(|channel| (gray + (channel - gray) * scale).clamp(0.0, 1.0))
+    }
+
+    #[cfg(test)]
+    fn map_rgb(&self, f: impl Fn(f32) -> f32) -> Self {
+        Self {
+            r: f(self.r),
+            g: f(self.g),
+            b: f(self.b),
+            a: self.a,
+        }
+    }
+
+    /// Componentwise product, as when tinting one color with another
+    pub fn multiply(&self, other: &Self) -> Self {
+        Self {
+            r: self.r * other.r,
+            g: self.g * other.g,
+            b: self.b * other.b,
+            a: self.a * other.a,
+        }
+    }
+
+    /// Componentwise blend, `self` at `t = 0` and `other` at `t = 1`
+    pub fn lerp(&self, other: &Self, t: f32) -> Self {
+        Self {
+            r: self.r + (other.r - self.r) * t,
+            g: self.g + (other.g - self.g) * t,
+            b: self.b + (other.b - self.b) * t,
+            a: self.a + (other.a - self.a) * t,
+        }
+    }
+}
+
+/// Frames queued ahead of the one on screen by default. 1 is the snappiest,
+/// more keep the GPU busier at the cost of input lag.
+const DEFAULT_FRAME_LATENCY: u32 = 2;
+/// What `desired_maximum_frame_latency` can usefully be set to
+const FRAME_LATENCY_RANGE: std::ops::RangeInclusive<u32> = 1..=3;
+
+/// Format frames are drawn in where the adapter can, so colors can go past
+/// white until they're tone mapped for the screen
+pub const HDR_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+
+/// Where frames end up: the window's surface, or a texture when rendering
+/// without a window
+enum RenderTarget {
+    Surface(Surface<'static>),
+    #[cfg(test)]
+    Offscreen(Texture),
 }
 
 pub struct RenderingSystem {
-    surface: Surface<'static>,
+    target: RenderTarget,
     device: Device,
     queue: Queue,
+    // The window's surface, or the offscreen texture
     config: SurfaceConfiguration,
-    size: winit::dpi::PhysicalSize<u32>,
-    target_aspect_ratio: f32,
+    // The frame everything's drawn into before it's scaled up to the target
+    internal_config: SurfaceConfiguration,
+    upscaler: Upscaler,
 
     ortographic_transform: Transform,
 
     gizmo_pipeline: GizmoRenderPipeline,
-
-    alignment_hint: u32,
+    lighting_pipeline: LightingPipeline,
+    post: PostProcessor,
 
     white_gizmo_texture: GizmoBindableTexture,
 
     pub text_pipeline: Rc<RefCell<TextRenderPipeline>>,
     original_size: (u32, u32),
+    // Size of the canvas the surface is shown in
+    window_size: winit::dpi::PhysicalSize<u32>,
+
+    frame_cap: Option<f32>,
+    #[cfg(test)]
+    brightness: f32,
+    #[cfg(test)]
+    tonemap: Tonemap,
+    #[cfg(test)]
+    exposure: f32,
+    // Only there while depth testing is on, see `set_depth_buffer`
+    depth_view: Option<TextureView>,
+    // Samples per pixel. Above 1 everything is drawn into `msaa_view` and
+    // resolved into the frame after each pass.
+    #[cfg(test)]
+    sample_count: u32,
+    msaa_view: Option<TextureView>,
+
+    encoded_textures: EncodedImageCache<Rc<GizmoBindableTexture>>,
+}
+
+/// Where a draw lands in the frame. Lower layers are drawn first, and the
+/// draws on a layer in the order they were made in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DrawLayer(pub i16);
+
+impl DrawLayer {
+    pub const LEVEL: Self = Self(-100);
+    /// Characters and everything else in the room, the default
+    pub const WORLD: Self = Self(0);
+    /// Things over the characters, like their health bars
+    pub const OVERLAY: Self = Self(100);
+    /// Multiplies the light over everything under it, see `Drawer::draw_lighting_slow`
+    pub const LIGHTING: Self = Self(150);
+    pub const UI: Self = Self(200);
+    /// Over everything, see `transition::Transition`
+    pub const TRANSITION: Self = Self(300);
+
+    /// Moves whatever `transform` projects to the layer's depth, nearer for
+    /// higher layers, from 1 at the lowest to almost 0 at the highest
+    fn depth_transform(self) -> Transform {
+        let depth = 0.5 - self.0 as f32 / (2.0 * -(i16::MIN as f32));
+        Transform::from_matrix(Mat4::from_cols(
+            Vec4::X,
+            Vec4::Y,
+            Vec4::ZERO,
+            Vec4::new(0.0, 0.0, depth, 1.0),
+        ))
+    }
+}
+
+/// A draw waiting for `Drawer::flush`, with what's needed to submit it then
+enum QueuedDraw {
+    Clear(Color),
+    Gizmo {
+        transform: Box<Transform>,
+        color: EngineColor,
+        sprite_spec: Box<SpriteSpecPadded>,
+        alpha: TextureAlpha,
+        vertex_buffer: Buffer,
+        index_buffer: Buffer,
+        num_indices: u32,
+        // Instance buffer of `TileInstance`s and how many, for tilemaps
+        instances: Option<(Buffer, u32)>,
+        material: Option<Material>,
+        palette: Option<PaletteHandle>,
+        blend: BlendMode,
+    },
+    Text {
+        text_buffer: Box<FeaturedTextBuffer>,
+        x: f32,
+        y: f32,
+        scale: f32,
+        color: GlyphonColor,
+    },
+    Lighting {
+        uniform: Box<LightingUniform>,
+        occlusion: Option<BindGroup>,
+    },
+}
+
+/// A gizmo draw whose uniforms are in `slot` of the batch it's drawn with
+struct BatchedGizmoDraw {
+    slot: u32,
+    key: PipelineKey,
+    vertex_buffer: Buffer,
+    index_buffer: Buffer,
+    num_indices: u32,
+    instances: Option<(Buffer, u32)>,
 }
 
 pub struct Drawer<'a> {
     //pass: RenderPass<'a>,
     pub renderer: &'a RenderingSystem,
     view: &'a TextureView,
-    command_buffers: Vec<CommandBuffer>,
+    queued: Vec<(DrawLayer, QueuedDraw)>,
+    layer: DrawLayer,
     pub ortho: &'a Transform,
+    // Multiplied into the color of everything drawn
+    ambient: EngineColor,
+    material: Option<Material>,
+    palette: Option<PaletteHandle>,
+    blend_mode: BlendMode,
 }
 
 impl RenderingSystem {
-    pub async fn new(window: Arc<Window>, width: u32, height: u32, alignment_hint: u32) -> Self {
-        let target_aspect_ratio = width as f32 / height as f32;
+    /// A renderer drawing at `width` by `height` and scaling that up into
+    /// `window`, with `sample_count` samples per pixel to smooth edges out,
+    /// or fewer if the adapter can't do as many, through the first of the
+    /// `backend`'s backends that has an adapter for the window. Fails if
+    /// none of them, software adapters included, can render to it.
+    pub async fn new(
+        window: Arc<Window>,
+        width: u32,
+        height: u32,
+        sample_count: u32,
+        backend: RendererBackend,
+    ) -> Result<Self, RendererInitError> {
         let size = winit::dpi::PhysicalSize::new(width, height);
-        let instance = wgpu::Instance::new(&wgpu::InstanceDescriptor {
-            backends: wgpu::Backends::GL,
-            ..Default::default()
-        });
-
-        let surface = instance.create_surface(window).unwrap();
-
-        let adapter = instance
-            .request_adapter(&wgpu::RequestAdapterOptions {
-                power_preference: wgpu::PowerPreference::default(),
-                compatible_surface: Some(&surface),
-                force_fallback_adapter: false,
-            })
-            .await
-            .unwrap();
-
-        let (device, queue) = adapter
-            .request_device(&wgpu::DeviceDescriptor {
-                label: None,
-                required_features: wgpu::Features::empty(),
-                required_limits: wgpu::Limits::downlevel_webgl2_defaults(),
-                memory_hints: wgpu::MemoryHints::Performance,
-                trace: wgpu::Trace::default(),
-            })
-            .await
-            .unwrap();
+        let mut found = None;
+        let mut error = RendererInitError::NoAdapter;
+        for backends in backend.attempts() {
+            let instance = backend::create_instance(*backends).await;
+            let surface = match instance.create_surface(window.clone()) {
+                Ok(surface) => surface,
+                Err(err) => {
+                    error = RendererInitError::Surface(err);
+                    continue;
+                }
+            };
+            match backend::request_adapter_and_device(&instance, Some(&surface)).await {
+                Ok((adapter, device, queue)) => {
+                    found = Some((surface, adapter, device, queue));
+                    break;
+                }
+                Err(err) if err.is_more_specific_than(&error) => error = err,
+                Err(_) => {}
+            }
+        }
+        let Some((surface, adapter, device, queue)) = found else {
+            return Err(error);
+        };
+        log::info!("Rendering with {:?}", adapter.get_info().backend);
 
         let surface_caps = surface.get_capabilities(&adapter);
         let surface_format = surface_caps
@@ -151,22 +393,108 @@ impl RenderingSystem {
             .iter()
             .copied()
             .find(|f| f.is_srgb())
-            .unwrap_or(surface_caps.formats[0]);
+            .or(surface_caps.formats.first().copied())
+            .ok_or(RendererInitError::NoSurfaceFormat)?;
 
         let config = wgpu::SurfaceConfiguration {
             usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
             format: surface_format,
             width: size.width.max(1),
             height: size.height.max(1),
-            present_mode: surface_caps.present_modes[0],
-            alpha_mode: surface_caps.alpha_modes[0],
+            // Fifo is the one mode every surface supports
+            present_mode: surface_caps
+                .present_modes
+                .first()
+                .copied()
+                .unwrap_or(wgpu::PresentMode::Fifo),
+            alpha_mode: surface_caps
+                .alpha_modes
+                .first()
+                .copied()
+                .unwrap_or(wgpu::CompositeAlphaMode::Auto),
             view_formats: vec![],
-            desired_maximum_frame_latency: 2,
+            desired_maximum_frame_latency: DEFAULT_FRAME_LATENCY,
         };
 
         surface.configure(&device, &config);
+        let frame_format = frame_format(&adapter, config.format, sample_count);
+        let sample_count = supported_sample_count(&adapter, frame_format, sample_count);
+
+        Ok(Self::with_target(
+            RenderTarget::Surface(surface),
+            device,
+            queue,
+            config,
+            frame_format,
+            sample_count,
+        ))
+    }
+
+    /// A renderer drawing into a `width` by `height` texture instead of a
+    /// window, read back with `read_frame`, with `sample_count` samples per
+    /// pixel like `new`. `None` if there's no adapter to render with.
+    #[cfg(test)]
+    pub async fn new_headless_multisampled(
+        width: u32,
+        height: u32,
+        sample_count: u32,
+    ) -> Option<Self> {
+        // GL unless overridden, the backend the game runs on in most browsers
+        let backend = RendererBackend::from_override().unwrap_or(RendererBackend::Gl);
+        let mut found = None;
+        for backends in backend.attempts() {
+            let instance = backend::create_instance(*backends).await;
+            if let Ok(created) = backend::request_adapter_and_device(&instance, None).await {
+                found = Some(created);
+                break;
+            }
+        }
+        let (adapter, device, queue) = found?;
+
+        let config = wgpu::SurfaceConfiguration {
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+            format: wgpu::TextureFormat::Rgba8UnormSrgb,
+            width: width.max(1),
+            height: height.max(1),
+            present_mode: wgpu::PresentMode::Fifo,
+            alpha_mode: wgpu::CompositeAlphaMode::Auto,
+            view_formats: vec![],
+            desired_maximum_frame_latency: DEFAULT_FRAME_LATENCY,
+        };
+        let texture = create_offscreen_texture(&device, &config);
+        let frame_format = frame_format(&adapter, config.format, sample_count);
+        let sample_count = supported_sample_count(&adapter, frame_format, sample_count);
+
+        Some(Self::with_target(
+            RenderTarget::Offscreen(texture),
+            device,
+            queue,
+            config,
+            frame_format,
+            sample_count,
+        ))
+    }
+
+    fn with_target(
+        target: RenderTarget,
+        device: Device,
+        queue: Queue,
+        config: SurfaceConfiguration,
+        frame_format: wgpu::TextureFormat,
+        sample_count: u32,
+    ) -> Self {
+        let (width, height) = (config.width, config.height);
+        let size = winit::dpi::PhysicalSize::new(width, height);
+        // Stays this size whatever the target is resized to
+        let internal_config = SurfaceConfiguration {
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+            format: frame_format,
+            ..config.clone()
+        };
+        let upscaler = Upscaler::new(&device, &internal_config, config.format);
+        upscaler.write_tonemap(&queue, Tonemap::default(), 1.0);
 
-        let gizmo_pipeline = GizmoRenderPipeline::new(&device, &config);
+        let mut gizmo_pipeline = GizmoRenderPipeline::new(&device, &internal_config, sample_count);
 
         let ortographic_transform = Transform::from_matrix(Mat4::orthographic_rh(
             0.0,
@@ -177,85 +505,343 @@ impl RenderingSystem {
             100.0,
         ));
 
-        let white_gizmo_texture = gizmo_pipeline.make_texture_bindable(
-            &device,
-            Self::create_texture(&device, &queue, 1, 1, Some(&[255, 255, 255, 255])),
-        );
+        let white_gizmo_texture = gizmo_pipeline
+            .make_texture_bindable(
+                &device,
+                &queue,
+                Self::create_texture(&device, &queue, 1, 1, Some(&[255, 255, 255, 255])),
+                TextureSampling::default(),
+            )
+            .expect("The texture array starts with room for a pixel");
 
-        let text_pipeline = TextRenderPipeline::new(&device, &queue, surface_format);
+        let text_pipeline =
+            TextRenderPipeline::new(&device, &queue, internal_config.format, sample_count);
+        let lighting_pipeline =
+            LightingPipeline::new(&device, &queue, internal_config.format, sample_count);
+        let msaa_view = create_msaa_view(&device, &internal_config, sample_count);
+        let post = PostProcessor::new(&device, &queue, internal_config.format);
 
         Self {
-            surface,
+            target,
             device,
             queue,
             config,
-            size,
+            internal_config,
+            upscaler,
             ortographic_transform,
-            target_aspect_ratio,
             gizmo_pipeline,
-            alignment_hint,
+            lighting_pipeline,
+            post,
             white_gizmo_texture,
             text_pipeline: Rc::new(RefCell::new(text_pipeline)),
             original_size: (width, height),
+            window_size: size,
+            frame_cap: None,
+            #[cfg(test)]
+            brightness: 1.0,
+            #[cfg(test)]
+            tonemap: Tonemap::default(),
+            #[cfg(test)]
+            exposure: 1.0,
+            depth_view: None,
+            #[cfg(test)]
+            sample_count,
+            msaa_view,
+            encoded_textures: EncodedImageCache::new(),
         }
     }
     pub fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>) {
         if new_size.width > 0 && new_size.height > 0 {
-            // First, calculate what size we'd want to maintain aspect ratio
-            let new_aspect_ratio = new_size.width as f32 / new_size.height as f32;
-            let (mut width, mut height) = if new_aspect_ratio > self.target_aspect_ratio {
-                (
-                    new_size.width,
-                    (new_size.width as f32 / self.target_aspect_ratio) as u32,
-                )
-            } else {
-                (
-                    (new_size.height as f32 * self.target_aspect_ratio) as u32,
-                    new_size.height,
-                )
-            };
-
-            // Now scale down if either dimension exceeds 2047
-            if width > 2047 || height > 2047 {
-                let scale_factor = (2047.0 / width as f32).min(2047.0 / height as f32);
-                width = (width as f32 * scale_factor) as u32;
-                height = (height as f32 * scale_factor) as u32;
-            }
-
-            // Apply alignment
-            let (width, height) = (
-                width / self.alignment_hint * self.alignment_hint,
-                height / self.alignment_hint * self.alignment_hint,
+            let (width, height) = surface_size_for(
+                (new_size.width, new_size.height),
+                self.device.limits().max_texture_dimension_2d,
             );
 
-            self.size = winit::dpi::PhysicalSize::new(width, height);
+            self.window_size = new_size;
             self.config.width = width;
             self.config.height = height;
-            self.surface.configure(&self.device, &self.config);
+            self.configure_target();
+        }
+    }
+
+    fn configure_target(&mut self) {
+        match &mut self.target {
+            RenderTarget::Surface(surface) => surface.configure(&self.device, &self.config),
+            #[cfg(test)]
+            RenderTarget::Offscreen(texture) => {
+                *texture = create_offscreen_texture(&self.device, &self.config)
+            }
+        }
+    }
+
+    /// How many frames may be queued up before presenting blocks, next to
+    /// the present mode in the surface's configuration. 1 lowers input lag,
+    /// e.g. for tighter combat timing, maybe at some cost in frame rate.
+    /// Values out of the useful range are clamped, and the clamped value is
+    /// returned.
+    pub fn set_frame_latency(&mut self, frames: u32) -> u32 {
+        let frames = frames.clamp(*FRAME_LATENCY_RANGE.start(), *FRAME_LATENCY_RANGE.end());
+        if frames != self.config.desired_maximum_frame_latency {
+            self.config.desired_maximum_frame_latency = frames;
+            self.configure_target();
         }
+        frames
+    }
+
+    pub fn frame_latency(&self) -> u32 {
+        self.config.desired_maximum_frame_latency
+    }
+
+    pub fn present_mode(&self) -> wgpu::PresentMode {
+        self.config.present_mode
+    }
+
+    /// Maps a cursor position in physical window pixels to the internal
+    /// resolution, or `None` if it's over one of the letterbox bars.
+    pub fn window_to_internal(&self, physical: Vec2) -> Option<Vec2> {
+        letterbox_to_internal(
+            Vec2::new(
+                self.window_size.width as f32,
+                self.window_size.height as f32,
+            ),
+            Vec2::new(self.config.width as f32, self.config.height as f32),
+            Vec2::new(self.original_size.0 as f32, self.original_size.1 as f32),
+            physical,
+        )
+    }
+
+    /// Caps the frame rate to `fps` frames per second, or uncaps it with `None`
+    pub fn set_frame_cap(&mut self, fps: Option<f32>) {
+        self.frame_cap = fps.filter(|fps| *fps > 0.0);
+    }
+
+    /// Brightens (above 1) or darkens (below 1) everything drawn. It's applied
+    /// as a gamma, so blacks and whites stay put while the rest shifts.
+    #[cfg(test)]
+    pub fn set_brightness(&mut self, brightness: f32) {
+        self.brightness = brightness.clamp(0.1, 10.0);
+        self.write_output();
+    }
+
+    /// How colors past white are brought into what the screen shows. The
+    /// default clamps them, so frames look the same as without HDR.
+    #[cfg(test)]
+    pub fn set_tonemap(&mut self, tonemap: Tonemap) {
+        self.tonemap = tonemap;
+        self.write_tonemap();
+    }
+
+    /// Scales every color before it's tone mapped, e.g. to adjust to a dark
+    /// cave with bright torches in it. Negative exposures are treated as 0.
+    #[cfg(test)]
+    pub fn set_exposure(&mut self, exposure: f32) {
+        self.exposure = exposure.max(0.0);
+        self.write_tonemap();
+    }
+
+    /// Whether frames are drawn in `HDR_FORMAT`, keeping colors past white,
+    /// rather than the target's format on adapters that can't draw into it
+    #[cfg(test)]
+    pub fn is_hdr(&self) -> bool {
+        self.internal_config.format == HDR_FORMAT
+    }
+
+    #[cfg(test)]
+    fn write_tonemap(&self) {
+        self.upscaler
+            .write_tonemap(&self.queue, self.tonemap, self.exposure);
+    }
+
+    /// Draws with a depth buffer instead of sorting by layer: draws go in the
+    /// order they're made in, and the depth test keeps higher layers in
+    /// front. Fully transparent pixels are skipped so they don't hide what's
+    /// under them, but blending is only right over what's drawn before.
+    #[cfg(test)]
+    pub fn set_depth_buffer(&mut self, enabled: bool) {
+        self.depth_view = enabled
+            .then(|| create_depth_view(&self.device, &self.internal_config, self.sample_count));
+        self.write_output();
+    }
+
+    #[cfg(test)]
+    pub fn depth_buffer_enabled(&self) -> bool {
+        self.depth_view.is_some()
+    }
+
+    /// Samples per pixel everything's drawn with, 1 without MSAA
+    #[cfg(test)]
+    pub fn sample_count(&self) -> u32 {
+        self.sample_count
+    }
+
+    /// Occlusion for `Lighting::with_occlusion`, from rows of tiles, `true`
+    /// for the ones that block light
+    pub fn create_occlusion_map(&self, occluders: &[Vec<bool>]) -> OcclusionMap {
+        self.lighting_pipeline
+            .create_occlusion_map(&self.device, &self.queue, occluders)
+    }
+
+    #[cfg(test)]
+    fn write_output(&self) {
+        self.gizmo_pipeline
+            .write_output(&self.queue, self.brightness, self.depth_view.is_some());
+    }
+
+    /// Target frame time in milliseconds, if the frame rate is capped
+    pub fn target_frame_ms(&self) -> Option<f64> {
+        self.frame_cap.map(|fps| 1000.0 / fps as f64)
     }
 
     pub fn canonical_resize(&mut self) {
-        self.resize(self.size);
+        self.resize(self.window_size);
     }
 
+    /// Renders a frame of `game`. A lost or outdated surface is reconfigured
+    /// and the frame skipped; other surface errors are returned to the caller.
     pub fn render(&mut self, game: &Game) -> Result<(), wgpu::SurfaceError> {
-        let output = self.surface.get_current_texture()?;
-        let view = output
-            .texture
-            .create_view(&wgpu::TextureViewDescriptor::default());
+        self.render_with(|drawer| game.render(drawer))
+    }
 
-        let mut drawer = Drawer::new(self, &view);
+    /// Like `render`, with `draw` drawing the frame, e.g. while there's no
+    /// game yet
+    pub fn render_with(
+        &mut self,
+        draw: impl FnOnce(&mut Drawer),
+    ) -> Result<(), wgpu::SurfaceError> {
+        let output = match &self.target {
+            RenderTarget::Surface(surface) => {
+                let acquired = surface.get_current_texture();
+                let Some(output) = recover_surface(acquired, || self.canonical_resize())? else {
+                    return Ok(());
+                };
+                Some(output)
+            }
+            #[cfg(test)]
+            RenderTarget::Offscreen(_) => None,
+        };
+        let view = match (&output, &self.target) {
+            (Some(output), _) => output.texture.create_view(&Default::default()),
+            #[cfg(test)]
+            (None, RenderTarget::Offscreen(texture)) => texture.create_view(&Default::default()),
+            (None, RenderTarget::Surface(_)) => unreachable!("Surface frames are acquired above"),
+        };
+
+        self.draw_frame(&view, draw);
+
+        if let Some(output) = output {
+            output.present();
+        }
+
+        self.text_pipeline.borrow_mut().trim();
 
-        game.render(&mut drawer);
+        Ok(())
+    }
 
+    /// Draws a frame with `draw`, through the enabled post effects, and
+    /// scales it up into `view`
+    fn draw_frame(&self, view: &TextureView, draw: impl FnOnce(&mut Drawer)) {
+        let frame_view = self.upscaler.frame_view();
+        let mut drawer = Drawer::new(self, self.post.scene_view().unwrap_or(frame_view));
+        draw(&mut drawer);
         drawer.flush();
+        self.post.apply(&self.device, &self.queue, frame_view);
+        self.upscaler.present(
+            &self.device,
+            &self.queue,
+            view,
+            (self.config.width, self.config.height),
+        );
+    }
+
+    /// Adds `effect` to the end of the post-processing chain, enabled. Every
+    /// enabled effect is applied to the frame in the order they were added.
+    pub fn add_post_effect(&mut self, effect: PostEffect) -> PostEffectHandle {
+        self.post.add(&self.device, &self.internal_config, effect)
+    }
 
-        output.present();
+    /// Replaces an effect in place, e.g. to fade a vignette out
+    pub fn set_post_effect(&mut self, handle: &PostEffectHandle, effect: PostEffect) {
+        self.post.set(handle, effect);
+    }
 
-        self.text_pipeline.borrow_mut().atlas.trim();
+    /// Turns an effect on or off, keeping its place in the chain
+    pub fn set_post_effect_enabled(&mut self, handle: &PostEffectHandle, enabled: bool) {
+        self.post.set_enabled(handle, enabled);
+    }
 
-        Ok(())
+    /// Compiles a material's fragment shader, for `Drawer::set_material`.
+    /// See `material` for what its WGSL looks like, like `material::OUTLINE`.
+    pub fn add_material(&mut self, source: &str) -> MaterialHandle {
+        self.gizmo_pipeline.add_material(&self.device, source)
+    }
+
+    /// Stores up to `gizmo::PALETTE_SIZE` sRGB colors as a palette for
+    /// `Drawer::set_palette`, `None` once there are `gizmo::MAX_PALETTES`
+    #[cfg(test)]
+    pub fn add_palette(&mut self, colors: &[[u8; 4]]) -> Option<PaletteHandle> {
+        self.gizmo_pipeline.add_palette(&self.queue, colors)
+    }
+
+    /// Changes the colors of a palette, e.g. a skin being recolored
+    #[cfg(test)]
+    pub fn set_palette(&self, palette: &PaletteHandle, colors: &[[u8; 4]]) {
+        self.gizmo_pipeline
+            .set_palette(&self.queue, palette, colors);
+    }
+
+    /// A lookup table for `PostEffect::ColorGrading` from an image laid out
+    /// like `post::identity_lut_image`, `None` if it isn't
+    pub fn create_color_lut(&self, image: &RgbaImage) -> Option<ColorLut> {
+        self.post.create_color_lut(&self.device, &self.queue, image)
+    }
+
+    /// Copies the last frame back from the GPU. Only offscreen renderers have
+    /// one to read, windowed ones hand their frames to the window.
+    #[cfg(test)]
+    pub fn read_frame(&self) -> Option<RgbaImage> {
+        let RenderTarget::Offscreen(texture) = &self.target else {
+            return None;
+        };
+        let (width, height) = (texture.width(), texture.height());
+        // Rows of a copy into a buffer have to be aligned
+        let row_bytes = width * 4;
+        let padded_row_bytes = row_bytes.next_multiple_of(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT);
+
+        let buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Frame Readback Buffer"),
+            size: (padded_row_bytes * height) as u64,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Frame Readback Encoder"),
+            });
+        encoder.copy_texture_to_buffer(
+            texture.as_image_copy(),
+            wgpu::TexelCopyBufferInfo {
+                buffer: &buffer,
+                layout: TexelCopyBufferLayout {
+                    offset: 0,
+                    bytes_per_row: Some(padded_row_bytes),
+                    rows_per_image: None,
+                },
+            },
+            texture.size(),
+        );
+        self.queue.submit([encoder.finish()]);
+
+        let slice = buffer.slice(..);
+        slice.map_async(wgpu::MapMode::Read, |_| {});
+        self.device.poll(wgpu::PollType::Wait).ok()?;
+        let pixels = slice
+            .get_mapped_range()
+            .chunks(padded_row_bytes as usize)
+            .flat_map(|row| &row[..row_bytes as usize])
+            .copied()
+            .collect();
+        RgbaImage::from_raw(width, height, pixels)
     }
 
     pub fn create_texture(
@@ -276,7 +862,9 @@ impl RenderingSystem {
             sample_count: 1,
             dimension: wgpu::TextureDimension::D2,
             format: wgpu::TextureFormat::Rgba8UnormSrgb,
-            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING
+                | wgpu::TextureUsages::COPY_DST
+                | wgpu::TextureUsages::COPY_SRC,
             view_formats: &[],
         });
         if let Some(data) = data {
@@ -306,53 +894,152 @@ impl RenderingSystem {
     pub fn create_gizmo_texture(
         device: &Device,
         queue: &Queue,
-        gizmo_pipeline: &GizmoRenderPipeline,
+        gizmo_pipeline: &mut GizmoRenderPipeline,
         width: u32,
         height: u32,
         data: &[u8],
-    ) -> GizmoBindableTexture {
+        sampling: TextureSampling,
+    ) -> Result<GizmoBindableTexture, TextureError> {
         let texture = Self::create_texture(device, queue, width, height, Some(data));
-        gizmo_pipeline.make_texture_bindable(device, texture)
+        gizmo_pipeline.make_texture_bindable(device, queue, texture, sampling)
+    }
+
+    pub fn gizmo_texture_from_encoded_image(
+        &mut self,
+        image_data: &[u8],
+    ) -> Result<GizmoBindableTexture, TextureError> {
+        self.gizmo_texture_from_encoded_image_with_alpha(image_data, TextureAlpha::Straight)
+    }
+
+    /// Like `gizmo_texture_from_encoded_image`, handing out the texture
+    /// already made from the same bytes instead of uploading them again
+    pub fn shared_gizmo_texture_from_encoded_image(
+        &mut self,
+        image_data: &[u8],
+    ) -> Result<Rc<GizmoBindableTexture>, TextureError> {
+        if let Some(texture) = self.encoded_textures.get(image_data) {
+            return Ok(texture);
+        }
+        let texture = Rc::new(self.gizmo_texture_from_encoded_image(image_data)?);
+        self.encoded_textures.insert(image_data, texture.clone());
+        Ok(texture)
     }
 
-    pub fn gizmo_texture_from_encoded_image(&mut self, image_data: &[u8]) -> GizmoBindableTexture {
-        let image = image::load_from_memory(image_data).unwrap();
-        let (width, height) = image.dimensions();
-        let rgba = image.to_rgba8();
+    /// Like `gizmo_texture_from_encoded_image`, premultiplying the image
+    /// when `alpha` asks for it. Draws with the texture blend to match.
+    pub fn gizmo_texture_from_encoded_image_with_alpha(
+        &mut self,
+        image_data: &[u8],
+        alpha: TextureAlpha,
+    ) -> Result<GizmoBindableTexture, TextureError> {
+        let image = image::load_from_memory(image_data)?;
+        let mut rgba = image.to_rgba8();
+        if alpha == TextureAlpha::Premultiplied {
+            premultiply_srgb_alpha(&mut rgba);
+        }
+        let mut texture = self.gizmo_texture_from_image(&rgba)?;
+        texture.alpha = alpha;
+        Ok(texture)
+    }
+
+    /// Uploads an already decoded image, e.g. one generated at runtime. Fails
+    /// if it's bigger than `TEXTURE_ARRAY_LAYER_SIZE` either way, or there's
+    /// no room left in the texture array.
+    pub fn gizmo_texture_from_image(
+        &mut self,
+        image: &RgbaImage,
+    ) -> Result<GizmoBindableTexture, TextureError> {
+        self.gizmo_texture_from_image_with_sampling(image, TextureSampling::default())
+    }
+
+    /// Like `gizmo_texture_from_image`, for textures that aren't pixel art,
+    /// e.g. a smooth, repeating background drawn scaled down
+    pub fn gizmo_texture_from_image_with_sampling(
+        &mut self,
+        image: &RgbaImage,
+        sampling: TextureSampling,
+    ) -> Result<GizmoBindableTexture, TextureError> {
         Self::create_gizmo_texture(
             &self.device,
             &self.queue,
-            &self.gizmo_pipeline,
-            width,
-            height,
-            rgba.as_raw().as_slice(),
+            &mut self.gizmo_pipeline,
+            image.width(),
+            image.height(),
+            image.as_raw().as_slice(),
+            sampling,
         )
     }
 
+    #[cfg(test)]
     pub fn gizmo_sprite_sheet_from_encoded_image(
         &mut self,
         image_data: &[u8],
         region_start: [f32; 2],
         region_end: [f32; 2],
         num_tiles: [u32; 2],
+    ) -> Result<GizmoSpriteSheet, TextureError> {
+        Ok(GizmoSpriteSheet::new(
+            self.shared_gizmo_texture_from_encoded_image(image_data)?,
+            region_start,
+            region_end,
+            num_tiles,
+        ))
+    }
+
+    /// Sheet over a texture that's already in the texture array, e.g. one
+    /// generated at runtime rather than decoded from an image.
+    pub fn gizmo_sprite_sheet_from_texture(
+        &self,
+        texture: GizmoBindableTexture,
+        region_start: [f32; 2],
+        region_end: [f32; 2],
+        num_tiles: [u32; 2],
     ) -> GizmoSpriteSheet {
-        let texture = self.gizmo_texture_from_encoded_image(image_data);
         GizmoSpriteSheet::new(Rc::new(texture), region_start, region_end, num_tiles)
     }
 
+    /// Lets a texture that's no longer drawn give its layer of the texture
+    /// array to the next one uploaded, e.g. a level's that was reloaded
+    #[cfg(any(test, all(debug_assertions, target_arch = "wasm32")))]
+    pub fn free_gizmo_texture(&mut self, texture: &GizmoBindableTexture) {
+        self.gizmo_pipeline.free_texture(texture);
+    }
+
+    /// A buffer of `text` laid out in a `width` by `height` box as `options`
+    /// say, to draw with `Drawer::draw_text_slow`. `metrics` are in pixels of
+    /// the internal resolution.
     pub fn create_text_buffer(
         &mut self,
-        font_size: f32,
-        line_size: f32,
-        width: f32,
-        height: f32,
+        metrics: glyphon::Metrics,
+        size: (f32, f32),
         text: &str,
         attrs: glyphon::Attrs<'static>,
         align: glyphon::cosmic_text::Align,
+        options: TextOptions,
     ) -> FeaturedTextBuffer {
         self.text_pipeline
             .borrow_mut()
-            .create_buffer(font_size, line_size, width, height, text, attrs, align)
+            .create_buffer(metrics, size, text, attrs, align, options)
+    }
+
+    /// A buffer of `text` drawn with `font`'s glyphs, crisp at the internal
+    /// resolution, aligned within `width` pixels. It doesn't wrap or shrink.
+    #[cfg(test)]
+    pub fn create_bitmap_text_buffer(
+        &mut self,
+        font: &Rc<BitmapFont>,
+        width: f32,
+        height: f32,
+        text: &str,
+        align: glyphon::cosmic_text::Align,
+    ) -> FeaturedTextBuffer {
+        self.text_pipeline.borrow_mut().create_bitmap_buffer(
+            font.clone(),
+            width,
+            height,
+            text,
+            align,
+        )
     }
 
     pub fn load_font(&mut self, bytes: &[u8]) {
@@ -365,54 +1052,65 @@ impl<'a> Drawer<'a> {
         Self {
             renderer,
             view,
-            command_buffers: Vec::new(),
+            queued: Vec::new(),
+            layer: DrawLayer::default(),
             ortho: &renderer.ortographic_transform,
+            ambient: EngineColor::WHITE,
+            material: None,
+            palette: None,
+            blend_mode: BlendMode::Alpha,
         }
     }
 
-    fn apply_gizmo_transform(&mut self, transform: &Transform) {
-        // we need to flush or else it will be out of order
-        self.flush();
-        self.renderer
-            .gizmo_pipeline
-            .write_transform(&self.renderer.queue, transform);
+    /// Tints everything drawn from now on with `color`, e.g. to give a room
+    /// its atmosphere. `EngineColor::WHITE` turns it off.
+    pub fn set_ambient(&mut self, color: EngineColor) {
+        self.ambient = color;
     }
 
-    pub fn clear_slow(&mut self, color: Color) {
-        let mut encoder =
-            self.renderer
-                .device
-                .create_command_encoder(&wgpu::CommandEncoderDescriptor {
-                    label: Some("Gizmo Encoder"),
-                });
+    /// Puts everything drawn from now on in `layer`, above the lower layers
+    /// no matter what order they're drawn in
+    pub fn set_layer(&mut self, layer: DrawLayer) {
+        self.layer = layer;
+    }
 
-        {
-            encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                label: Some("Gizmo Pass"),
-                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                    view: self.view,
-                    resolve_target: None,
-                    ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(color),
-                        store: wgpu::StoreOp::Store,
-                    },
-                })],
-                depth_stencil_attachment: None,
-                occlusion_query_set: None,
-                timestamp_writes: None,
-            });
-        }
-        //self.renderer
-        //    .queue
-        //    .submit(std::iter::once(encoder.finish()));
-        self.command_buffers.push(encoder.finish());
+    pub fn layer(&self) -> DrawLayer {
+        self.layer
     }
 
-    pub fn apply_gizmo_color(&mut self, color: EngineColor) {
-        self.flush();
-        self.renderer
-            .gizmo_pipeline
-            .write_color(&self.renderer.queue, color);
+    /// Draws the sprites, shapes and tilemaps from now on with `material`,
+    /// e.g. an enemy breaking up with `material::DISSOLVE` as it dies. `None`
+    /// goes back to the plain sprite shader.
+    pub fn set_material(&mut self, material: Option<Material>) {
+        self.material = material;
+    }
+
+    #[cfg(test)]
+    pub fn material(&self) -> Option<Material> {
+        self.material
+    }
+
+    /// Draws indexed sprites from now on in the colors of `palette`, e.g. an
+    /// enemy variant sharing the sheet of the plain one. Their red channel
+    /// picks the color, so sprites that aren't indexed shouldn't be drawn
+    /// with one. `None` draws the texels as they are again.
+    #[cfg(test)]
+    pub fn set_palette(&mut self, palette: Option<PaletteHandle>) {
+        self.palette = palette;
+    }
+
+    /// Blends the sprites, shapes and tilemaps drawn from now on into the
+    /// frame with `blend_mode`, e.g. `BlendMode::Additive` for glows
+    pub fn set_blend_mode(&mut self, blend_mode: BlendMode) {
+        self.blend_mode = blend_mode;
+    }
+
+    /// Clears the frame to `color`, under everything drawn after it in any
+    /// layer. What was drawn before it is dropped.
+    pub fn clear_slow(&mut self, color: Color) {
+        self.queued.clear();
+        self.queued
+            .push((DrawLayer(i16::MIN), QueuedDraw::Clear(color)));
     }
 
     pub fn draw_geometry_slow(
@@ -424,66 +1122,64 @@ impl<'a> Drawer<'a> {
         color: Option<&EngineColor>,
         texture: GizmoSprite,
     ) {
-        if let Some(t) = transform {
-            self.apply_gizmo_transform(t);
-        } else {
-            self.apply_gizmo_transform(self.ortho);
-        }
-        if let Some(c) = color {
-            self.apply_gizmo_color(*c);
-        } else {
-            self.apply_gizmo_color(EngineColor {
-                r: 1.0,
-                g: 1.0,
-                b: 1.0,
-                a: 1.0,
-            });
-        }
-
+        let color = color.copied().unwrap_or(EngineColor::WHITE);
         let GizmoSprite {
             texture,
             sprite_spec,
         } = texture;
+        let draw = QueuedDraw::Gizmo {
+            transform: Box::new(transform.unwrap_or(self.ortho).clone()),
+            color: color.multiply(&self.ambient),
+            sprite_spec: Box::new(SpriteSpecPadded::for_texture(sprite_spec, texture)),
+            alpha: texture.alpha,
+            vertex_buffer: vertex_buffer.clone(),
+            index_buffer: index_buffer.clone(),
+            num_indices,
+            instances: None,
+            material: self.material,
+            palette: self.palette,
+            blend: self.blend_mode,
+        };
+        self.queued.push((self.layer, draw));
+    }
 
-        self.renderer
-            .gizmo_pipeline
-            .write_sprite_spec(&self.renderer.queue, sprite_spec);
-
-        let mut encoder =
-            self.renderer
-                .device
-                .create_command_encoder(&wgpu::CommandEncoderDescriptor {
-                    label: Some("Gizmo Encoder"),
-                });
-
-        {
-            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                label: Some("Gizmo Pass"),
-                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                    view: self.view,
-                    resolve_target: None,
-                    ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Load,
-                        store: wgpu::StoreOp::Store,
-                    },
-                })],
-                depth_stencil_attachment: None,
-                occlusion_query_set: None,
-                timestamp_writes: None,
-            });
-
-            self.renderer.gizmo_pipeline.setup_pass(&mut render_pass);
-            self.renderer
-                .gizmo_pipeline
-                .bind_texture(&mut render_pass, texture);
-            render_pass.set_vertex_buffer(0, vertex_buffer.slice(..));
-            render_pass.set_index_buffer(index_buffer.slice(..), wgpu::IndexFormat::Uint16);
-            render_pass.draw_indexed(0..num_indices, 0, 0..1);
+    /// Draws every tile of `tilemap` in one go, with `transform` mapping its
+    /// world units, a tile each, like the sprites in the level
+    pub fn draw_tilemap_slow(
+        &mut self,
+        tilemap: &TilemapRenderer,
+        transform: Option<&Transform>,
+        color: Option<&EngineColor>,
+    ) {
+        let Some(GizmoSprite {
+            texture,
+            sprite_spec,
+        }) = tilemap.sheet().get_sprite([0, 0])
+        else {
+            return; // A sheet without tiles, nothing to draw
+        };
+        if tilemap.num_instances() == 0 {
+            return;
         }
-        //self.renderer
-        //    .queue
-        //    .submit(std::iter::once(encoder.finish()));
-        self.command_buffers.push(encoder.finish());
+        let color = color.copied().unwrap_or(EngineColor::WHITE);
+        self.renderer.gizmo_pipeline.with_quad_geometry(
+            |vertex_buffer, index_buffer, num_indices| {
+                let draw = QueuedDraw::Gizmo {
+                    transform: Box::new(transform.unwrap_or(self.ortho).clone()),
+                    color: color.multiply(&self.ambient),
+                    sprite_spec: Box::new(SpriteSpecPadded::for_texture(sprite_spec, texture)),
+                    alpha: texture.alpha,
+                    vertex_buffer: vertex_buffer.clone(),
+                    index_buffer: index_buffer.clone(),
+                    num_indices,
+                    instances: Some((tilemap.instance_buffer().clone(), tilemap.num_instances())),
+                    material: self.material,
+                    palette: self.palette,
+                    blend: self.blend_mode,
+                };
+                self.queued.push((self.layer, draw));
+            },
+        );
     }
 
     pub fn draw_square_slow(
@@ -507,6 +1203,163 @@ impl<'a> Drawer<'a> {
         );
     }
 
+    /// Fills the part of a circle swept from `start_angle` over `sweep`
+    /// radians, in the internal resolution's pixels like the HUD, e.g. a
+    /// cooldown filling up from 0 to a full turn. See `arc_geometry`.
+    #[cfg(test)]
+    pub fn draw_arc_slow(
+        &mut self,
+        center: Vec2,
+        radius: f32,
+        start_angle: f32,
+        sweep: f32,
+        color: &EngineColor,
+        segments: u32,
+    ) {
+        if sweep == 0.0 {
+            return;
+        }
+        let geometry = arc_geometry(center.into(), radius, start_angle, sweep, segments);
+        self.draw_shape_slow(geometry, None, color);
+    }
+
+    /// Fills a ring between `inner_radius` and `outer_radius`, in the
+    /// internal resolution's pixels like the HUD. See `ring_geometry`.
+    pub fn draw_ring_slow(
+        &mut self,
+        center: Vec2,
+        inner_radius: f32,
+        outer_radius: f32,
+        color: &EngineColor,
+        segments: u32,
+    ) {
+        let geometry = ring_geometry(center.into(), inner_radius, outer_radius, segments);
+        self.draw_shape_slow(geometry, None, color);
+    }
+
+    /// A `thickness` wide line from `start` to `end`, e.g. an enemy's line
+    /// of sight. Like the other shapes, `transform` maps the points and
+    /// sizes, and without one they're in the internal resolution's pixels.
+    #[cfg(test)]
+    pub fn draw_line_slow(
+        &mut self,
+        transform: Option<&Transform>,
+        start: Vec2,
+        end: Vec2,
+        thickness: f32,
+        color: &EngineColor,
+    ) {
+        let geometry = line_geometry(start.into(), end.into(), thickness);
+        self.draw_shape_slow(geometry, transform, color);
+    }
+
+    /// A circle, filled without a `thickness`, or an outline that wide
+    /// centered on its edge
+    #[cfg(test)]
+    pub fn draw_circle_slow(
+        &mut self,
+        transform: Option<&Transform>,
+        center: Vec2,
+        radius: f32,
+        thickness: Option<f32>,
+        color: &EngineColor,
+        segments: u32,
+    ) {
+        let geometry = match thickness {
+            Some(thickness) => ring_geometry(
+                center.into(),
+                (radius - thickness / 2.0).max(0.0),
+                radius + thickness / 2.0,
+                segments,
+            ),
+            None => ring_geometry(center.into(), 0.0, radius, segments),
+        };
+        self.draw_shape_slow(geometry, transform, color);
+    }
+
+    /// A polygon through `points`, filled without a `thickness`, which only
+    /// works for convex ones, or an outline that wide centered on its edges
+    #[cfg(test)]
+    pub fn draw_polygon_slow(
+        &mut self,
+        transform: Option<&Transform>,
+        points: &[Vec2],
+        thickness: Option<f32>,
+        color: &EngineColor,
+    ) {
+        let points: Vec<[f32; 2]> = points.iter().map(|point| (*point).into()).collect();
+        let geometry = match thickness {
+            Some(thickness) => outline_geometry(&points, thickness, true),
+            None => polygon_geometry(&points),
+        };
+        self.draw_shape_slow(geometry, transform, color);
+    }
+
+    /// Fills freshly built geometry with `color`
+    fn draw_shape_slow(
+        &mut self,
+        (vertices, indices): (Vec<Vertex>, Vec<u16>),
+        transform: Option<&Transform>,
+        color: &EngineColor,
+    ) {
+        if indices.is_empty() {
+            return;
+        }
+        let device = &self.renderer.device;
+        let vertex_buffer = GizmoRenderPipeline::create_vertex_buffer_internal(device, &vertices);
+        let index_buffer = GizmoRenderPipeline::create_index_buffer_internal(device, &indices);
+        let sprite = self.white_sprite();
+        self.draw_geometry_slow(
+            &vertex_buffer,
+            &index_buffer,
+            indices.len() as u32,
+            transform,
+            Some(color),
+            sprite,
+        );
+    }
+
+    /// Draws `sprite` over the rectangle from `rect[0]` to `rect[1]`, in the
+    /// internal resolution's pixels like the HUD, e.g. panels and bars of any
+    /// size. The `border_px` pixels along each side of the sprite keep their
+    /// size, so only the edges and the middle stretch and the corners don't.
+    #[cfg(test)]
+    pub fn draw_nine_slice_slow(
+        &mut self,
+        sprite: GizmoSprite,
+        rect: [Vec2; 2],
+        border_px: f32,
+        color: Option<&EngineColor>,
+    ) {
+        let [width, height] = sprite.pixel_size();
+        let uv_border = [(border_px / width).min(0.5), (border_px / height).min(0.5)];
+        let (vertices, indices) = nine_slice_geometry(
+            rect[0].into(),
+            rect[1].into(),
+            [border_px, border_px],
+            uv_border,
+        );
+        let device = &self.renderer.device;
+        let vertex_buffer = GizmoRenderPipeline::create_vertex_buffer_internal(device, &vertices);
+        let index_buffer = GizmoRenderPipeline::create_index_buffer_internal(device, &indices);
+        self.draw_geometry_slow(
+            &vertex_buffer,
+            &index_buffer,
+            indices.len() as u32,
+            None,
+            color,
+            sprite,
+        );
+    }
+
+    /// Size of the internal resolution the HUD is drawn in, in pixels
+    pub fn internal_size(&self) -> Vec2 {
+        Vec2::new(
+            self.renderer.original_size.0 as f32,
+            self.renderer.original_size.1 as f32,
+        )
+    }
+
     pub fn white_sprite(&self) -> GizmoSprite<'a> {
         GizmoSprite {
             texture: &self.renderer.white_gizmo_texture,
@@ -516,6 +1369,7 @@ impl<'a> Drawer<'a> {
                 region_end: [1.0, 1.0],
                 num_tiles: [1, 1],
                 selected_tile: [0, 0],
+                uv_rect: None,
             },
         }
     }
@@ -528,62 +1382,1650 @@ impl<'a> Drawer<'a> {
         scale: f32,
         color: GlyphonColor,
     ) {
-        self.renderer
-            .text_pipeline
-            .borrow_mut()
-            .prepare_for_text_draw(
-                &self.renderer.device,
-                &self.renderer.queue,
-                text_buffer,
-                Resolution {
-                    width: self.renderer.original_size.0,
-                    height: self.renderer.original_size.1,
-                },
-                color,
-                x,
-                y,
-                scale,
-            )
-            .expect("Failed to prepare text draw");
+        if let Some((font, glyphs)) = text_buffer.bitmap_glyphs() {
+            // Quads like any other sprite, so they sort with them by layer
+            let [width, height] = font.glyph_size();
+            let text_color = EngineColor::from_srgb8(color.as_rgba());
+            for glyph in glyphs {
+                let Some(sprite) = font.sprite(glyph.tile) else {
+                    continue;
+                };
+                let transform = self
+                    .ortho
+                    .translate(Vec3::new(
+                        x + glyph.position[0] * scale,
+                        y + glyph.position[1] * scale,
+                        0.0,
+                    ))
+                    .scale(Vec3::new(width * scale, height * scale, 1.0));
+                let color = glyph.color.map_or(text_color, EngineColor::from_srgb8);
+                self.draw_square_slow(Some(&transform), Some(&color), sprite);
+            }
+            return;
+        }
+        let draw = QueuedDraw::Text {
+            text_buffer: Box::new(text_buffer.clone()),
+            x,
+            y,
+            scale,
+            color,
+        };
+        self.queued.push((self.layer, draw));
+    }
+
+    /// Multiplies `lighting` over what's under the current layer, usually
+    /// `DrawLayer::LIGHTING`, so the UI above it stays unlit
+    pub fn draw_lighting_slow(&mut self, lighting: &Lighting) {
+        let (uniform, occlusion) = LightingPipeline::prepare(lighting);
+        let draw = QueuedDraw::Lighting {
+            uniform: Box::new(uniform),
+            occlusion,
+        };
+        self.queued.push((self.layer, draw));
+    }
+
+    /// Submits everything drawn so far, layer by layer. With a depth buffer
+    /// the draws go in the order they were made in instead, and the depth
+    /// test keeps the higher layers in front.
+    pub fn flush(&mut self) {
+        let mut queued = mem::take(&mut self.queued);
+        let depth_view = self.renderer.depth_view.as_ref();
+        match depth_view {
+            // Stable, so each layer keeps the order its draws were made in
+            None => queued.sort_by_key(|(layer, _)| *layer),
+            Some(depth_view) => self.submit(|encoder| {
+                encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                    label: Some("Depth Clear Pass"),
+                    color_attachments: &[],
+                    depth_stencil_attachment: Some(depth_attachment(
+                        depth_view,
+                        wgpu::LoadOp::Clear(1.0),
+                    )),
+                    occlusion_query_set: None,
+                    timestamp_writes: None,
+                });
+            }),
+        }
+
+        // Gizmo draws in a row get a slot each in the gizmo pipeline's
+        // uniforms and go in a single pass. Text and lighting share their
+        // pipelines' uniforms, so each of those is submitted on its own.
+        let mut batch = self.renderer.gizmo_pipeline.draw_uniform_batch();
+        let mut batched = Vec::new();
+        for (layer, draw) in queued {
+            if !matches!(draw, QueuedDraw::Gizmo { .. }) || batch.is_full() {
+                self.submit_gizmo_batch(&mut batch, &mut batched);
+            }
+            match draw {
+                QueuedDraw::Clear(color) => self.submit(|encoder| {
+                    self.begin_pass(encoder, "Gizmo Pass", wgpu::LoadOp::Clear(color), None);
+                }),
+                QueuedDraw::Gizmo {
+                    transform,
+                    color,
+                    sprite_spec,
+                    alpha,
+                    vertex_buffer,
+                    index_buffer,
+                    num_indices,
+                    instances,
+                    material,
+                    palette,
+                    blend,
+                } => {
+                    let transform = match depth_view {
+                        Some(_) => layer.depth_transform().then(&transform),
+                        None => *transform,
+                    };
+                    let slot = batch
+                        .push(&DrawUniforms {
+                            transform,
+                            color,
+                            sprite_spec: *sprite_spec,
+                            material_params: material.map_or([0.0; 4], |material| material.params),
+                            draw_settings: gizmo::draw_settings(palette.as_ref(), blend),
+                        })
+                        .expect("Full batches are submitted before pushing to them");
+                    batched.push(BatchedGizmoDraw {
+                        slot,
+                        key: PipelineKey {
+                            depth_tested: depth_view.is_some(),
+                            material: material.map(|material| material.handle),
+                            instanced: instances.is_some(),
+                            ..PipelineKey::new(blend, alpha)
+                        },
+                        vertex_buffer,
+                        index_buffer,
+                        num_indices,
+                        instances,
+                    });
+                }
+                QueuedDraw::Text {
+                    text_buffer,
+                    x,
+                    y,
+                    scale,
+                    color,
+                } => {
+                    let mut text_pipeline = self.renderer.text_pipeline.borrow_mut();
+                    text_pipeline
+                        .prepare_for_text_draw(self.renderer, &text_buffer, color, x, y, scale)
+                        .expect("Failed to prepare text draw");
+                    // Text isn't depth tested, it's drawn over whatever came before it
+                    self.submit(|encoder| {
+                        let mut render_pass =
+                            self.begin_pass(encoder, "Text Pass", wgpu::LoadOp::Load, None);
+                        text_pipeline
+                            .render(&mut render_pass)
+                            .expect("Failed to render text");
+                    });
+                }
+                QueuedDraw::Lighting { uniform, occlusion } => {
+                    let pipeline = &self.renderer.lighting_pipeline;
+                    pipeline.write_uniform(&self.renderer.queue, &uniform);
+                    // Covers the whole frame, so it isn't depth tested either
+                    self.submit(|encoder| {
+                        let mut render_pass =
+                            self.begin_pass(encoder, "Lighting Pass", wgpu::LoadOp::Load, None);
+                        pipeline.draw(&mut render_pass, occlusion.as_ref());
+                    });
+                }
+            }
+        }
+        self.submit_gizmo_batch(&mut batch, &mut batched);
+    }
 
+    /// Writes the uniforms of the batched gizmo draws and submits them in a
+    /// single pass, leaving both empty
+    fn submit_gizmo_batch(
+        &self,
+        batch: &mut DrawUniformBatch,
+        batched: &mut Vec<BatchedGizmoDraw>,
+    ) {
+        if batched.is_empty() {
+            return;
+        }
+        let pipeline = &self.renderer.gizmo_pipeline;
+        pipeline.write_draw_uniforms(&self.renderer.queue, batch);
+        let depth_view = self.renderer.depth_view.as_ref();
+        self.submit(|encoder| {
+            let mut render_pass =
+                self.begin_pass(encoder, "Gizmo Pass", wgpu::LoadOp::Load, depth_view);
+            let mut state = GizmoPassState::default();
+            for draw in batched.drain(..) {
+                pipeline.setup_pass(
+                    &self.renderer.device,
+                    &mut render_pass,
+                    &mut state,
+                    draw.key,
+                    draw.slot,
+                );
+                let num_instances = match &draw.instances {
+                    Some((instance_buffer, num_instances)) => {
+                        state.set_vertex_buffer(&mut render_pass, 1, instance_buffer);
+                        *num_instances
+                    }
+                    None => 1,
+                };
+                state.set_vertex_buffer(&mut render_pass, 0, &draw.vertex_buffer);
+                state.set_index_buffer(&mut render_pass, &draw.index_buffer);
+                render_pass.draw_indexed(0..draw.num_indices, 0, 0..num_instances);
+            }
+        });
+        batch.clear();
+    }
+
+    /// Records commands with `record` and submits them right away
+    fn submit(&self, record: impl FnOnce(&mut wgpu::CommandEncoder)) {
         let mut encoder =
             self.renderer
                 .device
                 .create_command_encoder(&wgpu::CommandEncoderDescriptor {
-                    label: Some("Text Encoder"),
+                    label: Some("Gizmo Encoder"),
                 });
+        record(&mut encoder);
+        self.renderer
+            .queue
+            .submit(std::iter::once(encoder.finish()));
+    }
+
+    fn begin_pass<'e>(
+        &self,
+        encoder: &'e mut wgpu::CommandEncoder,
+        label: &str,
+        load: wgpu::LoadOp<Color>,
+        depth_view: Option<&TextureView>,
+    ) -> wgpu::RenderPass<'e> {
+        // Multisampled draws keep their samples for the next pass and
+        // resolve into the frame as they go
+        let (view, resolve_target) = match &self.renderer.msaa_view {
+            Some(msaa_view) => (msaa_view, Some(self.view)),
+            None => (self.view, None),
+        };
+        encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some(label),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view,
+                resolve_target,
+                ops: wgpu::Operations {
+                    load,
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: depth_view
+                .map(|view| depth_attachment(view, wgpu::LoadOp::Load)),
+            occlusion_query_set: None,
+            timestamp_writes: None,
+        })
+    }
+}
+
+fn depth_attachment(
+    view: &TextureView,
+    load: wgpu::LoadOp<f32>,
+) -> wgpu::RenderPassDepthStencilAttachment<'_> {
+    wgpu::RenderPassDepthStencilAttachment {
+        view,
+        depth_ops: Some(wgpu::Operations {
+            load,
+            store: wgpu::StoreOp::Store,
+        }),
+        stencil_ops: None,
+    }
+}
+
+#[cfg(test)]
+fn create_depth_view(
+    device: &Device,
+    config: &SurfaceConfiguration,
+    sample_count: u32,
+) -> TextureView {
+    device
+        .create_texture(&TextureDescriptor {
+            label: Some("Depth Buffer"),
+            size: wgpu::Extent3d {
+                width: config.width,
+                height: config.height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count,
+            dimension: wgpu::TextureDimension::D2,
+            format: DEPTH_FORMAT,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+            view_formats: &[],
+        })
+        .create_view(&Default::default())
+}
+
+/// The multisampled target draws go to before they're resolved into the
+/// frame, `None` when there's a single sample
+fn create_msaa_view(
+    device: &Device,
+    config: &SurfaceConfiguration,
+    sample_count: u32,
+) -> Option<TextureView> {
+    if sample_count <= 1 {
+        return None;
+    }
+    let texture = device.create_texture(&TextureDescriptor {
+        label: Some("Multisampled Frame"),
+        size: wgpu::Extent3d {
+            width: config.width,
+            height: config.height,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count,
+        dimension: wgpu::TextureDimension::D2,
+        format: config.format,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+        view_formats: &[],
+    });
+    Some(texture.create_view(&Default::default()))
+}
+
+/// `HDR_FORMAT` if `adapter` can draw, blend and filter it with as many
+/// samples as `output_format`, otherwise `output_format`
+fn frame_format(
+    adapter: &wgpu::Adapter,
+    output_format: wgpu::TextureFormat,
+    sample_count: u32,
+) -> wgpu::TextureFormat {
+    let hdr = adapter.get_texture_format_features(HDR_FORMAT);
+    let output = adapter.get_texture_format_features(output_format).flags;
+    let usable =
+        hdr.allowed_usages.contains(
+            wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+        ) && hdr.flags.contains(
+            wgpu::TextureFormatFeatureFlags::FILTERABLE
+                | wgpu::TextureFormatFeatureFlags::BLENDABLE,
+        ) && (hdr.flags.sample_count_supported(sample_count)
+            || !output.sample_count_supported(sample_count));
+    if usable {
+        HDR_FORMAT
+    } else {
+        log::warn!(
+            "Can't draw in {:?}, colors past white will clip",
+            HDR_FORMAT
+        );
+        output_format
+    }
+}
+
+/// The most samples up to `requested` that both `format` and the depth
+/// buffer support on `adapter`, falling back to 1
+fn supported_sample_count(
+    adapter: &wgpu::Adapter,
+    format: wgpu::TextureFormat,
+    requested: u32,
+) -> u32 {
+    let color = adapter.get_texture_format_features(format).flags;
+    let depth = adapter.get_texture_format_features(DEPTH_FORMAT).flags;
+    let count = [16, 8, 4, 2]
+        .into_iter()
+        .filter(|count| *count <= requested)
+        .find(|count| color.sample_count_supported(*count) && depth.sample_count_supported(*count))
+        .unwrap_or(1);
+    if count != requested {
+        log::warn!(
+            "{} samples per pixel aren't supported, using {}",
+            requested,
+            count
+        );
+    }
+    count
+}
+
+/// Surface size for a window of `window` pixels: the window's own size,
+/// scaled down to fit within `max_size` on windows too big for the GPU.
+fn surface_size_for(window: (u32, u32), max_size: u32) -> (u32, u32) {
+    let (width, height) = window;
+    if width <= max_size && height <= max_size {
+        return (width.max(1), height.max(1));
+    }
+    let scale_factor = (max_size as f32 / width as f32).min(max_size as f32 / height as f32);
+    (
+        ((width as f32 * scale_factor) as u32).clamp(1, max_size),
+        ((height as f32 * scale_factor) as u32).clamp(1, max_size),
+    )
+}
+
+/// The canvas shows the surface scaled to fit inside the window (CSS
+/// `object-fit: contain`), which only adds bars when it had to be shrunk, and
+/// the surface shows the frame scaled up by `upscale::integer_viewport`.
+/// Undoes both, back to the internal resolution.
+fn letterbox_to_internal(
+    window: Vec2,
+    surface: Vec2,
+    internal: Vec2,
+    physical: Vec2,
+) -> Option<Vec2> {
+    let canvas_scale = (window.x / surface.x).min(window.y / surface.y);
+    let canvas_offset = (window - surface * canvas_scale) * 0.5;
+    let on_surface = (physical - canvas_offset) / canvas_scale;
+    let (offset, scale) = upscale::integer_viewport(
+        (surface.x as u32, surface.y as u32),
+        (internal.x as u32, internal.y as u32),
+    );
+    let on_frame = (on_surface - offset) / scale;
+    if on_frame.x < 0.0 || on_frame.y < 0.0 || on_frame.x >= internal.x || on_frame.y >= internal.y
+    {
+        return None;
+    }
+    Some(on_frame)
+}
+
+/// Remembers what was made from encoded images, keyed by their contents, so
+/// identical bytes are only decoded and uploaded once. Hashes only pick the
+/// bucket; the bytes themselves are compared, so a collision can't hand out
+/// another image's texture.
+struct EncodedImageCache<T: Clone> {
+    entries: HashMap<u64, Vec<EncodedEntry<T>>>,
+}
+
+/// The bytes of an encoded image and what was made from them
+type EncodedEntry<T> = (Box<[u8]>, T);
+
+impl<T: Clone> EncodedImageCache<T> {
+    fn new() -> Self {
+        Self {
+            entries: HashMap::new(),
+        }
+    }
+
+    fn key(image_data: &[u8]) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        image_data.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn get(&self, image_data: &[u8]) -> Option<T> {
+        self.entries
+            .get(&Self::key(image_data))?
+            .iter()
+            .find(|(bytes, _)| **bytes == *image_data)
+            .map(|(_, value)| value.clone())
+    }
+
+    fn insert(&mut self, image_data: &[u8], value: T) {
+        self.entries
+            .entry(Self::key(image_data))
+            .or_default()
+            .push((image_data.into(), value));
+    }
+}
+
+/// Multiplies the color of each RGBA8 pixel by its alpha. The color is sRGB
+/// encoded (textures are `Rgba8UnormSrgb`), so the product is taken in
+/// linear space, where the GPU filters and blends, and encoded back.
+fn premultiply_srgb_alpha(pixels: &mut [u8]) {
+    let to_linear = |c: f32| {
+        if c <= 0.04045 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    };
+    let to_srgb = |c: f32| {
+        if c <= 0.0031308 {
+            c * 12.92
+        } else {
+            1.055 * c.powf(1.0 / 2.4) - 0.055
+        }
+    };
+    for pixel in pixels.chunks_exact_mut(4) {
+        let alpha = pixel[3] as f32 / 255.0;
+        for channel in &mut pixel[..3] {
+            let linear = to_linear(*channel as f32 / 255.0) * alpha;
+            *channel = (to_srgb(linear) * 255.0).round() as u8;
+        }
+    }
+}
+
+#[cfg(test)]
+fn create_offscreen_texture(device: &Device, config: &SurfaceConfiguration) -> Texture {
+    device.create_texture(&TextureDescriptor {
+        label: Some("Offscreen Target"),
+        size: wgpu::Extent3d {
+            width: config.width,
+            height: config.height,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: config.format,
+        usage: config.usage,
+        view_formats: &[],
+    })
+}
+
+/// Unwraps an acquired surface texture, calling `reconfigure` and yielding
+/// `None` when the surface was lost or outdated (e.g. after a resize or GPU
+/// reset) so the frame can be skipped.
+fn recover_surface<T>(
+    acquired: Result<T, wgpu::SurfaceError>,
+    reconfigure: impl FnOnce(),
+) -> Result<Option<T>, wgpu::SurfaceError> {
+    match acquired {
+        Ok(texture) => Ok(Some(texture)),
+        Err(wgpu::SurfaceError::Lost | wgpu::SurfaceError::Outdated) => {
+            reconfigure();
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::headless;
+    use crate::renderer::gizmo::{
+        SpriteSpecPadded, TextureFilter, TextureWrap, SPRITE_SPEC_UNIFORM_SIZE,
+        TEXTURE_ARRAY_LAYER_SIZE,
+    };
+    use crate::renderer::transition::{Transition, TransitionStyle};
+
+    #[test]
+    fn identical_image_bytes_share_a_texture() {
+        let mut cache = EncodedImageCache::new();
+        let bytes = include_bytes!("../assets/char_template.png");
+        cache.insert(bytes, Rc::new(1));
+
+        // A copy, so it's the bytes that match and not where they are
+        let copy = bytes.to_vec();
+        let first = cache.get(bytes).unwrap();
+        let second = cache.get(&copy).unwrap();
+        assert!(Rc::ptr_eq(&first, &second));
+        assert!(cache.get(include_bytes!("../assets/ui.png")).is_none());
+    }
+
+    #[test]
+    fn sheets_from_the_same_bytes_share_a_texture() {
+        let Some(mut renderer) = headless::renderer(64, 64) else {
+            return;
+        };
+        let bytes = include_bytes!("../assets/ui.png");
+        let copy = bytes.to_vec();
+        let first = renderer
+            .gizmo_sprite_sheet_from_encoded_image(bytes, [0.0, 0.0], [1.0, 1.0], [1, 1])
+            .unwrap();
+        let second = renderer
+            .gizmo_sprite_sheet_from_encoded_image(&copy, [0.0, 0.0], [0.5, 0.5], [2, 2])
+            .unwrap();
+        let texture = first.get_sprite([0, 0]).unwrap().texture;
+        assert!(std::ptr::eq(
+            texture,
+            second.get_sprite([0, 0]).unwrap().texture
+        ));
+
+        let other = renderer
+            .shared_gizmo_texture_from_encoded_image(include_bytes!("../assets/char_template.png"))
+            .unwrap();
+        assert!(!std::ptr::eq(texture, &*other));
+    }
+
+    #[test]
+    fn hash_collisions_dont_share_a_texture() {
+        let mut cache = EncodedImageCache::new();
+        let bytes = include_bytes!("../assets/char_template.png");
+        let other = include_bytes!("../assets/ui.png");
+        // Another image's texture filed under the same hash, as a collision
+        // would
+        cache.entries.insert(
+            EncodedImageCache::<Rc<i32>>::key(bytes),
+            vec![(other[..].into(), Rc::new(2))],
+        );
+        assert!(cache.get(bytes).is_none());
+
+        cache.insert(bytes, Rc::new(1));
+        assert_eq!(*cache.get(bytes).unwrap(), 1);
+        assert_eq!(cache.entries.len(), 1);
+    }
+
+    #[test]
+    fn lost_surface_reconfigures_and_skips_the_frame() {
+        let mut reconfigured = false;
+        let result = recover_surface::<()>(Err(wgpu::SurfaceError::Lost), || reconfigured = true);
+        assert_eq!(result, Ok(None));
+        assert!(reconfigured);
+    }
+
+    // 320x240 internal resolution in a 1000x500 window, so it's scaled up
+    // twice with wide bars on the left and right and thin ones on the top
+    // and bottom
+    fn wide_window() -> (Vec2, Vec2, Vec2) {
+        let window = Vec2::new(1000.0, 500.0);
+        let (width, height) = surface_size_for((1000, 500), 2048);
+        (
+            window,
+            Vec2::new(width as f32, height as f32),
+            Vec2::new(320.0, 240.0),
+        )
+    }
+
+    #[test]
+    fn surface_size_keeps_aspect_ratio_within_limits() {
+        assert_eq!(surface_size_for((1000, 500), 2048), (1000, 500));
+        let (width, height) = surface_size_for((4000, 1000), 2048);
+        assert!(width <= 2048 && height <= 2048);
+        assert!((width as f32 / height as f32 - 4.0).abs() < 0.05);
+    }
+
+    #[test]
+    fn window_center_maps_to_internal_center() {
+        let (window, surface, internal) = wide_window();
+        let center = letterbox_to_internal(window, surface, internal, window * 0.5).unwrap();
+        assert!((center - Vec2::new(160.0, 120.0)).length() < 0.5);
+    }
+
+    #[test]
+    fn viewport_edge_maps_to_internal_edge() {
+        let (window, surface, internal) = wide_window();
+        let top_left = Vec2::new(180.0, 10.0);
+        let point = letterbox_to_internal(window, surface, internal, top_left);
+        assert!((point.unwrap() - Vec2::ZERO).length() < 1e-3);
+        let bottom_right = window - top_left - 0.01;
+        let point = letterbox_to_internal(window, surface, internal, bottom_right).unwrap();
+        assert!((point - internal).length() < 0.5);
+    }
+
+    #[test]
+    fn letterbox_bars_map_to_nothing() {
+        let (window, surface, internal) = wide_window();
+        assert!(letterbox_to_internal(window, surface, internal, Vec2::new(5.0, 250.0)).is_none());
+        assert!(
+            letterbox_to_internal(window, surface, internal, Vec2::new(995.0, 250.0)).is_none()
+        );
+        assert!(letterbox_to_internal(window, surface, internal, Vec2::new(500.0, 5.0)).is_none());
+    }
+
+    #[test]
+    fn out_of_memory_is_propagated() {
+        let mut reconfigured = false;
+        let result =
+            recover_surface::<()>(Err(wgpu::SurfaceError::OutOfMemory), || reconfigured = true);
+        assert_eq!(result, Err(wgpu::SurfaceError::OutOfMemory));
+        assert!(!reconfigured);
+    }
+
+    #[test]
+    fn premultiplying_scales_color_by_alpha_in_linear_space() {
+        let mut pixels = [255, 255, 255, 128, 10, 20, 30, 255, 200, 100, 50, 0];
+        premultiply_srgb_alpha(&mut pixels);
+        // Half-covered white is half as bright, which is 188 once sRGB encoded
+        assert_eq!(pixels[..4], [188, 188, 188, 128]);
+        assert_eq!(pixels[4..8], [10, 20, 30, 255]);
+        assert_eq!(pixels[8..], [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn sheet_over_an_existing_texture() {
+        let white = GizmoBindableTexture {
+            layer: 3,
+            width: 1,
+            height: 1,
+            alpha: TextureAlpha::Straight,
+            sampling: TextureSampling::default(),
+        };
+        let sheet = GizmoSpriteSheet::new(Rc::new(white), [0.0, 0.0], [1.0, 1.0], [1, 1]);
+
+        let sprite = sheet.get_sprite([0, 0]).expect("1x1 sheet has one sprite");
+        let padded = SpriteSpecPadded::for_texture(sprite.sprite_spec, sprite.texture);
+        assert_eq!(padded.use_texture_and_padding[1], 3);
+        let texel = 1.0 / TEXTURE_ARRAY_LAYER_SIZE as f32;
+        assert_eq!(padded.region_start_and_end, [0.0, 0.0, texel, texel]);
+        assert!(sheet.get_sprite([1, 0]).is_none());
+    }
+
+    #[test]
+    fn tiny_windows_keep_a_valid_surface() {
+        assert_eq!(surface_size_for((10, 10), 2048), (10, 10));
+
+        let Some(mut renderer) = headless::renderer(320, 240) else {
+            return;
+        };
+        renderer.resize(winit::dpi::PhysicalSize::new(10, 10));
+        assert_eq!((renderer.config.width, renderer.config.height), (10, 10));
+        renderer.resize(winit::dpi::PhysicalSize::new(0, 0));
+        assert_eq!((renderer.config.width, renderer.config.height), (10, 10));
+        // Shrunk to fit, the internal resolution doesn't change
+        let frame = render_offscreen(&renderer, |drawer| {
+            assert_eq!(drawer.internal_size(), Vec2::new(320.0, 240.0));
+        });
+        assert_eq!(frame.dimensions(), (10, 10));
+    }
+
+    fn assert_color_eq(actual: EngineColor, expected: [f32; 4]) {
+        let actual = [actual.r, actual.g, actual.b, actual.a];
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 1e-4, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    const ORANGE: EngineColor = EngineColor {
+        r: 1.0,
+        g: 0.5,
+        b: 0.0,
+        a: 0.8,
+    };
+
+    #[test]
+    fn darken_scales_towards_black() {
+        assert_color_eq(ORANGE.darken(0.5), [0.5, 0.25, 0.0, 0.8]);
+        assert_color_eq(ORANGE.darken(1.0), [0.0, 0.0, 0.0, 0.8]);
+        assert_color_eq(ORANGE.darken(0.0), [1.0, 0.5, 0.0, 0.8]);
+    }
+
+    #[test]
+    fn lighten_blends_towards_white() {
+        assert_color_eq(ORANGE.lighten(0.5), [1.0, 0.75, 0.5, 0.8]);
+        assert_color_eq(ORANGE.lighten(1.0), [1.0, 1.0, 1.0, 0.8]);
+    }
+
+    #[test]
+    fn grayscale_keeps_luminance() {
+        let gray = 0.2126 + 0.7152 * 0.5;
+        assert_color_eq(ORANGE.grayscale(), [gray, gray, gray, 0.8]);
+        assert_color_eq(EngineColor::WHITE.grayscale(), [1.0, 1.0, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn desaturate_blends_towards_gray() {
+        let gray = 0.2126 + 0.7152 * 0.5;
+        let half = ORANGE.desaturate(0.5);
+        assert_color_eq(
+            half,
+            [(1.0 + gray) / 2.0, (0.5 + gray) / 2.0, gray / 2.0, 0.8],
+        );
+        assert_color_eq(ORANGE.desaturate(1.0), [gray, gray, gray, 0.8]);
+    }
+
+    #[test]
+    fn saturate_pushes_away_from_gray() {
+        let muted = EngineColor {
+            r: 0.6,
+            g: 0.4,
+            b: 0.4,
+            a: 1.0,
+        };
+        let gray = muted.luminance();
+        let vivid = muted.saturate(1.0);
+        assert_color_eq(
+            vivid,
+            [
+                gray + (0.6 - gray) * 2.0,
+                gray + (0.4 - gray) * 2.0,
+                gray + (0.4 - gray) * 2.0,
+                1.0,
+            ],
+        );
+        // Clamped instead of going out of range
+        assert_color_eq(ORANGE.saturate(10.0), [1.0, 0.0, 0.0, 0.8]);
+    }
+
+    /// The unit quad over the whole frame, top to top
+    fn full_frame() -> Transform {
+        Transform::from_matrix(
+            Mat4::from_translation(glam::Vec3::new(-1.0, 1.0, 0.0))
+                * Mat4::from_scale(glam::Vec3::new(2.0, -2.0, 1.0)),
+        )
+    }
+
+    /// Clears a headless renderer's frame to black, draws on it and reads it back
+    fn render_offscreen(renderer: &RenderingSystem, draw: impl FnOnce(&mut Drawer)) -> RgbaImage {
+        let RenderTarget::Offscreen(target) = &renderer.target else {
+            unreachable!("Headless renderers render offscreen");
+        };
+        let view = target.create_view(&Default::default());
+        renderer.draw_frame(&view, |drawer| {
+            drawer.clear_slow(Color::BLACK);
+            draw(drawer);
+        });
+        renderer.read_frame().unwrap()
+    }
+
+    #[test]
+    fn textures_get_a_layer_each_until_freed() {
+        let Some(mut renderer) = headless::renderer(64, 64) else {
+            return;
+        };
+        let image = RgbaImage::from_pixel(4, 4, image::Rgba([255, 0, 0, 255]));
+        let first = renderer.gizmo_texture_from_image(&image).unwrap();
+        let second = renderer.gizmo_texture_from_image(&image).unwrap();
+        assert_ne!(first.layer, second.layer);
+        // Past the white texture and the initial layers, the array grows
+        let more: Vec<_> = (0..8)
+            .map(|_| renderer.gizmo_texture_from_image(&image).unwrap().layer)
+            .collect();
+        assert!(!more.contains(&first.layer) && !more.contains(&second.layer));
+
+        renderer.free_gizmo_texture(&first);
+        let reused = renderer.gizmo_texture_from_image(&image).unwrap();
+        assert_eq!(reused.layer, first.layer);
+
+        let too_large = RgbaImage::new(TEXTURE_ARRAY_LAYER_SIZE + 1, 4);
+        assert!(matches!(
+            renderer.gizmo_texture_from_image(&too_large),
+            Err(TextureError::TooLarge { width, height: 4 }) if width == TEXTURE_ARRAY_LAYER_SIZE + 1
+        ));
+    }
+
+    #[test]
+    fn uv_rects_sample_their_sub_rectangle() {
+        let Some(mut renderer) = headless::renderer(64, 64) else {
+            return;
+        };
+        // Quadrants of different colors, the right ones narrower than the left
+        let quadrants = RgbaImage::from_fn(8, 8, |x, y| match (x < 6, y < 4) {
+            (true, true) => image::Rgba([255, 0, 0, 255]),
+            (false, true) => image::Rgba([0, 255, 0, 255]),
+            (true, false) => image::Rgba([0, 0, 255, 255]),
+            (false, false) => image::Rgba([255, 255, 255, 255]),
+        });
+        let texture = renderer.gizmo_texture_from_image(&quadrants).unwrap();
+
+        for (uv_min, uv_max, expected) in [
+            ([0.75, 0.0], [1.0, 0.5], [0, 255, 0, 255]),
+            ([0.0, 0.5], [0.75, 1.0], [0, 0, 255, 255]),
+            ([0.75, 0.5], [1.0, 1.0], [255, 255, 255, 255]),
+        ] {
+            let frame = render_offscreen(&renderer, |drawer| {
+                let sprite = GizmoSprite::from_uv_rect(&texture, uv_min, uv_max);
+                drawer.draw_square_slow(Some(&full_frame()), None, sprite);
+            });
+            for (x, y) in [(1, 1), (32, 32), (62, 62)] {
+                assert_eq!(
+                    frame.get_pixel(x, y).0,
+                    expected,
+                    "{:?} at {:?}",
+                    uv_min,
+                    (x, y)
+                );
+            }
+        }
+
+        // The grid path still picks whole tiles
+        let tile = SpriteSpecPadded::from(
+            GizmoSpriteSheet::new(Rc::new(texture), [0.0, 0.0], [1.0, 1.0], [2, 2])
+                .get_sprite([1, 0])
+                .unwrap()
+                .sprite_spec,
+        );
+        assert_eq!(tile.use_texture_and_padding[3], 0);
+        assert_eq!(tile.tiles_info, [2, 2, 1, 0]);
+    }
+
+    #[test]
+    fn textures_sample_with_their_own_settings() {
+        let Some(mut renderer) = headless::renderer(64, 64) else {
+            return;
+        };
+        let (red, blue) = (image::Rgba([255, 0, 0, 255]), image::Rgba([0, 0, 255, 255]));
+        let halves = RgbaImage::from_fn(2, 1, |x, _| if x == 0 { red } else { blue });
+        let sampling = |filter, wrap| TextureSampling {
+            filter,
+            wrap,
+            mipmaps: false,
+        };
+
+        // Twice across the texture, which repeats
+        let repeating = renderer
+            .gizmo_texture_from_image_with_sampling(
+                &halves,
+                sampling(TextureFilter::Nearest, TextureWrap::Repeat),
+            )
+            .unwrap();
+        let frame = render_offscreen(&renderer, |drawer| {
+            let sprite = GizmoSprite::from_uv_rect(&repeating, [0.0, 0.0], [2.0, 1.0]);
+            drawer.draw_square_slow(Some(&full_frame()), None, sprite);
+        });
+        for (x, expected) in [(8, red), (24, blue), (40, red), (56, blue)] {
+            assert_eq!(frame.get_pixel(x, 32).0, expected.0, "at {}", x);
+        }
+
+        // Blended in the middle, without the empty layer bleeding in at the
+        // edges
+        let smooth = renderer
+            .gizmo_texture_from_image_with_sampling(
+                &halves,
+                sampling(TextureFilter::Linear, TextureWrap::ClampToEdge),
+            )
+            .unwrap();
+        let frame = render_offscreen(&renderer, |drawer| {
+            let sprite = GizmoSprite::from_uv_rect(&smooth, [0.0, 0.0], [1.0, 1.0]);
+            drawer.draw_square_slow(Some(&full_frame()), None, sprite);
+        });
+        assert_eq!(frame.get_pixel(0, 32).0, red.0);
+        assert_eq!(frame.get_pixel(63, 32).0, blue.0);
+        let middle = frame.get_pixel(32, 32).0;
+        assert!(middle[0] > 64 && middle[2] > 64, "{:?}", middle);
+
+        // Stripes a pixel wide, drawn at a quarter of their size: the mips
+        // average them out, where the first level alone picks a stripe
+        let stripes = RgbaImage::from_fn(64, 64, |x, _| {
+            image::Rgba(if x % 2 == 0 { [0, 0, 0, 255] } else { [255; 4] })
+        });
+        let pixel_art = renderer.gizmo_texture_from_image(&stripes).unwrap();
+        let mipmapped = renderer
+            .gizmo_texture_from_image_with_sampling(
+                &stripes,
+                TextureSampling {
+                    mipmaps: true,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let quarter = full_frame().scale(glam::Vec3::new(0.25, 0.25, 1.0));
+        let frame = render_offscreen(&renderer, |drawer| {
+            for (texture, x) in [(&pixel_art, 0.0), (&mipmapped, 2.0)] {
+                let space = quarter.translate(glam::Vec3::new(x, 0.0, 0.0));
+                let sprite = GizmoSprite::from_uv_rect(texture, [0.0, 0.0], [1.0, 1.0]);
+                drawer.draw_square_slow(Some(&space), None, sprite);
+            }
+        });
+        assert_eq!(frame.get_pixel(8, 8).0, [0, 0, 0, 255]);
+        let gray = frame.get_pixel(40, 8).0;
+        assert!((150..=220).contains(&gray[0]), "{:?}", gray);
+    }
+
+    #[test]
+    fn draws_past_a_batch_keep_their_own_uniforms() {
+        let Some(renderer) = headless::renderer(64, 64) else {
+            return;
+        };
+        // Green from the second batch on
+        let shade = |i: u32| EngineColor {
+            r: ((i / 64) % 2) as f32,
+            g: (i >= gizmo::DRAWS_PER_BATCH) as u32 as f32,
+            b: 1.0,
+            a: 1.0,
+        };
+        // Rows of draws a column each, until past the first batch
+        let draws = gizmo::DRAWS_PER_BATCH + 8;
+        let frame = render_offscreen(&renderer, |drawer| {
+            let sprite = drawer.white_sprite();
+            for i in 0..draws {
+                let column = i % 64;
+                let space = full_frame()
+                    .translate(glam::Vec3::new(column as f32 / 64.0, 0.0, 0.0))
+                    .scale(glam::Vec3::new(1.0 / 64.0, 1.0, 1.0));
+                drawer.draw_square_slow(Some(&space), Some(&shade(i)), sprite);
+            }
+        });
+        // The last draw in each column, some from the second batch
+        for column in 0..64 {
+            let last = (column..draws).step_by(64).next_back().unwrap();
+            let expected = shade(last);
+            let pixel = frame.get_pixel(column, 32).0;
+            assert_eq!(
+                pixel,
+                [
+                    (expected.r * 255.0) as u8,
+                    (expected.g * 255.0) as u8,
+                    255,
+                    255
+                ],
+                "column {}",
+                column
+            );
+        }
+    }
+
+    #[test]
+    fn repeated_draws_reuse_pipelines_and_uniforms() {
+        let Some(renderer) = headless::renderer(64, 64) else {
+            return;
+        };
+        let pipeline = &renderer.gizmo_pipeline;
+        let uniforms = |color| DrawUniforms {
+            transform: full_frame(),
+            color,
+            sprite_spec: bytemuck::Zeroable::zeroed(),
+            material_params: [0.0; 4],
+            draw_settings: gizmo::draw_settings(None, BlendMode::Alpha),
+        };
+        let mut batch = pipeline.draw_uniform_batch();
+        assert_eq!(batch.push(&uniforms(EngineColor::WHITE)), Some(0));
+        assert_eq!(batch.push(&uniforms(EngineColor::WHITE)), Some(0));
+        assert_eq!(batch.push(&uniforms(EngineColor::BLACK)), Some(1));
+        assert_eq!(batch.len(), 2);
+
+        let before = pipeline.num_pipelines();
+        for _ in 0..3 {
+            render_offscreen(&renderer, |drawer| {
+                let sprite = drawer.white_sprite();
+                drawer.draw_square_slow(None, None, sprite);
+                drawer.set_blend_mode(BlendMode::Additive);
+                drawer.draw_square_slow(None, None, sprite);
+            });
+        }
+        // The alpha and additive pipelines, each made once
+        assert_eq!(pipeline.num_pipelines(), before + 2);
+    }
+
+    #[test]
+    fn sprite_spec_matches_the_shader_layout() {
+        // Five vec4s, and uniform structs are padded to 16 bytes
+        assert_eq!(mem::size_of::<SpriteSpecPadded>(), 80);
+        assert_eq!(SPRITE_SPEC_UNIFORM_SIZE, 80);
+        assert_eq!(mem::size_of::<SpriteSpecPadded>() % 16, 0);
+        assert_eq!(mem::size_of::<EngineColor>(), 16);
+    }
+
+    #[test]
+    fn brightness_lightens_the_output() {
+        let Some(mut renderer) = headless::renderer(64, 64) else {
+            return;
+        };
+        let gray = EngineColor {
+            r: 0.2,
+            g: 0.2,
+            b: 0.2,
+            a: 1.0,
+        };
+        let mut render_at = |brightness| {
+            renderer.set_brightness(brightness);
+            let frame = render_offscreen(&renderer, |drawer| {
+                let sprite = drawer.white_sprite();
+                drawer.draw_square_slow(Some(&full_frame()), Some(&gray), sprite);
+            });
+            frame.get_pixel(32, 32).0
+        };
+
+        let normal = render_at(1.0);
+        let bright = render_at(2.0);
+        let dark = render_at(0.5);
+        assert!(bright[0] > normal[0] + 20, "{:?} vs {:?}", bright, normal);
+        assert!(dark[0] + 20 < normal[0], "{:?} vs {:?}", dark, normal);
+        assert_eq!(bright[3], 255);
+        // Back to normal gives the same frame again
+        assert_eq!(render_at(1.0), normal);
+    }
+
+    #[test]
+    fn colors_past_white_survive_until_tone_mapped() {
+        let Some(mut renderer) = headless::renderer(64, 64) else {
+            return;
+        };
+        if !renderer.is_hdr() {
+            eprintln!("No HDR frames on this adapter, skipping");
+            return;
+        }
+        let glow = EngineColor {
+            r: 4.0,
+            g: 1.0,
+            b: 0.0,
+            a: 1.0,
+        };
+        let mut render_with = |tonemap, exposure| {
+            renderer.set_tonemap(tonemap);
+            renderer.set_exposure(exposure);
+            let frame = render_offscreen(&renderer, |drawer| {
+                let sprite = drawer.white_sprite();
+                drawer.draw_square_slow(Some(&full_frame()), Some(&glow), sprite);
+            });
+            frame.get_pixel(32, 32).0
+        };
+
+        // Clamped, red stays at white when exposed down by a quarter while
+        // green drops
+        let exposed = render_with(Tonemap::Clamp, 0.25);
+        assert_eq!(exposed[0], 255, "{:?}", exposed);
+        assert!(exposed[1] < 160, "{:?}", exposed);
+        // Reinhard keeps the brighter channel brighter without clipping it
+        let mapped = render_with(Tonemap::Reinhard, 1.0);
+        assert!(mapped[0] > mapped[1] + 20, "{:?}", mapped);
+        assert!(mapped[0] < 255, "{:?}", mapped);
+        // ACES too, lifting the dimmer channel above Reinhard's
+        let filmic = render_with(Tonemap::Aces, 1.0);
+        assert!(filmic[0] > filmic[1], "{:?}", filmic);
+        assert!(filmic[1] > mapped[1], "{:?} vs {:?}", filmic, mapped);
+        assert_eq!(render_with(Tonemap::Clamp, 1.0), [255, 255, 0, 255]);
+    }
+
+    #[test]
+    fn half_arcs_take_half_the_segments() {
+        let center = [100.0, 50.0];
+        let (full_vertices, full_indices) =
+            arc_geometry(center, 10.0, 0.0, std::f32::consts::TAU, 32);
+        let (vertices, indices) = arc_geometry(center, 10.0, 1.0, std::f32::consts::PI, 32);
+        assert_eq!(full_indices.len(), 32 * 3);
+        assert_eq!(indices.len(), 16 * 3);
+        assert_eq!((full_vertices.len(), vertices.len()), (34, 18));
+
+        // The rim spans from the start angle to the end of the sweep
+        assert_eq!(vertices[0].position, [100.0, 50.0, 0.0]);
+        let angles: Vec<f32> = vertices[1..]
+            .iter()
+            .map(|vertex| (vertex.position[1] - 50.0).atan2(vertex.position[0] - 100.0))
+            .map(|angle| angle.rem_euclid(std::f32::consts::TAU))
+            .collect();
+        assert!((angles[0] - 1.0).abs() < 1e-4);
+        assert!((angles[16] - (1.0 + std::f32::consts::PI)).abs() < 1e-4);
+        assert!(angles.windows(2).all(|pair| pair[1] > pair[0]));
+        for vertex in &vertices[1..] {
+            let offset = Vec2::new(vertex.position[0] - 100.0, vertex.position[1] - 50.0);
+            assert!((offset.length() - 10.0).abs() < 1e-3);
+        }
+
+        // Even a sliver is drawn, and sweeping backwards flips the winding
+        assert_eq!(arc_geometry(center, 10.0, 0.0, 0.01, 32).1.len(), 3);
+        let (_, backwards) = arc_geometry(center, 10.0, 0.0, -std::f32::consts::PI, 32);
+        assert_eq!(backwards[..3], [0, 1, 2]);
+        assert_eq!(indices[..3], [0, 2, 1]);
+    }
+
+    #[test]
+    fn arcs_cover_their_sweep_on_screen() {
+        let Some(renderer) = headless::renderer(64, 64) else {
+            return;
+        };
+        // The right half of a circle in the middle of a 64x64 internal view
+        let frame = render_offscreen(&renderer, |drawer| {
+            drawer.draw_arc_slow(
+                Vec2::splat(32.0),
+                20.0,
+                -std::f32::consts::FRAC_PI_2,
+                std::f32::consts::PI,
+                &EngineColor::WHITE,
+                32,
+            );
+        });
+        assert_eq!(frame.get_pixel(42, 32).0, [255, 255, 255, 255]);
+        assert_eq!(frame.get_pixel(22, 32).0, [0, 0, 0, 255]);
+        assert_eq!(frame.get_pixel(60, 32).0, [0, 0, 0, 255]);
+    }
+
+    #[test]
+    fn frame_latency_reconfigures_within_range() {
+        let Some(mut renderer) = headless::renderer(64, 64) else {
+            return;
+        };
+        assert_eq!(renderer.frame_latency(), DEFAULT_FRAME_LATENCY);
+        assert_eq!(renderer.set_frame_latency(1), 1);
+        assert_eq!(renderer.config.desired_maximum_frame_latency, 1);
+        assert_eq!(renderer.set_frame_latency(0), 1);
+        assert_eq!(renderer.set_frame_latency(10), 3);
+        assert_eq!(renderer.frame_latency(), 3);
+        assert_eq!(renderer.present_mode(), wgpu::PresentMode::Fifo);
+
+        // The reconfigured target still renders
+        let frame = render_offscreen(&renderer, |_| {});
+        assert_eq!(frame.dimensions(), (64, 64));
+    }
+
+    #[test]
+    fn higher_layers_draw_over_lower_ones() {
+        let Some(mut renderer) = headless::renderer(64, 64) else {
+            return;
+        };
+        let square = |drawer: &mut Drawer, layer, color: EngineColor| {
+            let sprite = drawer.white_sprite();
+            drawer.set_layer(layer);
+            drawer.draw_square_slow(Some(&full_frame()), Some(&color), sprite);
+        };
+        let transparent = EngineColor {
+            a: 0.0,
+            ..EngineColor::GREEN
+        };
+        for depth_buffer in [false, true] {
+            renderer.set_depth_buffer(depth_buffer);
+            let layered = render_offscreen(&renderer, |drawer| {
+                square(drawer, DrawLayer::UI, EngineColor::RED);
+                square(drawer, DrawLayer::LEVEL, EngineColor::BLUE);
+            });
+            assert_eq!(layered.get_pixel(32, 32).0, [255, 0, 0, 255]);
+
+            // Within a layer, the last draw ends up on top
+            let same_layer = render_offscreen(&renderer, |drawer| {
+                square(drawer, DrawLayer::WORLD, EngineColor::RED);
+                square(drawer, DrawLayer::WORLD, EngineColor::BLUE);
+            });
+            assert_eq!(same_layer.get_pixel(32, 32).0, [0, 0, 255, 255]);
+
+            // Nothing to see doesn't hide what's under it, depth or not
+            let see_through = render_offscreen(&renderer, |drawer| {
+                square(drawer, DrawLayer::UI, transparent);
+                square(drawer, DrawLayer::LEVEL, EngineColor::BLUE);
+            });
+            assert_eq!(see_through.get_pixel(32, 32).0, [0, 0, 255, 255]);
+        }
+        assert!(renderer.depth_buffer_enabled());
+    }
+
+    #[test]
+    fn walls_cast_shadows_from_point_lights() {
+        let Some(renderer) = headless::renderer(64, 64) else {
+            return;
+        };
+        // 8 by 8 tiles of 8 pixels, with a wall down column 6
+        let occluders: Vec<Vec<bool>> = (0..8).map(|_| (0..8).map(|x| x == 6).collect()).collect();
+        let occlusion = renderer.create_occlusion_map(&occluders);
+        let frame = render_offscreen(&renderer, |drawer| {
+            let sprite = drawer.white_sprite();
+            drawer.draw_square_slow(Some(&full_frame()), None, sprite);
+            let space = drawer.ortho.scale(glam::Vec3::new(8.0, 8.0, 1.0));
+            let mut lighting = Lighting::new(&space, EngineColor::BLACK).with_occlusion(&occlusion);
+            lighting.lights.push(lighting::PointLight {
+                position: Vec2::new(4.0, 4.0),
+                radius: 10.0,
+                color: EngineColor::WHITE,
+            });
+            drawer.set_layer(DrawLayer::LIGHTING);
+            drawer.draw_lighting_slow(&lighting);
+        });
+        let lit = |x, y| frame.get_pixel(x, y).0[0] > 0;
+        assert!(lit(44, 36), "In front of the wall");
+        assert!(lit(50, 36), "The wall itself, on the side facing the light");
+        assert!(!lit(60, 36), "Behind the wall");
+        assert!(lit(4, 60), "Away from the wall");
+        assert_eq!(
+            frame.get_pixel(60, 36).0[3],
+            255,
+            "Lighting keeps the alpha"
+        );
+    }
+
+    #[test]
+    fn post_effects_apply_in_order_while_enabled() {
+        let Some(mut renderer) = headless::renderer(64, 64) else {
+            return;
+        };
+        let white_frame = |drawer: &mut Drawer| {
+            let sprite = drawer.white_sprite();
+            drawer.draw_square_slow(Some(&full_frame()), None, sprite);
+        };
+        let vignette = renderer.add_post_effect(PostEffect::Vignette {
+            color: EngineColor::RED,
+            radius: 0.5,
+        });
+        // The corner pixel's middle is a little short of the corner
+        let near = |actual: [u8; 4], expected: [u8; 4]| {
+            actual
+                .iter()
+                .zip(expected)
+                .all(|(&a, e)| a.abs_diff(e) <= 16)
+        };
+        let vignetted = render_offscreen(&renderer, white_frame);
+        assert_eq!(vignetted.get_pixel(32, 32).0, [255, 255, 255, 255]);
+        assert!(near(vignetted.get_pixel(0, 0).0, [255, 0, 0, 255]));
+
+        // Inverting after the vignette turns its red cyan
+        let inverted = RgbaImage::from_fn(4, 2, |x, y| {
+            let lut = post::identity_lut_image(2);
+            let [r, g, b, a] = lut.get_pixel(x, y).0;
+            image::Rgba([255 - r, 255 - g, 255 - b, a])
+        });
+        let lut = renderer.create_color_lut(&inverted).unwrap();
+        renderer.add_post_effect(PostEffect::ColorGrading(lut));
+        let graded = render_offscreen(&renderer, white_frame);
+        assert_eq!(graded.get_pixel(32, 32).0, [0, 0, 0, 255]);
+        assert!(near(graded.get_pixel(0, 0).0, [0, 255, 255, 255]));
+
+        renderer.set_post_effect_enabled(&vignette, false);
+        let unvignetted = render_offscreen(&renderer, white_frame);
+        assert_eq!(unvignetted.get_pixel(0, 0).0, [0, 0, 0, 255]);
+
+        assert!(renderer.create_color_lut(&RgbaImage::new(5, 2)).is_none());
+    }
+
+    #[test]
+    fn transitions_cover_the_frame_in_their_style() {
+        let Some(renderer) = headless::renderer(64, 64) else {
+            return;
+        };
+        let halfway = |style| {
+            let mut transition = Transition::new(style, 1.0).with_color(EngineColor::RED);
+            transition.update(0.5);
+            render_offscreen(&renderer, |drawer| {
+                let sprite = drawer.white_sprite();
+                drawer.draw_square_slow(Some(&full_frame()), None, sprite);
+                transition.draw(drawer);
+            })
+        };
+        let red = [255, 0, 0, 255];
+        let white = [255, 255, 255, 255];
+
+        let wiped = halfway(TransitionStyle::Wipe);
+        assert_eq!(wiped.get_pixel(8, 32).0, red);
+        assert_eq!(wiped.get_pixel(56, 32).0, white);
+
+        let iris = halfway(TransitionStyle::Iris);
+        assert_eq!(iris.get_pixel(32, 32).0, white);
+        assert_eq!(iris.get_pixel(1, 1).0, red);
+        assert_eq!(iris.get_pixel(62, 62).0, red);
+
+        let faded = halfway(TransitionStyle::Fade);
+        let [r, g, _, _] = faded.get_pixel(32, 32).0;
+        assert_eq!(r, 255);
+        assert!(g > 0 && g < 255);
+    }
+
+    #[test]
+    fn bitmap_text_draws_a_tile_per_glyph() {
+        let Some(mut renderer) = headless::renderer(64, 64) else {
+            return;
+        };
+        // A white 1 and a see-through 0
+        let image = RgbaImage::from_fn(8, 4, |x, _| match x < 4 {
+            true => image::Rgba([255; 4]),
+            false => image::Rgba([0; 4]),
+        });
+        let texture = renderer.gizmo_texture_from_image(&image).unwrap();
+        let sheet = GizmoSpriteSheet::new(Rc::new(texture), [0.0, 0.0], [1.0, 1.0], [2, 1]);
+        let font = Rc::new(BitmapFont::from_grid(sheet, "10"));
+        let mut text = renderer.create_bitmap_text_buffer(
+            &font,
+            32.0,
+            16.0,
+            "",
+            glyphon::cosmic_text::Align::Left,
+        );
+        text.set_markup(&mut renderer, "0[color=#00ff00]1[/color]\n1");
+
+        let frame = render_offscreen(&renderer, |drawer| {
+            drawer.draw_text_slow(&text, 8.0, 8.0, 2.0, GlyphonColor::rgb(255, 0, 0));
+        });
+        assert_eq!(frame.get_pixel(12, 12).0, [0, 0, 0, 255]);
+        assert_eq!(frame.get_pixel(20, 12).0, [0, 255, 0, 255]);
+        assert_eq!(frame.get_pixel(12, 20).0, [255, 0, 0, 255]);
+        assert_eq!(frame.get_pixel(20, 20).0, [0, 0, 0, 255]);
+    }
+
+    #[test]
+    fn tilemaps_draw_a_tile_per_cell() {
+        let Some(mut renderer) = headless::renderer(64, 64) else {
+            return;
+        };
+        // A sheet of 2 by 2 tiles of different colors
+        let colors = [
+            [255, 0, 0, 255],
+            [0, 255, 0, 255],
+            [0, 0, 255, 255],
+            [255; 4],
+        ];
+        let image = RgbaImage::from_fn(4, 4, |x, y| {
+            image::Rgba(colors[(x / 2 + y / 2 * 2) as usize])
+        });
+        let texture = renderer.gizmo_texture_from_image(&image).unwrap();
+        let sheet = GizmoSpriteSheet::new(Rc::new(texture), [0.0, 0.0], [1.0, 1.0], [2, 2]);
+        let mut tilemap = TilemapRenderer::new(
+            &renderer,
+            sheet,
+            &[
+                vec![Some([0, 0]), Some([1, 0])],
+                vec![Some([0, 1]), Some([5, 5])],
+            ],
+        );
+        // Tiles the sheet doesn't have are left empty
+        assert_eq!(tilemap.tile((1, 1)), None);
+        assert_eq!(tilemap.tile((1, 0)), Some([1, 0]));
+
+        // Two tiles across the frame
+        let space = full_frame().scale(glam::Vec3::new(0.5, 0.5, 1.0));
+        let cells = [(16, 16), (48, 16), (16, 48), (48, 48)];
+        let frame = render_offscreen(&renderer, |drawer| {
+            drawer.draw_tilemap_slow(&tilemap, Some(&space), None);
+        });
+        for ((x, y), expected) in
+            cells
+                .iter()
+                .zip([colors[0], colors[1], colors[2], [0, 0, 0, 255]])
         {
-            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                label: Some("Text Pass"),
-                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                    view: self.view,
-                    resolve_target: None,
-                    ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Load,
-                        store: wgpu::StoreOp::Store,
-                    },
-                })],
-                depth_stencil_attachment: None,
-                occlusion_query_set: None,
-                timestamp_writes: None,
+            assert_eq!(frame.get_pixel(*x, *y).0, expected, "at {:?}", (x, y));
+        }
+
+        assert!(tilemap.set_tile(&renderer, (1, 1), Some([1, 1])));
+        assert!(!tilemap.set_tile(&renderer, (2, 0), Some([1, 1])));
+        let frame = render_offscreen(&renderer, |drawer| {
+            drawer.draw_tilemap_slow(&tilemap, Some(&space), None);
+        });
+        assert_eq!(frame.get_pixel(48, 48).0, colors[3]);
+        assert_eq!(frame.get_pixel(16, 16).0, colors[0]);
+    }
+
+    #[test]
+    fn nine_slices_keep_their_corners() {
+        let Some(mut renderer) = headless::renderer(64, 64) else {
+            return;
+        };
+        // A 6 by 6 panel: blue corners, red edges and a green middle, each
+        // 2 pixels wide
+        let (corner, edge, middle) = ([0, 0, 255, 255], [255, 0, 0, 255], [0, 255, 0, 255]);
+        let panel = RgbaImage::from_fn(6, 6, |x, y| {
+            let on_edge = |i: u32| !(2..4).contains(&i);
+            image::Rgba(match (on_edge(x), on_edge(y)) {
+                (true, true) => corner,
+                (false, false) => middle,
+                _ => edge,
+            })
+        });
+        let texture = renderer.gizmo_texture_from_image(&panel).unwrap();
+        let sprite = GizmoSprite::from_uv_rect(&texture, [0.0, 0.0], [1.0, 1.0]);
+        assert_eq!(sprite.pixel_size(), [6.0, 6.0]);
+
+        let frame = render_offscreen(&renderer, |drawer| {
+            let rect = [Vec2::new(0.0, 0.0), drawer.internal_size()];
+            drawer.draw_nine_slice_slow(sprite, rect, 2.0, None);
+        });
+        for ((x, y), expected) in [
+            ((0, 0), corner),
+            ((1, 1), corner),
+            ((63, 62), corner),
+            ((3, 3), middle),
+            ((32, 32), middle),
+            ((32, 1), edge),
+            ((1, 32), edge),
+            ((62, 40), edge),
+        ] {
+            assert_eq!(frame.get_pixel(x, y).0, expected, "at {:?}", (x, y));
+        }
+    }
+
+    #[test]
+    fn materials_replace_the_sprite_shader_per_draw() {
+        let Some(mut renderer) = headless::renderer(64, 64) else {
+            return;
+        };
+        // A red square in the middle of a transparent 4 by 4 sprite
+        let sprite_image = RgbaImage::from_fn(4, 4, |x, y| {
+            if (1..3).contains(&x) && (1..3).contains(&y) {
+                image::Rgba([255, 0, 0, 255])
+            } else {
+                image::Rgba([0, 0, 0, 0])
+            }
+        });
+        let texture = renderer.gizmo_texture_from_image(&sprite_image).unwrap();
+        let outline = renderer.add_material(material::OUTLINE);
+        let grayscale = renderer.add_material(material::GRAYSCALE);
+        // Compiles like the others, though it's random what it keeps
+        renderer.add_material(material::DISSOLVE);
+        let sprite = GizmoSprite::from_uv_rect(&texture, [0.0, 0.0], [1.0, 1.0]);
+
+        // A sprite pixel is 16 by 16 on screen
+        let frame = render_offscreen(&renderer, |drawer| {
+            drawer.set_material(Some(Material::new(outline, [1.0, 1.0, 1.0, 1.0])));
+            assert_eq!(
+                drawer.material().map(|material| material.handle),
+                Some(outline)
+            );
+            drawer.draw_square_slow(Some(&full_frame()), None, sprite);
+        });
+        assert_eq!(frame.get_pixel(24, 24).0, [255, 0, 0, 255]);
+        assert_eq!(frame.get_pixel(24, 8).0, [255, 255, 255, 255]);
+        assert_eq!(frame.get_pixel(8, 40).0, [255, 255, 255, 255]);
+        // Diagonal to the square, so not touching it
+        assert_eq!(frame.get_pixel(8, 8).0, [0, 0, 0, 255]);
+
+        let frame = render_offscreen(&renderer, |drawer| {
+            let left = full_frame().scale(glam::Vec3::new(0.5, 1.0, 1.0));
+            let right = full_frame()
+                .translate(glam::Vec3::new(0.5, 0.0, 0.0))
+                .scale(glam::Vec3::new(0.5, 1.0, 1.0));
+            drawer.set_material(Some(Material::new(grayscale, [1.0, 0.0, 0.0, 0.0])));
+            drawer.draw_square_slow(Some(&left), None, sprite);
+            drawer.set_material(None);
+            drawer.draw_square_slow(Some(&right), None, sprite);
+        });
+        let [r, g, b, _] = frame.get_pixel(16, 32).0;
+        assert!(r > 0 && r == g && g == b, "{:?}", (r, g, b));
+        assert_eq!(frame.get_pixel(48, 32).0, [255, 0, 0, 255]);
+    }
+
+    #[test]
+    fn palettes_recolor_indexed_sprites() {
+        let Some(mut renderer) = headless::renderer(64, 64) else {
+            return;
+        };
+        // Indices 1 and 2 side by side
+        let indexed = RgbaImage::from_fn(2, 1, |x, _| image::Rgba([x as u8 + 1, 0, 0, 255]));
+        let texture = renderer.gizmo_texture_from_image(&indexed).unwrap();
+        let sprite = GizmoSprite::from_uv_rect(&texture, [0.0, 0.0], [1.0, 1.0]);
+        let (red, green) = ([255, 0, 0, 255], [0, 255, 0, 255]);
+        let (blue, white) = ([0, 0, 255, 255], [255; 4]);
+        let warm = renderer.add_palette(&[[0; 4], red, green]).unwrap();
+        let cold = renderer.add_palette(&[[0; 4], blue, blue]).unwrap();
+        renderer.set_palette(&cold, &[[0; 4], blue, white]);
+        assert!(renderer.add_palette(&[[0; 4]; 257]).is_none());
+
+        for (palette, expected) in [(Some(warm), [red, green]), (Some(cold), [blue, white])] {
+            let frame = render_offscreen(&renderer, |drawer| {
+                drawer.set_palette(palette);
+                drawer.draw_square_slow(Some(&full_frame()), None, sprite);
             });
+            assert_eq!(frame.get_pixel(16, 32).0, expected[0], "{:?}", palette);
+            assert_eq!(frame.get_pixel(48, 32).0, expected[1], "{:?}", palette);
+        }
 
-            self.renderer
-                .text_pipeline
-                .borrow_mut()
-                .render(&mut render_pass)
-                .expect("Failed to render text");
+        // Without a palette the indices are drawn as colors, barely red
+        let frame = render_offscreen(&renderer, |drawer| {
+            drawer.draw_square_slow(Some(&full_frame()), None, sprite);
+        });
+        assert_eq!(frame.get_pixel(16, 32).0, [1, 0, 0, 255]);
+    }
+
+    #[test]
+    fn multisampling_smooths_the_edges_of_rotated_quads() {
+        let Some(mut renderer) = headless::multisampled_renderer(64, 64, 4) else {
+            return;
+        };
+        assert!([1, 2, 4].contains(&renderer.sample_count()));
+        if renderer.sample_count() == 1 {
+            eprintln!("No multisampling on this adapter, skipping");
+            return;
+        }
+        renderer.set_depth_buffer(true);
+        let frame = render_offscreen(&renderer, |drawer| {
+            // A white diamond with its corners on the middles of the edges
+            let space = drawer
+                .ortho
+                .translate(glam::Vec3::new(32.0, 0.0, 0.0))
+                .rotate(std::f32::consts::FRAC_PI_4, glam::Vec3::Z)
+                .scale(glam::Vec3::new(45.254_83, 45.254_83, 1.0));
+            let sprite = drawer.white_sprite();
+            drawer.draw_square_slow(Some(&space), None, sprite);
+            // Goes through the same multisampled target
+            let lighting = Lighting::new(drawer.ortho, EngineColor::WHITE);
+            drawer.set_layer(DrawLayer::LIGHTING);
+            drawer.draw_lighting_slow(&lighting);
+        });
+        assert_eq!(frame.get_pixel(32, 32).0, [255; 4]);
+        assert_eq!(frame.get_pixel(2, 2).0, [0, 0, 0, 255]);
+        // Pixels the diagonal edge cuts through come out in between
+        let partial = (0..64).any(|x| {
+            let [r, ..] = frame.get_pixel(x, 16).0;
+            r > 0 && r < 255
+        });
+        assert!(partial, "No partly covered pixels along the edge");
+    }
+
+    #[test]
+    fn primitives_cover_their_shapes_either_way_round() {
+        let Some(renderer) = headless::renderer(64, 64) else {
+            return;
+        };
+        let red = EngineColor {
+            r: 1.0,
+            g: 0.0,
+            b: 0.0,
+            a: 1.0,
+        };
+        let drawn = |frame: &RgbaImage, x, y| frame.get_pixel(x, y).0 == [255, 0, 0, 255];
+
+        let frame = render_offscreen(&renderer, |drawer| {
+            drawer.draw_line_slow(None, Vec2::new(4.0, 8.0), Vec2::new(60.0, 8.0), 4.0, &red);
+            drawer.draw_circle_slow(None, Vec2::new(32.0, 40.0), 12.0, Some(2.0), &red, 32);
+        });
+        assert!(drawn(&frame, 32, 8) && drawn(&frame, 32, 9));
+        assert!(!drawn(&frame, 32, 12) && !drawn(&frame, 2, 8));
+        assert!(drawn(&frame, 44, 40) && drawn(&frame, 32, 28));
+        assert!(!drawn(&frame, 32, 40), "Outlines leave the middle empty");
+
+        let triangle = [
+            Vec2::new(4.0, 20.0),
+            Vec2::new(36.0, 20.0),
+            Vec2::new(4.0, 52.0),
+        ];
+        let reversed = [triangle[2], triangle[1], triangle[0]];
+        for points in [triangle, reversed] {
+            let frame = render_offscreen(&renderer, |drawer| {
+                drawer.draw_polygon_slow(None, &points, None, &red);
+            });
+            assert!(drawn(&frame, 10, 26), "{:?}", points);
+            assert!(!drawn(&frame, 30, 46), "{:?}", points);
+
+            let frame = render_offscreen(&renderer, |drawer| {
+                drawer.draw_polygon_slow(None, &points, Some(2.0), &red);
+            });
+            assert!(
+                drawn(&frame, 20, 20) && drawn(&frame, 4, 36),
+                "{:?}",
+                points
+            );
+            assert!(drawn(&frame, 20, 36), "The closing edge, {:?}", points);
+            assert!(!drawn(&frame, 10, 26), "{:?}", points);
         }
+    }
 
-        self.command_buffers.push(encoder.finish());
+    #[test]
+    fn blend_modes_brighten_and_darken_what_they_cover() {
+        let Some(mut renderer) = headless::renderer(64, 64) else {
+            return;
+        };
+        // Dark gray on the left, transparent on the right
+        let image = RgbaImage::from_fn(2, 1, |x, _| match x {
+            0 => image::Rgba([64, 64, 64, 255]),
+            _ => image::Rgba([255, 255, 255, 0]),
+        });
+        let texture = renderer.gizmo_texture_from_image(&image).unwrap();
+        let sprite = GizmoSprite::from_uv_rect(&texture, [0.0, 0.0], [1.0, 1.0]);
+        let gray = Color {
+            r: 0.5,
+            g: 0.5,
+            b: 0.5,
+            a: 1.0,
+        };
+        let draw = |blend_mode| {
+            render_offscreen(&renderer, |drawer| {
+                drawer.clear_slow(gray);
+                drawer.set_blend_mode(blend_mode);
+                drawer.draw_square_slow(Some(&full_frame()), None, sprite);
+                // Drawn again through the same pipeline
+                drawer.draw_square_slow(Some(&full_frame()), None, sprite);
+            })
+        };
+        let background = render_offscreen(&renderer, |drawer| drawer.clear_slow(gray));
+        let [base, ..] = background.get_pixel(16, 32).0;
+
+        let covered = |blend_mode| {
+            let frame = draw(blend_mode);
+            // Transparent texels leave the frame as it was in every mode
+            assert_eq!(frame.get_pixel(48, 32).0, background.get_pixel(48, 32).0);
+            frame.get_pixel(16, 32).0
+        };
+        assert_eq!(covered(BlendMode::Alpha), [64, 64, 64, 255]);
+        let [added, ..] = covered(BlendMode::Additive);
+        assert!(added > base, "{} isn't brighter than {}", added, base);
+        let [multiplied, ..] = covered(BlendMode::Multiply);
+        assert!(
+            multiplied < base.min(64),
+            "{} isn't darker than {}",
+            multiplied,
+            base
+        );
+        assert!(multiplied > 0);
     }
 
-    pub fn flush(&mut self) {
-        if !self.command_buffers.is_empty() {
-            self.renderer
-                .queue
-                .submit(mem::take(&mut self.command_buffers));
-            self.command_buffers.clear();
+    #[test]
+    fn frames_scale_up_by_whole_pixels_between_bars() {
+        let Some(mut renderer) = headless::renderer(32, 24) else {
+            return;
+        };
+        // Twice the frame fits, 64x48 in the middle
+        renderer.resize(winit::dpi::PhysicalSize::new(100, 60));
+        let frame = render_offscreen(&renderer, |drawer| {
+            drawer.clear_slow(Color::WHITE);
+            // The top-left pixel black again
+            let space = drawer.ortho.clone();
+            let sprite = drawer.white_sprite();
+            drawer.draw_square_slow(Some(&space), Some(&EngineColor::BLACK), sprite);
+        });
+        assert_eq!(frame.dimensions(), (100, 60));
+        let white = [255; 4];
+        let black = [0, 0, 0, 255];
+        // Every pixel is two by two, with no blending into its neighbors
+        for (x, y) in [(18, 6), (19, 7)] {
+            assert_eq!(frame.get_pixel(x, y).0, black, "{}, {}", x, y);
+        }
+        for (x, y) in [(20, 6), (18, 8), (20, 8), (81, 53)] {
+            assert_eq!(frame.get_pixel(x, y).0, white, "{}, {}", x, y);
+        }
+        // The bars around it
+        for (x, y) in [(17, 30), (82, 30), (50, 5), (50, 54)] {
+            assert_eq!(frame.get_pixel(x, y).0, black, "{}, {}", x, y);
         }
     }
 }
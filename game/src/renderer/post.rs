@@ -0,0 +1,467 @@
+//! Post-processing: with effects added, the frame is drawn offscreen and the
+//! effects are applied one after the other on its way to the screen.
+
+use std::mem;
+
+use image::RgbaImage;
+use wgpu::{
+    BindGroup, BindGroupLayout, Buffer, Device, Queue, RenderPipeline, Sampler,
+    SurfaceConfiguration, TextureView,
+};
+
+use crate::renderer::{EngineColor, HDR_FORMAT};
+
+/// `Post` in post.wgsl, three `vec4`s
+const POST_UNIFORM_SIZE: u64 = 48;
+
+const _: () = assert!(mem::size_of::<PostUniform>() as u64 == POST_UNIFORM_SIZE);
+
+#[derive(Debug, Clone)]
+pub enum PostEffect {
+    /// Fades the edges of the frame into `color`, by up to its alpha, e.g. a
+    /// red flash when hurt. The fade starts `radius` of the way from the
+    /// middle to the corners.
+    Vignette { color: EngineColor, radius: f32 },
+    /// Pulls red and blue apart towards the edges, by `strength` of the
+    /// distance from the middle
+    ChromaticAberration { strength: f32 },
+    /// Bulges the frame out like an old CRT, more the higher `curvature`.
+    /// What's bent off the screen is left black.
+    CrtCurvature { curvature: f32 },
+    /// Maps every color through a lookup table
+    ColorGrading(ColorLut),
+}
+
+impl PostEffect {
+    fn uniform(&self, srgb: bool) -> PostUniform {
+        let (kind, params, color, lut_size) = match self {
+            Self::Vignette { color, radius } => (0, [*radius, 0.0, 0.0, 0.0], *color, 0),
+            Self::ChromaticAberration { strength } => {
+                (1, [*strength, 0.0, 0.0, 0.0], EngineColor::WHITE, 0)
+            }
+            Self::CrtCurvature { curvature } => {
+                (2, [*curvature, 0.0, 0.0, 0.0], EngineColor::WHITE, 0)
+            }
+            Self::ColorGrading(lut) => (3, [0.0; 4], EngineColor::WHITE, lut.size),
+        };
+        PostUniform {
+            params,
+            color: bytemuck::cast(color),
+            info: [kind, lut_size, srgb as u32, 0],
+        }
+    }
+
+    fn lut(&self) -> Option<&ColorLut> {
+        match self {
+            Self::ColorGrading(lut) => Some(lut),
+            _ => None,
+        }
+    }
+}
+
+/// A color lookup table for `PostEffect::ColorGrading`, see
+/// `RenderingSystem::create_color_lut`
+#[derive(Debug, Clone)]
+pub struct ColorLut {
+    bind_group: BindGroup,
+    size: u32,
+}
+
+/// The lookup table that leaves every color as it is, `size` slices of
+/// `size` by `size` side by side. Red grows to the right and green down
+/// within a slice, and blue from slice to slice. Grades are made by
+/// recoloring it, e.g. in an image editor next to a screenshot.
+pub fn identity_lut_image(size: u32) -> RgbaImage {
+    let level = |value: u32| (value * 255 / (size - 1).max(1)) as u8;
+    RgbaImage::from_fn(size * size, size, |x, y| {
+        image::Rgba([level(x % size), level(y), level(x / size), 255])
+    })
+}
+
+/// Refers to an effect added with `RenderingSystem::add_post_effect`
+#[derive(Debug, Clone)]
+pub struct PostEffectHandle {
+    index: usize,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct PostUniform {
+    params: [f32; 4],
+    color: [f32; 4],
+    info: [u32; 4],
+}
+
+/// A frame-sized texture effects draw into and read from
+struct PostTarget {
+    view: TextureView,
+    bind_group: BindGroup,
+}
+
+pub struct PostProcessor {
+    pipeline: RenderPipeline,
+    uniform_buffer: Buffer,
+    uniform_bind_group: BindGroup,
+    source_layout: BindGroupLayout,
+    source_sampler: Sampler,
+    lut_layout: BindGroupLayout,
+    lut_sampler: Sampler,
+    // Bound for the effects that don't grade, as something has to be
+    identity_lut: ColorLut,
+    srgb: bool,
+    effects: Vec<(PostEffect, bool)>,
+    // Two, so each effect can read the frame the one before it drew. Only
+    // there while there are effects.
+    targets: Option<[PostTarget; 2]>,
+}
+
+impl PostProcessor {
+    pub fn new(device: &Device, queue: &Queue, format: wgpu::TextureFormat) -> Self {
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Post Shader"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("../assets/post.wgsl").into()),
+        });
+
+        let uniform_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Post Buffer"),
+            size: POST_UNIFORM_SIZE,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let uniform_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Post Bind Group Layout"),
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: wgpu::BufferSize::new(POST_UNIFORM_SIZE),
+                },
+                count: None,
+            }],
+        });
+        let uniform_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Post Bind Group"),
+            layout: &uniform_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: uniform_buffer.as_entire_binding(),
+            }],
+        });
+
+        let texture_layout = |label| {
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some(label),
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            multisampled: false,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                        count: None,
+                    },
+                ],
+            })
+        };
+        let source_layout = texture_layout("Post Source Bind Group Layout");
+        let lut_layout = texture_layout("Color LUT Bind Group Layout");
+
+        let sampler = |label, filter| {
+            device.create_sampler(&wgpu::SamplerDescriptor {
+                label: Some(label),
+                address_mode_u: wgpu::AddressMode::ClampToEdge,
+                address_mode_v: wgpu::AddressMode::ClampToEdge,
+                address_mode_w: wgpu::AddressMode::ClampToEdge,
+                mag_filter: filter,
+                min_filter: filter,
+                mipmap_filter: wgpu::FilterMode::Nearest,
+                ..Default::default()
+            })
+        };
+        // Nearest keeps the pixels crisp, the LUT's levels are blended
+        let source_sampler = sampler("Post Source Sampler", wgpu::FilterMode::Nearest);
+        let lut_sampler = sampler("Color LUT Sampler", wgpu::FilterMode::Linear);
+
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Post Pipeline Layout"),
+            bind_group_layouts: &[&uniform_layout, &source_layout, &lut_layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Post Pipeline"),
+            layout: Some(&layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: Some("vs_main"),
+                buffers: &[],
+                compilation_options: wgpu::PipelineCompilationOptions::default(),
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: Some("fs_main"),
+                targets: &[Some(wgpu::ColorTargetState {
+                    format,
+                    blend: None,
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+                compilation_options: wgpu::PipelineCompilationOptions::default(),
+            }),
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+            cache: None,
+        });
+
+        let identity_lut = Self::create_color_lut_internal(
+            device,
+            queue,
+            &lut_layout,
+            &lut_sampler,
+            &identity_lut_image(2),
+        );
+
+        Self {
+            pipeline,
+            uniform_buffer,
+            uniform_bind_group,
+            source_layout,
+            source_sampler,
+            lut_layout,
+            lut_sampler,
+            identity_lut,
+            // Float frames hold linear colors, as sRGB ones do once read
+            srgb: format.is_srgb() || format == HDR_FORMAT,
+            effects: Vec::new(),
+            targets: None,
+        }
+    }
+
+    /// A lookup table laid out like `identity_lut_image`, `None` if the
+    /// image isn't `size * size` by `size` for some size
+    pub fn create_color_lut(
+        &self,
+        device: &Device,
+        queue: &Queue,
+        image: &RgbaImage,
+    ) -> Option<ColorLut> {
+        let size = image.height();
+        (size > 0 && image.width() == size * size).then(|| {
+            Self::create_color_lut_internal(
+                device,
+                queue,
+                &self.lut_layout,
+                &self.lut_sampler,
+                image,
+            )
+        })
+    }
+
+    fn create_color_lut_internal(
+        device: &Device,
+        queue: &Queue,
+        layout: &BindGroupLayout,
+        sampler: &Sampler,
+        image: &RgbaImage,
+    ) -> ColorLut {
+        let size = wgpu::Extent3d {
+            width: image.width(),
+            height: image.height(),
+            depth_or_array_layers: 1,
+        };
+        // Not sRGB, the table is looked up with and gives sRGB colors as they are
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Color LUT"),
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba8Unorm,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            view_formats: &[],
+        });
+        queue.write_texture(
+            texture.as_image_copy(),
+            image.as_raw(),
+            wgpu::TexelCopyBufferLayout {
+                offset: 0,
+                bytes_per_row: Some(4 * image.width()),
+                rows_per_image: Some(image.height()),
+            },
+            size,
+        );
+        let view = texture.create_view(&Default::default());
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Color LUT Bind Group"),
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(sampler),
+                },
+            ],
+        });
+        ColorLut {
+            bind_group,
+            size: image.height(),
+        }
+    }
+
+    /// Adds `effect`, enabled, after the ones already there
+    pub fn add(
+        &mut self,
+        device: &Device,
+        config: &SurfaceConfiguration,
+        effect: PostEffect,
+    ) -> PostEffectHandle {
+        self.effects.push((effect, true));
+        if self.targets.is_none() {
+            self.resize(device, config);
+        }
+        PostEffectHandle {
+            index: self.effects.len() - 1,
+        }
+    }
+
+    pub fn set(&mut self, handle: &PostEffectHandle, effect: PostEffect) {
+        self.effects[handle.index].0 = effect;
+    }
+
+    pub fn set_enabled(&mut self, handle: &PostEffectHandle, enabled: bool) {
+        self.effects[handle.index].1 = enabled;
+    }
+
+    /// Recreates the frame-sized targets, if there are effects to use them
+    pub fn resize(&mut self, device: &Device, config: &SurfaceConfiguration) {
+        if self.effects.is_empty() {
+            return;
+        }
+        let target = |label| {
+            let view = device
+                .create_texture(&wgpu::TextureDescriptor {
+                    label: Some(label),
+                    size: wgpu::Extent3d {
+                        width: config.width,
+                        height: config.height,
+                        depth_or_array_layers: 1,
+                    },
+                    mip_level_count: 1,
+                    sample_count: 1,
+                    dimension: wgpu::TextureDimension::D2,
+                    format: config.format,
+                    usage: wgpu::TextureUsages::RENDER_ATTACHMENT
+                        | wgpu::TextureUsages::TEXTURE_BINDING,
+                    view_formats: &[],
+                })
+                .create_view(&Default::default());
+            let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("Post Source Bind Group"),
+                layout: &self.source_layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(&view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::Sampler(&self.source_sampler),
+                    },
+                ],
+            });
+            PostTarget { view, bind_group }
+        };
+        self.targets = Some([target("Post Target A"), target("Post Target B")]);
+    }
+
+    fn enabled_effects(&self) -> impl Iterator<Item = &PostEffect> {
+        self.effects
+            .iter()
+            .filter(|(_, enabled)| *enabled)
+            .map(|(effect, _)| effect)
+    }
+
+    /// Where to draw the frame before `apply`, `None` to draw it straight
+    /// to the screen when no effect is enabled
+    pub fn scene_view(&self) -> Option<&TextureView> {
+        self.enabled_effects().next()?;
+        self.targets.as_ref().map(|[scene, _]| &scene.view)
+    }
+
+    /// Applies every enabled effect, in the order they were added, to the
+    /// frame drawn into `scene_view`, drawing the result into `output`
+    pub fn apply(&self, device: &Device, queue: &Queue, output: &TextureView) {
+        let Some(targets) = &self.targets else {
+            return;
+        };
+        let effects: Vec<&PostEffect> = self.enabled_effects().collect();
+        for (i, effect) in effects.iter().enumerate() {
+            let source = &targets[i % 2];
+            let destination = match effects.get(i + 1) {
+                Some(_) => &targets[(i + 1) % 2].view,
+                None => output,
+            };
+            // Every effect shares the uniform, so each one is submitted
+            // before the next one overwrites it
+            queue.write_buffer(
+                &self.uniform_buffer,
+                0,
+                bytemuck::cast_slice(&[effect.uniform(self.srgb)]),
+            );
+            let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Post Encoder"),
+            });
+            {
+                let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                    label: Some("Post Pass"),
+                    color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                        view: destination,
+                        resolve_target: None,
+                        ops: wgpu::Operations {
+                            load: wgpu::LoadOp::Load,
+                            store: wgpu::StoreOp::Store,
+                        },
+                    })],
+                    depth_stencil_attachment: None,
+                    occlusion_query_set: None,
+                    timestamp_writes: None,
+                });
+                let lut = effect.lut().unwrap_or(&self.identity_lut);
+                render_pass.set_pipeline(&self.pipeline);
+                render_pass.set_bind_group(0, &self.uniform_bind_group, &[]);
+                render_pass.set_bind_group(1, &source.bind_group, &[]);
+                render_pass.set_bind_group(2, &lut.bind_group, &[]);
+                render_pass.draw(0..3, 0..1);
+            }
+            queue.submit(std::iter::once(encoder.finish()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identity_lut_spans_every_channel() {
+        let lut = identity_lut_image(4);
+        assert_eq!(lut.dimensions(), (16, 4));
+        assert_eq!(lut.get_pixel(0, 0).0, [0, 0, 0, 255]);
+        // Last red of the second blue slice, on the last green row
+        assert_eq!(lut.get_pixel(7, 3).0, [255, 255, 85, 255]);
+        assert_eq!(lut.get_pixel(15, 3).0, [255, 255, 255, 255]);
+    }
+}
@@ -1,13 +1,18 @@
-use std::rc::Rc;
+use std::{collections::HashMap, rc::Rc};
 
 use glyphon::{
-    cosmic_text::Align, Attrs, Buffer, Cache, Color, FontSystem, Metrics, Resolution, SwashCache,
-    TextArea, TextAtlas, TextBounds, TextRenderer, Viewport,
+    cosmic_text::Align, Attrs, Buffer, Cache, Color, ContentType, CustomGlyph, CustomGlyphId,
+    FontSystem, Metrics, RasterizeCustomGlyphRequest, RasterizedCustomGlyph, Resolution,
+    SwashCache, TextArea, TextAtlas, TextBounds, TextRenderer, Viewport, Weight, Wrap,
 };
-use rand::rand_core::le;
-use wgpu::{Device, MultisampleState, TextureFormat};
+use image::RgbaImage;
+use wgpu::{MultisampleState, TextureFormat};
 
-use crate::renderer::RenderingSystem;
+use crate::renderer::{
+    bitmap_font::{BitmapFont, PlacedGlyph},
+    markup::{parse_markup, MarkupSpan},
+    RenderingSystem,
+};
 
 pub struct TextRenderPipeline {
     font_system: FontSystem,
@@ -15,9 +20,83 @@ pub struct TextRenderPipeline {
     viewport: Viewport,
     pub atlas: TextAtlas,
     text_renderer: TextRenderer,
-    cache: Cache,
+    icons: TextIcons,
+}
+
+/// Images that can be put in text with `[icon=name]`, by name
+#[derive(Default)]
+struct TextIcons {
+    ids: HashMap<String, CustomGlyphId>,
+    images: Vec<RgbaImage>,
+}
+
+impl TextIcons {
+    /// Registering a name again gives it a new id, so glyphon doesn't keep
+    /// drawing the old image it rasterized
+    #[cfg(test)]
+    fn register(&mut self, name: &str, image: RgbaImage) {
+        let id = self.images.len() as CustomGlyphId;
+        self.images.push(image);
+        self.ids.insert(name.to_string(), id);
+    }
+
+    /// How many icons were ever registered, including ones since replaced
+    fn len(&self) -> usize {
+        self.images.len()
+    }
+
+    /// The icon scaled to the size glyphon asks for, by whole pixels where
+    /// it can be
+    fn rasterize(&self, request: RasterizeCustomGlyphRequest) -> Option<RasterizedCustomGlyph> {
+        let image = self.images.get(request.id as usize)?;
+        if request.width == 0 || request.height == 0 {
+            return None;
+        }
+        let scaled = image::imageops::resize(
+            image,
+            request.width as u32,
+            request.height as u32,
+            image::imageops::FilterType::Nearest,
+        );
+        Some(RasterizedCustomGlyph {
+            data: scaled.into_raw(),
+            content_type: ContentType::Color,
+        })
+    }
+}
+
+/// Where lines too long for a text buffer's width are broken
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TextWrap {
+    /// Between words, or within words too long for a line of their own
+    #[default]
+    Word,
+    /// Never, so lines run past the width
+    None,
+}
+
+impl TextWrap {
+    fn wrap(self) -> Wrap {
+        match self {
+            Self::Word => Wrap::WordOrGlyph,
+            Self::None => Wrap::None,
+        }
+    }
+}
+
+/// How text is laid out in a buffer's box, see
+/// `RenderingSystem::create_text_buffer`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TextOptions {
+    pub wrap: TextWrap,
+    /// Shrinks the font a pixel at a time, keeping the line size in
+    /// proportion, until the text fits the box, e.g. a counter that may
+    /// grow to many digits. Text that doesn't fit even at one pixel is left
+    /// overflowing.
+    pub shrink_to_fit: bool,
 }
 
+#[derive(Clone)]
 pub struct FeaturedTextBuffer {
     buffer: Buffer,
     text: String,
@@ -25,34 +104,245 @@ pub struct FeaturedTextBuffer {
     width: f32,
     height: f32,
     align: Align,
+    // Whether `text` is parsed for tags, see `set_markup`
+    markup: bool,
+    options: TextOptions,
+    // Font size and line size it's created with, before any shrinking
+    metrics: Metrics,
+    // Drawn with this font's glyphs instead of glyphon when there's one
+    bitmap: Option<Rc<BitmapFont>>,
+    glyphs: Vec<PlacedGlyph>,
+    // Where the text's `[icon=...]`s are drawn
+    icons: Vec<CustomGlyph>,
+    // How many icons had been registered when it was laid out
+    icons_registered: usize,
 }
 
 impl FeaturedTextBuffer {
+    /// Lays `text` out, unless it's what the buffer already has. Cheap to
+    /// call every frame.
     pub fn set_text(&mut self, rendering_system: &mut RenderingSystem, text: &str) {
         let pipeline = rendering_system.text_pipeline.clone();
-        let mut pipeline = pipeline.borrow_mut();
-        self.text = text.to_string();
-        self.buffer.set_text(
-            &mut pipeline.font_system,
-            text,
+        let pipeline = &mut *pipeline.borrow_mut();
+        self.update(&mut pipeline.font_system, &pipeline.icons, text, false);
+    }
+
+    /// Like `set_text`, styling the text with the tags in `markup`, e.g.
+    /// `[color=yellow]ilo[/color]`. See `markup` for every tag.
+    #[cfg(test)]
+    pub fn set_markup(&mut self, rendering_system: &mut RenderingSystem, markup: &str) {
+        let pipeline = rendering_system.text_pipeline.clone();
+        let pipeline = &mut *pipeline.borrow_mut();
+        self.update(&mut pipeline.font_system, &pipeline.icons, markup, true);
+    }
+
+    /// Lays the text out again if it changed, or icons were registered since
+    /// it was. Returns whether it did.
+    fn update(
+        &mut self,
+        font_system: &mut FontSystem,
+        icons: &TextIcons,
+        text: &str,
+        markup: bool,
+    ) -> bool {
+        if self.text == text && self.markup == markup && self.icons_registered == icons.len() {
+            return false;
+        }
+        self.text.clear();
+        self.text.push_str(text);
+        self.markup = markup;
+        self.icons_registered = icons.len();
+        self.layout(font_system, icons);
+        true
+    }
+
+    /// The font size the text is laid out at, shrunk to fit if the buffer
+    /// shrinks text
+    #[cfg(test)]
+    pub fn font_size(&self) -> f32 {
+        self.buffer.metrics().font_size / SCALING_FACTOR
+    }
+
+    /// The bitmap font the buffer is drawn with and its glyphs, if it's
+    /// drawn with one
+    pub fn bitmap_glyphs(&self) -> Option<(&BitmapFont, &[PlacedGlyph])> {
+        let font = self.bitmap.as_deref()?;
+        Some((font, &self.glyphs))
+    }
+
+    fn layout(&mut self, font_system: &mut FontSystem, icons: &TextIcons) {
+        if let Some(font) = &self.bitmap {
+            let spans = match self.markup {
+                true => parse_markup(&self.text),
+                false => vec![MarkupSpan {
+                    text: self.text.clone(),
+                    color: None,
+                    bold: false,
+                    scale: 1.0,
+                    icon: None,
+                }],
+            };
+            self.glyphs = font.layout(&spans, self.width / SCALING_FACTOR, self.align);
+            return;
+        }
+        let mut font_size = self.metrics.font_size;
+        loop {
+            self.shape(font_system, icons, font_size / self.metrics.font_size);
+            if !self.options.shrink_to_fit || font_size <= SCALING_FACTOR || self.fits(font_system)
+            {
+                break;
+            }
+            font_size -= SCALING_FACTOR;
+        }
+        self.place_icons();
+    }
+
+    /// Lays the text out with the font size and line size scaled by `scale`
+    fn shape(&mut self, font_system: &mut FontSystem, icons: &TextIcons, scale: f32) {
+        self.buffer.set_metrics(
+            font_system,
+            Metrics::new(
+                self.metrics.font_size * scale,
+                self.metrics.line_height * scale,
+            ),
+        );
+        if self.markup {
+            let metrics = self.buffer.metrics();
+            let mut spans = parse_markup(&self.text);
+            // Icons the pipeline doesn't have aren't drawn or made room for
+            spans.retain(|span| {
+                span.icon
+                    .as_ref()
+                    .is_none_or(|name| icons.ids.contains_key(name))
+            });
+            self.set_spans(font_system, icons, &spans, metrics, 0.0);
+            // Each icon stands on a space widened to an em, the icon's width.
+            // How wide a space is depends on the font, so it's measured first.
+            let space = self
+                .buffer
+                .layout_runs()
+                .flat_map(|run| run.glyphs)
+                .find(|glyph| glyph.metadata > 0)
+                .map(|glyph| glyph.w / glyph.font_size);
+            if let Some(space) = space {
+                self.set_spans(font_system, icons, &spans, metrics, 1.0 - space);
+            }
+        } else {
+            self.buffer.set_text(
+                font_system,
+                &self.text,
+                &self.attrs,
+                glyphon::Shaping::Advanced,
+            );
+            self.align_and_shape(font_system);
+        }
+    }
+
+    /// Sets the buffer's text to `spans`, with a space for each icon that's
+    /// `icon_spacing` ems wider
+    fn set_spans(
+        &mut self,
+        font_system: &mut FontSystem,
+        icons: &TextIcons,
+        spans: &[MarkupSpan],
+        metrics: Metrics,
+        icon_spacing: f32,
+    ) {
+        let attrs = spans.iter().map(|span| {
+            let attrs = span_attrs(&self.attrs, span, metrics);
+            match &span.icon {
+                // The icon's id, off by one as glyphs that aren't icons are 0
+                Some(name) => attrs
+                    .metadata(icons.ids[name] as usize + 1)
+                    .letter_spacing(icon_spacing),
+                None => attrs,
+            }
+        });
+        let text = spans.iter().map(|span| match span.icon {
+            Some(_) => " ",
+            None => span.text.as_str(),
+        });
+        self.buffer.set_rich_text(
+            font_system,
+            text.zip(attrs),
             &self.attrs,
             glyphon::Shaping::Advanced,
+            None,
         );
+        self.align_and_shape(font_system);
+    }
+
+    fn align_and_shape(&mut self, font_system: &mut FontSystem) {
         for line in self.buffer.lines.iter_mut() {
             line.set_align(Some(self.align));
         }
+        self.buffer.shape_until_scroll(font_system, false);
+    }
+
+    /// Puts an icon over each glyph an icon stands on, centered on its line
+    fn place_icons(&mut self) {
+        self.icons.clear();
+        for run in self.buffer.layout_runs() {
+            for glyph in run.glyphs.iter().filter(|glyph| glyph.metadata > 0) {
+                let size = glyph.font_size;
+                self.icons.push(CustomGlyph {
+                    id: (glyph.metadata - 1) as CustomGlyphId,
+                    left: glyph.x,
+                    top: run.line_top + (run.line_height - size) * 0.5,
+                    width: size,
+                    height: size,
+                    color: None,
+                    snap_to_physical_pixel: true,
+                    metadata: 0,
+                });
+            }
+        }
+    }
+
+    /// Whether every line of the text is within the box
+    fn fits(&mut self, font_system: &mut FontSystem) -> bool {
+        // Lines past the bottom aren't laid out while there is one
+        self.buffer.set_size(font_system, Some(self.width), None);
+        self.buffer.shape_until_scroll(font_system, false);
+        let (mut width, mut height) = (0.0f32, 0.0);
+        for run in self.buffer.layout_runs() {
+            width = width.max(run.line_w);
+            height += run.line_height;
+        }
         self.buffer
-            .shape_until_scroll(&mut pipeline.font_system, false);
+            .set_size(font_system, Some(self.width), Some(self.height));
+        width <= self.width && height <= self.height
+    }
+}
+
+/// `attrs` with `span`'s style over them, the buffer's `metrics` scaled
+fn span_attrs(attrs: &Attrs<'static>, span: &MarkupSpan, metrics: Metrics) -> Attrs<'static> {
+    let mut attrs = attrs.clone();
+    if let Some([r, g, b, a]) = span.color {
+        attrs = attrs.color(Color::rgba(r, g, b, a));
+    }
+    if span.bold {
+        attrs = attrs.weight(Weight::BOLD);
     }
+    if span.scale != 1.0 {
+        attrs = attrs.metrics(Metrics::new(
+            metrics.font_size * span.scale,
+            metrics.line_height * span.scale,
+        ));
+    }
+    attrs
 }
 
 const SCALING_FACTOR: f32 = 8.0;
+/// Frames a shaped run is kept for without being used
+const SHAPE_RUN_CACHE_FRAMES: u64 = 120;
 
 impl TextRenderPipeline {
     pub fn new(
         device: &wgpu::Device,
         queue: &wgpu::Queue,
         swapchain_format: TextureFormat,
+        sample_count: u32,
     ) -> Self {
         let font_system = FontSystem::new();
         let swash_cache = SwashCache::new();
@@ -63,8 +353,8 @@ impl TextRenderPipeline {
             &mut atlas,
             device,
             MultisampleState {
-                count: 1,
-                mask: 0,
+                count: sample_count,
+                mask: !0,
                 alpha_to_coverage_enabled: false,
             },
             None,
@@ -76,7 +366,7 @@ impl TextRenderPipeline {
             viewport,
             atlas,
             text_renderer,
-            cache,
+            icons: TextIcons::default(),
         }
     }
 
@@ -84,63 +374,106 @@ impl TextRenderPipeline {
         self.font_system.db_mut().load_font_data(bytes.to_vec());
     }
 
+    /// Drops glyphs and shaped runs no text has used for a while. Runs are
+    /// shared by every buffer, so text that changes back to something it
+    /// was, like a count, skips shaping.
+    pub fn trim(&mut self) {
+        self.atlas.trim();
+        self.font_system
+            .shape_run_cache
+            .trim(SHAPE_RUN_CACHE_FRAMES);
+    }
+
     pub fn create_buffer(
         &mut self,
-        font_size: f32,
-        line_size: f32,
-        width: f32,
-        height: f32,
+        metrics: Metrics,
+        (width, height): (f32, f32),
         text: &str,
         attrs: Attrs<'static>,
         align: Align,
+        options: TextOptions,
     ) -> FeaturedTextBuffer {
         let width = width * SCALING_FACTOR;
         let height = height * SCALING_FACTOR;
-        let font_size = font_size * SCALING_FACTOR;
-        let line_size = line_size * SCALING_FACTOR;
-        let mut buffer = Buffer::new(&mut self.font_system, Metrics::new(font_size, line_size));
+        let metrics = metrics.scale(SCALING_FACTOR);
+        let mut buffer = Buffer::new(&mut self.font_system, metrics);
         buffer.set_size(&mut self.font_system, Some(width), Some(height));
-        buffer.set_text(
-            &mut self.font_system,
-            text,
-            &attrs,
-            glyphon::Shaping::Advanced,
-        );
-        for line in buffer.lines.iter_mut() {
-            line.set_align(Some(align));
-        }
-        buffer.shape_until_scroll(&mut self.font_system, false);
+        buffer.set_wrap(&mut self.font_system, options.wrap.wrap());
 
-        FeaturedTextBuffer {
+        let mut text_buffer = FeaturedTextBuffer {
             buffer,
             text: text.to_string(),
             attrs,
             width,
             height,
             align,
-        }
+            markup: false,
+            options,
+            metrics,
+            bitmap: None,
+            glyphs: Vec::new(),
+            icons: Vec::new(),
+            icons_registered: self.icons.len(),
+        };
+        text_buffer.layout(&mut self.font_system, &self.icons);
+        text_buffer
+    }
+
+    /// A buffer drawn with `font`'s glyphs, `width` by `height` pixels. It
+    /// only breaks lines at `\n`.
+    #[cfg(test)]
+    pub fn create_bitmap_buffer(
+        &mut self,
+        font: Rc<BitmapFont>,
+        width: f32,
+        height: f32,
+        text: &str,
+        align: Align,
+    ) -> FeaturedTextBuffer {
+        // glyphon's buffer stays empty, it only needs valid metrics
+        let line_height = font.line_height().max(1.0) * SCALING_FACTOR;
+        let metrics = Metrics::new(line_height, line_height);
+        let mut text_buffer = FeaturedTextBuffer {
+            buffer: Buffer::new(&mut self.font_system, metrics),
+            text: text.to_string(),
+            attrs: Attrs::new(),
+            width: width * SCALING_FACTOR,
+            height: height * SCALING_FACTOR,
+            align,
+            markup: false,
+            options: TextOptions::default(),
+            metrics,
+            bitmap: Some(font),
+            glyphs: Vec::new(),
+            icons: Vec::new(),
+            icons_registered: self.icons.len(),
+        };
+        text_buffer.layout(&mut self.font_system, &self.icons);
+        text_buffer
     }
 
+    /// Lays `text_buffer` out to be drawn at the internal resolution of
+    /// `renderer`, `x` and `y` pixels from its top-left corner
     pub fn prepare_for_text_draw(
         &mut self,
-        device: &Device,
-        queue: &wgpu::Queue,
+        renderer: &RenderingSystem,
         text_buffer: &FeaturedTextBuffer,
-        resolution: Resolution,
         color: Color,
         x: f32,
         y: f32,
         scale: f32,
     ) -> Result<(), Box<dyn std::error::Error>> {
+        let (width, height) = renderer.original_size;
         let resolution = Resolution {
-            width: (resolution.width as f32 * SCALING_FACTOR) as u32,
-            height: (resolution.height as f32 * SCALING_FACTOR) as u32,
+            width: (width as f32 * SCALING_FACTOR) as u32,
+            height: (height as f32 * SCALING_FACTOR) as u32,
         };
-        self.viewport.update(queue, resolution);
+        self.viewport.update(&renderer.queue, resolution);
 
-        self.text_renderer.prepare(
-            device,
-            queue,
+        let icons = &self.icons;
+        self.text_renderer.prepare_with_custom(
+            &renderer.device,
+            &renderer.queue,
             &mut self.font_system,
             &mut self.atlas,
             &self.viewport,
@@ -156,9 +489,10 @@ impl TextRenderPipeline {
                 },
                 scale,
                 default_color: color,
-                custom_glyphs: &[],
+                custom_glyphs: &text_buffer.icons,
             }],
             &mut self.swash_cache,
+            |request| icons.rasterize(request),
         )?;
 
         Ok(())
@@ -173,3 +507,169 @@ impl TextRenderPipeline {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn font_system() -> FontSystem {
+        let mut font_system = FontSystem::new();
+        font_system
+            .db_mut()
+            .load_font_data(include_bytes!("../assets/leko majuna.ttf").to_vec());
+        font_system
+    }
+
+    /// Like `TextRenderPipeline::create_buffer`, without a device
+    fn text_buffer(
+        font_system: &mut FontSystem,
+        text: &str,
+        (width, height): (f32, f32),
+        markup: bool,
+        options: TextOptions,
+        icons: &TextIcons,
+    ) -> FeaturedTextBuffer {
+        let metrics = Metrics::new(16.0, 20.0);
+        let mut buffer = Buffer::new(font_system, metrics);
+        buffer.set_size(font_system, Some(width), Some(height));
+        buffer.set_wrap(font_system, options.wrap.wrap());
+        let mut text_buffer = FeaturedTextBuffer {
+            buffer,
+            text: text.to_string(),
+            attrs: Attrs::new(),
+            width,
+            height,
+            align: Align::Left,
+            markup,
+            options,
+            metrics,
+            bitmap: None,
+            glyphs: Vec::new(),
+            icons: Vec::new(),
+            icons_registered: icons.len(),
+        };
+        text_buffer.layout(font_system, icons);
+        text_buffer
+    }
+
+    #[test]
+    fn markup_styles_its_spans_of_the_buffer() {
+        let mut font_system = font_system();
+        let text_buffer = text_buffer(
+            &mut font_system,
+            "o lukin e [color=yellow]ilo [scale=2]pona[/scale][/color]",
+            (400.0, 100.0),
+            true,
+            TextOptions::default(),
+            &TextIcons::default(),
+        );
+
+        let line = &text_buffer.buffer.lines[0];
+        assert_eq!(line.text(), "o lukin e ilo pona");
+        let attrs = line.attrs_list();
+        assert_eq!(attrs.get_span(0).color_opt, None);
+        assert_eq!(
+            attrs.get_span(10).color_opt,
+            Some(Color::rgba(255, 255, 0, 255))
+        );
+        let scaled = attrs.get_span(14).metrics_opt.unwrap();
+        assert_eq!(Metrics::from(scaled).font_size, 32.0);
+    }
+
+    #[test]
+    fn long_text_shrinks_until_it_fits() {
+        let mut font_system = font_system();
+        let text = "mute mute mute mute luka luka tu wan";
+        // Wrapped onto lines past the bottom without shrinking
+        let mut wrapped = text_buffer(
+            &mut font_system,
+            text,
+            (200.0, 20.0),
+            false,
+            TextOptions::default(),
+            &TextIcons::default(),
+        );
+        assert!(wrapped.buffer.lines[0].layout_opt().unwrap().len() > 1);
+        assert!(!wrapped.fits(&mut font_system));
+        let options = TextOptions {
+            wrap: TextWrap::None,
+            shrink_to_fit: true,
+        };
+        let icons = TextIcons::default();
+        let mut shrunk = text_buffer(
+            &mut font_system,
+            text,
+            (200.0, 20.0),
+            false,
+            options,
+            &icons,
+        );
+        assert_eq!(shrunk.buffer.lines[0].layout_opt().unwrap().len(), 1);
+        assert!(shrunk.font_size() < 16.0 / SCALING_FACTOR);
+        assert!(shrunk.fits(&mut font_system));
+        // Short text keeps its size
+        let short = text_buffer(
+            &mut font_system,
+            "tu",
+            (200.0, 20.0),
+            false,
+            options,
+            &icons,
+        );
+        assert_eq!(short.font_size(), 16.0 / SCALING_FACTOR);
+    }
+
+    #[test]
+    fn icons_make_room_for_themselves_inline() {
+        let mut font_system = font_system();
+        let mut icons = TextIcons::default();
+        icons.register("flask", RgbaImage::new(2, 2));
+        let options = TextOptions::default();
+        let text = "a[icon=flask]b[icon=unknown]";
+        let text_buffer = text_buffer(&mut font_system, text, (400.0, 20.0), true, options, &icons);
+
+        // Unknown icons are left out, known ones are an em square
+        assert_eq!(text_buffer.icons.len(), 1);
+        let icon = &text_buffer.icons[0];
+        assert_eq!([icon.width, icon.height], [16.0, 16.0]);
+        assert_eq!(icon.top, 2.0);
+        let run = text_buffer.buffer.layout_runs().next().unwrap();
+        let [a, _, b] = run.glyphs else {
+            panic!("Expected a glyph for each of a, the icon and b");
+        };
+        assert_eq!(icon.left, a.x + a.w);
+        assert!((b.x - (icon.left + icon.width)).abs() < 0.01);
+
+        // Drawn at whatever size glyphon asks for
+        let rasterized = icons
+            .rasterize(RasterizeCustomGlyphRequest {
+                id: icon.id,
+                width: 4,
+                height: 4,
+                x_bin: glyphon::cosmic_text::SubpixelBin::Zero,
+                y_bin: glyphon::cosmic_text::SubpixelBin::Zero,
+                scale: 1.0,
+            })
+            .unwrap();
+        assert_eq!(rasterized.data.len(), 4 * 4 * 4);
+    }
+
+    #[test]
+    fn unchanged_text_isnt_laid_out_again() {
+        let mut font_system = font_system();
+        let mut icons = TextIcons::default();
+        let options = TextOptions::default();
+        let mut text_buffer =
+            text_buffer(&mut font_system, "3", (400.0, 20.0), false, options, &icons);
+
+        assert!(!text_buffer.update(&mut font_system, &icons, "3", false));
+        assert!(text_buffer.update(&mut font_system, &icons, "4", false));
+        // The same text as markup may not look the same
+        assert!(text_buffer.update(&mut font_system, &icons, "4", true));
+        assert!(!text_buffer.update(&mut font_system, &icons, "4", true));
+        // Nor once the icons it may use change
+        icons.register("flask", RgbaImage::new(1, 1));
+        assert!(text_buffer.update(&mut font_system, &icons, "4", true));
+        assert_eq!(text_buffer.buffer.lines[0].text(), "4");
+    }
+}
@@ -0,0 +1,126 @@
+//! Draws a grid of tiles from a sprite sheet straight from the level's
+//! layers, one instance of the quad per cell, instead of from a background
+//! baked into a single huge texture.
+
+use game_build_tools::level::TileGrid;
+use wgpu::{Buffer, Device};
+
+use crate::renderer::{
+    gizmo::{GizmoSpriteSheet, TileInstance, EMPTY_TILE},
+    RenderingSystem,
+};
+
+pub struct TilemapRenderer {
+    sheet: GizmoSpriteSheet,
+    // Row by row, so the cell at (x, y) is at y * width + x
+    tiles: Vec<TileInstance>,
+    #[cfg(test)]
+    dimensions: (usize, usize),
+    instance_buffer: Buffer,
+}
+
+impl TilemapRenderer {
+    /// Lays out `rows` of tiles of `sheet`, `None` for the empty cells.
+    /// Tiles the sheet doesn't have are left empty too.
+    pub fn new(
+        renderer: &RenderingSystem,
+        sheet: GizmoSpriteSheet,
+        rows: &[Vec<Option<[u32; 2]>>],
+    ) -> Self {
+        let grid = TileGrid::of_rows(rows);
+        let mut tiles = Vec::with_capacity(grid.dimensions.0 * grid.dimensions.1);
+        for (y, row) in rows.iter().enumerate() {
+            for x in 0..grid.dimensions.0 {
+                let tile = row.get(x).copied().flatten();
+                tiles.push(TileInstance {
+                    position: grid.tile_to_world((x, y)).into(),
+                    tile: Self::checked_tile(&sheet, tile),
+                });
+            }
+        }
+        let instance_buffer = create_instance_buffer(&renderer.device, &tiles);
+        Self {
+            sheet,
+            tiles,
+            #[cfg(test)]
+            dimensions: grid.dimensions,
+            instance_buffer,
+        }
+    }
+
+    /// Changes the tile in one cell, e.g. a door opening. Returns whether
+    /// the cell is in the map.
+    #[cfg(test)]
+    pub fn set_tile(
+        &mut self,
+        renderer: &RenderingSystem,
+        (x, y): (usize, usize),
+        tile: Option<[u32; 2]>,
+    ) -> bool {
+        let (width, height) = self.dimensions;
+        if x >= width || y >= height {
+            return false;
+        }
+        let index = y * width + x;
+        self.tiles[index].tile = Self::checked_tile(&self.sheet, tile);
+        let offset = (index * std::mem::size_of::<TileInstance>()) as wgpu::BufferAddress;
+        renderer.queue.write_buffer(
+            &self.instance_buffer,
+            offset,
+            bytemuck::bytes_of(&self.tiles[index]),
+        );
+        true
+    }
+
+    /// The tile in a cell, `None` if it's empty or outside of the map
+    #[cfg(test)]
+    pub fn tile(&self, (x, y): (usize, usize)) -> Option<[u32; 2]> {
+        let (width, height) = self.dimensions;
+        if x >= width || y >= height {
+            return None;
+        }
+        let tile = self.tiles[y * width + x].tile;
+        (tile != EMPTY_TILE).then_some(tile)
+    }
+
+    /// The cells of the map, a world unit each
+    #[cfg(test)]
+    pub fn grid(&self) -> TileGrid {
+        TileGrid::new(self.dimensions)
+    }
+
+    pub fn sheet(&self) -> &GizmoSpriteSheet {
+        &self.sheet
+    }
+
+    pub fn instance_buffer(&self) -> &Buffer {
+        &self.instance_buffer
+    }
+
+    pub fn num_instances(&self) -> u32 {
+        self.tiles.len() as u32
+    }
+
+    fn checked_tile(sheet: &GizmoSpriteSheet, tile: Option<[u32; 2]>) -> [u32; 2] {
+        tile.filter(|tile| sheet.get_sprite(*tile).is_some())
+            .unwrap_or(EMPTY_TILE)
+    }
+}
+
+fn create_instance_buffer(device: &Device, tiles: &[TileInstance]) -> Buffer {
+    // Never empty, wgpu doesn't bind buffers without a size
+    let size = (std::mem::size_of_val(tiles) as u64).max(wgpu::COPY_BUFFER_ALIGNMENT);
+    let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Tile Instance Buffer"),
+        size,
+        usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: true,
+    });
+    {
+        let mut buffer_view = buffer.slice(..).get_mapped_range_mut();
+        let tile_bytes = bytemuck::cast_slice(tiles);
+        buffer_view[..tile_bytes.len()].copy_from_slice(tile_bytes);
+    }
+    buffer.unmap();
+    buffer
+}
@@ -0,0 +1,161 @@
+//! Screen transitions: the frame is covered up, something changes behind the
+//! cover, like the room, and the frame is uncovered again.
+
+use crate::renderer::{DrawLayer, Drawer, EngineColor};
+
+const IRIS_SEGMENTS: u32 = 48;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransitionStyle {
+    /// The whole frame fades into the color and back out
+    Fade,
+    /// The color sweeps in from the left and goes on off to the right
+    #[cfg(test)]
+    Wipe,
+    /// A circle closes in on the middle of the frame and opens back up
+    Iris,
+}
+
+pub struct Transition {
+    pub style: TransitionStyle,
+    pub color: EngineColor,
+    /// Seconds each half takes, covering and uncovering
+    duration: f32,
+    elapsed: f32,
+}
+
+impl Transition {
+    /// Starts covering the frame right away, in black
+    pub fn new(style: TransitionStyle, duration: f32) -> Self {
+        Self {
+            style,
+            color: EngineColor::BLACK,
+            duration: duration.max(0.0),
+            elapsed: 0.0,
+        }
+    }
+
+    /// A transition that's already over, covering nothing
+    pub fn finished() -> Self {
+        Self::new(TransitionStyle::Fade, 0.0)
+    }
+
+    #[cfg(test)]
+    pub fn with_color(mut self, color: EngineColor) -> Self {
+        self.color = color;
+        self
+    }
+
+    /// Moves the transition along. Returns `true` once, on the update the
+    /// frame gets fully covered: the time to swap what's behind it.
+    pub fn update(&mut self, delta_time: f32) -> bool {
+        if self.is_finished() {
+            return false;
+        }
+        let before = self.elapsed;
+        self.elapsed = (self.elapsed + delta_time).min(self.duration * 2.0);
+        before < self.duration && self.elapsed >= self.duration
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.elapsed >= self.duration * 2.0
+    }
+
+    /// Whether the frame is still being covered, before the swap
+    #[cfg(test)]
+    pub fn is_covering(&self) -> bool {
+        self.elapsed < self.duration
+    }
+
+    /// How much of the frame is covered, eased from 0 up to 1 at the swap
+    /// and back down to 0
+    pub fn coverage(&self) -> f32 {
+        if self.is_finished() {
+            return 0.0;
+        }
+        let progress = self.elapsed / self.duration;
+        let t = if progress <= 1.0 {
+            progress
+        } else {
+            2.0 - progress
+        };
+        t * t * (3.0 - 2.0 * t)
+    }
+
+    /// Draws the cover over everything else, on `DrawLayer::TRANSITION`
+    pub fn draw(&self, drawer: &mut Drawer) {
+        let coverage = self.coverage();
+        if coverage <= 0.0 {
+            return;
+        }
+        let layer = drawer.layer();
+        drawer.set_layer(DrawLayer::TRANSITION);
+        let size = drawer.internal_size();
+        let white_sprite = drawer.white_sprite();
+        match self.style {
+            TransitionStyle::Fade => {
+                let color = EngineColor {
+                    a: self.color.a * coverage,
+                    ..self.color
+                };
+                let space = drawer.ortho.scale(size.extend(1.0));
+                drawer.draw_square_slow(Some(&space), Some(&color), white_sprite);
+            }
+            #[cfg(test)]
+            TransitionStyle::Wipe => {
+                // The covered part starts at the left edge while covering and
+                // ends at the right edge while uncovering
+                let width = size.x * coverage;
+                let left = if self.is_covering() {
+                    0.0
+                } else {
+                    size.x - width
+                };
+                let space = drawer
+                    .ortho
+                    .translate(glam::Vec3::new(left, 0.0, 0.0))
+                    .scale(glam::Vec3::new(width, size.y, 1.0));
+                drawer.draw_square_slow(Some(&space), Some(&self.color), white_sprite);
+            }
+            TransitionStyle::Iris => {
+                // From the middle to the corners, with room for the ring's
+                // straight edges
+                let reach = size.length() * 0.5;
+                drawer.draw_ring_slow(
+                    size / 2.0,
+                    reach * (1.0 - coverage),
+                    reach * 2.0,
+                    &self.color,
+                    IRIS_SEGMENTS,
+                );
+            }
+        }
+        drawer.set_layer(layer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transitions_swap_once_fully_covered() {
+        let mut transition = Transition::new(TransitionStyle::Fade, 0.5);
+        assert_eq!(transition.coverage(), 0.0);
+        assert!(!transition.update(0.25));
+        assert_eq!(transition.coverage(), 0.5);
+        assert!(transition.is_covering());
+
+        assert!(transition.update(0.3));
+        assert!(!transition.is_covering());
+        assert!(transition.coverage() > 0.9);
+        assert!(!transition.update(0.1));
+
+        assert!(!transition.update(1.0));
+        assert!(transition.is_finished());
+        assert_eq!(transition.coverage(), 0.0);
+        assert!(!transition.update(1.0));
+
+        assert!(Transition::finished().is_finished());
+    }
+}
@@ -0,0 +1,267 @@
+//! The game is drawn at a fixed internal resolution, then scaled up to the
+//! window by a whole number of pixels, between black bars, so every pixel
+//! comes out the same size. On the way, the frame's colors are exposed and
+//! tone mapped from however bright they were drawn into what the screen
+//! shows.
+
+use glam::Vec2;
+use wgpu::{BindGroup, Buffer, Device, Queue, RenderPipeline, SurfaceConfiguration, TextureView};
+
+/// How colors brighter than white are brought into what the screen shows
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Tonemap {
+    /// Cut off at white, so colors up to white come out as drawn
+    #[default]
+    Clamp,
+    /// Reinhard's curve, `c / (1 + c)`, never quite reaching white
+    #[cfg(test)]
+    Reinhard,
+    /// A fit of the ACES filmic curve, with more contrast than Reinhard's
+    #[cfg(test)]
+    Aces,
+}
+
+impl Tonemap {
+    fn index(self) -> u32 {
+        match self {
+            Self::Clamp => 0,
+            #[cfg(test)]
+            Self::Reinhard => 1,
+            #[cfg(test)]
+            Self::Aces => 2,
+        }
+    }
+}
+
+pub struct Upscaler {
+    pipeline: RenderPipeline,
+    frame_view: TextureView,
+    bind_group: BindGroup,
+    tonemap_buffer: Buffer,
+    frame_size: (u32, u32),
+}
+
+impl Upscaler {
+    /// A frame the size and format of `config`, scaled up to targets of
+    /// `output_format`
+    pub fn new(
+        device: &Device,
+        config: &SurfaceConfiguration,
+        output_format: wgpu::TextureFormat,
+    ) -> Self {
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Upscale Shader"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("../assets/upscale.wgsl").into()),
+        });
+
+        let frame_view = device
+            .create_texture(&wgpu::TextureDescriptor {
+                label: Some("Internal Frame"),
+                size: wgpu::Extent3d {
+                    width: config.width,
+                    height: config.height,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: config.format,
+                usage: wgpu::TextureUsages::RENDER_ATTACHMENT
+                    | wgpu::TextureUsages::TEXTURE_BINDING,
+                view_formats: &[],
+            })
+            .create_view(&Default::default());
+        // Nearest, so the pixels stay crisp
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Internal Frame Sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Nearest,
+            min_filter: wgpu::FilterMode::Nearest,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+        let tonemap_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Tonemap Buffer"),
+            size: std::mem::size_of::<[f32; 4]>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Upscale Bind Group Layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Upscale Bind Group"),
+            layout: &layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&frame_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: tonemap_buffer.as_entire_binding(),
+                },
+            ],
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Upscale Pipeline Layout"),
+            bind_group_layouts: &[&layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Upscale Pipeline"),
+            layout: Some(&pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: Some("vs_main"),
+                buffers: &[],
+                compilation_options: wgpu::PipelineCompilationOptions::default(),
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: Some("fs_main"),
+                targets: &[Some(wgpu::ColorTargetState {
+                    format: output_format,
+                    blend: None,
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+                compilation_options: wgpu::PipelineCompilationOptions::default(),
+            }),
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+            cache: None,
+        });
+
+        Self {
+            pipeline,
+            frame_view,
+            bind_group,
+            tonemap_buffer,
+            frame_size: (config.width, config.height),
+        }
+    }
+
+    /// Where to draw the frame, at the internal resolution
+    pub fn frame_view(&self) -> &TextureView {
+        &self.frame_view
+    }
+
+    /// Scales the frame's colors by `exposure` and maps them through
+    /// `tonemap` from then on
+    pub fn write_tonemap(&self, queue: &Queue, tonemap: Tonemap, exposure: f32) {
+        let settings = [exposure, tonemap.index() as f32, 0.0, 0.0];
+        queue.write_buffer(&self.tonemap_buffer, 0, bytemuck::cast_slice(&settings));
+    }
+
+    /// Draws the frame scaled up into `output`, `output_size` pixels, black
+    /// around it
+    pub fn present(
+        &self,
+        device: &Device,
+        queue: &Queue,
+        output: &TextureView,
+        output_size: (u32, u32),
+    ) {
+        let (offset, scale) = integer_viewport(output_size, self.frame_size);
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Upscale Encoder"),
+        });
+        {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Upscale Pass"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: output,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                        store: wgpu::StoreOp::Store,
+                    },
+                })],
+                depth_stencil_attachment: None,
+                occlusion_query_set: None,
+                timestamp_writes: None,
+            });
+            render_pass.set_viewport(
+                offset.x,
+                offset.y,
+                self.frame_size.0 as f32 * scale,
+                self.frame_size.1 as f32 * scale,
+                0.0,
+                1.0,
+            );
+            render_pass.set_pipeline(&self.pipeline);
+            render_pass.set_bind_group(0, &self.bind_group, &[]);
+            render_pass.draw(0..3, 0..1);
+        }
+        queue.submit(std::iter::once(encoder.finish()));
+    }
+}
+
+/// Where a `frame` pixels frame goes in an `output` pixels target, as its
+/// top-left corner and how many times bigger it's drawn. It's the biggest
+/// whole number of times that fits, centered on whole pixels, or shrunk to
+/// fit targets smaller than the frame.
+pub fn integer_viewport(output: (u32, u32), frame: (u32, u32)) -> (Vec2, f32) {
+    let output = Vec2::new(output.0 as f32, output.1 as f32);
+    let frame = Vec2::new(frame.0 as f32, frame.1 as f32);
+    let fit = (output / frame).min_element();
+    let scale = if fit >= 1.0 { fit.floor() } else { fit };
+    let offset = ((output - frame * scale) * 0.5).floor().max(Vec2::ZERO);
+    (offset, scale)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_scale_by_whole_pixels() {
+        // 4.5 times as tall, so 4 times, with bars all around
+        assert_eq!(
+            integer_viewport((1920, 1080), (320, 240)),
+            (Vec2::new(320.0, 60.0), 4.0)
+        );
+        assert_eq!(integer_viewport((640, 480), (320, 240)), (Vec2::ZERO, 2.0));
+        // Too small for even one pixel each
+        let (offset, scale) = integer_viewport((160, 240), (320, 240));
+        assert_eq!((offset, scale), (Vec2::new(0.0, 60.0), 0.5));
+    }
+}
@@ -0,0 +1,312 @@
+//! Loading of levels off the main thread. Decoding the images and parsing the
+//! csvs is the slow part of bringing in a room and none of it needs the GPU,
+//! so it runs on a worker thread and only the texture upload is left for the
+//! main thread once the level is ready.
+//!
+//! The browser build has no threads to spawn, so there requested levels wait
+//! in a queue and are decoded one per `poll`, on the main thread. That still
+//! stalls the frame it happens in, but only the one.
+
+use std::{
+    collections::VecDeque,
+    io::Cursor,
+    sync::mpsc::{self, Receiver, TryRecvError},
+};
+
+use game_build_tools::level::{TileGrid, TILE_SIZE};
+use image::{ImageReader, RgbaImage};
+
+use crate::game::GameLevelLoadData;
+
+pub type LoadError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Parses a grid of integers as written by `LevelLayer::dump_csv`
+pub fn parse_csv_grid(csv: &str) -> Result<Vec<Vec<u32>>, LoadError> {
+    csv.lines()
+        .map(|row| {
+            row.split(',')
+                .map(|value| Ok(value.trim().parse()?))
+                .collect()
+        })
+        .collect()
+}
+
+/// Checks that a level's images are drawn with `TILE_SIZE` tiles, going by
+/// the size of its grid, as the camera and the colliders both assume them
+pub fn check_tile_size(
+    name: &str,
+    image_size: (u32, u32),
+    grid: &[Vec<u32>],
+) -> Result<(), LoadError> {
+    let grid = TileGrid::of_rows(grid);
+    let (grid_size, expected) = (grid.dimensions, grid.pixel_size());
+    if image_size != expected {
+        return Err(format!(
+            "Level {} is {}x{} pixels over a {}x{} grid, but tiles are {}x{} pixels",
+            name, image_size.0, image_size.1, grid_size.0, grid_size.1, TILE_SIZE.0, TILE_SIZE.1
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Checks that a level's floor covers the same cells as its collisions
+fn check_same_grid(name: &str, floor: &[Vec<u32>], grid: &[Vec<u32>]) -> Result<(), LoadError> {
+    let (floor, grid) = (TileGrid::of_rows(floor), TileGrid::of_rows(grid));
+    if floor.dimensions != grid.dimensions {
+        return Err(format!(
+            "Level {} has a {}x{} floor over a {}x{} grid",
+            name, floor.dimensions.0, floor.dimensions.1, grid.dimensions.0, grid.dimensions.1
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// A level with everything but the GPU upload done
+pub struct DecodedLevel {
+    pub name: &'static str,
+    // Indices of tiles of the level tileset, see `GeneratedRoom::floor_tiles`
+    pub floor: Vec<Vec<u32>>,
+    pub decoration: RgbaImage,
+    pub collision: Vec<Vec<u32>>,
+    pub enemies: Vec<Vec<u32>>,
+}
+
+impl DecodedLevel {
+    pub fn decode(load_data: &GameLevelLoadData<'_>) -> Result<Self, LoadError> {
+        let level = Self {
+            name: load_data.name,
+            floor: parse_csv_grid(load_data.floor_csv)?,
+            decoration: image::load_from_memory(load_data.decoration_bytes)?.to_rgba8(),
+            collision: parse_csv_grid(load_data.collision_csv)?,
+            enemies: parse_csv_grid(load_data.enemies_csv)?,
+        };
+        check_tile_size(level.name, level.decoration.dimensions(), &level.collision)?;
+        check_same_grid(level.name, &level.floor, &level.collision)?;
+        Ok(level)
+    }
+
+    /// Makes the same checks as `decode`, reading only the size of the
+    /// image, so broken levels are found up front without decoding them
+    pub fn validate(load_data: &GameLevelLoadData<'_>) -> Result<(), LoadError> {
+        let decoration_size = ImageReader::new(Cursor::new(load_data.decoration_bytes))
+            .with_guessed_format()?
+            .into_dimensions()?;
+        let floor = parse_csv_grid(load_data.floor_csv)?;
+        let collision = parse_csv_grid(load_data.collision_csv)?;
+        parse_csv_grid(load_data.enemies_csv)?;
+        check_tile_size(load_data.name, decoration_size, &collision)?;
+        check_same_grid(load_data.name, &floor, &collision)
+    }
+}
+
+pub type LoadResult = (&'static str, Result<DecodedLevel, LoadError>);
+
+/// Decodes waiting for a `poll` to run them, in the browser build
+#[cfg(target_arch = "wasm32")]
+const DECODES_PER_POLL: usize = 1;
+
+pub struct BackgroundLevelLoader {
+    // Each level hears back from its own worker, so one that panics shows up
+    // as an error rather than a level that never arrives
+    pending: Vec<(&'static str, Receiver<Result<DecodedLevel, LoadError>>)>,
+    // Decodes waiting for a `poll` to run them, in the browser build
+    queued: VecDeque<Box<dyn FnOnce() + Send>>,
+}
+
+impl BackgroundLevelLoader {
+    pub fn new() -> Self {
+        Self {
+            pending: Vec::new(),
+            queued: VecDeque::new(),
+        }
+    }
+
+    /// Starts decoding `load_data`, to be picked up by `poll` or `wait`
+    pub fn request(&mut self, load_data: GameLevelLoadData<'static>) {
+        self.request_with(load_data.name, move || DecodedLevel::decode(&load_data));
+    }
+
+    fn request_with<F>(&mut self, name: &'static str, decode: F)
+    where
+        F: FnOnce() -> Result<DecodedLevel, LoadError> + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+        self.pending.push((name, receiver));
+        let job = move || {
+            // The loader going away just means nobody wants the level anymore
+            let _ = sender.send(decode());
+        };
+
+        #[cfg(not(target_arch = "wasm32"))]
+        std::thread::spawn(job);
+        #[cfg(target_arch = "wasm32")]
+        self.queued.push_back(Box::new(job));
+    }
+
+    /// Whether some requested level hasn't been picked up yet
+    pub fn is_loading(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// The levels that finished decoding since the last call, without blocking
+    /// on the workers
+    pub fn poll(&mut self) -> Vec<LoadResult> {
+        #[cfg(target_arch = "wasm32")]
+        for _ in 0..DECODES_PER_POLL {
+            if let Some(job) = self.queued.pop_front() {
+                job();
+            }
+        }
+        self.take_ready(false)
+    }
+
+    /// Blocks until every requested level is decoded, for when a level is
+    /// needed right away
+    pub fn wait(&mut self) -> Vec<LoadResult> {
+        while let Some(job) = self.queued.pop_front() {
+            job();
+        }
+        self.take_ready(true)
+    }
+
+    /// A worker that went away without a result, e.g. by panicking, failed
+    fn take_ready(&mut self, block: bool) -> Vec<LoadResult> {
+        let mut ready = Vec::new();
+        self.pending.retain(|(name, receiver)| {
+            let result = if block {
+                receiver.recv().map_err(|_| TryRecvError::Disconnected)
+            } else {
+                receiver.try_recv()
+            };
+            let result = match result {
+                Ok(result) => result,
+                Err(TryRecvError::Empty) => return true,
+                Err(TryRecvError::Disconnected) => {
+                    Err(format!("Loading level {} stopped before it was done", name).into())
+                }
+            };
+            ready.push((*name, result));
+            false
+        });
+        ready
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        thread,
+    };
+
+    use super::*;
+    use crate::assets;
+
+    #[test]
+    fn decodes_off_the_main_thread() {
+        let mut loader = BackgroundLevelLoader::new();
+        let decoded_on = Arc::new(Mutex::new(None));
+        let recorder = decoded_on.clone();
+        loader.request_with("spawn", move || {
+            *recorder.lock().unwrap() = Some(thread::current().id());
+            DecodedLevel::decode(&assets::embedded_level("spawn").unwrap())
+        });
+        assert!(loader.is_loading());
+
+        let mut ready = loader.wait();
+        assert!(!loader.is_loading());
+        assert_eq!(ready.len(), 1);
+        let (name, result) = ready.pop().unwrap();
+        let level = result.unwrap();
+        assert_eq!(name, "spawn");
+
+        let worker = decoded_on.lock().unwrap().expect("Decode never ran");
+        assert_ne!(worker, thread::current().id());
+
+        // The payload only needs uploading
+        let collision = assets::embedded_level("spawn").unwrap().collision_csv;
+        assert_eq!(level.collision, parse_csv_grid(collision).unwrap());
+        assert_eq!(level.collision.len(), level.enemies.len());
+        assert_eq!(level.floor.len(), level.collision.len());
+        assert!(level.decoration.width() > 0);
+    }
+
+    #[test]
+    fn reports_decode_errors() {
+        let mut loader = BackgroundLevelLoader::new();
+        loader.request(GameLevelLoadData {
+            name: "broken",
+            floor_csv: "",
+            decoration_bytes: &[],
+            collision_csv: "",
+            enemies_csv: "",
+        });
+        let ready = loader.wait();
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].0, "broken");
+        assert!(ready[0].1.is_err());
+    }
+
+    #[test]
+    fn reports_workers_that_panicked() {
+        let mut loader = BackgroundLevelLoader::new();
+        loader.request_with("cursed", || panic!("Out of memory"));
+        loader.request(assets::embedded_level("spawn").unwrap());
+
+        let ready = loader.wait();
+        assert!(!loader.is_loading());
+        assert_eq!(ready.len(), 2);
+        let error = ready[0].1.as_ref().err().expect("The panic went unnoticed");
+        assert_eq!(
+            error.to_string(),
+            "Loading level cursed stopped before it was done"
+        );
+        assert!(ready[1].1.is_ok());
+    }
+
+    #[test]
+    fn detects_mismatched_tile_size() {
+        let spawn = assets::embedded_level("spawn").unwrap();
+        assert!(DecodedLevel::decode(&spawn).is_ok());
+        assert!(DecodedLevel::validate(&spawn).is_ok());
+
+        // Twice as many cells over the same images means tiles of half the size
+        let doubled = |csv: &str| {
+            csv.lines()
+                .flat_map(|row| {
+                    let row = format!("{},{}", row, row);
+                    [row.clone(), row]
+                })
+                .collect::<Vec<_>>()
+                .join("\n")
+        };
+        let collision = doubled(spawn.collision_csv);
+        let enemies = doubled(spawn.enemies_csv);
+        let mismatched = GameLevelLoadData {
+            collision_csv: &collision,
+            enemies_csv: &enemies,
+            ..spawn
+        };
+        let error = DecodedLevel::decode(&mismatched)
+            .err()
+            .expect("Mismatched tile size went unnoticed");
+        assert!(error.to_string().contains("tiles are 32x32"));
+        let error = DecodedLevel::validate(&mismatched).unwrap_err();
+        assert!(error.to_string().contains("tiles are 32x32"));
+
+        let floor = doubled(spawn.floor_csv);
+        let error = DecodedLevel::decode(&GameLevelLoadData {
+            floor_csv: &floor,
+            ..spawn
+        })
+        .err()
+        .expect("Mismatched floor went unnoticed");
+        assert!(error.to_string().contains("floor over"));
+
+        let (width, height) = (TILE_SIZE.0 * 3, TILE_SIZE.1 * 2);
+        assert!(check_tile_size("grid", (width, height), &[vec![0; 3], vec![0; 3]]).is_ok());
+        assert!(check_tile_size("grid", (width, height), &[vec![0; 2], vec![0; 2]]).is_err());
+    }
+}
@@ -0,0 +1,74 @@
+//! Buckets points into a grid of square cells, so looking for the points near
+//! a spot only visits the cells around it instead of every point.
+
+use std::collections::HashMap;
+
+use glam::{IVec2, Vec2};
+
+pub struct SpatialHash {
+    cell_size: f32,
+    cells: HashMap<IVec2, Vec<(usize, Vec2)>>,
+}
+
+impl SpatialHash {
+    pub fn new(cell_size: f32) -> Self {
+        Self {
+            cell_size,
+            cells: HashMap::new(),
+        }
+    }
+
+    fn cell_of(&self, point: Vec2) -> IVec2 {
+        (point / self.cell_size).floor().as_ivec2()
+    }
+
+    pub fn insert(&mut self, id: usize, point: Vec2) {
+        self.cells
+            .entry(self.cell_of(point))
+            .or_default()
+            .push((id, point));
+    }
+
+    /// Ids of the points within `radius` of `center`, in a stable order
+    pub fn within(&self, center: Vec2, radius: f32) -> Vec<usize> {
+        let min = self.cell_of(center - radius);
+        let max = self.cell_of(center + radius);
+        let mut found = Vec::new();
+        for y in min.y..=max.y {
+            for x in min.x..=max.x {
+                let Some(cell) = self.cells.get(&IVec2::new(x, y)) else {
+                    continue;
+                };
+                found.extend(
+                    cell.iter()
+                        .filter(|(_, point)| point.distance(center) <= radius)
+                        .map(|(id, _)| *id),
+                );
+            }
+        }
+        found
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_points_across_cell_borders() {
+        let mut hash = SpatialHash::new(1.0);
+        hash.insert(0, Vec2::new(0.9, 0.9));
+        hash.insert(1, Vec2::new(1.1, 1.1));
+        hash.insert(2, Vec2::new(-0.2, 1.0));
+        hash.insert(3, Vec2::new(5.0, 5.0));
+
+        let mut near = hash.within(Vec2::new(1.0, 1.0), 0.5);
+        near.sort();
+        assert_eq!(near, vec![0, 1]);
+
+        let mut wider = hash.within(Vec2::new(1.0, 1.0), 1.5);
+        wider.sort();
+        assert_eq!(wider, vec![0, 1, 2]);
+        assert!(hash.within(Vec2::new(-3.0, -3.0), 1.0).is_empty());
+    }
+}
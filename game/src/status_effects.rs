@@ -0,0 +1,151 @@
+//! Effects that linger on a character for a while, ticking every update.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StatusEffectKind {
+    /// Drains health over time. Reapplying refreshes the duration.
+    Poison { damage_per_second: f32 },
+    /// Drains health over time. Every application stacks on top of the others.
+    Burn { damage_per_second: f32 },
+    /// Multiplies movement speed. Only the strongest slow applies, while the
+    /// weaker ones keep running out underneath it.
+    Slow { speed_factor: f32 },
+}
+
+#[derive(Debug, Clone, Copy)]
+struct StatusEffect {
+    kind: StatusEffectKind,
+    duration_left: f32,
+}
+
+/// What the active effects did over one update
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StatusTick {
+    pub damage: f32,
+    pub speed_multiplier: f32,
+}
+
+pub struct StatusEffects {
+    active: Vec<StatusEffect>,
+}
+
+impl StatusEffects {
+    pub fn new() -> Self {
+        Self { active: Vec::new() }
+    }
+
+    pub fn apply(&mut self, kind: StatusEffectKind, duration: f32) {
+        let refreshed = match kind {
+            StatusEffectKind::Burn { .. } => None,
+            // Each slow keeps its own duration, only the same one is refreshed
+            StatusEffectKind::Slow { .. } => {
+                self.active.iter_mut().find(|effect| effect.kind == kind)
+            }
+            StatusEffectKind::Poison { .. } => self
+                .active
+                .iter_mut()
+                .find(|effect| matches!(effect.kind, StatusEffectKind::Poison { .. })),
+        };
+        match refreshed {
+            Some(effect) => {
+                effect.kind = kind;
+                effect.duration_left = effect.duration_left.max(duration);
+            }
+            None => self.active.push(StatusEffect {
+                kind,
+                duration_left: duration,
+            }),
+        }
+    }
+
+    /// The effect put on most recently, not counting refreshes
+    pub fn newest(&self) -> Option<StatusEffectKind> {
+        self.active.last().map(|effect| effect.kind)
+    }
+
+    /// Removes every active effect
+    pub fn cure(&mut self) {
+        self.active.clear();
+    }
+
+    pub fn update(&mut self, delta_time: f32) -> StatusTick {
+        let mut tick = StatusTick {
+            damage: 0.0,
+            speed_multiplier: 1.0,
+        };
+        for effect in &mut self.active {
+            // Don't tick past the end of the effect
+            let active_time = delta_time.min(effect.duration_left);
+            match effect.kind {
+                StatusEffectKind::Poison { damage_per_second }
+                | StatusEffectKind::Burn { damage_per_second } => {
+                    tick.damage += damage_per_second * active_time;
+                }
+                StatusEffectKind::Slow { speed_factor } => {
+                    tick.speed_multiplier = tick.speed_multiplier.min(speed_factor);
+                }
+            }
+            effect.duration_left -= delta_time;
+        }
+        self.active.retain(|effect| effect.duration_left > 0.0);
+        tick
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn poison_drains_over_its_duration_and_expires() {
+        let mut effects = StatusEffects::new();
+        effects.apply(
+            StatusEffectKind::Poison {
+                damage_per_second: 4.0,
+            },
+            2.0,
+        );
+
+        let mut damage = 0.0;
+        for _ in 0..30 {
+            damage += effects.update(0.1).damage;
+        }
+        assert!((damage - 8.0).abs() < 1e-4);
+        assert!(effects.active.is_empty());
+        assert_eq!(effects.update(0.1).damage, 0.0);
+    }
+
+    #[test]
+    fn burns_stack_and_only_the_strongest_slow_applies() {
+        let mut effects = StatusEffects::new();
+        let burn = StatusEffectKind::Burn {
+            damage_per_second: 1.0,
+        };
+        effects.apply(burn, 1.0);
+        effects.apply(burn, 1.0);
+        effects.apply(StatusEffectKind::Slow { speed_factor: 0.5 }, 1.0);
+        effects.apply(StatusEffectKind::Slow { speed_factor: 0.8 }, 1.0);
+
+        let tick = effects.update(0.5);
+        assert!((tick.damage - 1.0).abs() < 1e-6);
+        assert_eq!(tick.speed_multiplier, 0.5);
+        assert_eq!(effects.active.len(), 4);
+
+        // The same slow again only refreshes it
+        effects.apply(StatusEffectKind::Slow { speed_factor: 0.5 }, 1.0);
+        assert_eq!(effects.active.len(), 4);
+    }
+
+    #[test]
+    fn weaker_slows_outlast_stronger_ones() {
+        let mut effects = StatusEffects::new();
+        effects.apply(StatusEffectKind::Slow { speed_factor: 0.8 }, 3.0);
+        effects.apply(StatusEffectKind::Slow { speed_factor: 0.5 }, 1.0);
+
+        assert_eq!(effects.update(0.5).speed_multiplier, 0.5);
+        // Only the strong slow ran out
+        assert_eq!(effects.update(1.0).speed_multiplier, 0.5);
+        assert_eq!(effects.update(1.0).speed_multiplier, 0.8);
+        assert_eq!(effects.update(1.0).speed_multiplier, 0.8);
+        assert_eq!(effects.update(0.1).speed_multiplier, 1.0);
+    }
+}
@@ -0,0 +1,103 @@
+//! A number that eases towards its target at a fixed speed, for animated HUD
+//! values like counters and bars.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TweenState {
+    Idle,
+    Active { duration: f32 },
+}
+
+pub struct Tween {
+    pub current: f32,
+    pub target: f32,
+    /// Units per second
+    pub speed: f32,
+    state: TweenState,
+}
+
+impl Tween {
+    pub fn new(current: f32, speed: f32) -> Self {
+        Self {
+            current,
+            target: current,
+            speed,
+            state: TweenState::Idle,
+        }
+    }
+
+    pub fn update(&mut self, delta_time: f32) {
+        let step = self.speed * delta_time;
+        let delta = self.target - self.current;
+        if delta.abs() < step {
+            self.current = self.target; // Snap to target if within reach
+        } else {
+            self.current += step * delta.signum();
+        }
+
+        self.state = if self.current == self.target {
+            TweenState::Idle
+        } else {
+            match self.state {
+                TweenState::Idle => TweenState::Active { duration: 0.0 },
+                TweenState::Active { duration } => TweenState::Active {
+                    duration: duration + delta_time,
+                },
+            }
+        };
+    }
+
+    #[cfg(test)]
+    pub fn is_active(&self) -> bool {
+        matches!(self.state, TweenState::Active { .. })
+    }
+
+    /// How long the value has been moving, or 0 when it's at rest
+    pub fn active_duration(&self) -> f32 {
+        match self.state {
+            TweenState::Idle => 0.0,
+            TweenState::Active { duration } => duration,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn converge(tween: &mut Tween) {
+        let distance = (tween.target - tween.current).abs();
+        let mut previous_distance = distance;
+        tween.update(0.1);
+        assert!(tween.is_active());
+        for _ in 0..100 {
+            tween.update(0.1);
+            let distance = (tween.target - tween.current).abs();
+            assert!(distance <= previous_distance);
+            previous_distance = distance;
+        }
+        assert_eq!(tween.current, tween.target);
+        assert!(!tween.is_active());
+        assert_eq!(tween.active_duration(), 0.0);
+    }
+
+    #[test]
+    fn converges_from_both_directions() {
+        let mut tween = Tween::new(0.0, 10.0);
+        assert!(!tween.is_active());
+
+        tween.target = 25.0;
+        converge(&mut tween);
+        tween.target = 3.0;
+        converge(&mut tween);
+    }
+
+    #[test]
+    fn active_duration_grows_while_moving() {
+        let mut tween = Tween::new(0.0, 1.0);
+        tween.target = 10.0;
+        tween.update(0.5);
+        tween.update(0.5);
+        tween.update(0.5);
+        assert_eq!(tween.active_duration(), 1.0);
+    }
+}